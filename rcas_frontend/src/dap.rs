@@ -0,0 +1,546 @@
+use crate::{Mode, Script, parse_script, read_file};
+use anyhow::{Context as _, Result, anyhow, bail};
+use rcas_lib::context::{Capability, Context, Output};
+use rcas_lib::debugger::{Breakpoint, DebugController, StopReason};
+use rcas_lib::limits::ExecutionLimits;
+use rcas_lib::stack_machine::{BufferedExecutor, RevPolStackMachine};
+use serde_json::{Value, json};
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, channel};
+
+const THREAD_ID: i64 = 1;
+const STACK_REFERENCE: i64 = 1;
+const VARIABLES_REFERENCE: i64 = 2;
+
+/// Everything but process control, so the script cannot end the adapter. Stdout is where the
+/// protocol itself goes, so whatever the script shows is buffered and sent on as output events.
+fn debuggee_context() -> Context {
+    Context {
+        output: Output::buffer(),
+        ..Context::with_capabilities(&[
+            Capability::Core,
+            Capability::Math,
+            Capability::Io,
+            Capability::Fs,
+            Capability::Debug,
+        ])
+    }
+}
+
+/// The script being debugged
+struct Session {
+    controller: DebugController,
+    script: Script,
+    source: String,
+    path: String,
+    stop_on_entry: bool,
+    /// Set once the script has failed, it is not run any further after that
+    failed: bool,
+}
+impl Session {
+    /// Source line the executor is at, or the last line once it is done
+    fn source_line(&self) -> usize {
+        let line = self.controller.executor().current_line;
+        let lines = &self.script.source_lines;
+        lines.get(line).or(lines.last()).copied().unwrap_or(1)
+    }
+}
+
+/// A Debug Adapter Protocol server for .mir scripts. It reads requests from `input` and writes
+/// responses and events to `output`, which are stdin and stdout when run by an editor.
+pub struct Server<W> {
+    /// Requests read on another thread, so a pause can get through while the script runs
+    requests: Receiver<Result<Value>>,
+    /// Set by the reader thread when a pause comes in, which stops the controller
+    pause: Arc<AtomicBool>,
+    output: W,
+    seq: i64,
+    mode: Mode,
+    session: Option<Session>,
+    /// Source lines with a breakpoint, kept so they can be set before the script is launched
+    line_breakpoints: Vec<usize>,
+    function_breakpoints: Vec<String>,
+    /// Controller breakpoint ids, and the ids the client knows the same breakpoints by
+    breakpoint_ids: Vec<(usize, usize)>,
+}
+impl<W: Write> Server<W> {
+    pub fn new<R: BufRead + Send + 'static>(mut input: R, output: W, mode: Mode) -> Self {
+        let (sender, requests) = channel();
+        let pause = Arc::new(AtomicBool::new(false));
+
+        let pause_flag = pause.clone();
+        std::thread::spawn(move || {
+            // Stops once the input is closed, after a bad message, or once the server has stopped
+            // listening. Dropping the sender lets the server know there is nothing more to read.
+            while let Some(message) = read_message(&mut input).transpose() {
+                let failed = message.is_err();
+                if let Ok(request) = &message
+                    && request["command"] == "pause"
+                {
+                    pause_flag.store(true, Ordering::Relaxed);
+                }
+                if sender.send(message).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Server {
+            requests,
+            pause,
+            output,
+            seq: 1,
+            mode,
+            session: None,
+            line_breakpoints: vec![],
+            function_breakpoints: vec![],
+            breakpoint_ids: vec![],
+        }
+    }
+
+    /// Serves requests until the client disconnects or closes the input
+    pub fn run(&mut self) -> Result<()> {
+        while let Ok(request) = self.requests.recv() {
+            let request = request?;
+            let command = request["command"].as_str().unwrap_or_default().to_string();
+            let args = &request["arguments"];
+
+            let result = match command.as_str() {
+                "initialize" => Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                })),
+                "launch" => self.launch(args),
+                "setBreakpoints" => self.set_breakpoints(args),
+                "setFunctionBreakpoints" => self.set_function_breakpoints(args),
+                "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+                "configurationDone" | "continue" | "next" | "stepIn" | "stepOut" | "pause" => {
+                    self.session().map(|_| json!({}))
+                }
+                "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+                "stackTrace" => self.stack_trace(),
+                "scopes" => Ok(json!({ "scopes": [
+                    { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+                    { "name": "Variables", "variablesReference": VARIABLES_REFERENCE, "expensive": false },
+                ]})),
+                "variables" => self.variables(args),
+                "disconnect" | "terminate" => Ok(json!({})),
+                other => Err(anyhow!("Unsupported request {}", other)),
+            };
+
+            self.respond(&request, result)?;
+
+            // Anything that runs the script does so once the client knows the request went through
+            match command.as_str() {
+                "initialize" => self.event("initialized", json!({}))?,
+                "configurationDone" => self.start()?,
+                "continue" => self.execute(DebugController::resume)?,
+                "next" => self.execute(DebugController::step_over)?,
+                "stepIn" => self.execute(DebugController::step_into)?,
+                // There is only ever one frame, so stepping out of it runs on until something
+                // stops the script
+                "stepOut" => self.execute(DebugController::resume)?,
+                "pause" => self.pause()?,
+                "disconnect" | "terminate" => break,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value>) -> Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(e) => response["message"] = json!(format!("{:#}", e)),
+        }
+
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn session(&self) -> Result<&Session> {
+        self.session
+            .as_ref()
+            .ok_or_else(|| anyhow!("No script has been launched"))
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value> {
+        let Some(path) = args["program"].as_str() else {
+            bail!("launch needs the path of the program to debug")
+        };
+
+        let source = read_file(path)?;
+        let context = debuggee_context();
+        let script = parse_script(&source, self.mode, &context)
+            .with_context(|| format!("Failed to parse <{}>", path))?;
+
+        let machine = RevPolStackMachine::new_with_ctx(context);
+        let executor = BufferedExecutor::new_with_limits(
+            machine,
+            script.lines.clone(),
+            ExecutionLimits::new(),
+        );
+        let mut controller =
+            DebugController::new(executor).with_source_lines(script.source_lines.clone());
+        controller.set_interrupt_flag(self.pause.clone());
+
+        self.session = Some(Session {
+            controller,
+            script,
+            source,
+            path: path.to_string(),
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+            failed: false,
+        });
+        self.apply_breakpoints();
+
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value> {
+        self.line_breakpoints = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|bp| bp["line"].as_u64())
+            .map(|line| line as usize)
+            .collect();
+        self.apply_breakpoints();
+
+        // A breakpoint on a line with nothing to run moves to the next line that has something
+        let session = self.session.as_ref();
+        let breakpoints = self
+            .line_breakpoints
+            .iter()
+            .enumerate()
+            .map(|(idx, line)| {
+                let actual = session.and_then(|s| {
+                    let idx = s.controller.line_for(*line)?;
+                    Some(s.script.source_lines[idx])
+                });
+                json!({
+                    "id": idx + 1,
+                    "verified": session.is_none() || actual.is_some(),
+                    "line": actual.unwrap_or(*line),
+                })
+            })
+            .collect::<Vec<Value>>();
+
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_function_breakpoints(&mut self, args: &Value) -> Result<Value> {
+        self.function_breakpoints = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|bp| bp["name"].as_str())
+            .map(String::from)
+            .collect();
+        self.apply_breakpoints();
+
+        // Function breakpoints are numbered after the line ones
+        let first_id = self.line_breakpoints.len() + 1;
+        let breakpoints = self
+            .function_breakpoints
+            .iter()
+            .enumerate()
+            .map(|(idx, _)| json!({ "id": first_id + idx, "verified": true }))
+            .collect::<Vec<Value>>();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// Sets every breakpoint on the controller. The client knows them by their position in the
+    /// line breakpoints followed by the function breakpoints, counting from 1.
+    fn apply_breakpoints(&mut self) {
+        let Some(session) = &mut self.session else {
+            return;
+        };
+        let controller = &mut session.controller;
+        controller.clear_breakpoints();
+        self.breakpoint_ids.clear();
+
+        for (idx, line) in self.line_breakpoints.iter().enumerate() {
+            if controller.line_for(*line).is_some() {
+                let id = controller.add_breakpoint(Breakpoint::Line(*line));
+                self.breakpoint_ids.push((id, idx + 1));
+            }
+        }
+
+        let first_id = self.line_breakpoints.len() + 1;
+        for (idx, name) in self.function_breakpoints.iter().enumerate() {
+            let id = controller.add_breakpoint(Breakpoint::Functor(name.clone()));
+            self.breakpoint_ids.push((id, first_id + idx));
+        }
+    }
+
+    fn stack_trace(&self) -> Result<Value> {
+        let session = self.session()?;
+        let line = session.source_line();
+        let name = session
+            .source
+            .lines()
+            .nth(line - 1)
+            .unwrap_or_default()
+            .trim();
+
+        Ok(json!({
+            "stackFrames": [{
+                "id": 0,
+                "name": name,
+                "line": line,
+                "column": 1,
+                "source": { "name": session.path, "path": session.path },
+            }],
+            "totalFrames": 1,
+        }))
+    }
+
+    fn variables(&self, args: &Value) -> Result<Value> {
+        let machine = &self.session()?.controller.executor().machine;
+
+        let variables = match args["variablesReference"].as_i64() {
+            // Top of the stack first, as that is what the next functor takes
+            Some(STACK_REFERENCE) => machine
+                .stack
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(idx, token)| variable(&format!("[{}]", idx), token.to_string()))
+                .collect(),
+            Some(VARIABLES_REFERENCE) => machine
+                .context
+                .variables
+                .iter()
+                .map(|var| {
+                    let value = var
+                        .value
+                        .as_ref()
+                        .map_or("<unbound>".into(), |v| v.to_string());
+                    variable(&var.name, value)
+                })
+                .collect(),
+            _ => vec![],
+        };
+
+        Ok(json!({ "variables": variables }))
+    }
+
+    /// Runs the script once the client has set everything up
+    fn start(&mut self) -> Result<()> {
+        let Some(session) = &self.session else {
+            return Ok(());
+        };
+        if session.stop_on_entry {
+            return self.stopped("entry", None);
+        }
+        self.execute(DebugController::resume)
+    }
+
+    /// Runs the script until the controller stops it, then tells the client why
+    fn execute(&mut self, run: fn(&mut DebugController) -> StopReason) -> Result<()> {
+        let Some(session) = &mut self.session else {
+            return Ok(());
+        };
+        if session.failed {
+            return self.finish(1);
+        }
+
+        let reason = run(&mut session.controller);
+
+        let output = session.controller.executor().machine.context.output.take();
+        if !output.is_empty() {
+            self.event("output", json!({ "category": "stdout", "output": output }))?;
+        }
+
+        match reason {
+            StopReason::Step => self.stopped("step", None),
+            StopReason::Breakpoint(id) => self.stopped("breakpoint", Some(id)),
+            StopReason::Pause | StopReason::Interrupted => self.stopped("pause", None),
+            StopReason::Error(e) => {
+                if let Some(session) = &mut self.session {
+                    session.failed = true;
+                }
+                self.event(
+                    "output",
+                    json!({ "category": "stderr", "output": format!("error: {:#}\n", e) }),
+                )?;
+                self.event(
+                    "stopped",
+                    json!({
+                        "reason": "exception",
+                        "description": format!("{:#}", e),
+                        "threadId": THREAD_ID,
+                    }),
+                )
+            }
+            StopReason::Finished => self.finish(0),
+        }
+    }
+
+    /// A pause which came in while the script was running has already stopped it, otherwise it
+    /// is stopped where it is
+    fn pause(&mut self) -> Result<()> {
+        let Some(session) = &self.session else {
+            return Ok(());
+        };
+        let done = session.failed || session.controller.is_finished();
+        if self.pause.swap(false, Ordering::Relaxed) && !done {
+            return self.stopped("pause", None);
+        }
+        Ok(())
+    }
+
+    fn stopped(&mut self, reason: &str, breakpoint: Option<usize>) -> Result<()> {
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID });
+        let hit = self
+            .breakpoint_ids
+            .iter()
+            .find(|(id, _)| Some(*id) == breakpoint);
+        if let Some((_, client_id)) = hit {
+            body["hitBreakpointIds"] = json!([client_id]);
+        }
+        self.event("stopped", body)
+    }
+
+    fn finish(&mut self, exit_code: i32) -> Result<()> {
+        self.event("exited", json!({ "exitCode": exit_code }))?;
+        self.event("terminated", json!({}))
+    }
+}
+
+/// Reads one message, or None once the input is closed
+fn read_message(input: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut length = None;
+
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse::<usize>()?);
+        }
+    }
+
+    let Some(length) = length else {
+        bail!("Message without a Content-Length header")
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Plays the requests against a server the way an editor would, returning every message it
+    /// sent back
+    fn session(requests: &[Value]) -> Vec<Value> {
+        let mut input = String::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            let body = request.to_string();
+            input.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        }
+
+        let mut output = vec![];
+        Server::new(Cursor::new(input), &mut output, Mode::Rpn)
+            .run()
+            .unwrap();
+
+        let mut output = Cursor::new(output);
+        std::iter::from_fn(|| read_message(&mut output).unwrap()).collect()
+    }
+
+    fn response<'a>(messages: &'a [Value], command: &str) -> &'a Value {
+        messages
+            .iter()
+            .find(|m| m["type"] == "response" && m["command"] == command)
+            .unwrap_or_else(|| panic!("no response to {}", command))
+    }
+
+    fn events<'a>(messages: &'a [Value], event: &str) -> Vec<&'a Value> {
+        messages.iter().filter(|m| m["event"] == event).collect()
+    }
+
+    #[test]
+    fn stops_at_a_breakpoint_and_runs_to_the_end() {
+        rcas_lib::set_debug_logging(false);
+        let path = std::env::temp_dir().join(format!("rcas_dap_{}.mir", std::process::id()));
+        std::fs::write(&path, "2 3 +\nx 7 :=\nPrint\n\"x\" \"x\" 0 1 \"-\" Plot\n").unwrap();
+        let program = path.to_str().unwrap();
+
+        let messages = session(&[
+            json!({ "command": "initialize", "arguments": {} }),
+            json!({ "command": "launch", "arguments": { "program": program } }),
+            json!({ "command": "setBreakpoints", "arguments": { "breakpoints": [{ "line": 3 }] } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "variables", "arguments": { "variablesReference": STACK_REFERENCE } }),
+            json!({ "command": "stepOut" }),
+            json!({ "command": "disconnect" }),
+        ]);
+        std::fs::remove_file(&path).unwrap();
+
+        let failed = messages.iter().find(|m| m["success"] == false);
+        assert!(failed.is_none(), "{:?}", failed);
+
+        let stopped = events(&messages, "stopped");
+        assert_eq!(stopped[0]["body"]["reason"], "breakpoint");
+        assert_eq!(stopped[0]["body"]["hitBreakpointIds"], json!([1]));
+
+        let stack = &response(&messages, "variables")["body"]["variables"];
+        assert_eq!(stack[0]["value"], "5");
+
+        // Print and the terminal plot go to the client instead of stdout
+        let output = events(&messages, "output")
+            .iter()
+            .map(|e| e["body"]["output"].as_str().unwrap().to_string())
+            .collect::<String>();
+        assert!(output.starts_with("5\n"));
+        assert!(output.lines().count() > 2);
+
+        assert_eq!(events(&messages, "exited")[0]["body"]["exitCode"], 0);
+    }
+}
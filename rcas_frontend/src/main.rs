@@ -1,36 +1,542 @@
+mod dap;
+mod repl;
+mod report;
+
+use anyhow::{Context as _, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use rcas_lib::{
     context::{Context, get_default_functions},
-    // debugger::{BufExecDebugger, IntoDebugger, Stepper},
-    parse_rpol_notation::*,
+    debugger::{BufExecDebugger, RevPolBufSnapshot, StepMode, Stepper},
+    limits::ExecutionLimits,
+    parse_infix::infix_to_commands,
+    parse_rpol_notation::{
+        Span, SpannedError, spanned_commands_to_exec_order, split_into_spanned_commands,
+    },
+    pretty::PrettyStyle,
     stack_machine::{BufferedExecutor, RevPolStackMachine},
+    token_defs::Token,
+    trace::TraceDebugger,
 };
+use std::io::Write;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+/// A Rust Computer Algebra System
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    #[command(flatten)]
+    options: Options,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a .mir script
+    Run {
+        file: String,
+        /// Print whatever is left on the stack once the script is done
+        #[arg(long)]
+        print_stack: bool,
+        /// Print how long each functor and each line took to stderr once the script is done
+        #[arg(long)]
+        profile: bool,
+        /// Write how long each functor on each line took to this file, as folded stacks for
+        /// flamegraph tools
+        #[arg(long)]
+        flamegraph: Option<String>,
+    },
+    /// Evaluate a single expression and print the resulting stack
+    Eval { expr: String },
+    /// Parse a .mir script without running it
+    Check { file: String },
+    /// Run a .mir script line by line, printing the state after each line
+    Debug {
+        file: String,
+        /// Step a token at a time, showing each functor call inside a line
+        #[arg(long)]
+        tokens: bool,
+        /// Record every step to this trace file, implies --tokens
+        #[arg(long)]
+        trace: Option<String>,
+        /// Write the snapshot after every step to this file as JSON lines (- for stdout) instead
+        /// of showing the steps
+        #[arg(long)]
+        trace_json: Option<String>,
+    },
+    /// Start an interactive session
+    Repl,
+    /// Serve the Debug Adapter Protocol on stdin and stdout, so editors can debug .mir scripts
+    Dap,
+}
+
+/// How input is written
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Mode {
+    /// Reverse polish notation, e.g. `1 2 +`
+    Rpn,
+    /// Infix notation, one expression per line, e.g. `1 + 2`
+    Infix,
+}
+
+/// How results are printed
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// One token per line
+    Text,
+    /// A JSON array of tokens
+    Json,
+}
+
+/// How expressions are drawn
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Pretty {
+    /// Over several lines, in ASCII
+    Ascii,
+    /// Over several lines, with box drawing characters
+    Unicode,
+}
+
+#[derive(Args)]
+struct Options {
+    /// Number of digits shown after the decimal point of floats
+    #[arg(long, global = true)]
+    precision: Option<usize>,
+
+    /// Notation the input is written in
+    #[arg(long, global = true, value_enum, default_value_t = Mode::Rpn)]
+    mode: Mode,
+
+    /// Format of printed results
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Draw expressions over several lines, with fractions over bars and raised exponents. Can
+    /// also be switched with PrettyPrint.
+    #[arg(long, global = true, value_enum)]
+    pretty: Option<Pretty>,
+
+    /// Maximum number of execution steps
+    #[arg(long, global = true)]
+    max_steps: Option<usize>,
+
+    /// Maximum number of tokens on the stack
+    #[arg(long, global = true)]
+    max_stack_depth: Option<usize>,
+
+    /// Maximum bit length of any integer
+    #[arg(long, global = true)]
+    max_int_bits: Option<u64>,
+
+    /// Maximum run time, in milliseconds
+    #[arg(long, global = true)]
+    timeout: Option<u64>,
+
+    /// Print the executor's debug log
+    #[arg(long, short, global = true)]
+    verbose: bool,
+}
+impl Options {
+    /// The default context, set up to print the way these options ask for
+    fn context(&self) -> Context {
+        let mut context = default_context();
+        context.pretty_print = self.pretty.map(|pretty| match pretty {
+            Pretty::Ascii => PrettyStyle::Ascii,
+            Pretty::Unicode => PrettyStyle::Unicode,
+        });
+        context
+    }
+
+    fn limits(&self) -> ExecutionLimits {
+        ExecutionLimits {
+            max_steps: self.max_steps,
+            max_stack_depth: self.max_stack_depth,
+            max_int_bits: self.max_int_bits,
+            deadline: self
+                .timeout
+                .map(|ms| Instant::now() + Duration::from_millis(ms)),
+            cancellation: None,
+        }
+    }
+}
 
-fn main() {
-    let file = "D:\\rcas\\test_files\\test_file.mir";
-    println!("{}", file);
+/// Parsed source, along with the source line each executor line came from
+pub struct Script {
+    pub lines: Vec<Vec<Token>>,
+    pub source_lines: Vec<usize>,
+}
+
+/// Parses source text in the given mode
+fn parse_script(source: &str, mode: Mode, context: &Context) -> Result<Script> {
+    let commands = match mode {
+        Mode::Rpn => split_into_spanned_commands(source),
+        Mode::Infix => infix_to_spanned_commands(source, context)?,
+    };
 
-    let file_content = get_content_at(file.to_string()).unwrap();
+    Ok(Script {
+        lines: spanned_commands_to_exec_order(&commands, context)?,
+        source_lines: commands.iter().map(|line| line[0].1.line).collect(),
+    })
+}
 
-    let commands: Vec<Vec<String>> = split_into_commands(file_content);
+/// Converts every source line from infix, pointing each resulting command at the line it came from.
+/// An expression with a parenthesis or string still open carries on over the line break.
+fn infix_to_spanned_commands(source: &str, context: &Context) -> Result<Vec<Vec<(String, Span)>>> {
+    let mut commands = vec![];
+    let mut lines = source.split('\n').map(|line| line.trim_end_matches('\r'));
+    let mut idx = 0;
+
+    while let Some(first) = lines.next() {
+        idx += 1;
+        if first.trim().is_empty() || first.starts_with("//") {
+            continue;
+        }
+
+        let span = Span {
+            line: idx,
+            column: 1,
+            len: first.chars().count(),
+        };
+        let mut line = first.to_string();
+        while is_unfinished(&line, Mode::Infix)
+            && let Some(next) = lines.next()
+        {
+            idx += 1;
+            line.push('\n');
+            line.push_str(next);
+        }
+        let line = line.as_str();
+        let converted = infix_to_commands(line, context).map_err(|e| SpannedError {
+            span,
+            message: e.to_string(),
+        })?;
+
+        commands.extend(
+            converted
+                .into_iter()
+                .map(|cmds| cmds.into_iter().map(|cmd| (cmd, span)).collect()),
+        );
+    }
+
+    Ok(commands)
+}
 
+/// Whether input stops partway through a string, or in infix partway through a parenthesis, so
+/// the next line is part of it
+fn is_unfinished(input: &str, mode: Mode) -> bool {
+    let unclosed_quote = input.chars().filter(|c| *c == '"').count() % 2 == 1;
+    let unclosed_paren = mode == Mode::Infix
+        && input.chars().filter(|c| *c == '(').count()
+            > input.chars().filter(|c| *c == ')').count();
+    unclosed_quote || unclosed_paren
+}
+
+fn default_context() -> Context {
     let mut context = Context::new();
     context.functions.append(&mut get_default_functions());
+    context
+}
+
+fn read_file(file: &str) -> Result<String> {
+    std::fs::read_to_string(file).with_context(|| format!("Failed to read file <{}>", file))
+}
+
+/// Formats a token for output, rounding floats to the requested precision and otherwise writing
+/// it the way the context asks for
+fn format_token(token: &Token, context: &Context, precision: Option<usize>) -> String {
+    match (token, precision) {
+        (Token::Const(num), Some(digits)) => num.to_string_with_precision(digits),
+        (token, _) => context.show(token),
+    }
+}
+
+fn print_stack(stack: &[Token], context: &Context, options: &Options) {
+    let items = stack
+        .iter()
+        .map(|token| format_token(token, context, options.precision));
+
+    match options.format {
+        Format::Text => items.for_each(|item| println!("{}", item)),
+        Format::Json => println!(
+            "{}",
+            serde_json::Value::Array(items.map(serde_json::Value::String).collect())
+        ),
+    }
+}
 
-    let exec_cmds = commands_to_sequential_exec_order(commands, &context);
+/// Runs a script, pointing at the offending line of the source if it fails. Returns the executor
+/// along with the source line each of its lines came from.
+fn execute(
+    source: &str,
+    name: &str,
+    profile: bool,
+    options: &Options,
+) -> Result<(BufferedExecutor, Vec<usize>), ExitCode> {
+    let context = options.context();
+    let script = parse_script(source, options.mode, &context)
+        .map_err(|e| report::error(&e, source, name, None))?;
+
+    let machine = RevPolStackMachine::new_with_ctx(context);
+    let mut executor = BufferedExecutor::new_with_limits(machine, script.lines, options.limits());
+    if profile {
+        executor.enable_profiling();
+    }
+
+    executor.run_stack().map_err(|e| {
+        let line = script.source_lines.get(executor.current_line).copied();
+        report::error(&e, source, name, line)
+    })?;
+
+    Ok((executor, script.source_lines))
+}
+
+/// What the run command does besides running the script
+struct RunOptions<'a> {
+    print_stack: bool,
+    profile: bool,
+    flamegraph: Option<&'a str>,
+}
+
+fn run(file: &str, run_options: &RunOptions, options: &Options) -> Result<(), ExitCode> {
+    let source = read_file(file).map_err(|e| report::error(&e, "", file, None))?;
+    let profile = run_options.profile || run_options.flamegraph.is_some();
+    let (executor, source_lines) = execute(&source, file, profile, options)?;
+
+    if run_options.print_stack {
+        print_stack(&executor.machine.stack, &executor.machine.context, options);
+    }
+
+    if let Some(profiler) = &executor.profiler {
+        let line_name = |line: usize| format!("{}:{}", file, source_lines[line]);
+
+        if run_options.profile {
+            eprint!("{}", profiler.report(line_name));
+        }
+        if let Some(path) = run_options.flamegraph {
+            std::fs::File::create(path)
+                .and_then(|out| profiler.write_folded(std::io::BufWriter::new(out), line_name))
+                .map_err(|e| report::error(&e.into(), "", path, None))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn eval(expr: &str, options: &Options) -> Result<(), ExitCode> {
+    let (executor, _) = execute(expr, "<expr>", false, options)?;
+    print_stack(&executor.machine.stack, &executor.machine.context, options);
+    Ok(())
+}
+
+fn check(file: &str, options: &Options) -> Result<(), ExitCode> {
+    let source = read_file(file).map_err(|e| report::error(&e, "", file, None))?;
+    let script = parse_script(&source, options.mode, &default_context())
+        .map_err(|e| report::error(&e, &source, file, None))?;
+
+    println!("{}: ok ({} lines)", file, script.lines.len());
+    Ok(())
+}
+
+/// How the debug command runs a script
+struct DebugOptions<'a> {
+    tokens: bool,
+    trace: Option<&'a str>,
+    trace_json: Option<&'a str>,
+}
+
+fn debug(file: &str, debug_options: &DebugOptions, options: &Options) -> Result<(), ExitCode> {
+    let source = read_file(file).map_err(|e| report::error(&e, "", file, None))?;
+    let context = options.context();
+    let script = parse_script(&source, options.mode, &context)
+        .map_err(|e| report::error(&e, &source, file, None))?;
+
+    let machine = RevPolStackMachine::new_with_ctx(context);
+    let executor =
+        BufferedExecutor::new_with_limits(machine, script.lines.clone(), options.limits());
+
+    let Some(path) = debug_options.trace else {
+        let mode = if debug_options.tokens {
+            StepMode::Token
+        } else {
+            StepMode::Line
+        };
+        let debugger = Stepper::new(BufExecDebugger::new(executor).with_mode(mode));
+        return output_frames(debugger, debug_options, &script, &source, file, options);
+    };
+
+    // The trace is written even if the script fails, that is when it is most useful
+    let mut debugger = Stepper::new(TraceDebugger::new(executor));
+    let result = output_frames(
+        &mut debugger,
+        debug_options,
+        &script,
+        &source,
+        file,
+        options,
+    );
+    debugger
+        .inner
+        .trace()
+        .save(path)
+        .map_err(|e| report::error(&e, "", path, None))?;
+    result
+}
+
+fn output_frames(
+    frames: impl Iterator<Item = RevPolBufSnapshot>,
+    debug_options: &DebugOptions,
+    script: &Script,
+    source: &str,
+    file: &str,
+    options: &Options,
+) -> Result<(), ExitCode> {
+    match debug_options.trace_json {
+        Some(path) => write_json_lines(frames, path, script, source, file),
+        None => show_frames(frames, script, source, file, options),
+    }
+}
+
+/// Writes every snapshot as a line of JSON, stopping at the first error
+fn write_json_lines(
+    frames: impl Iterator<Item = RevPolBufSnapshot>,
+    path: &str,
+    script: &Script,
+    source: &str,
+    file: &str,
+) -> Result<(), ExitCode> {
+    let mut out: Box<dyn Write> = if path == "-" {
+        Box::new(std::io::stdout().lock())
+    } else {
+        let created = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create <{}>", path))
+            .map_err(|e| report::error(&e, "", path, None))?;
+        Box::new(std::io::BufWriter::new(created))
+    };
+
+    for frame in frames {
+        serde_json::to_writer(&mut out, &frame)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| Ok(writeln!(out)?))
+            .map_err(|e| report::error(&e, "", path, None))?;
+
+        if let Some(Err(e)) = &frame.last_result {
+            let line_no = script.source_lines[frame.ran_line()];
+            return Err(report::error(e, source, file, Some(line_no)));
+        }
+    }
+
+    out.flush()
+        .map_err(|e| report::error(&e.into(), "", path, None))
+}
+
+/// Prints each line of a script as the debugger reaches it, followed by the functor calls in it
+/// (when stepping a token at a time) and the stack once it is done
+fn show_frames(
+    frames: impl Iterator<Item = RevPolBufSnapshot>,
+    script: &Script,
+    source: &str,
+    file: &str,
+    options: &Options,
+) -> Result<(), ExitCode> {
+    let source_text = source.lines().collect::<Vec<&str>>();
+    let mut last_shown = None;
+
+    for frame in frames {
+        // A line is only shown once, however many token steps it takes
+        let line_no = script.source_lines[frame.ran_line()];
+        if last_shown != Some(frame.ran_line()) {
+            println!("{:>4} | {}", line_no, source_text[line_no - 1]);
+            last_shown = Some(frame.ran_line());
+        }
+
+        if let Some(Err(e)) = &frame.last_result {
+            return Err(report::error(e, source, file, Some(line_no)));
+        }
+
+        if let Some(step) = &frame.last_step
+            && let Some(func) = &step.functor
+        {
+            // Kept to one line, so expressions are never drawn over several
+            let show = |tokens: &[Token]| {
+                tokens
+                    .iter()
+                    .map(|token| format_token(token, &Context::new(), options.precision))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            };
+            println!(
+                "     = {} {} -> {}",
+                show(&step.consumed),
+                func.name,
+                show(&step.produced)
+            );
+        }
+
+        // The stack is shown once the whole line has run
+        if !frame.line_in_progress {
+            print_stack(
+                &frame.stack_machine_stack,
+                &frame.stack_machine_ctx,
+                options,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn dap_server(options: &Options) -> Result<(), ExitCode> {
+    // Stdout is where the protocol goes, nothing else can be written to it
+    rcas_lib::set_debug_logging(false);
+
+    let stdin = std::io::BufReader::new(std::io::stdin());
+    let stdout = std::io::stdout();
+    dap::Server::new(stdin, stdout.lock(), options.mode)
+        .run()
+        .map_err(|e| report::error(&e, "", "<dap>", None))
+}
 
-    println!("Now running!");
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    rcas_lib::set_debug_logging(cli.options.verbose);
 
-    let stack_machine = RevPolStackMachine::new_with_ctx(context);
-    let mut stack_executor = BufferedExecutor::new(stack_machine, exec_cmds.unwrap());
-    stack_executor.run_stack().unwrap();
+    let result = match &cli.command {
+        Command::Run {
+            file,
+            print_stack,
+            profile,
+            flamegraph,
+        } => {
+            let run_options = RunOptions {
+                print_stack: *print_stack,
+                profile: *profile,
+                flamegraph: flamegraph.as_deref(),
+            };
+            run(file, &run_options, &cli.options)
+        }
+        Command::Eval { expr } => eval(expr, &cli.options),
+        Command::Check { file } => check(file, &cli.options),
+        Command::Debug {
+            file,
+            tokens,
+            trace,
+            trace_json,
+        } => {
+            let debug_options = DebugOptions {
+                tokens: *tokens,
+                trace: trace.as_deref(),
+                trace_json: trace_json.as_deref(),
+            };
+            debug(file, &debug_options, &cli.options)
+        }
+        Command::Repl => repl::run(&cli.options),
+        Command::Dap => dap_server(&cli.options),
+    };
 
-    // let debug = Stepper::new(BufExecDebugger::new(stack_executor));
-    //
-    // println!("Initial snapshot:\n{}", debug.inner.static_snapshot());
-    // println!("------------------------------------------------");
-    //
-    // for frame in debug.into_iter() {
-    //     println!("{}", frame);
-    //     println!("------------------------------------------------");
-    // }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => code,
+    }
 }
@@ -0,0 +1,397 @@
+use crate::{Mode, Options, is_unfinished, parse_script, print_stack, read_file, report};
+use rcas_lib::stack_machine::{BufferedExecutor, RevPolStackMachine};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Editor, Helper, Highlighter, Hinter};
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::rc::Rc;
+
+const HELP: &str = "\
+Enter RevPol (or infix with --mode infix) input to run it, the stack is shown after each input.
+End a line with \\ to keep typing more lines before they run.
+
+:stack        show the stack
+:ctx          show the variables and functions
+:undo         undo the last input
+:redo         redo the last undone input
+:load <file>  run a .mir file in this session
+:help         show this message
+:quit         leave (so does Ctrl-D)";
+
+const COMMANDS: [&str; 7] = [
+    ":stack", ":ctx", ":undo", ":redo", ":load", ":help", ":quit",
+];
+
+/// Completes REPL commands and the names of functions and variables, and keeps asking for more
+/// input while a line is unfinished
+#[derive(Helper, Hinter, Highlighter)]
+struct ReplHelper {
+    /// Names which can be completed, refreshed after each input
+    names: Rc<RefCell<Vec<String>>>,
+    mode: Mode,
+}
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, names) = completions(line, pos, &self.names.borrow());
+        let candidates = names
+            .into_iter()
+            .map(|name| Pair {
+                display: name.clone(),
+                replacement: name,
+            })
+            .collect();
+
+        Ok((start, candidates))
+    }
+}
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+
+        if input.ends_with('\\') || is_unfinished(input, self.mode) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+/// Where the word being typed at pos starts, and the commands and names it could be completed to.
+/// Commands are only offered at the start of the line.
+fn completions(line: &str, pos: usize, names: &[String]) -> (usize, Vec<String>) {
+    let start = line[..pos]
+        .rfind(|c: char| c.is_whitespace() || c == '(' || c == ',')
+        .map_or(0, |idx| idx + 1);
+    let word = &line[start..pos];
+
+    let mut candidates = COMMANDS
+        .iter()
+        .copied()
+        .filter(|_| start == 0)
+        .chain(names.iter().map(String::as_str))
+        .filter(|name| !word.is_empty() && name.starts_with(word))
+        .map(str::to_string)
+        .collect::<Vec<String>>();
+    candidates.sort();
+    candidates.dedup();
+
+    (start, candidates)
+}
+
+/// The machine which lives for the whole session. The machine keeps undo history for every line,
+/// the session keeps how many lines each input was so whole inputs can be undone.
+struct Session {
+    executor: BufferedExecutor,
+    /// Number of lines in each input which ran, the latest last
+    inputs: Vec<usize>,
+    /// Number of lines in each input which was undone, the latest undone last
+    undone: Vec<usize>,
+}
+impl Session {
+    fn new(options: &Options) -> Self {
+        let mut machine = RevPolStackMachine::new_with_ctx(options.context());
+        machine.enable_history();
+        Session {
+            executor: BufferedExecutor::new(machine, vec![]),
+            inputs: vec![],
+            undone: vec![],
+        }
+    }
+
+    /// Runs some source on the session's machine, printing any error
+    fn run(&mut self, source: &str, name: &str, options: &Options) -> bool {
+        let script = match parse_script(source, options.mode, &self.executor.machine.context) {
+            Ok(script) => script,
+            Err(e) => {
+                report::error(&e, source, name, None);
+                return false;
+            }
+        };
+
+        // Running something new throws away whatever was undone
+        self.executor.lines.truncate(self.executor.current_line);
+        self.undone.clear();
+
+        // Limits apply to each input on its own
+        self.executor.set_limits(options.limits());
+        self.executor.steps = 0;
+        let first_line = self.executor.lines.len();
+        let line_count = script.lines.len();
+        self.executor.lines.extend(script.lines);
+
+        if let Err(e) = self.executor.run_stack() {
+            let line = script
+                .source_lines
+                .get(self.executor.current_line - first_line)
+                .copied();
+            report::error(&e, source, name, line);
+
+            // Put everything back the way it was before this input, so the next one starts fresh.
+            // Lines which cannot be undone have to stay, along with what they did.
+            self.executor.machine.rollback();
+            while self.executor.current_line > first_line && self.executor.undo_line() {}
+            self.executor.lines.truncate(self.executor.current_line);
+            if self.executor.current_line > first_line {
+                self.inputs.push(self.executor.current_line - first_line);
+            }
+            return false;
+        }
+
+        if line_count > 0 {
+            self.inputs.push(line_count);
+        }
+        true
+    }
+
+    fn undo(&mut self) {
+        let Some(lines) = self.inputs.pop() else {
+            eprintln!("Nothing to undo");
+            return;
+        };
+
+        for _ in 0..lines {
+            self.executor.undo_line();
+        }
+        self.undone.push(lines);
+    }
+
+    fn redo(&mut self) {
+        let Some(lines) = self.undone.pop() else {
+            eprintln!("Nothing to redo");
+            return;
+        };
+
+        for _ in 0..lines {
+            self.executor.redo_line();
+        }
+        self.inputs.push(lines);
+    }
+
+    fn print_context(&self) {
+        let context = &self.executor.machine.context;
+
+        println!("Variables:");
+        for var in &context.variables {
+            println!("  {}", var);
+        }
+
+        println!("Functions:");
+        for func in &context.functions {
+            println!("  {}", func.signature());
+        }
+    }
+
+    /// Everything that tab completion should offer
+    fn names(&self) -> Vec<String> {
+        let context = &self.executor.machine.context;
+        context
+            .functions
+            .iter()
+            .map(|f| f.name.clone())
+            .chain(context.variables.iter().map(|v| v.name.clone()))
+            .collect()
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".rcas_history"))
+}
+
+/// Runs an interactive session on a single machine, showing the stack after each input
+pub fn run(options: &Options) -> Result<(), ExitCode> {
+    let mut session = Session::new(options);
+
+    let names = Rc::new(RefCell::new(session.names()));
+    let mut editor: Editor<ReplHelper, DefaultHistory> =
+        Editor::new().map_err(|e| report::error(&e.into(), "", "<repl>", None))?;
+    editor.set_helper(Some(ReplHelper {
+        names: names.clone(),
+        mode: options.mode,
+    }));
+
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history the first time round
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        let input = match editor.readline("> ") {
+            Ok(input) => input,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(report::error(&e.into(), "", "<repl>", None)),
+        };
+
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(input);
+
+        match input.split_once(' ').unwrap_or((input, "")) {
+            (":quit", _) => break,
+            (":help", _) => println!("{}", HELP),
+            (":stack", _) => print_stack(
+                &session.executor.machine.stack,
+                &session.executor.machine.context,
+                options,
+            ),
+            (":ctx", _) => session.print_context(),
+            (":undo", _) => {
+                session.undo();
+                print_stack(
+                    &session.executor.machine.stack,
+                    &session.executor.machine.context,
+                    options,
+                );
+            }
+            (":redo", _) => {
+                session.redo();
+                print_stack(
+                    &session.executor.machine.stack,
+                    &session.executor.machine.context,
+                    options,
+                );
+            }
+            (":load", file) => match read_file(file.trim()) {
+                Ok(source) => {
+                    if session.run(&source, file.trim(), options) {
+                        print_stack(
+                            &session.executor.machine.stack,
+                            &session.executor.machine.context,
+                            options,
+                        );
+                    }
+                }
+                Err(e) => {
+                    report::error(&e, "", file.trim(), None);
+                }
+            },
+            (command, _) if command.starts_with(':') => {
+                eprintln!("Unknown command {}, try :help", command)
+            }
+            _ => {
+                // A line ending in \ was continued onto the next one
+                let source = input.replace("\\\n", "\n");
+                if session.run(&source, "<repl>", options) {
+                    print_stack(
+                        &session.executor.machine.stack,
+                        &session.executor.machine.context,
+                        options,
+                    );
+                }
+            }
+        }
+
+        *names.borrow_mut() = session.names();
+    }
+
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cli;
+    use clap::Parser;
+    use rcas_lib::number::Number;
+    use rcas_lib::token_defs::Token;
+
+    fn options(args: &[&str]) -> Options {
+        let args = ["rcas_frontend", "repl"].iter().chain(args).copied();
+        Cli::parse_from(args).options
+    }
+
+    fn int(value: i32) -> Token {
+        Token::Const(Number::Int(value.into()))
+    }
+
+    #[test]
+    fn completes_commands_and_names() {
+        let names = ["factorial", "floor", "x"].map(String::from);
+        assert_eq!(
+            completions(":st", 3, &names),
+            (0, vec![":stack".to_string()])
+        );
+        assert_eq!(
+            completions("5 fa", 4, &names),
+            (2, vec!["factorial".to_string()])
+        );
+        let both = vec!["factorial".to_string(), "floor".to_string()];
+        assert_eq!(completions("gcd(f", 5, &names), (4, both));
+        // Commands only make sense at the start of a line
+        assert_eq!(completions("1 :s", 4, &names), (2, vec![]));
+        assert_eq!(completions("", 0, &names), (0, vec![]));
+    }
+
+    #[test]
+    fn waits_for_unfinished_input() {
+        assert!(is_unfinished("\"two", Mode::Rpn));
+        assert!(!is_unfinished("\"two\" 2", Mode::Rpn));
+        assert!(is_unfinished("(1 + 2", Mode::Infix));
+        assert!(!is_unfinished("(1 + 2", Mode::Rpn));
+        assert!(!is_unfinished("(1 + 2) * 3", Mode::Infix));
+    }
+
+    #[test]
+    fn runs_input_over_several_lines() {
+        let options = options(&[]);
+        let mut session = Session::new(&options);
+        assert!(session.run("\"two\nlines\" 1", "<test>", &options));
+        let string = Token::String("two\nlines".to_string());
+        assert_eq!(session.executor.machine.stack, vec![string, int(1)]);
+
+        let options = self::options(&["--mode", "infix"]);
+        let mut session = Session::new(&options);
+        assert!(session.run("(1 +\n2) * 3", "<test>", &options));
+        assert_eq!(session.executor.machine.stack, vec![int(9)]);
+    }
+
+    #[test]
+    fn failed_input_is_rolled_back() {
+        let options = options(&[]);
+        let mut session = Session::new(&options);
+        assert!(session.run("1\n2", "<test>", &options));
+        assert!(!session.run("3\n\"a\" 1 +", "<test>", &options));
+        assert_eq!(session.executor.machine.stack, vec![int(1), int(2)]);
+        assert_eq!(session.executor.lines.len(), 2);
+
+        session.undo();
+        assert!(session.executor.machine.stack.is_empty());
+        session.redo();
+        assert_eq!(session.executor.machine.stack, vec![int(1), int(2)]);
+    }
+
+    #[test]
+    fn lines_which_cannot_be_undone_stay() {
+        let options = options(&[]);
+        let machine = RevPolStackMachine::new_with_ctx(options.context());
+        let mut session = Session {
+            executor: BufferedExecutor::new(machine, vec![]),
+            inputs: vec![],
+            undone: vec![],
+        };
+
+        assert!(!session.run("3\n\"a\" 1 +", "<test>", &options));
+        assert_eq!(session.executor.machine.stack, vec![int(3)]);
+        assert_eq!(session.executor.lines.len(), 1);
+        assert!(session.run("4", "<test>", &options));
+        assert_eq!(session.executor.machine.stack, vec![int(3), int(4)]);
+    }
+}
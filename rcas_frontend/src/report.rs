@@ -0,0 +1,50 @@
+use rcas_lib::parse_rpol_notation::{Span, SpannedError};
+use std::process::ExitCode;
+
+/// Prints an error to stderr, pointing at the source it came from when that is known: either the
+/// exact span of a SpannedError in the chain, or else the given line as a whole. Returns the exit
+/// code the program should fail with.
+pub fn error(e: &anyhow::Error, source: &str, name: &str, line: Option<usize>) -> ExitCode {
+    let spanned = e
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<SpannedError>());
+
+    match spanned {
+        Some(spanned) => {
+            eprintln!("error: {}", spanned.message);
+            print_span(source, name, spanned.span);
+        }
+        None => {
+            eprintln!("error: {:#}", e);
+
+            let text = line.and_then(|line| source.lines().nth(line - 1));
+            if let (Some(line), Some(text)) = (line, text) {
+                let span = Span {
+                    line,
+                    column: 1,
+                    len: text.chars().count(),
+                };
+                print_span(source, name, span);
+            }
+        }
+    }
+
+    ExitCode::FAILURE
+}
+
+fn print_span(source: &str, name: &str, span: Span) {
+    let Some(text) = source.lines().nth(span.line - 1) else {
+        return;
+    };
+
+    let gutter = " ".repeat(span.line.to_string().len());
+    eprintln!("{}--> {}:{}:{}", gutter, name, span.line, span.column);
+    eprintln!("{} |", gutter);
+    eprintln!("{} | {}", span.line, text.trim_end_matches('\r'));
+    eprintln!(
+        "{} | {}{}",
+        gutter,
+        " ".repeat(span.column - 1),
+        "^".repeat(span.len.max(1))
+    );
+}
@@ -0,0 +1,114 @@
+//! Compares `BufferedExecutor` against the bytecode `VirtualMachine` on generated numeric scripts.
+//!
+//! The debugger feature logs every step of `BufferedExecutor`, so run this with
+//! `cargo bench --no-default-features` to compare the executors rather than stdout.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use rcas_lib::bytecode::Program;
+use rcas_lib::context::{Context, get_default_functions};
+use rcas_lib::parse_rpol_notation::{commands_to_sequential_exec_order, split_into_commands};
+use rcas_lib::stack_machine::{BufferedExecutor, RevPolStackMachine};
+use rcas_lib::token_defs::Token;
+use rcas_lib::vm::VirtualMachine;
+use std::hint::black_box;
+
+const SIZES: [usize; 3] = [100, 1_000, 5_000];
+
+fn default_context() -> Context {
+    let mut context = Context::new();
+    context.functions.append(&mut get_default_functions());
+    context
+}
+
+fn parse(script: String) -> Vec<Vec<Token>> {
+    commands_to_sequential_exec_order(split_into_commands(script), &default_context()).unwrap()
+}
+
+/// Keeps a single running total on the stack: `0`, then `1 +` on every line
+fn accumulate_script(lines: usize) -> String {
+    let mut script = String::from("0");
+    for _ in 0..lines {
+        script.push_str("\n1 +");
+    }
+    script
+}
+
+/// Leaves a result on the stack for every line, so the stack keeps growing, and reassigns a
+/// variable every tenth line
+fn growing_stack_script(lines: usize) -> String {
+    let mut script = String::from("x 1 :=");
+    for i in 0..lines {
+        if i % 10 == 0 {
+            script.push_str(&format!("\nx {} :=", i));
+        } else {
+            script.push_str(&format!("\n{} x *", i));
+        }
+    }
+    script
+}
+
+fn bench_script(c: &mut Criterion, group_name: &str, make_script: fn(usize) -> String) {
+    let mut group = c.benchmark_group(group_name);
+
+    for size in SIZES {
+        let lines = parse(make_script(size));
+
+        group.bench_with_input(BenchmarkId::new("buffered", size), &lines, |b, lines| {
+            b.iter_batched(
+                || {
+                    let machine = RevPolStackMachine::new_with_ctx(default_context());
+                    BufferedExecutor::new(machine, lines.clone())
+                },
+                |mut executor| {
+                    executor.run_stack().unwrap();
+                    black_box(executor.machine.stack)
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        group.bench_with_input(BenchmarkId::new("vm", size), &lines, |b, lines| {
+            b.iter_batched(
+                || RevPolStackMachine::new_with_ctx(default_context()),
+                |machine| {
+                    let mut vm = VirtualMachine::new(machine, Program::compile(lines).unwrap());
+                    vm.run().unwrap();
+                    black_box(vm.machine.stack)
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        let program = Program::compile(&lines).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("vm_precompiled", size),
+            &program,
+            |b, program| {
+                b.iter_batched(
+                    || {
+                        let machine = RevPolStackMachine::new_with_ctx(default_context());
+                        VirtualMachine::new(machine, program.clone())
+                    },
+                    |mut vm| {
+                        vm.run().unwrap();
+                        black_box(vm.machine.stack)
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+
+    group.finish();
+}
+
+fn accumulate(c: &mut Criterion) {
+    bench_script(c, "accumulate", accumulate_script);
+}
+
+fn growing_stack(c: &mut Criterion) {
+    bench_script(c, "growing_stack", growing_stack_script);
+}
+
+criterion_group!(benches, accumulate, growing_stack);
+criterion_main!(benches);
@@ -0,0 +1,96 @@
+//! Compares evaluating an expression at many points through `lambdify`, `lambdify_number` and the
+//! stack machine, which is what plotting would otherwise do.
+//!
+//! As with the executor bench, run this with `cargo bench --no-default-features` so
+//! `BufferedExecutor` isn't logging every step.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use num_bigfloat::BigFloat;
+use rcas_lib::context::{Context, get_default_functions};
+use rcas_lib::expression::Expr;
+use rcas_lib::lambdify::{lambdify, lambdify_number};
+use rcas_lib::number::Number;
+use rcas_lib::parse_infix::infix_to_commands;
+use rcas_lib::parse_rpol_notation::commands_to_sequential_exec_order;
+use rcas_lib::stack_machine::{BufferedExecutor, RevPolStackMachine};
+use rcas_lib::token_defs::Token;
+use std::hint::black_box;
+
+const POINTS: [usize; 3] = [10, 100, 1_000];
+const SOURCE: &str = "x ^ 3 - 2 * x * y + sin(x) / (y + 1)";
+
+fn default_context() -> Context {
+    let mut context = Context::new();
+    context.functions.append(&mut get_default_functions());
+    context
+}
+
+fn variables() -> Vec<String> {
+    vec!["x".to_string(), "y".to_string()]
+}
+
+fn points(count: usize) -> Vec<(f64, f64)> {
+    (0..count)
+        .map(|i| (i as f64 / count as f64, 1.0 - i as f64 / count as f64))
+        .collect()
+}
+
+fn float(value: f64) -> Number {
+    Number::Float(BigFloat::from(value))
+}
+
+fn evaluate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("evaluate");
+    let context = default_context();
+    let expr = Expr::parse(SOURCE, &context).unwrap();
+    let commands = infix_to_commands(SOURCE, &context).unwrap();
+    let lines = commands_to_sequential_exec_order(commands, &context).unwrap();
+
+    for count in POINTS {
+        let points = points(count);
+
+        let func = lambdify(&expr, &variables()).unwrap();
+        group.bench_with_input(BenchmarkId::new("lambdify", count), &points, |b, points| {
+            b.iter(|| {
+                let sum: f64 = points.iter().map(|&(x, y)| func(&[x, y]).unwrap()).sum();
+                black_box(sum)
+            })
+        });
+
+        let func = lambdify_number(&expr, &variables()).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("lambdify_number", count),
+            &points,
+            |b, points| {
+                b.iter(|| {
+                    for &(x, y) in points {
+                        black_box(func(&[float(x), float(y)]).unwrap());
+                    }
+                })
+            },
+        );
+
+        group.bench_with_input(BenchmarkId::new("buffered", count), &points, |b, points| {
+            b.iter_batched(
+                || lines.clone(),
+                |lines| {
+                    for &(x, y) in points {
+                        let mut context = default_context();
+                        context.set_variable("x".to_string(), Token::Const(float(x)));
+                        context.set_variable("y".to_string(), Token::Const(float(y)));
+                        let machine = RevPolStackMachine::new_with_ctx(context);
+                        let mut executor = BufferedExecutor::new(machine, lines.clone());
+                        executor.run_stack().unwrap();
+                        black_box(executor.machine.stack);
+                    }
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, evaluate);
+criterion_main!(benches);
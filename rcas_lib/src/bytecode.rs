@@ -0,0 +1,176 @@
+use crate::number::Number;
+use crate::token_defs::{Functor, Token};
+use anyhow::{Result, anyhow};
+use hashbrown::HashMap;
+use num_bigint::BigInt;
+
+/// A single bytecode instruction. Every instruction pushes one token, the operand is an index into
+/// one of the pools of the `Program` it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// Push `constants[idx]` as a `Token::Const`
+    Const(u32),
+    /// Push `names[idx]` as a `Token::String`
+    String(u32),
+    /// Push `names[idx]` as a `Token::Symbol`
+    Symbol(u32),
+    /// Push `functions[idx]` as a `Token::Functor`, which is called once it is popped
+    Call(u32),
+    /// Push `tokens[idx]` as-is, for anything that has no dedicated instruction
+    Token(u32),
+}
+
+/// Where a source line lives in the instruction stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineInfo {
+    pub start: usize,
+    pub end: usize,
+    /// Index of the last `Instruction::Call` of the line, if it has one. Everything after it is
+    /// popped and thrown away before it runs, so the VM can skip pushing those tokens entirely.
+    pub last_call: Option<usize>,
+}
+
+/// A compiled .mir script: a flat instruction stream, the pools its operands index into, and the
+/// boundaries of each source line
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub code: Vec<Instruction>,
+    pub lines: Vec<LineInfo>,
+    pub constants: Vec<Number>,
+    pub names: Vec<String>,
+    pub functions: Vec<Functor>,
+    pub tokens: Vec<Token>,
+}
+impl Program {
+    /// Compiles parsed lines (see `commands_to_sequential_exec_order`) into a program. Fails if
+    /// a pool would need more entries than an operand can index.
+    pub fn compile(lines: &[Vec<Token>]) -> Result<Self> {
+        let mut compiler = Compiler::default();
+
+        for line in lines {
+            compiler.compile_line(line)?;
+        }
+
+        Ok(compiler.program)
+    }
+
+    /// Turns a single instruction back into the token it pushes
+    pub fn materialize(&self, instruction: Instruction) -> Token {
+        match instruction {
+            Instruction::Const(idx) => Token::Const(self.constants[idx as usize].clone()),
+            Instruction::String(idx) => Token::String(self.names[idx as usize].clone()),
+            Instruction::Symbol(idx) => Token::Symbol(self.names[idx as usize].clone()),
+            Instruction::Call(idx) => Token::Functor(self.functions[idx as usize].clone()),
+            Instruction::Token(idx) => self.tokens[idx as usize].clone(),
+        }
+    }
+}
+
+/// Hashable stand-in for a `Number`, keeping Int(1) and Float(1) apart
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Int(BigInt),
+    Float(String),
+    Mod(BigInt, BigInt),
+    Rational(BigInt, BigInt),
+}
+
+/// Holds the interning tables while a program is being built
+#[derive(Default)]
+struct Compiler {
+    program: Program,
+    constant_ids: HashMap<ConstantKey, u32>,
+    name_ids: HashMap<String, u32>,
+    function_ids: HashMap<String, u32>,
+}
+impl Compiler {
+    fn compile_line(&mut self, line: &[Token]) -> Result<()> {
+        let start = self.program.code.len();
+        let mut last_call = None;
+
+        for token in line {
+            let instruction = self.compile_token(token)?;
+
+            if let Instruction::Call(_) = instruction {
+                last_call = Some(self.program.code.len());
+            }
+
+            self.program.code.push(instruction);
+        }
+
+        self.program.lines.push(LineInfo {
+            start,
+            end: self.program.code.len(),
+            last_call,
+        });
+        Ok(())
+    }
+
+    fn compile_token(&mut self, token: &Token) -> Result<Instruction> {
+        Ok(match token {
+            Token::Const(num) => Instruction::Const(self.intern_constant(num)?),
+            Token::String(str) => Instruction::String(self.intern_name(str)?),
+            Token::Symbol(name) => Instruction::Symbol(self.intern_name(name)?),
+            Token::Functor(func) => Instruction::Call(self.intern_function(func)?),
+            other => {
+                self.program.tokens.push(other.clone());
+                Instruction::Token(last_index(&self.program.tokens)?)
+            }
+        })
+    }
+
+    fn intern_constant(&mut self, num: &Number) -> Result<u32> {
+        let key = match num {
+            Number::Int(int) => ConstantKey::Int(int.clone()),
+            // NaN is never equal to itself, so it is not worth pooling
+            Number::Float(float) if float.is_nan() => return self.push_constant(num),
+            Number::Float(float) => ConstantKey::Float(float.to_string()),
+            Number::Mod(value, modulus) => ConstantKey::Mod(value.clone(), modulus.clone()),
+            Number::Rational(numerator, denominator) => {
+                ConstantKey::Rational(numerator.clone(), denominator.clone())
+            }
+        };
+
+        if let Some(idx) = self.constant_ids.get(&key) {
+            return Ok(*idx);
+        }
+
+        let idx = self.push_constant(num)?;
+        self.constant_ids.insert(key, idx);
+        Ok(idx)
+    }
+
+    fn push_constant(&mut self, num: &Number) -> Result<u32> {
+        self.program.constants.push(num.clone());
+        last_index(&self.program.constants)
+    }
+
+    fn intern_name(&mut self, name: &str) -> Result<u32> {
+        if let Some(idx) = self.name_ids.get(name) {
+            return Ok(*idx);
+        }
+
+        self.program.names.push(name.to_string());
+        let idx = last_index(&self.program.names)?;
+        self.name_ids.insert(name.to_string(), idx);
+        Ok(idx)
+    }
+
+    fn intern_function(&mut self, func: &Functor) -> Result<u32> {
+        if let Some(idx) = self.function_ids.get(&func.name) {
+            return Ok(*idx);
+        }
+
+        self.program.functions.push(func.clone());
+        let idx = last_index(&self.program.functions)?;
+        self.function_ids.insert(func.name.clone(), idx);
+        Ok(idx)
+    }
+}
+
+/// The operand pointing at the last entry of a pool, which has just been pushed
+fn last_index<T>(pool: &[T]) -> Result<u32> {
+    u32::try_from(pool.len() - 1).map_err(|_| {
+        anyhow!("Too many operands for one program, their indices must fit in 32 bits")
+    })
+}
@@ -0,0 +1,592 @@
+use crate::expression::Expr;
+use crate::number::Number;
+use anyhow::{Result, bail};
+use hashbrown::HashMap;
+
+/// The language a function is generated in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Rust,
+    C,
+}
+
+/// What the generated function computes with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Plain doubles
+    F64,
+    /// Big floats: num-bigfloat's BigFloat in Rust, and MPFR in C
+    BigFloat,
+}
+
+/// Generates a standalone function computing an expression from a list of inputs, in Rust or C.
+/// Subexpressions which appear more than once are only computed once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeGen {
+    pub language: Language,
+    pub backend: Backend,
+    /// The name of the generated function
+    pub name: String,
+}
+
+/// Something an operation works on: an input, a constant or the result of an earlier operation
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Operand {
+    Input(String),
+    Const(Number),
+    Pi,
+    Temp(usize),
+}
+
+/// A single operation of the expression, by the name of its functor (neg for a negation)
+#[derive(Debug, Clone)]
+pub(crate) struct Operation {
+    pub(crate) name: &'static str,
+    pub(crate) args: Vec<Operand>,
+}
+
+const OPERATIONS: [&str; 13] = [
+    "+", "-", "*", "/", "^", "neg", "sqrt", "sin", "cos", "tan", "exp", "ln", "abs",
+];
+
+/// The operations an expression is made of, in an order where every operation comes after the
+/// ones it uses, with common subexpressions merged
+pub(crate) struct Lowered {
+    pub(crate) operations: Vec<Operation>,
+    /// How many times the result of each operation is used
+    pub(crate) uses: Vec<usize>,
+    pub(crate) result: Operand,
+}
+impl Lowered {
+    pub(crate) fn new(expr: &Expr, inputs: &[String]) -> Result<Lowered> {
+        let mut lowering = Lowering {
+            operations: vec![],
+            uses: vec![],
+            seen: HashMap::new(),
+        };
+        let result = lowering.lower(expr, inputs)?;
+        if let Operand::Temp(idx) = result {
+            lowering.uses[idx] += 1;
+        }
+
+        Ok(Lowered {
+            operations: lowering.operations,
+            uses: lowering.uses,
+            result,
+        })
+    }
+}
+
+/// Holds what has been lowered so far while an expression is being lowered
+struct Lowering {
+    operations: Vec<Operation>,
+    uses: Vec<usize>,
+    /// The operation each subexpression was lowered to, by the text of the subexpression
+    seen: HashMap<String, usize>,
+}
+impl Lowering {
+    fn lower(&mut self, expr: &Expr, inputs: &[String]) -> Result<Operand> {
+        let (name, args) = match expr {
+            Expr::Num(num) => return Ok(Operand::Const(num.clone())),
+            Expr::Symbol(name) if inputs.contains(name) => {
+                return Ok(Operand::Input(name.clone()));
+            }
+            Expr::Symbol(name) if name == "pi" => return Ok(Operand::Pi),
+            Expr::Symbol(name) => bail!("{} is not one of the inputs", name),
+            Expr::Matrix(_) => bail!("Matrices cannot be compiled"),
+            Expr::Apply(name, args) if name == "-" && args.len() == 1 => ("neg", args),
+            Expr::Apply(name, args) => (name.as_str(), args),
+        };
+
+        // The expression as text identifies it, as it is written out in full
+        let key = expr.to_string();
+        if let Some(idx) = self.seen.get(&key) {
+            return Ok(Operand::Temp(*idx));
+        }
+
+        let arity = match name {
+            "+" | "-" | "*" | "/" | "^" => 2,
+            _ => 1,
+        };
+        let Some(name) = OPERATIONS.iter().find(|op| **op == name) else {
+            bail!("{} cannot be compiled", name)
+        };
+        if args.len() != arity {
+            bail!("{} takes {} argument(s), not {}", name, arity, args.len())
+        }
+
+        let args = args
+            .iter()
+            .map(|arg| self.lower(arg, inputs))
+            .collect::<Result<Vec<_>>>()?;
+        for arg in &args {
+            if let Operand::Temp(idx) = arg {
+                self.uses[*idx] += 1;
+            }
+        }
+
+        self.operations.push(Operation { name, args });
+        self.uses.push(0);
+        self.seen.insert(key, self.operations.len() - 1);
+        Ok(Operand::Temp(self.operations.len() - 1))
+    }
+}
+
+impl CodeGen {
+    /// Generates a function called f
+    pub fn new(language: Language, backend: Backend) -> Self {
+        CodeGen {
+            language,
+            backend,
+            name: "f".to_string(),
+        }
+    }
+
+    /// Looks a language and backend up by name, as given to the CodeGen command, e.g. "rust" and
+    /// "f64" or "c" and "bigfloat"
+    pub fn from_names(language: &str, backend: &str) -> Result<Self> {
+        let language = match language.to_lowercase().as_str() {
+            "rust" => Language::Rust,
+            "c" => Language::C,
+            other => bail!("Unknown language {}, expected rust or c", other),
+        };
+        let backend = match backend.to_lowercase().as_str() {
+            "f64" | "double" => Backend::F64,
+            "bigfloat" | "bignum" | "mpfr" => Backend::BigFloat,
+            other => bail!("Unknown backend {}, expected f64 or bigfloat", other),
+        };
+        Ok(CodeGen::new(language, backend))
+    }
+
+    /// The source of a function computing the expression, taking the inputs as arguments in the
+    /// order given. The only other name the expression may use is pi.
+    pub fn generate(&self, expr: &Expr, inputs: &[String]) -> Result<String> {
+        let lowered = Lowered::new(expr, inputs)?;
+
+        Ok(match (self.language, self.backend) {
+            (Language::C, Backend::BigFloat) => self.mpfr(expr, inputs, &lowered),
+            _ => self.inline(expr, inputs, &lowered)?,
+        })
+    }
+
+    /// Writes operations whose result is used once inside the one using it, and gives the rest
+    /// a name of their own
+    fn inline(&self, expr: &Expr, inputs: &[String], lowered: &Lowered) -> Result<String> {
+        let mut names = HashMap::new();
+        let mut statements = vec![];
+
+        for (idx, operation) in lowered.operations.iter().enumerate() {
+            if lowered.uses[idx] > 1 {
+                let value = self.operation(operation, lowered, &names)?;
+                let name = format!("t{}", names.len());
+                statements.push(match (self.language, self.backend) {
+                    (Language::C, _) => format!("    const double {} = {};", name, value),
+                    (Language::Rust, _) => format!("    let {} = {};", name, value),
+                });
+                names.insert(idx, name);
+            }
+        }
+
+        let mut result = self.operand(&lowered.result, lowered, &names)?;
+        if let (Language::Rust, Backend::BigFloat, Operand::Input(_)) =
+            (self.language, self.backend, &lowered.result)
+        {
+            result = format!("{}.clone()", result);
+        }
+
+        Ok(match (self.language, self.backend) {
+            (Language::Rust, backend) => {
+                let (header, ty, arg_ty) = match backend {
+                    Backend::F64 => ("", "f64", "f64"),
+                    Backend::BigFloat => {
+                        ("use num_bigfloat::BigFloat;\n\n", "BigFloat", "&BigFloat")
+                    }
+                };
+                let args = inputs
+                    .iter()
+                    .map(|input| format!("{}: {}", input, arg_ty))
+                    .collect::<Vec<_>>();
+                statements.push(format!("    {}", result));
+
+                format!(
+                    "{}/// Generated by rcas from {}\npub fn {}({}) -> {} {{\n{}\n}}\n",
+                    header,
+                    expr,
+                    self.name,
+                    args.join(", "),
+                    ty,
+                    statements.join("\n")
+                )
+            }
+            (Language::C, _) => {
+                let args = inputs
+                    .iter()
+                    .map(|input| format!("double {}", input))
+                    .collect::<Vec<_>>();
+                statements.push(format!("    return {};", result));
+
+                format!(
+                    "#include <math.h>\n\n/* Generated by rcas from {} */\n\
+                     double {}({})\n{{\n{}\n}}\n",
+                    expr,
+                    self.name,
+                    match args.is_empty() {
+                        true => "void".to_string(),
+                        false => args.join(", "),
+                    },
+                    statements.join("\n")
+                )
+            }
+        })
+    }
+
+    fn operand(
+        &self,
+        operand: &Operand,
+        lowered: &Lowered,
+        names: &HashMap<usize, String>,
+    ) -> Result<String> {
+        Ok(match (operand, self.backend) {
+            (Operand::Input(name), _) => name.clone(),
+            (Operand::Temp(idx), _) => match names.get(idx) {
+                Some(name) => name.clone(),
+                None => self.operation(&lowered.operations[*idx], lowered, names)?,
+            },
+            (Operand::Pi, Backend::F64) => match self.language {
+                Language::Rust => "std::f64::consts::PI".to_string(),
+                Language::C => "M_PI".to_string(),
+            },
+            (Operand::Pi, Backend::BigFloat) => "num_bigfloat::PI".to_string(),
+            (Operand::Const(num), Backend::F64) => {
+                let float = num.clone().to_float().to_f64();
+                if !float.is_finite() {
+                    bail!("{} does not fit in a double", num)
+                }
+                format!("{:?}", float)
+            }
+            (Operand::Const(num), Backend::BigFloat) => {
+                format!("BigFloat::parse(\"{}\").unwrap()", decimal(num))
+            }
+        })
+    }
+
+    /// Writes an operation out as an expression of its operands
+    fn operation(
+        &self,
+        operation: &Operation,
+        lowered: &Lowered,
+        names: &HashMap<usize, String>,
+    ) -> Result<String> {
+        let args = operation
+            .args
+            .iter()
+            .map(|arg| self.operand(arg, lowered, names))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Operands written inline as an operator, or negative numbers, are put in parentheses
+        // wherever they could otherwise be read differently
+        let wrap = |idx: usize| {
+            let simple = match &operation.args[idx] {
+                Operand::Temp(temp) => {
+                    let name = lowered.operations[*temp].name;
+                    names.contains_key(temp) || !matches!(name, "+" | "-" | "*" | "/" | "neg")
+                }
+                Operand::Const(num) => !num.is_negative(),
+                _ => true,
+            };
+            match simple {
+                true => args[idx].clone(),
+                false => format!("({})", args[idx]),
+            }
+        };
+
+        Ok(match (self.language, self.backend, operation.name) {
+            (Language::Rust, Backend::BigFloat, name) => {
+                let method = match name {
+                    "+" => "add",
+                    "-" => "sub",
+                    "*" => "mul",
+                    "/" => "div",
+                    "^" => "pow",
+                    "neg" => "inv_sign",
+                    name => name,
+                };
+                match args.get(1) {
+                    // Inputs are already references
+                    Some(rhs) if matches!(operation.args[1], Operand::Input(_)) => {
+                        format!("{}.{}({})", args[0], method, rhs)
+                    }
+                    Some(rhs) => format!("{}.{}(&{})", args[0], method, rhs),
+                    None => format!("{}.{}()", args[0], method),
+                }
+            }
+            (_, _, "neg") => format!("-{}", wrap(0)),
+            (_, _, name @ ("+" | "-" | "*" | "/")) => {
+                format!("{} {} {}", wrap(0), name, wrap(1))
+            }
+            (Language::Rust, _, "^") => match &operation.args[1] {
+                Operand::Const(Number::Int(int)) if i32::try_from(int).is_ok() => {
+                    format!("{}.powi({})", wrap(0), int)
+                }
+                _ => format!("{}.powf({})", wrap(0), args[1]),
+            },
+            (Language::Rust, _, name) => format!("{}.{}()", wrap(0), name),
+            (Language::C, _, name) => {
+                let function = match name {
+                    "^" => "pow",
+                    "ln" => "log",
+                    "abs" => "fabs",
+                    name => name,
+                };
+                format!("{}({})", function, args.join(", "))
+            }
+        })
+    }
+
+    /// MPFR numbers have to be initialised and cleared, and every operation writes into a
+    /// variable, so each operation and constant gets a variable of its own
+    fn mpfr(&self, expr: &Expr, inputs: &[String], lowered: &Lowered) -> String {
+        let mut constants: Vec<(String, String)> = vec![];
+        let mut body = vec![];
+
+        let mut operand = |operand: &Operand, body: &mut Vec<String>| match operand {
+            Operand::Input(name) => name.clone(),
+            Operand::Temp(idx) => format!("t{}", idx),
+            Operand::Const(_) | Operand::Pi => {
+                let key = format!("{:?}", operand);
+                if let Some((_, name)) = constants.iter().find(|(k, _)| *k == key) {
+                    return name.clone();
+                }
+                let name = format!("c{}", constants.len());
+                body.push(match operand {
+                    Operand::Const(num) => {
+                        let num = decimal(num);
+                        format!("    mpfr_set_str({}, \"{}\", 10, MPFR_RNDN);", name, num)
+                    }
+                    _ => format!("    mpfr_const_pi({}, MPFR_RNDN);", name),
+                });
+                constants.push((key, name.clone()));
+                name
+            }
+        };
+
+        for (idx, operation) in lowered.operations.iter().enumerate() {
+            let args = operation
+                .args
+                .iter()
+                .map(|arg| operand(arg, &mut body))
+                .collect::<Vec<_>>();
+            let function = match operation.name {
+                "+" => "add",
+                "-" => "sub",
+                "*" => "mul",
+                "/" => "div",
+                "^" => "pow",
+                "ln" => "log",
+                name => name,
+            };
+            body.push(format!(
+                "    mpfr_{}(t{}, {}, MPFR_RNDN);",
+                function,
+                idx,
+                args.join(", ")
+            ));
+        }
+        let result = operand(&lowered.result, &mut body);
+        body.push(format!("    mpfr_set(result, {}, MPFR_RNDN);", result));
+
+        let variables = (0..lowered.operations.len())
+            .map(|idx| format!("t{}", idx))
+            .chain(constants.iter().map(|(_, name)| name.clone()))
+            .collect::<Vec<_>>();
+        let args = std::iter::once("mpfr_t result".to_string())
+            .chain(inputs.iter().map(|input| format!("const mpfr_t {}", input)))
+            .collect::<Vec<_>>();
+
+        let mut source = format!(
+            "#include <mpfr.h>\n\n/* Generated by rcas from {}, at the precision of result */\n\
+             void {}({})\n{{\n",
+            expr,
+            self.name,
+            args.join(", ")
+        );
+        if !variables.is_empty() {
+            source.push_str(&format!(
+                "    mpfr_t {};\n    mpfr_inits2(mpfr_get_prec(result), {}, (mpfr_ptr) 0);\n",
+                variables.join(", "),
+                variables.join(", ")
+            ));
+        }
+        source.push_str(&body.join("\n"));
+        source.push('\n');
+        if !variables.is_empty() {
+            source.push_str(&format!(
+                "    mpfr_clears({}, (mpfr_ptr) 0);\n",
+                variables.join(", ")
+            ));
+        }
+        source.push_str("}\n");
+        source
+    }
+}
+
+/// A constant as a number both BigFloat and MPFR can parse, which fractions are not
+fn decimal(num: &Number) -> String {
+    match num {
+        Number::Rational(..) => num.clone().to_float().to_string(),
+        _ => num.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use std::process::Command;
+
+    /// x + 1 is used three times, so it should only be computed once
+    const SOURCE: &str = "(x + 1) * (x + 1) - sin(x + 1) / y";
+    const POINTS: [(f64, f64); 3] = [(0.5, 2.0), (-3.25, 0.75), (10.0, -4.0)];
+
+    fn generate(language: Language, backend: Backend) -> String {
+        crate::set_debug_logging(false);
+        let expr = Expr::parse(SOURCE, &Context::pure_math()).unwrap();
+        let inputs = ["x".to_string(), "y".to_string()];
+        CodeGen::new(language, backend)
+            .generate(&expr, &inputs)
+            .unwrap()
+    }
+
+    fn expected(x: f64, y: f64) -> f64 {
+        (x + 1.0) * (x + 1.0) - (x + 1.0).sin() / y
+    }
+
+    /// Compiles the source and runs it, giving what it printed, or None if there is no compiler
+    fn compile_and_run(
+        source: &str,
+        extension: &str,
+        compiler: &str,
+        args: &[&str],
+    ) -> Option<String> {
+        let dir =
+            std::env::temp_dir().join(format!("rcas_codegen_{}_{}", std::process::id(), extension));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source_path = dir.join(format!("main.{}", extension));
+        let binary = dir.join("main");
+        std::fs::write(&source_path, source).unwrap();
+
+        let compiled = Command::new(compiler)
+            .arg(&source_path)
+            .arg("-o")
+            .arg(&binary)
+            .args(args)
+            .output()
+            .ok()?;
+        assert!(
+            compiled.status.success(),
+            "{}",
+            String::from_utf8_lossy(&compiled.stderr)
+        );
+        let output = Command::new(&binary).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        Some(String::from_utf8(output.stdout).unwrap())
+    }
+
+    fn check_output(output: &str) {
+        assert_eq!(output.lines().count(), POINTS.len());
+        let values = output.lines().map(|line| line.parse::<f64>().unwrap());
+        for ((x, y), value) in POINTS.into_iter().zip(values) {
+            assert!(
+                (value - expected(x, y)).abs() < 1e-12,
+                "f({}, {}) = {}",
+                x,
+                y,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn names_shared_subexpressions_in_rust() {
+        assert_eq!(
+            generate(Language::Rust, Backend::F64),
+            "/// Generated by rcas from (x + 1) * (x + 1) - sin(x + 1) / y\n\
+             pub fn f(x: f64, y: f64) -> f64 {\n    \
+                 let t0 = x + 1.0;\n    \
+                 (t0 * t0) - (t0.sin() / y)\n\
+             }\n"
+        );
+        assert_eq!(
+            generate(Language::Rust, Backend::BigFloat),
+            "use num_bigfloat::BigFloat;\n\n\
+             /// Generated by rcas from (x + 1) * (x + 1) - sin(x + 1) / y\n\
+             pub fn f(x: &BigFloat, y: &BigFloat) -> BigFloat {\n    \
+                 let t0 = x.add(&BigFloat::parse(\"1\").unwrap());\n    \
+                 t0.mul(&t0).sub(&t0.sin().div(y))\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn names_shared_subexpressions_in_c() {
+        assert_eq!(
+            generate(Language::C, Backend::F64),
+            "#include <math.h>\n\n\
+             /* Generated by rcas from (x + 1) * (x + 1) - sin(x + 1) / y */\n\
+             double f(double x, double y)\n{\n    \
+                 const double t0 = x + 1.0;\n    \
+                 return (t0 * t0) - (sin(t0) / y);\n\
+             }\n"
+        );
+
+        let mpfr = generate(Language::C, Backend::BigFloat);
+        assert_eq!(mpfr.matches("mpfr_add").count(), 1);
+        assert!(mpfr.contains("    mpfr_mul(t1, t0, t0, MPFR_RNDN);\n"));
+        assert!(mpfr.contains("    mpfr_sin(t2, t0, MPFR_RNDN);\n"));
+        assert!(mpfr.contains("    mpfr_clears(t0, t1, t2, t3, t4, c0, (mpfr_ptr) 0);\n"));
+    }
+
+    #[test]
+    fn generated_rust_computes_the_expression() {
+        let mut source = generate(Language::Rust, Backend::F64);
+        source.push_str("\nfn main() {\n");
+        for (x, y) in POINTS {
+            source.push_str(&format!("    println!(\"{{:?}}\", f({:?}, {:?}));\n", x, y));
+        }
+        source.push_str("}\n");
+
+        if let Some(output) = compile_and_run(&source, "rs", "rustc", &["--edition", "2021"]) {
+            check_output(&output);
+        }
+    }
+
+    #[test]
+    fn generated_c_computes_the_expression() {
+        let mut source = generate(Language::C, Backend::F64);
+        source.push_str("\n#include <stdio.h>\n\nint main(void)\n{\n");
+        for (x, y) in POINTS {
+            source.push_str(&format!("    printf(\"%.17g\\n\", f({:?}, {:?}));\n", x, y));
+        }
+        source.push_str("    return 0;\n}\n");
+
+        if let Some(output) = compile_and_run(&source, "c", "cc", &["-lm"]) {
+            check_output(&output);
+        }
+    }
+
+    #[test]
+    fn refuses_what_it_cannot_compile() {
+        let context = Context::pure_math();
+        let codegen = CodeGen::new(Language::C, Backend::F64);
+        let inputs = ["x".to_string()];
+        for source in ["x + y", "gcd(x, 2)"] {
+            let expr = Expr::parse(source, &context).unwrap();
+            assert!(codegen.generate(&expr, &inputs).is_err(), "{}", source);
+        }
+        assert!(CodeGen::from_names("python", "f64").is_err());
+        assert_eq!(
+            CodeGen::from_names("C", "mpfr").unwrap(),
+            CodeGen::new(Language::C, Backend::BigFloat)
+        );
+    }
+}
@@ -1,31 +1,331 @@
 use crate::default_ctx_macros::DEFAULT_FUNCTIONS;
-use crate::token_defs::{Functor, Variable};
+use crate::number_format::NumberFormat;
+use crate::pretty::PrettyStyle;
+use crate::token_defs::{Functor, Token, Variable};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+
+/// The groups the default functions are split into, so that a context can be built with only the
+/// functions it should be allowed to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Stack and variable handling, e.g. Clear and :=
+    Core,
+    /// Pure arithmetic, e.g. + and *
+    Math,
+    /// Anything that reads from or writes to the terminal, e.g. Print and ::PAUSE
+    Io,
+    /// Anything that reads or writes files, e.g. Save, Load and Plot
+    Fs,
+    /// Anything that controls the host process, e.g. Exit
+    Process,
+    /// Introspection for debugging, e.g. ::STACK_DUMP and ::CONTEXT_DUMP
+    Debug,
+}
+impl Capability {
+    /// Every capability, which is what `get_default_functions` hands out
+    pub const ALL: [Capability; 6] = [
+        Capability::Core,
+        Capability::Math,
+        Capability::Io,
+        Capability::Fs,
+        Capability::Process,
+        Capability::Debug,
+    ];
+
+    /// Only what is needed to compute: no I/O, no files, no process control and no introspection
+    pub const PURE_MATH: [Capability; 2] = [Capability::Core, Capability::Math];
+}
+
+/// Where functors such as Print write what they show
+#[derive(Debug, Clone, Default)]
+pub enum Output {
+    #[default]
+    Stdout,
+    /// Kept in memory until it is taken, e.g. by a debug adapter, whose stdout carries the protocol
+    /// so it has to pass the output on itself
+    Buffer(Arc<Mutex<String>>),
+}
+impl Output {
+    pub fn buffer() -> Self {
+        Output::Buffer(Arc::new(Mutex::new(String::new())))
+    }
+
+    /// Writes the text followed by a newline
+    pub fn write_line(&self, text: impl Display) {
+        match self {
+            Output::Stdout => println!("{}", text),
+            Output::Buffer(buffer) => {
+                let mut buffer = buffer.lock().unwrap_or_else(|e| e.into_inner());
+                buffer.push_str(&format!("{}\n", text));
+            }
+        }
+    }
+
+    /// Everything written to a buffer since it was last taken, always empty for stdout
+    pub fn take(&self) -> String {
+        match self {
+            Output::Stdout => String::new(),
+            Output::Buffer(buffer) => {
+                std::mem::take(&mut *buffer.lock().unwrap_or_else(|e| e.into_inner()))
+            }
+        }
+    }
+}
+/// Two buffers are only the same if they are shared, whatever they hold
+impl PartialEq for Output {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Output::Stdout, Output::Stdout) => true,
+            (Output::Buffer(lhs), Output::Buffer(rhs)) => Arc::ptr_eq(lhs, rhs),
+            _ => false,
+        }
+    }
+}
 
 /// A Context is a vec of variables and functions which have been provided already. This is
 /// used to tell the stack machine what some functions and variables are, for example passing pi or
 /// e through as constants.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Context {
     pub variables: Vec<Variable>,
     pub functions: Vec<Functor>,
+    /// How Print draws expressions, on one line with `Display` if None. Set by PrettyPrint.
+    #[serde(default)]
+    pub pretty_print: Option<PrettyStyle>,
+    /// How Print writes numbers. Set by Format.
+    #[serde(default)]
+    pub number_format: NumberFormat,
+    /// The executor's `ExecutionLimits::max_int_bits`, so functors which can build huge integers
+    /// can refuse to before doing the work. Set by the executor before every step.
+    #[serde(skip)]
+    pub max_int_bits: Option<u64>,
+    /// Where Print and the other functors which show something write to
+    #[serde(skip)]
+    pub output: Output,
+    /// Goes up whenever the variables or settings are changed through the methods here, so the
+    /// undo history can tell a line changed something without comparing whole contexts. Anything
+    /// changing them directly should bump it too.
+    #[serde(skip)]
+    pub revision: u64,
 }
 impl Context {
     pub fn new() -> Self {
-        return Context {
+        Context {
             variables: vec![],
             functions: vec![],
+            pretty_print: None,
+            number_format: NumberFormat::Auto,
+            max_int_bits: None,
+            output: Output::Stdout,
+            revision: 0,
+        }
+    }
+
+    /// Creates a context holding only the default functions from the given capability groups
+    pub fn with_capabilities(capabilities: &[Capability]) -> Self {
+        Context {
+            functions: get_functions_with(capabilities),
+            ..Context::new()
+        }
+    }
+
+    /// Creates a context which can only compute, see `Capability::PURE_MATH`. This is what should
+    /// be used to evaluate untrusted input.
+    pub fn pure_math() -> Self {
+        Self::with_capabilities(&Capability::PURE_MATH)
+    }
+
+    /// Finds the variable currently bound to a name, if any
+    pub fn get_variable(&self, name: &str) -> Option<&Variable> {
+        self.variables.iter().find(|var| var.name == name)
+    }
+
+    /// Binds a value to a name, overwriting the existing binding if the name is already in use
+    pub fn set_variable(&mut self, name: String, value: Token) {
+        self.revision += 1;
+        match self.variables.iter_mut().find(|var| var.name == name) {
+            Some(var) => var.value = Some(Box::new(value)),
+            None => self.variables.push(Variable {
+                name,
+                value: Some(Box::new(value)),
+            }),
+        }
+    }
+
+    /// Unbinds a name, returning false if nothing was bound to it
+    pub fn remove_variable(&mut self, name: &str) -> bool {
+        let Some(idx) = self.variables.iter().position(|var| var.name == name) else {
+            return false;
+        };
+        self.revision += 1;
+        self.variables.remove(idx);
+        true
+    }
+
+    /// A token the way Print shows it: numbers in the number format, and expressions drawn by the
+    /// pretty printer if it is on
+    pub fn show(&self, token: &Token) -> String {
+        match (token, self.pretty_print) {
+            (Token::Const(num), _) => num.format(self.number_format),
+            (token, Some(style)) => token.to_pretty(style),
+            (token, None) => token.to_string(),
+        }
+    }
+
+    /// Resolves a token against the live variables: a bound symbol or variable is replaced by its
+    /// current value, everything else (including unbound symbols) is returned untouched
+    pub fn resolve(&self, token: Token) -> Token {
+        let name = match &token {
+            Token::Symbol(name) => name,
+            Token::Variable(var) => &var.name,
+            _ => return token,
         };
+
+        match self.get_variable(name).and_then(|var| var.value.as_deref()) {
+            Some(value) => value.clone(),
+            None => token,
+        }
+    }
+}
+
+/// The revision only counts changes, two contexts holding the same are equal whatever it is
+impl PartialEq for Context {
+    fn eq(&self, other: &Self) -> bool {
+        self.variables == other.variables
+            && self.functions == other.functions
+            && self.pretty_print == other.pretty_print
+            && self.number_format == other.number_format
+            && self.max_int_bits == other.max_int_bits
+            && self.output == other.output
     }
 }
 
-/// Grabs the default set of functions
+/// Grabs the default set of functions, including the ones which can do I/O or exit the process.
+/// Use `get_functions_with` to only grab some of them.
 pub fn get_default_functions() -> Vec<Functor> {
+    get_functions_with(&Capability::ALL)
+}
+
+/// Grabs the default functions which belong to one of the given capability groups
+pub fn get_functions_with(capabilities: &[Capability]) -> Vec<Functor> {
     DEFAULT_FUNCTIONS
-        .to_vec()
         .iter()
-        .map(|f| Functor {
-            name: f.0.to_string(),
-            func: f.1,
-        })
+        .filter(|def| capabilities.contains(&def.capability))
+        .map(|def| def.to_functor())
         .collect::<Vec<Functor>>()
 }
+
+/// Finds the default function registered under a name, e.g. to turn a saved name back into a
+/// functor. This looks through every capability group, see `find_function_with`.
+pub fn find_default_function(name: &str) -> Option<Functor> {
+    find_function_with(name, &Capability::ALL)
+}
+
+/// Finds the default function registered under a name, if it belongs to one of the given
+/// capability groups
+pub fn find_function_with(name: &str, capabilities: &[Capability]) -> Option<Functor> {
+    DEFAULT_FUNCTIONS
+        .iter()
+        .find(|def| def.name == name && capabilities.contains(&def.capability))
+        .map(|def| def.to_functor())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_ctx_macros::DEFAULT_CTX;
+    use crate::number::Number;
+    use crate::parse_rpol_notation::single_command_to_token;
+
+    fn has(context: &Context, name: &str) -> bool {
+        context.functions.iter().any(|func| func.name == name)
+    }
+
+    #[test]
+    fn pure_math_lacks_everything_but_computing() {
+        for context in [Context::pure_math(), DEFAULT_CTX.clone()] {
+            assert!(has(&context, "+"));
+            assert!(has(&context, ":="));
+            for name in ["Print", "Save", "Load", "Plot", "Exit", "::STACK_DUMP"] {
+                assert!(!has(&context, name), "{} should not be available", name);
+            }
+        }
+
+        let files = Context::with_capabilities(&[Capability::Fs]);
+        assert!(has(&files, "Save") && has(&files, "Plot"));
+        assert!(!has(&files, "Print"));
+    }
+
+    #[test]
+    fn finds_functions_only_in_the_given_groups() {
+        assert!(find_function_with("Exit", &Capability::PURE_MATH).is_none());
+        assert!(find_function_with("Load", &[Capability::Io]).is_none());
+        assert!(find_function_with("Load", &[Capability::Fs]).is_some());
+        assert!(find_default_function("Exit").is_some());
+    }
+
+    #[test]
+    fn refuses_to_parse_functions_it_lacks() {
+        let context = Context::pure_math();
+        let exit = single_command_to_token("Exit".to_string(), &context).unwrap();
+        assert_eq!(exit, Token::Symbol("Exit".to_string()));
+
+        let all = Context::with_capabilities(&Capability::ALL);
+        let exit = single_command_to_token("Exit".to_string(), &all).unwrap();
+        assert!(matches!(exit, Token::Functor(func) if func.name == "Exit"));
+    }
+
+    fn int(value: i32) -> Token {
+        Token::Const(Number::Int(value.into()))
+    }
+
+    #[test]
+    fn checks_arguments_before_running() {
+        let mut context = Context::pure_math();
+        let add = find_default_function("+").unwrap();
+        let assign = find_default_function(":=").unwrap();
+        let error = |func: &Functor, stack: &[Token], context: &Context| {
+            func.check_args(stack, context).unwrap_err().to_string()
+        };
+
+        assert!(add.check_args(&[int(1), int(2)], &context).is_ok());
+        assert_eq!(
+            error(&add, &[int(1)], &context),
+            "+ expects 2 argument(s) but the stack only holds 1 (usage: Number Number +)"
+        );
+        let text = Token::String("a".to_string());
+        assert!(error(&add, &[int(1), text], &context).contains("expects a Number argument"));
+
+        // Names count as numbers once they are bound to one
+        let x = Token::Symbol("x".to_string());
+        assert!(add.check_args(&[x.clone(), int(1)], &context).is_err());
+        context.set_variable("x".to_string(), int(3));
+        assert!(add.check_args(&[x.clone(), int(1)], &context).is_ok());
+
+        // Only the arguments at the top of the stack are checked
+        assert!(assign.check_args(&[int(1), x, int(2)], &context).is_ok());
+        assert!(error(&assign, &[int(1), int(2)], &context).contains("expects a Name argument"));
+    }
+
+    #[test]
+    fn help_shows_the_usage_and_docs() {
+        let mut context = Context::with_capabilities(&[Capability::Math, Capability::Io]);
+        context.output = Output::buffer();
+        let help = find_default_function("Help").unwrap();
+
+        let mut stack = vec![Token::String("+".to_string())];
+        assert!((help.func)(&mut stack, &mut context).unwrap().is_empty());
+        assert_eq!(context.output.take(), "Number Number +\n    + operator\n");
+
+        let mut stack = vec![Token::Functor(help.clone())];
+        (help.func)(&mut stack, &mut context).unwrap();
+        let shown = context.output.take();
+        assert!(shown.starts_with("Any Help\n    Help command"));
+
+        let mut stack = vec![Token::Symbol("Nothing".to_string())];
+        assert!((help.func)(&mut stack, &mut context).is_err());
+        assert!((help.func)(&mut vec![int(1)], &mut context).is_err());
+    }
+}
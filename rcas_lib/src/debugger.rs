@@ -2,27 +2,52 @@
 use crate::{
     context::Context,
     stack_machine::{BufferedExecutor, RevPolStackMachine},
-    token_defs::Token,
+    token_defs::{Functor, Token},
 };
 use anyhow::Result;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// What a single call to `RevPolStackMachine::next` did
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenStep {
+    /// The token which was popped off the stack
+    pub token: Token,
+    /// The functor which fired, if the token was one
+    pub functor: Option<Functor>,
+    /// The arguments the functor took off the stack, bottom first
+    pub consumed: Vec<Token>,
+    /// The results the functor put on the stack, bottom first
+    pub produced: Vec<Token>,
+}
 
 pub struct RevPolBufSnapshot {
     pub stack_machine_stack: Vec<Token>,
     pub stack_machine_ctx: Context,
-    pub lines: Vec<Vec<Token>>,
+    /// Shared, as it is the same for every snapshot of a run
+    pub lines: Arc<Vec<Vec<Token>>>,
     pub current_line: usize,
+    /// Whether current_line is partway through running, i.e. the snapshot was taken between two
+    /// token steps
+    pub line_in_progress: bool,
     pub machine_is_complete: bool,
     pub last_result: Option<Result<()>>,
+    /// What the last token step did, only filled in when stepping a token at a time
+    pub last_step: Option<TokenStep>,
 }
 impl RevPolBufSnapshot {
     pub fn from_machine(machine: &BufferedExecutor, lines: Vec<Vec<Token>>) -> RevPolBufSnapshot {
         Self {
             stack_machine_stack: machine.machine.stack.clone(),
             stack_machine_ctx: machine.machine.context.clone(),
-            lines,
+            lines: Arc::new(lines),
             current_line: machine.current_line,
+            line_in_progress: machine.line_in_progress(),
             machine_is_complete: machine.machine_is_complete(),
             last_result: None,
+            last_step: None,
         }
     }
 
@@ -34,19 +59,46 @@ impl RevPolBufSnapshot {
         Self {
             stack_machine_stack: machine.machine.stack.clone(),
             stack_machine_ctx: machine.machine.context.clone(),
-            lines,
+            lines: Arc::new(lines),
             current_line: machine.current_line,
+            line_in_progress: machine.line_in_progress(),
             machine_is_complete: machine.machine_is_complete(),
             last_result: Some(result),
+            last_step: None,
         }
     }
 
     pub fn is_error(&self) -> bool {
-        if let Some(Err(_)) = self.last_result {
-            return true;
+        matches!(self.last_result, Some(Err(_)))
+    }
+
+    /// Index of the line the last step ran in
+    pub fn ran_line(&self) -> usize {
+        if self.line_in_progress || self.is_error() {
+            self.current_line
+        } else {
+            self.current_line - 1
         }
+    }
+}
+/// Errors are serialized as their message, e.g. `"last_result": {"Err": "..."}`
+impl Serialize for RevPolBufSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let last_result = self
+            .last_result
+            .as_ref()
+            .map(|result| result.as_ref().map(|_| ()).map_err(|e| format!("{:#}", e)));
 
-        return false;
+        let mut snap = serializer.serialize_struct("RevPolBufSnapshot", 8)?;
+        snap.serialize_field("stack", &self.stack_machine_stack)?;
+        snap.serialize_field("context", &self.stack_machine_ctx)?;
+        snap.serialize_field("lines", self.lines.as_slice())?;
+        snap.serialize_field("current_line", &self.current_line)?;
+        snap.serialize_field("line_in_progress", &self.line_in_progress)?;
+        snap.serialize_field("machine_is_complete", &self.machine_is_complete)?;
+        snap.serialize_field("last_result", &last_result)?;
+        snap.serialize_field("last_step", &self.last_step)?;
+        snap.end()
     }
 }
 impl std::fmt::Display for RevPolBufSnapshot {
@@ -55,8 +107,12 @@ impl std::fmt::Display for RevPolBufSnapshot {
         write!(f, "\nContext: {:#?}", self.stack_machine_ctx)?;
         write!(f, "\nLines: {:#?}", self.lines)?;
         write!(f, "\nCurrent line: {}", self.current_line)?;
+        write!(f, "\nLine in progress: {}", self.line_in_progress)?;
         write!(f, "\nMachine is done: {}", self.machine_is_complete)?;
         write!(f, "\nLast result: {:?}", self.last_result)?;
+        if let Some(step) = &self.last_step {
+            write!(f, "\nLast step: {:#?}", step)?;
+        }
 
         Ok(())
     }
@@ -68,22 +124,106 @@ pub trait IntoDebugger<Snapshot> {
     fn is_done(&self) -> bool;
 }
 
-pub struct BufExecDebugger(BufferedExecutor);
+/// How far each step of a BufExecDebugger goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StepMode {
+    /// Run a whole line
+    #[default]
+    Line,
+    /// Pop a single token, so the stack can be seen partway through a line
+    Token,
+}
+
+/// Steps through a BufferedExecutor a line or a token at a time. It keeps undo history, so lines
+/// can be stepped back over too.
+pub struct BufExecDebugger {
+    executor: BufferedExecutor,
+    mode: StepMode,
+}
 impl BufExecDebugger {
-    pub fn new(executor: BufferedExecutor) -> Self {
-        Self(executor)
+    pub fn new(mut executor: BufferedExecutor) -> Self {
+        if !executor.machine.keeps_history() {
+            executor.machine.enable_history();
+        }
+        Self {
+            executor,
+            mode: StepMode::Line,
+        }
     }
 
     pub fn new_machine(inner: RevPolStackMachine, lines: Vec<Vec<Token>>) -> Self {
-        Self(BufferedExecutor::new(inner, lines))
+        Self::new(BufferedExecutor::new(inner, lines))
+    }
+
+    pub fn with_mode(mut self, mode: StepMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn set_mode(&mut self, mode: StepMode) {
+        self.mode = mode;
     }
 
     pub fn machine_is_complete(&self) -> bool {
-        self.0.machine_is_complete()
+        self.executor.machine_is_complete()
+    }
+
+    pub fn executor(&self) -> &BufferedExecutor {
+        &self.executor
     }
 
     pub fn run_line(&mut self) -> Result<()> {
-        self.0.run_line()
+        self.executor.run_line()
+    }
+
+    /// Pops a single token off the stack (putting the current line on first if it is not yet),
+    /// returning what that did. Returns None for a line with no functors in it, as that is done as
+    /// soon as it is on the stack.
+    pub fn step_token(&mut self) -> Result<Option<TokenStep>> {
+        let executor = &mut self.executor;
+        let started = executor.line_in_progress();
+        // Only what changed since the last commit is copied, the rest is shared with the history
+        let below = executor.machine.unchanged_stack();
+        let mut above = executor.machine.stack[below.len()..].to_vec();
+        if !started {
+            above.extend(executor.lines[executor.current_line].iter().cloned());
+        }
+        let steps = executor.steps;
+
+        executor.step()?;
+        if executor.steps == steps {
+            return Ok(None);
+        }
+
+        // The token was popped off the top, and the functor took its arguments from right below it
+        let token = above.pop().expect("a step always pops a token");
+        let bottom = below.len() + above.len() - executor.machine.last_consumed;
+        let consumed = match bottom.checked_sub(below.len()) {
+            Some(idx) => above.split_off(idx),
+            None => below.skip(bottom).into_iter().chain(above).collect(),
+        };
+        let functor = match &token {
+            Token::Functor(func) => Some(func.clone()),
+            _ => None,
+        };
+
+        Ok(Some(TokenStep {
+            token,
+            functor,
+            consumed,
+            produced: executor.machine.stack[bottom..].to_vec(),
+        }))
+    }
+
+    /// Steps back over the last line, returning the state it left the machine in, or None if
+    /// there is nothing to undo
+    pub fn undo_with_snapshot(&mut self) -> Option<RevPolBufSnapshot> {
+        self.executor.undo_line().then(|| self.static_snapshot())
+    }
+
+    /// Steps forward over the last undone line, without running it again
+    pub fn redo_with_snapshot(&mut self) -> Option<RevPolBufSnapshot> {
+        self.executor.redo_line().then(|| self.static_snapshot())
     }
 }
 impl IntoDebugger<RevPolBufSnapshot> for BufExecDebugger {
@@ -91,13 +231,21 @@ impl IntoDebugger<RevPolBufSnapshot> for BufExecDebugger {
         self.machine_is_complete()
     }
     fn next_with_snapshot(&mut self) -> RevPolBufSnapshot {
-        let result = self.run_line();
+        let (result, step) = match self.mode {
+            StepMode::Line => (self.run_line(), None),
+            StepMode::Token => match self.step_token() {
+                Ok(step) => (Ok(()), step),
+                Err(e) => (Err(e), None),
+            },
+        };
+
         let mut snap = self.static_snapshot();
         snap.last_result = Some(result);
+        snap.last_step = step;
         snap
     }
     fn static_snapshot(&self) -> RevPolBufSnapshot {
-        RevPolBufSnapshot::from_machine(&self.0, self.0.lines.clone())
+        RevPolBufSnapshot::from_machine(&self.executor, self.executor.lines.clone())
     }
 }
 
@@ -124,3 +272,479 @@ impl<W, T: IntoDebugger<W>> Iterator for Stepper<W, T> {
         Some(self.inner.next_with_snapshot())
     }
 }
+
+/// Where a DebugController should stop
+pub enum Breakpoint {
+    /// Before the first line which comes from this source line or a later one starts, counting
+    /// from 1, see `DebugController::with_source_lines`
+    Line(usize),
+    /// Before any functor with this name is called
+    Functor(String),
+    /// Once the stack grows past this many tokens
+    StackDepth(usize),
+    /// Once the value bound to this variable changes, including it being bound or deleted
+    VariableChanged(String),
+    /// Once the condition starts to hold
+    When(Box<dyn Fn(&RevPolStackMachine) -> bool + Send>),
+}
+impl Breakpoint {
+    /// What the condition of the breakpoint depends on, compared after every step to see whether
+    /// it changed. Line and functor breakpoints are checked before steps instead.
+    fn watch(&self, machine: &RevPolStackMachine) -> Watched {
+        match self {
+            Breakpoint::StackDepth(depth) => Watched::Holds(machine.stack.len() > *depth),
+            Breakpoint::When(condition) => Watched::Holds(condition(machine)),
+            Breakpoint::VariableChanged(name) => Watched::Value(
+                machine
+                    .context
+                    .get_variable(name)
+                    .and_then(|var| var.value.as_deref())
+                    .cloned(),
+            ),
+            Breakpoint::Line(_) | Breakpoint::Functor(_) => Watched::Holds(false),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum Watched {
+    Holds(bool),
+    Value(Option<Token>),
+}
+impl Watched {
+    /// Whether going from self to now should stop execution
+    fn fires(&self, now: &Watched) -> bool {
+        match (self, now) {
+            (Watched::Holds(before), Watched::Holds(now)) => !before && *now,
+            (before, now) => before != now,
+        }
+    }
+}
+
+/// Why a DebugController handed control back
+#[derive(Debug)]
+pub enum StopReason {
+    /// The step that was asked for is done
+    Step,
+    /// The breakpoint with this id was hit
+    Breakpoint(usize),
+    /// A ::PAUSE is next. It is skipped by the next step rather than waiting on stdin.
+    Pause,
+    /// Something else asked it to stop, see `DebugController::set_interrupt_flag`
+    Interrupted,
+    /// The current line failed, running on will start it again
+    Error(anyhow::Error),
+    /// Every line has run
+    Finished,
+}
+
+/// Runs a BufferedExecutor under the control of something else, e.g. a GUI or an editor: it never
+/// touches the terminal, it only stops where it is told to and reports why.
+pub struct DebugController {
+    executor: BufferedExecutor,
+    breakpoints: Vec<(usize, Breakpoint, Watched)>,
+    next_id: usize,
+    /// Whether the last stop was at a breakpoint on the current position, which should not stop
+    /// execution again when it carries on
+    stopped_before: bool,
+    /// The source line each of the executor's lines came from, or None if they are one to one
+    source_lines: Option<Vec<usize>>,
+    interrupt: Arc<AtomicBool>,
+}
+impl DebugController {
+    pub fn new(executor: BufferedExecutor) -> Self {
+        Self {
+            executor,
+            breakpoints: vec![],
+            next_id: 0,
+            stopped_before: false,
+            source_lines: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Sets the source line each of the executor's lines came from, which line breakpoints are
+    /// given as. Blank and comment lines are skipped when a script is split into lines, so
+    /// without this a script is taken to have none.
+    pub fn with_source_lines(mut self, source_lines: Vec<usize>) -> Self {
+        self.source_lines = Some(source_lines);
+        self
+    }
+
+    /// The executor line a breakpoint on this source line stops before, if there is one at or
+    /// after it
+    pub fn line_for(&self, source_line: usize) -> Option<usize> {
+        let line = match &self.source_lines {
+            Some(lines) => lines.partition_point(|line| *line < source_line),
+            None => source_line.saturating_sub(1),
+        };
+        (line < self.executor.lines.len()).then_some(line)
+    }
+
+    /// Lets another thread stop `resume` and `step_over` before their next step by setting the
+    /// flag, which makes them return `StopReason::Interrupted`. It is cleared once it has stopped
+    /// them.
+    pub fn set_interrupt_flag(&mut self, flag: Arc<AtomicBool>) {
+        self.interrupt = flag;
+    }
+
+    pub fn executor(&self) -> &BufferedExecutor {
+        &self.executor
+    }
+
+    /// True once every line has been run, after which nothing will stop it again
+    pub fn is_finished(&self) -> bool {
+        self.executor.machine_is_complete()
+    }
+
+    pub fn snapshot(&self) -> RevPolBufSnapshot {
+        RevPolBufSnapshot::from_machine(&self.executor, self.executor.lines.clone())
+    }
+
+    /// Adds a breakpoint, returning the id it is reported with and can be removed by
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let watched = breakpoint.watch(&self.executor.machine);
+        self.breakpoints.push((id, breakpoint, watched));
+        id
+    }
+
+    /// Removes a breakpoint, returning false if there was none with that id
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|(bp_id, _, _)| *bp_id != id);
+        self.breakpoints.len() != len
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Runs a single step, i.e. one token off the stack, stepping into the middle of lines
+    pub fn step_into(&mut self) -> StopReason {
+        if self.executor.machine_is_complete() {
+            return StopReason::Finished;
+        }
+
+        self.stopped_before = false;
+        if let Err(e) = self.advance() {
+            return StopReason::Error(e);
+        }
+        match self.check_watches() {
+            Some(id) => StopReason::Breakpoint(id),
+            None => StopReason::Step,
+        }
+    }
+
+    /// Runs the rest of the current line, or the whole of the next one, unless something stops it
+    /// before that
+    pub fn step_over(&mut self) -> StopReason {
+        self.run(true)
+    }
+
+    /// Runs until a breakpoint is hit, a line fails, or every line has run
+    pub fn resume(&mut self) -> StopReason {
+        self.run(false)
+    }
+
+    fn run(&mut self, stop_at_line_end: bool) -> StopReason {
+        loop {
+            if self.executor.machine_is_complete() {
+                return StopReason::Finished;
+            }
+            if self.interrupt.swap(false, Ordering::Relaxed) {
+                return StopReason::Interrupted;
+            }
+            // Otherwise it could never get past the breakpoint it stopped at
+            if !std::mem::take(&mut self.stopped_before)
+                && let Some(reason) = self.check_position()
+            {
+                self.stopped_before = true;
+                return reason;
+            }
+
+            let line_done = match self.advance() {
+                Ok(done) => done,
+                Err(e) => return StopReason::Error(e),
+            };
+            if let Some(id) = self.check_watches() {
+                return StopReason::Breakpoint(id);
+            }
+            if line_done && stop_at_line_end {
+                return StopReason::Step;
+            }
+        }
+    }
+
+    /// Steps the executor, skipping over ::PAUSE instead of waiting on stdin
+    fn advance(&mut self) -> anyhow::Result<bool> {
+        if is_pause(self.executor.next_token()) {
+            self.executor.skip()
+        } else {
+            self.executor.step()
+        }
+    }
+
+    /// Checks the breakpoints which stop before a step
+    fn check_position(&self) -> Option<StopReason> {
+        let next = self.executor.next_token();
+        if is_pause(next) {
+            return Some(StopReason::Pause);
+        }
+
+        self.breakpoints
+            .iter()
+            .find(|(_, breakpoint, _)| match (breakpoint, next) {
+                (Breakpoint::Line(line), _) => {
+                    !self.executor.line_in_progress()
+                        && self.line_for(*line) == Some(self.executor.current_line)
+                }
+                (Breakpoint::Functor(name), Some(Token::Functor(func))) => func.name == *name,
+                _ => false,
+            })
+            .map(|(id, _, _)| StopReason::Breakpoint(*id))
+    }
+
+    /// Checks the breakpoints which stop after a step, returning the first one hit. Every watch is
+    /// updated, so none of them fire again for the same change.
+    fn check_watches(&mut self) -> Option<usize> {
+        let mut hit = None;
+
+        for (id, breakpoint, watched) in &mut self.breakpoints {
+            let now = breakpoint.watch(&self.executor.machine);
+            if watched.fires(&now) && hit.is_none() {
+                hit = Some(*id);
+            }
+            *watched = now;
+        }
+
+        hit
+    }
+}
+
+fn is_pause(token: Option<&Token>) -> bool {
+    matches!(token, Some(Token::Functor(func)) if func.name == "::PAUSE")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::number::Number;
+    use crate::parse_rpol_notation::{
+        commands_to_sequential_exec_order, spanned_commands_to_exec_order, split_into_commands,
+        split_into_spanned_commands,
+    };
+
+    fn controller(script: &str) -> DebugController {
+        crate::set_debug_logging(false);
+        let context = Context::pure_math();
+        let commands = split_into_spanned_commands(script);
+        let lines = spanned_commands_to_exec_order(&commands, &context).unwrap();
+        let source_lines = commands.iter().map(|line| line[0].1.line).collect();
+        let machine = RevPolStackMachine::new_with_ctx(context);
+        DebugController::new(BufferedExecutor::new(machine, lines)).with_source_lines(source_lines)
+    }
+
+    fn int(value: i32) -> Token {
+        Token::Const(Number::Int(value.into()))
+    }
+
+    fn stack(controller: &DebugController) -> Vec<Token> {
+        controller.executor().machine.stack.clone()
+    }
+
+    fn hit(reason: StopReason) -> usize {
+        match reason {
+            StopReason::Breakpoint(id) => id,
+            other => panic!("expected a breakpoint, stopped for {:?}", other),
+        }
+    }
+
+    #[test]
+    fn line_breakpoints_are_source_lines() {
+        let mut controller = controller("1\n\n// comment\n2\n+");
+        let id = controller.add_breakpoint(Breakpoint::Line(4));
+        assert_eq!(hit(controller.resume()), id);
+        assert_eq!(controller.executor().current_line, 1);
+        assert_eq!(stack(&controller), vec![int(1)]);
+        assert!(matches!(controller.resume(), StopReason::Finished));
+        assert_eq!(stack(&controller), vec![int(3)]);
+
+        // A breakpoint on a line with nothing to run stops at the next line that has something
+        let controller = self::controller("1\n\n// comment\n2\n+");
+        assert_eq!(controller.line_for(2), Some(1));
+        assert_eq!(controller.line_for(5), Some(2));
+        assert_eq!(controller.line_for(6), None);
+    }
+
+    #[test]
+    fn functor_breakpoints_stop_before_the_call() {
+        let mut controller = controller("1 2 +\n3 *");
+        let add = controller.add_breakpoint(Breakpoint::Functor("+".to_string()));
+        let mul = controller.add_breakpoint(Breakpoint::Functor("*".to_string()));
+
+        assert_eq!(hit(controller.resume()), add);
+        assert!(stack(&controller).is_empty());
+        assert_eq!(hit(controller.resume()), mul);
+        assert_eq!(stack(&controller), vec![int(3)]);
+        assert!(matches!(controller.resume(), StopReason::Finished));
+        assert_eq!(stack(&controller), vec![int(9)]);
+    }
+
+    #[test]
+    fn stack_depth_breakpoints_stop_once_it_is_passed() {
+        let mut controller = controller("1\n2\n3\n+\n4");
+        let id = controller.add_breakpoint(Breakpoint::StackDepth(2));
+        assert_eq!(hit(controller.resume()), id);
+        assert_eq!(stack(&controller), vec![int(1), int(2), int(3)]);
+
+        // Going back under the depth and over it again stops again
+        assert_eq!(hit(controller.resume()), id);
+        assert_eq!(stack(&controller), vec![int(1), int(5), int(4)]);
+        assert!(matches!(controller.resume(), StopReason::Finished));
+    }
+
+    #[test]
+    fn variable_breakpoints_stop_when_the_value_changes() {
+        let mut controller = controller("x 1 :=\n2\nx 3 :=\nx 3 :=\nx &");
+        let id = controller.add_breakpoint(Breakpoint::VariableChanged("x".to_string()));
+
+        assert_eq!(hit(controller.resume()), id);
+        assert_eq!(controller.executor().current_line, 1);
+        assert_eq!(hit(controller.resume()), id);
+        assert_eq!(controller.executor().current_line, 3);
+        // Binding the same value again is not a change, but deleting it is
+        assert_eq!(hit(controller.resume()), id);
+        assert_eq!(controller.executor().current_line, 5);
+        assert!(matches!(controller.resume(), StopReason::Finished));
+    }
+
+    #[test]
+    fn condition_breakpoints_stop_when_it_starts_to_hold() {
+        let mut controller = controller("1\n2\n+\n3");
+        let id = controller.add_breakpoint(Breakpoint::When(Box::new(|machine| {
+            machine.stack.first() == Some(&int(3))
+        })));
+
+        assert_eq!(hit(controller.resume()), id);
+        assert_eq!(stack(&controller), vec![int(3)]);
+        // It still holds after the next line, but it did not start to
+        assert!(matches!(controller.resume(), StopReason::Finished));
+
+        assert!(controller.remove_breakpoint(id));
+        assert!(!controller.remove_breakpoint(id));
+    }
+
+    #[test]
+    fn steps_over_a_line_at_a_time() {
+        let mut controller = controller("1\n2 3 +\n4");
+        assert!(matches!(controller.step_over(), StopReason::Step));
+        assert_eq!(controller.executor().current_line, 1);
+        assert!(matches!(controller.step_over(), StopReason::Step));
+        assert_eq!(stack(&controller), vec![int(1), int(5)]);
+        assert!(matches!(controller.step_into(), StopReason::Step));
+        assert!(controller.is_finished());
+        assert!(matches!(controller.step_over(), StopReason::Finished));
+        assert!(matches!(controller.step_into(), StopReason::Finished));
+    }
+
+    #[test]
+    fn failed_lines_stop_and_start_again() {
+        let mut controller = controller("1\n\"a\" 2 +\n3");
+        for _ in 0..2 {
+            assert!(matches!(controller.resume(), StopReason::Error(_)));
+            assert_eq!(stack(&controller), vec![int(1)]);
+            assert_eq!(controller.executor().current_line, 1);
+        }
+    }
+
+    #[test]
+    fn interrupts_stop_before_the_next_step() {
+        let mut controller = controller("1\n2");
+        let flag = Arc::new(AtomicBool::new(true));
+        controller.set_interrupt_flag(flag.clone());
+
+        assert!(matches!(controller.resume(), StopReason::Interrupted));
+        assert!(stack(&controller).is_empty());
+        assert!(!flag.load(Ordering::Relaxed));
+        assert!(matches!(controller.resume(), StopReason::Finished));
+    }
+
+    fn debugger(script: &str, mode: StepMode) -> BufExecDebugger {
+        crate::set_debug_logging(false);
+        let context = Context::pure_math();
+        let commands = split_into_commands(script.to_string());
+        let lines = commands_to_sequential_exec_order(commands, &context).unwrap();
+        let machine = RevPolStackMachine::new_with_ctx(context);
+        BufExecDebugger::new_machine(machine, lines).with_mode(mode)
+    }
+
+    /// Steps a single token, which has to be a call to the functor given
+    fn assert_step(
+        debugger: &mut BufExecDebugger,
+        name: &str,
+        consumed: &[Token],
+        produced: &[Token],
+    ) {
+        let step = debugger.step_token().unwrap().unwrap();
+        assert!(matches!(step.token, Token::Functor(func) if func.name == name));
+        assert_eq!(step.functor.unwrap().name, name);
+        assert_eq!(step.consumed, consumed);
+        assert_eq!(step.produced, produced);
+    }
+
+    #[test]
+    fn steps_a_token_at_a_time() {
+        let mut debugger = debugger("1 2 +\n3 *\n4\n5 6\nClear", StepMode::Token);
+        assert_step(&mut debugger, "+", &[int(1), int(2)], &[int(3)]);
+        assert_eq!(debugger.executor().current_line, 1);
+
+        // The 3 left by the line before is taken from below the line
+        assert_step(&mut debugger, "*", &[int(3), int(3)], &[int(9)]);
+
+        // A line without functors is done as soon as it is on the stack
+        assert!(debugger.step_token().unwrap().is_none());
+        assert!(debugger.step_token().unwrap().is_none());
+        assert_eq!(debugger.executor().machine.stack.len(), 4);
+
+        // Clear takes everything, not just the arguments it declares
+        let everything = [int(9), int(4), int(5), int(6)];
+        assert_step(&mut debugger, "Clear", &everything, &[]);
+        assert!(debugger.machine_is_complete());
+    }
+
+    #[test]
+    fn token_steps_after_undoing() {
+        let mut debugger = debugger("1 2 +\n3 *", StepMode::Token);
+        debugger.step_token().unwrap();
+        debugger.step_token().unwrap();
+        assert!(debugger.undo_with_snapshot().is_some());
+        assert_eq!(debugger.executor().machine.stack, vec![int(3)]);
+
+        assert_step(&mut debugger, "*", &[int(3), int(3)], &[int(9)]);
+    }
+
+    #[test]
+    fn steps_by_the_mode() {
+        let mut debugger = debugger("1 2 +\n3 *", StepMode::Line);
+        let snapshot = debugger.next_with_snapshot();
+        assert!(snapshot.last_step.is_none() && !snapshot.is_error());
+        assert_eq!(snapshot.stack_machine_stack, vec![int(3)]);
+
+        debugger.set_mode(StepMode::Token);
+        let snapshot = debugger.next_with_snapshot();
+        let step = snapshot.last_step.unwrap();
+        assert_eq!(step.consumed, vec![int(3), int(3)]);
+        assert_eq!(step.produced, vec![int(9)]);
+
+        let mut debugger = self::debugger("\"a\" 2 +", StepMode::Token);
+        let snapshot = debugger.next_with_snapshot();
+        assert!(snapshot.is_error() && snapshot.last_step.is_none());
+        assert!(debugger.executor().machine.stack.is_empty());
+
+        let snapshots = Stepper::new(self::debugger("1\n2\n+", StepMode::Token)).count();
+        assert_eq!(snapshots, 3);
+    }
+}
@@ -1,125 +1,921 @@
+use crate::codegen::CodeGen;
 use crate::context::Context;
+use crate::expression::Expr;
+use crate::limits::check_int_size;
+use crate::number::Number;
+use crate::number_format::{MAX_DIGITS, NumberFormat};
+use crate::number_theory;
+use crate::plot::Plot;
+use crate::polynomial::{FiniteField, Polynomial};
+use crate::pretty::PrettyStyle;
+use crate::save_file::{load_machine, save_machine};
+use crate::token_defs::Token;
+use crate::{ctx, end, fetch_name, fetch_pop, fetch_resolved, return_one_as};
+#[cfg(feature = "debugger")]
 use crate::debugger_pause;
-use crate::token_defs::{Token, Variable};
-use crate::{ctx, debug, end, fetch_pop, return_one_as};
-use anyhow::Result;
+use anyhow::{Result, bail};
+use num_bigfloat::BigFloat;
+use num_bigint::BigInt;
 
-/// + operator
-fn add(tokens: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
-    let arg1 = fetch_pop!(tokens, Const);
-    let arg2 = fetch_pop!(tokens, Const);
-    let res = arg1 + arg2;
+ctx! {
+    "+", Math, [Number, Number];
+    /// + operator
+    fn add(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let arg1 = fetch_resolved!(tokens, ctx, Const);
+        let arg2 = fetch_resolved!(tokens, ctx, Const);
+        let res = arg1.checked_add(arg2)?;
 
-    return_one_as!(res, Const)
+        return_one_as!(res, Const)
+    }
 }
-ctx!("+", add);
 
-/// - operator
-fn sub(tokens: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
-    let arg1 = fetch_pop!(tokens, Const);
-    let arg2 = fetch_pop!(tokens, Const);
-    let res = arg1 - arg2;
+ctx! {
+    "-", Math, [Number, Number];
+    /// - operator
+    fn sub(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let arg1 = fetch_resolved!(tokens, ctx, Const);
+        let arg2 = fetch_resolved!(tokens, ctx, Const);
+        let res = arg1.checked_sub(arg2)?;
 
-    return_one_as!(res, Const)
+        return_one_as!(res, Const)
+    }
 }
-ctx!("-", sub);
 
-/// * operator
-fn mul(tokens: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
-    let arg1 = fetch_pop!(tokens, Const);
-    let arg2 = fetch_pop!(tokens, Const);
-    let res = arg1 * arg2;
+ctx! {
+    "*", Math, [Number, Number];
+    /// * operator
+    fn mul(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let arg1 = fetch_resolved!(tokens, ctx, Const);
+        let arg2 = fetch_resolved!(tokens, ctx, Const);
+        let res = arg1.checked_mul(arg2, ctx.max_int_bits)?;
 
-    return_one_as!(res, Const)
+        return_one_as!(res, Const)
+    }
 }
-ctx!("*", mul);
 
-/// Print command
-fn print(tokens: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
-    println!("{}", fetch_pop!(tokens));
-    end!()
+ctx! {
+    "/", Math, [Number, Number];
+    /// / operator (integers which do not divide evenly give a float)
+    fn div(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let rhs = fetch_resolved!(tokens, ctx, Const);
+        let lhs = fetch_resolved!(tokens, ctx, Const);
+        let res = lhs.checked_div(rhs)?;
+
+        return_one_as!(res, Const)
+    }
 }
-ctx!("Print", print);
 
-/// Exit command
-fn exit(_: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
-    std::process::exit(0)
+ctx! {
+    "^", Math, [Number, Number];
+    /// ^ operator (a b ^ is a to the power of b)
+    fn pow(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let exponent = fetch_resolved!(tokens, ctx, Const);
+        let base = fetch_resolved!(tokens, ctx, Const);
+        let res = base.pow(exponent, ctx.max_int_bits)?;
+
+        return_one_as!(res, Const)
+    }
 }
-ctx!("Exit", exit);
 
-/// Nop (No operation) command
-fn nop(_: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
-    end!()
+ctx! {
+    "Mod", Math, [Number, Number];
+    /// Mod command (an integer modulo another, e.g. 3 7 Mod, which arithmetic then stays modulo 7
+    /// in, dividing by multiplying with the inverse. A prime modulus gives the field GF(p).)
+    fn modular(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let modulus = int_arg(fetch_resolved!(tokens, ctx, Const), "Mod")?;
+        let value = match fetch_resolved!(tokens, ctx, Const) {
+            // Taking a modular number modulo another keeps its value
+            Number::Mod(value, _) => value,
+            other => int_arg(other, "Mod")?,
+        };
+        let res = Number::modular(value, modulus)?;
+
+        return_one_as!(res, Const)
+    }
 }
-ctx!("Nop", nop);
 
-/// Clear command (clears stack)
-fn clear(tokens: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
-    tokens.clear();
-    end!();
+ctx! {
+    "lift", Math, [Number];
+    /// The value of a modular number as a plain integer, in 0 to the modulus - 1
+    fn lift(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let res = match fetch_resolved!(tokens, ctx, Const) {
+            Number::Mod(value, _) => Number::Int(value),
+            other => other,
+        };
+
+        return_one_as!(res, Const)
+    }
 }
-ctx!("Clear", clear);
 
-/// Assign (turns a string to a variable with a value)
-fn assign(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
-    let value = fetch_pop!(tokens);
-    let name = fetch_pop!(tokens, String);
+ctx! {
+    "abs", Math, [Number];
+    /// Absolute value
+    fn abs(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let res = fetch_resolved!(tokens, ctx, Const).abs();
 
-    ctx.variables.push(Variable {
-        name,
-        value: Some(Box::new(value)),
-    });
+        return_one_as!(res, Const)
+    }
+}
+
+/// Defines a function of one number which is computed as a float
+macro_rules! float_function {
+    ($name:expr, $func:ident, $doc:literal) => {
+        ctx! {
+            $name, Math, [Number];
+            #[doc = $doc]
+            fn $func(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+                let arg = fetch_resolved!(tokens, ctx, Const);
+                let res = arg.map_float(BigFloat::$func);
 
-    end!();
+                return_one_as!(res, Const)
+            }
+        }
+    };
 }
-ctx!(":=", assign);
 
-/// RevAssign (assignment but with backwards ordering)
-fn rev_assign(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
-    let name = fetch_pop!(tokens, String);
-    let value = fetch_pop!(tokens);
+float_function!("sqrt", sqrt, "Square root");
+float_function!("sin", sin, "Sine, in radians");
+float_function!("cos", cos, "Cosine, in radians");
+float_function!("tan", tan, "Tangent, in radians");
+float_function!("exp", exp, "Exponential function");
+float_function!("ln", ln, "Natural logarithm");
+
+/// The integer in a number given to one of the number theory functions
+fn int_arg(num: Number, func: &str) -> Result<BigInt> {
+    match num {
+        Number::Int(int) => Ok(int),
+        other => bail!("{} only works on integers, not {}", func, other),
+    }
+}
 
-    ctx.variables.push(Variable {
-        name,
-        value: Some(Box::new(value)),
-    });
+/// Defines a number theory function of integers which gives back a single integer
+macro_rules! int_function {
+    ($name:expr, $func:ident, [$a:ident], $doc:literal, $body:expr) => {
+        int_function!(@define $name, $func, [Number], [$a], $doc, $body);
+    };
+    ($name:expr, $func:ident, [$a:ident, $b:ident], $doc:literal, $body:expr) => {
+        int_function!(@define $name, $func, [Number, Number], [$a, $b], $doc, $body);
+    };
+    ($name:expr, $func:ident, [$a:ident, $b:ident, $c:ident], $doc:literal, $body:expr) => {
+        int_function!(@define $name, $func, [Number, Number, Number], [$a, $b, $c], $doc, $body);
+    };
+    (@define $name:expr, $func:ident, [$($ty:ident),+], [$($arg:ident),+], $doc:literal,
+        $body:expr) => {
+        ctx! {
+            $name, Math, [$($ty),+];
+            #[doc = $doc]
+            fn $func(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+                int_function!(@pop tokens, ctx, $name, [$($arg),+]);
+                let res: Result<BigInt> = $body;
+                let res = Number::Int(res?);
 
-    end!();
+                return_one_as!(res, Const)
+            }
+        }
+    };
+    // Arguments come off the stack last first
+    (@pop $tokens:ident, $ctx:ident, $name:expr, [$first:ident $(, $rest:ident)*]) => {
+        int_function!(@pop $tokens, $ctx, $name, [$($rest),*]);
+        let $first = int_arg(fetch_resolved!($tokens, $ctx, Const), $name)?;
+    };
+    (@pop $tokens:ident, $ctx:ident, $name:expr, []) => {};
 }
-ctx!("=:", rev_assign);
 
-/// & operator (deletes a variable)
-fn deassign(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
-    let var = fetch_pop!(tokens, Variable);
+int_function!(
+    "gcd",
+    gcd,
+    [a, b],
+    "Greatest common divisor",
+    Ok(number_theory::gcd(&a, &b))
+);
+int_function!(
+    "lcm",
+    lcm,
+    [a, b],
+    "Least common multiple",
+    Ok(number_theory::lcm(&a, &b))
+);
+int_function!(
+    "modinv",
+    modinv,
+    [a, m],
+    "Inverse of a modulo m (a m modinv)",
+    number_theory::modinv(&a, &m)
+);
+int_function!(
+    "modpow",
+    modpow,
+    [base, exponent, m],
+    "base^exponent modulo m (base exponent m modpow)",
+    number_theory::modpow(&base, &exponent, &m)
+);
+int_function!(
+    "isprime",
+    isprime,
+    [n],
+    "1 if n is prime and 0 if not (Miller-Rabin, exact below 3.3e24)",
+    Ok(BigInt::from(number_theory::is_prime(&n) as u8))
+);
+int_function!(
+    "nextprime",
+    nextprime,
+    [n],
+    "The smallest prime greater than n",
+    Ok(number_theory::next_prime(&n))
+);
+int_function!(
+    "totient",
+    totient,
+    [n],
+    "Euler's totient, how many of 1 to n are coprime to n",
+    number_theory::totient(&n)
+);
+int_function!(
+    "jacobi",
+    jacobi,
+    [a, n],
+    "Jacobi symbol (a/n) for an odd positive n",
+    number_theory::jacobi(&a, &n).map(BigInt::from)
+);
 
-    let idx = ctx.variables.iter().position(|x| *x == var).unwrap();
-    ctx.variables.remove(idx);
+ctx! {
+    "binomial", Math, [Number, Number];
+    /// Binomial coefficient n choose k
+    fn binomial(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let k = int_arg(fetch_resolved!(tokens, ctx, Const), "binomial")?;
+        let n = int_arg(fetch_resolved!(tokens, ctx, Const), "binomial")?;
+        check_int_size(ctx.max_int_bits, number_theory::binomial_bits(&n, &k))?;
+        let res = Number::Int(number_theory::binomial(&n, &k)?);
 
-    end!();
+        return_one_as!(res, Const)
+    }
 }
-ctx!("&", deassign);
 
-/// ::PAUSE operator (pauses execution for debugging)
-#[cfg(feature = "debugger")]
-fn debug_interrupt(_: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
-    debugger_pause();
+ctx! {
+    "factorial", Math, [Number];
+    /// Factorial n!
+    fn factorial(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let n = int_arg(fetch_resolved!(tokens, ctx, Const), "factorial")?;
+        check_int_size(ctx.max_int_bits, number_theory::factorial_bits(&n))?;
+        let res = Number::Int(number_theory::factorial(&n)?);
 
-    end!();
+        return_one_as!(res, Const)
+    }
 }
-ctx!("::PAUSE", debug_interrupt);
 
-/// ::STACK_JUMP (dumps the stack for debugging)
-#[cfg(feature = "debugger")]
-fn stack_dump(stack: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
-    debug!("STACK_DUMP: {:?}", stack);
-    end!();
+ctx! {
+    "egcd", Math, [Number, Number];
+    /// Extended gcd (a b egcd gives g x y, where g is the gcd and a x + b y = g)
+    fn egcd(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let b = int_arg(fetch_resolved!(tokens, ctx, Const), "egcd")?;
+        let a = int_arg(fetch_resolved!(tokens, ctx, Const), "egcd")?;
+        let (g, x, y) = number_theory::egcd(&a, &b);
+
+        Ok([g, x, y].into_iter().map(|n| Token::Const(Number::Int(n))).collect())
+    }
 }
-ctx!("::STACK_DUMP", stack_dump);
 
-/// ::CONTEXT_DUMP (dumps the context for debugging)
-#[cfg(feature = "debugger")]
-fn ctx_dump(_: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
-    debug!("CONTEXT_DUMP: {:?}", ctx);
-    end!();
+ctx! {
+    "crt", Math, [Number, Number, Number, Number];
+    /// Chinese remainder theorem (r1 m1 r2 m2 crt gives r m, where x = r mod m is the same as
+    /// x = r1 mod m1 and x = r2 mod m2, so 2 3 3 5 crt 2 7 crt gives 23 105)
+    fn crt(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let m2 = int_arg(fetch_resolved!(tokens, ctx, Const), "crt")?;
+        let r2 = int_arg(fetch_resolved!(tokens, ctx, Const), "crt")?;
+        let m1 = int_arg(fetch_resolved!(tokens, ctx, Const), "crt")?;
+        let r1 = int_arg(fetch_resolved!(tokens, ctx, Const), "crt")?;
+        let (r, m) = number_theory::crt(&r1, &m1, &r2, &m2)?;
+
+        Ok(vec![Token::Const(Number::Int(r)), Token::Const(Number::Int(m))])
+    }
+}
+
+ctx! {
+    "factorint", Math, [Number];
+    /// Prime factorisation (gives a matrix with a row of [prime, multiplicity] for each factor)
+    fn factorint(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let n = int_arg(fetch_resolved!(tokens, ctx, Const), "factorint")?;
+        let res = number_theory::factorint(&n)?
+            .into_iter()
+            .map(|(prime, count)| {
+                let count = Expr::Num(Number::Int(BigInt::from(count)));
+                vec![Expr::Num(Number::Int(prime)), count]
+            })
+            .collect();
+        let res = Expr::Matrix(res);
+
+        return_one_as!(res, Expr)
+    }
+}
+
+ctx! {
+    "divisors", Math, [Number];
+    /// Positive divisors, smallest first (as a matrix with a single row)
+    fn divisors(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let n = int_arg(fetch_resolved!(tokens, ctx, Const), "divisors")?;
+        let res = number_theory::divisors(&n)?
+            .into_iter()
+            .map(|divisor| Expr::Num(Number::Int(divisor)))
+            .collect();
+        let res = Expr::Matrix(vec![res]);
+
+        return_one_as!(res, Expr)
+    }
+}
+
+/// The base given to ToBase or FromBase, which has to be from 2 to 36 so every digit is 0-9 or A-Z
+fn radix_arg(num: Number, func: &str) -> Result<u32> {
+    let radix = int_arg(num, func)?;
+    match u32::try_from(&radix) {
+        Ok(radix @ 2..=36) => Ok(radix),
+        _ => bail!("{} needs a base from 2 to 36, not {}", func, radix),
+    }
+}
+
+ctx! {
+    "ToBase", Math, [Number, Number];
+    /// An integer written in another base, with letters for digits above 9 (255 16 ToBase gives
+    /// "FF")
+    fn to_base(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let radix = radix_arg(fetch_resolved!(tokens, ctx, Const), "ToBase")?;
+        let n = int_arg(fetch_resolved!(tokens, ctx, Const), "ToBase")?;
+        let res = n.to_str_radix(radix).to_uppercase();
+
+        return_one_as!(res, String)
+    }
+}
+
+ctx! {
+    "FromBase", Math, [String, Number];
+    /// Reads an integer written in another base ("FF" 16 FromBase gives 255)
+    fn from_base(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let radix = radix_arg(fetch_resolved!(tokens, ctx, Const), "FromBase")?;
+        let digits = fetch_pop!(tokens, String);
+        let Some(res) = BigInt::parse_bytes(digits.trim().replace('_', "").as_bytes(), radix)
+        else {
+            bail!("{} is not an integer in base {}", digits, radix)
+        };
+        let res = Number::Int(res);
+
+        return_one_as!(res, Const)
+    }
+}
+
+/// The variable and prime given last to the polynomial functions, e.g. "x" 7
+fn field_args(tokens: &mut Vec<Token>, ctx: &mut Context, func: &str) -> Result<(String, BigInt)> {
+    let prime = int_arg(fetch_resolved!(tokens, ctx, Const), func)?;
+    let variable = fetch_name!(tokens);
+    Ok((variable, prime))
+}
+
+/// A polynomial in the variable over GF(prime), read from an expression or a number
+fn poly_arg(token: Token, variable: &str, prime: &BigInt) -> Result<Polynomial> {
+    Polynomial::from_expr(&Expr::from_token(&token)?, variable, prime)
+}
+
+/// Defines a function of two polynomials over GF(p) which gives back a single polynomial
+macro_rules! poly_function {
+    ($name:expr, $func:ident, $doc:literal, $method:ident) => {
+        ctx! {
+            $name, Math, [Any, Any, Name, Number];
+            #[doc = $doc]
+            fn $func(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+                let (variable, prime) = field_args(tokens, ctx, $name)?;
+                let rhs = poly_arg(fetch_resolved!(tokens, ctx), &variable, &prime)?;
+                let lhs = poly_arg(fetch_resolved!(tokens, ctx), &variable, &prime)?;
+                let res = lhs.$method(&rhs)?.to_expr(&variable);
+
+                return_one_as!(res, Expr)
+            }
+        }
+    };
+}
+
+ctx! {
+    "polymod", Math, [Any, Name, Number];
+    /// A polynomial with its coefficients taken modulo a prime ((x^2 + 8*x) "x" 7 polymod gives
+    /// x^2 + x)
+    fn polymod(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let (variable, prime) = field_args(tokens, ctx, "polymod")?;
+        let res = poly_arg(fetch_resolved!(tokens, ctx), &variable, &prime)?.to_expr(&variable);
+
+        return_one_as!(res, Expr)
+    }
+}
+
+poly_function!(
+    "polyadd",
+    polyadd,
+    "Sum of two polynomials over GF(p)",
+    checked_add
+);
+poly_function!(
+    "polysub",
+    polysub,
+    "Difference of two polynomials over GF(p)",
+    checked_sub
+);
+poly_function!(
+    "polymul",
+    polymul,
+    "Product of two polynomials over GF(p)",
+    checked_mul
+);
+poly_function!(
+    "polygcd",
+    polygcd,
+    "Monic greatest common divisor over GF(p)",
+    gcd
+);
+
+ctx! {
+    "polydivmod", Math, [Any, Any, Name, Number];
+    /// Quotient and remainder of two polynomials over GF(p)
+    fn polydivmod(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let (variable, prime) = field_args(tokens, ctx, "polydivmod")?;
+        let divisor = poly_arg(fetch_resolved!(tokens, ctx), &variable, &prime)?;
+        let dividend = poly_arg(fetch_resolved!(tokens, ctx), &variable, &prime)?;
+        let (quotient, remainder) = dividend.div_rem(&divisor)?;
+
+        Ok(vec![
+            Token::Expr(quotient.to_expr(&variable)),
+            Token::Expr(remainder.to_expr(&variable)),
+        ])
+    }
+}
+
+ctx! {
+    "polyirreducible", Math, [Any, Name, Number];
+    /// 1 if a polynomial can't be factored over GF(p), 0 if it can
+    fn polyirreducible(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let (variable, prime) = field_args(tokens, ctx, "polyirreducible")?;
+        let poly = poly_arg(fetch_resolved!(tokens, ctx), &variable, &prime)?;
+        let res = Number::Int(BigInt::from(poly.is_irreducible()? as u8));
+
+        return_one_as!(res, Const)
+    }
+}
+
+/// The field GF(p^n) given by an irreducible polynomial, followed by the variable and prime. The
+/// prime is given back too, for reading the elements.
+fn field_arg(
+    tokens: &mut Vec<Token>,
+    ctx: &mut Context,
+    func: &str,
+) -> Result<(FiniteField, String, BigInt)> {
+    let (variable, prime) = field_args(tokens, ctx, func)?;
+    let modulus = poly_arg(fetch_resolved!(tokens, ctx), &variable, &prime)?;
+    Ok((FiniteField::new(modulus)?, variable, prime))
+}
+
+/// Defines a function of two elements of GF(p^n) which gives back a single element
+macro_rules! gf_function {
+    ($name:expr, $func:ident, $doc:literal, $method:ident) => {
+        ctx! {
+            $name, Math, [Any, Any, Any, Name, Number];
+            #[doc = $doc]
+            fn $func(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+                let (field, variable, prime) = field_arg(tokens, ctx, $name)?;
+                let rhs = poly_arg(fetch_resolved!(tokens, ctx), &variable, &prime)?;
+                let lhs = poly_arg(fetch_resolved!(tokens, ctx), &variable, &prime)?;
+                let res = field.$method(&lhs, &rhs)?.to_expr(&variable);
+
+                return_one_as!(res, Expr)
+            }
+        }
+    };
+}
+
+gf_function!(
+    "gfmul",
+    gfmul,
+    "Product in GF(p^n), given two elements then the field's polynomial, variable and prime \
+    (x (x + 1) (x^2 + x + 1) \"x\" 2 gfmul gives 1)",
+    mul
+);
+gf_function!("gfdiv", gfdiv, "Quotient in GF(p^n), taken like gfmul", div);
+
+ctx! {
+    "gfinv", Math, [Any, Any, Name, Number];
+    /// Inverse in GF(p^n), given an element then the field's polynomial, variable and prime
+    fn gfinv(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let (field, variable, prime) = field_arg(tokens, ctx, "gfinv")?;
+        let element = poly_arg(fetch_resolved!(tokens, ctx), &variable, &prime)?;
+        let res = field.inverse(&element)?.to_expr(&variable);
+
+        return_one_as!(res, Expr)
+    }
+}
+
+ctx! {
+    "gfpow", Math, [Any, Number, Any, Name, Number];
+    /// An element of GF(p^n) to an integer power, given like gfinv with the power after the
+    /// element (x 7 (x^2 + x + 1) "x" 2 gfpow gives x)
+    fn gfpow(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let (field, variable, prime) = field_arg(tokens, ctx, "gfpow")?;
+        let exponent = int_arg(fetch_resolved!(tokens, ctx, Const), "gfpow")?;
+        let element = poly_arg(fetch_resolved!(tokens, ctx), &variable, &prime)?;
+        let res = field.pow(&element, &exponent)?.to_expr(&variable);
+
+        return_one_as!(res, Expr)
+    }
+}
+
+ctx! {
+    "Expr", Math, [String];
+    /// Expr command (parses an infix expression without evaluating it, e.g. "(x + 1) / 2" Expr)
+    fn expr(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let input = fetch_pop!(tokens, String);
+        let res = Expr::parse(&input, ctx)?;
+
+        return_one_as!(res, Expr)
+    }
+}
+
+ctx! {
+    "Matrix", Math, [Number, Number];
+    /// Matrix command (takes rows * columns entries off the stack, row by row, e.g.
+    /// 1 2 3 4 2 2 Matrix)
+    fn matrix(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let dimension = |num: Number| match num {
+            Number::Int(int) => usize::try_from(&int).ok().filter(|n| *n > 0),
+            Number::Float(_) | Number::Mod(..) | Number::Rational(..) => None,
+        };
+        let columns = fetch_resolved!(tokens, ctx, Const);
+        let rows = fetch_resolved!(tokens, ctx, Const);
+        let (Some(rows), Some(columns)) = (dimension(rows.clone()), dimension(columns.clone()))
+        else {
+            bail!(
+                "A matrix needs a positive whole number of rows and columns, not {} and {}",
+                rows,
+                columns
+            )
+        };
+
+        let Some(size) = rows.checked_mul(columns) else {
+            bail!("A {}x{} matrix is too big", rows, columns)
+        };
+        let Some(start) = tokens.len().checked_sub(size) else {
+            bail!(
+                "A {}x{} matrix needs {} entries but the stack only holds {}",
+                rows,
+                columns,
+                size,
+                tokens.len()
+            )
+        };
+        let entries = tokens
+            .drain(start..)
+            .map(|token| Expr::from_token(&ctx.resolve(token)))
+            .collect::<Result<Vec<Expr>>>()?;
+        let res = Expr::Matrix(entries.chunks(columns).map(|row| row.to_vec()).collect());
+
+        return_one_as!(res, Expr)
+    }
+}
+
+ctx! {
+    "ToLatex", Math, [Any];
+    /// ToLatex command (turns a number, name or expression into a string of LaTeX)
+    fn to_latex(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let res = fetch_resolved!(tokens, ctx).to_latex();
+
+        return_one_as!(res, String)
+    }
+}
+
+ctx! {
+    "ToMathML", Math, [Any];
+    /// ToMathML command (turns a number, name or expression into a string of Presentation MathML)
+    fn to_mathml(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let res = Expr::from_token(&fetch_resolved!(tokens, ctx))?.to_presentation_mathml();
+
+        return_one_as!(res, String)
+    }
+}
+
+ctx! {
+    "ToContentMathML", Math, [Any];
+    /// ToContentMathML command (turns a number, name or expression into a string of Content
+    /// MathML)
+    fn to_content_mathml(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let res = Expr::from_token(&fetch_resolved!(tokens, ctx))?.to_content_mathml();
+
+        return_one_as!(res, String)
+    }
+}
+
+ctx! {
+    "FromMathML", Math, [String];
+    /// FromMathML command (reads an expression from a string of Content MathML)
+    fn from_mathml(tokens: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
+        let res = Expr::from_content_mathml(&fetch_pop!(tokens, String))?;
+
+        return_one_as!(res, Expr)
+    }
+}
+
+ctx! {
+    "FromOpenMath", Math, [String];
+    /// FromOpenMath command (reads an expression from a string of OpenMath XML)
+    fn from_openmath(tokens: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
+        let res = Expr::from_openmath(&fetch_pop!(tokens, String))?;
+
+        return_one_as!(res, Expr)
+    }
+}
+
+ctx! {
+    "CodeGen", Math, [Any, String, String, String];
+    /// CodeGen command (compiles an expression into a function of the given inputs, e.g.
+    /// e "x y" "rust" "f64" CodeGen, or "c" "bigfloat" for C using MPFR)
+    fn codegen(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let backend = fetch_pop!(tokens, String);
+        let language = fetch_pop!(tokens, String);
+        let inputs = fetch_pop!(tokens, String);
+        let expr = Expr::from_token(&fetch_resolved!(tokens, ctx))?;
+
+        let inputs = inputs
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|input| !input.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<String>>();
+        let res = CodeGen::from_names(&language, &backend)?.generate(&expr, &inputs)?;
+
+        return_one_as!(res, String)
+    }
+}
+
+/// The expression to plot: an expression, a value, or a string holding an infix expression
+fn plot_expr(token: Token, ctx: &Context) -> Result<Expr> {
+    match token {
+        Token::String(input) => Expr::parse(&input, ctx),
+        other => Expr::from_token(&other),
+    }
+}
+
+/// Writes a plot to an SVG file, or draws it to the context's output if the path is -
+fn show_plot(plot: &Plot, path: &str, ctx: &Context) -> Result<()> {
+    if path == "-" {
+        ctx.output.write_line(plot.to_ascii(72, 20));
+        return Ok(());
+    }
+    std::fs::write(path, plot.to_svg())
+        .map_err(|e| anyhow::anyhow!("Failed to write plot <{}>: {}", path, e))
+}
+
+ctx! {
+    "Plot", Fs, [Any, String, Number, Number, String];
+    /// Plot command (plots an expression of one variable into an SVG file, e.g.
+    /// "sin(x) / x" "x" -10 10 "plot.svg" Plot, or in the terminal if the file is "-")
+    fn plot(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let path = fetch_pop!(tokens, String);
+        let to = fetch_resolved!(tokens, ctx, Const).to_float().to_f64();
+        let from = fetch_resolved!(tokens, ctx, Const).to_float().to_f64();
+        let variable = fetch_pop!(tokens, String);
+        let expr = plot_expr(fetch_resolved!(tokens, ctx), ctx)?;
+
+        show_plot(&Plot::function(&expr, &variable, from, to)?, &path, ctx)?;
+        end!()
+    }
+}
+
+ctx! {
+    "ParametricPlot", Fs, [Any, Any, String, Number, Number, String];
+    /// ParametricPlot command (plots the curve traced by an x and a y expression, e.g.
+    /// "cos(t)" "sin(2*t)" "t" 0 6.3 "curve.svg" ParametricPlot, or "-" for the terminal)
+    fn parametric_plot(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let path = fetch_pop!(tokens, String);
+        let to = fetch_resolved!(tokens, ctx, Const).to_float().to_f64();
+        let from = fetch_resolved!(tokens, ctx, Const).to_float().to_f64();
+        let variable = fetch_pop!(tokens, String);
+        let y = plot_expr(fetch_resolved!(tokens, ctx), ctx)?;
+        let x = plot_expr(fetch_resolved!(tokens, ctx), ctx)?;
+
+        show_plot(&Plot::parametric(&x, &y, &variable, from, to)?, &path, ctx)?;
+        end!()
+    }
+}
+
+ctx! {
+    "Print", Io, [Any];
+    /// Print command (draws expressions over several lines if PrettyPrint is on, and writes numbers
+    /// as Format says)
+    fn print(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let token = fetch_resolved!(tokens, ctx);
+        ctx.output.write_line(ctx.show(&token));
+        end!()
+    }
+}
+
+ctx! {
+    "PrettyPrint", Core, [String];
+    /// PrettyPrint command (sets how Print draws expressions: "ascii", "unicode" or "off")
+    fn pretty_print(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let style = fetch_pop!(tokens, String);
+        ctx.pretty_print = match style.as_str() {
+            "off" => None,
+            name => match PrettyStyle::from_name(name) {
+                Some(style) => Some(style),
+                None => bail!(
+                    "Unknown pretty print style {}, expected ascii, unicode or off",
+                    name
+                ),
+            },
+        };
+        ctx.revision += 1;
+        end!()
+    }
+}
+
+ctx! {
+    "Format", Core, [String];
+    /// Format command (sets how Print writes numbers: "auto", "fixed", "sci", "eng", each with an
+    /// optional number of digits after the point as in "fixed 4", or "fraction", "mixed" or
+    /// "repeating" to write 1/7 as 0.(142857))
+    fn format(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let name = fetch_pop!(tokens, String);
+        ctx.number_format = match NumberFormat::from_name(&name) {
+            Some(format) => format,
+            None => bail!(
+                "Unknown number format {}, expected auto, fixed, sci or eng with at most {} \
+                 digits, fraction, mixed or repeating",
+                name,
+                MAX_DIGITS
+            ),
+        };
+        ctx.revision += 1;
+        end!()
+    }
+}
+
+ctx! {
+    "Help", Io, [Any];
+    /// Help command (describes a function, given either the function itself or its name)
+    fn help(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let func = match fetch_pop!(tokens) {
+            Token::Functor(func) => func,
+            Token::Symbol(name) | Token::String(name) => {
+                match ctx.functions.iter().find(|f| f.name == name) {
+                    Some(func) => func.clone(),
+                    None => bail!("No function is called {}", name),
+                }
+            }
+            other => bail!("Cannot get help for {}", other),
+        };
+
+        ctx.output.write_line(func.signature());
+        for line in func.doc.lines() {
+            ctx.output.write_line(format!("    {}", line.trim()));
+        }
+
+        end!()
+    }
+}
+
+ctx! {
+    "Functions", Io, [];
+    /// Functions command (lists every function that can be called)
+    fn functions(_: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let mut funcs = ctx.functions.iter().collect::<Vec<_>>();
+        funcs.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for func in funcs {
+            let summary = func.doc.lines().next().unwrap_or("").trim();
+            let line = format!("{:<32} {}", func.signature(), summary);
+            ctx.output.write_line(line);
+        }
+
+        end!()
+    }
+}
+
+ctx! {
+    "Save", Fs, [String];
+    /// Save command (saves the stack and variables to a file, e.g. "work.json" Save)
+    fn save(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let path = fetch_pop!(tokens, String);
+        save_machine(tokens, ctx, &path)?;
+
+        end!()
+    }
+}
+
+ctx! {
+    "Load", Fs, [String];
+    /// Load command (replaces the stack and variables with the ones in a file written by Save)
+    /// The functions stay as they are, so loading a file cannot grant functions this context does
+    /// not already have.
+    fn load(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let path = fetch_pop!(tokens, String);
+        let saved = load_machine(&path)?;
+        saved.check_functions(&ctx.functions)?;
+
+        // Only what was saved is replaced, the functions, limits and output stay as they are
+        ctx.variables = saved.context.variables;
+        ctx.pretty_print = saved.context.pretty_print;
+        ctx.number_format = saved.context.number_format;
+        ctx.revision += 1;
+
+        // Everything on the stack is replaced, which is the same as popping all of it
+        tokens.clear();
+        Ok(saved.stack)
+    }
+}
+
+ctx! {
+    "Exit", Process, [];
+    /// Exit command
+    fn exit(_: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
+        std::process::exit(0)
+    }
+}
+
+ctx! {
+    "Nop", Core, [];
+    /// Nop (No operation) command
+    fn nop(_: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
+        end!()
+    }
+}
+
+ctx! {
+    "Clear", Core, [];
+    /// Clear command (clears stack)
+    fn clear(tokens: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
+        tokens.clear();
+        end!();
+    }
+}
+
+ctx! {
+    ":=", Core, [Name, Any];
+    /// Assign (turns a string to a variable with a value)
+    fn assign(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let value = fetch_resolved!(tokens, ctx);
+        let name = fetch_name!(tokens);
+
+        ctx.set_variable(name, value);
+
+        end!();
+    }
+}
+
+ctx! {
+    "=:", Core, [Any, Name];
+    /// RevAssign (assignment but with backwards ordering)
+    fn rev_assign(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let name = fetch_name!(tokens);
+        let value = fetch_resolved!(tokens, ctx);
+
+        ctx.set_variable(name, value);
+
+        end!();
+    }
+}
+
+ctx! {
+    "&", Core, [Name];
+    /// & operator (deletes a variable)
+    fn deassign(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        let name = fetch_name!(tokens);
+
+        if !ctx.remove_variable(&name) {
+            bail!("Cannot delete undefined variable: {}", name)
+        }
+
+        end!();
+    }
+}
+
+ctx! {
+    "::PAUSE", Io, [];
+    /// ::PAUSE operator (pauses execution for debugging)
+    #[cfg(feature = "debugger")]
+    fn debug_interrupt(_: &mut Vec<Token>, _: &mut Context) -> Result<Vec<Token>> {
+        debugger_pause();
+
+        end!();
+    }
+}
+
+ctx! {
+    "::STACK_DUMP", Debug, [];
+    /// ::STACK_DUMP (dumps the stack for debugging)
+    #[cfg(feature = "debugger")]
+    fn stack_dump(stack: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        // Printed directly so the dump is shown even when debug logging is off
+        ctx.output.write_line(format!("[DEBUG] STACK_DUMP: {:?}", stack));
+        end!();
+    }
+}
+
+ctx! {
+    "::CONTEXT_DUMP", Debug, [];
+    /// ::CONTEXT_DUMP (dumps the context for debugging)
+    #[cfg(feature = "debugger")]
+    fn ctx_dump(_: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> {
+        ctx.output.write_line(format!("[DEBUG] CONTEXT_DUMP: {:?}", ctx));
+        end!();
+    }
 }
-ctx!("::CONTEXT_DUMP", ctx_dump);
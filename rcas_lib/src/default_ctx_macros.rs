@@ -1,36 +1,74 @@
-use crate::context::Context;
-use crate::token_defs::Functor;
+use crate::context::{Capability, Context};
+use crate::token_defs::{ArgType, FunctionObject, Functor};
 use lazy_static::lazy_static;
 use linkme::distributed_slice;
 
+/// Everything registered about a default function by the ctx! macro
+#[derive(Debug, Clone, Copy)]
+pub struct FunctorDef {
+    pub name: &'static str,
+    pub func: FunctionObject,
+    pub capability: Capability,
+    pub args: &'static [ArgType],
+    pub doc: &'static str,
+}
+impl FunctorDef {
+    pub fn to_functor(&self) -> Functor {
+        Functor {
+            name: self.name.to_string(),
+            func: self.func,
+            args: self.args,
+            doc: self.doc,
+        }
+    }
+}
+
 #[distributed_slice]
-pub static DEFAULT_FUNCTIONS: [(&'static str, crate::token_defs::FunctionObject)] = [..];
+pub static DEFAULT_FUNCTIONS: [FunctorDef] = [..];
 
+/// Defines a function and registers it in the default context. The header gives the name it is
+/// called by, its capability group and the types of its arguments (as written, so the last one is
+/// the top of the stack), and the doc comment of the function becomes its Help text:
+///
+/// ctx! {
+///     "+", Math, [Number, Number];
+///     /// + operator
+///     fn add(tokens: &mut Vec<Token>, ctx: &mut Context) -> Result<Vec<Token>> { ... }
+/// }
+///
+/// A #[cfg(...)] after the doc comment applies to both the function and its registration.
 #[macro_export]
 macro_rules! ctx {
-    ($name:expr, $func:ident) => {
+    (
+        $name:expr, $capability:ident, [$($arg:ident),* $(,)?];
+        $(#[doc = $doc:literal])*
+        $(#[cfg($cfg:meta)])*
+        fn $func:ident $($rest:tt)*
+    ) => {
+        $(#[doc = $doc])*
+        $(#[cfg($cfg)])*
+        fn $func $($rest)*
+
         paste::paste! {
-            #[::linkme::distributed_slice(crate::default_ctx_macros::DEFAULT_FUNCTIONS)]
+            $(#[cfg($cfg)])*
+            #[::linkme::distributed_slice($crate::default_ctx_macros::DEFAULT_FUNCTIONS)]
             #[allow(non_upper_case_globals)]
-            static [<_REGISTER_ $func>]: (
-                &'static str,
-                crate::token_defs::FunctionObject
-            ) = ($name, $func);
+            static [<_REGISTER_ $func>]: $crate::default_ctx_macros::FunctorDef =
+                $crate::default_ctx_macros::FunctorDef {
+                    name: $name,
+                    func: $func,
+                    capability: $crate::context::Capability::$capability,
+                    args: &[$($crate::token_defs::ArgType::$arg),*],
+                    doc: concat!($($doc, "\n"),*),
+                };
         }
     };
 }
 
 lazy_static! {
-    pub static ref DEFAULT_CTX: Context = {
-        let mut ctx = Context::new();
-        for (name, func) in DEFAULT_FUNCTIONS.iter() {
-            ctx.functions.push(Functor {
-                name: name.to_string(),
-                func: *func,
-            });
-        }
-        ctx
-    };
+    /// A context to compute in, so only with the functions of `Capability::PURE_MATH`. Anything
+    /// which needs I/O, files or process control has to build its own context.
+    pub static ref DEFAULT_CTX: Context = Context::pure_math();
 }
 
 /// Fetch_pop is a syntactic sugar macro
@@ -51,17 +89,53 @@ macro_rules! fetch_pop {
 
     ($tokens:ident, $variant:ident) => {
         match $tokens.pop() {
-            Some(crate::token_defs::Token::$variant(item)) => item,
+            Some($crate::token_defs::Token::$variant(item)) => item,
             _ => anyhow::bail!("Incorrect argument type or number"),
         }
     };
 }
 
+/// Fetch_resolved is fetch_pop, but any symbol or variable popped is first resolved against the
+/// live context, so it yields the value the name is bound to right now.
+/// Branch 1 matches fetch_resolved!(tokens, ctx) and returns the resolved token.
+/// Branch 2 matches fetch_resolved!(tokens, ctx, Variant) and returns the inner value of the
+/// resolved token if it is of variant Variant.
+#[macro_export]
+macro_rules! fetch_resolved {
+    ($tokens:ident, $ctx:ident) => {
+        match $tokens.pop() {
+            Some(t) => $ctx.resolve(t),
+            _ => anyhow::bail!("Not enough arguments"),
+        }
+    };
+
+    ($tokens:ident, $ctx:ident, $variant:ident) => {
+        match $tokens.pop().map(|t| $ctx.resolve(t)) {
+            Some($crate::token_defs::Token::$variant(item)) => item,
+            _ => anyhow::bail!("Incorrect argument type or number"),
+        }
+    };
+}
+
+/// Fetch_name pops the name of a variable, which may be given as a symbol, a string or an already
+/// bound variable
+#[macro_export]
+macro_rules! fetch_name {
+    ($tokens:ident) => {
+        match $tokens.pop() {
+            Some($crate::token_defs::Token::Symbol(name))
+            | Some($crate::token_defs::Token::String(name)) => name,
+            Some($crate::token_defs::Token::Variable(var)) => var.name,
+            _ => anyhow::bail!("Expected a variable name"),
+        }
+    };
+}
+
 /// Takes in a single item and it's associated token variant, and returns it in proper form
 #[macro_export]
 macro_rules! return_one_as {
     ($input:ident, $variant:ident) => {
-        return Ok(vec![crate::token_defs::Token::$variant($input)])
+        return Ok(vec![$crate::token_defs::Token::$variant($input)])
     };
 }
 
@@ -0,0 +1,205 @@
+use crate::context::Context;
+use crate::number::Number;
+use crate::parse_infix::infix_to_commands;
+use crate::parse_rpol_notation::single_command_to_token;
+use crate::token_defs::Token;
+use anyhow::{Result, bail};
+use num_bigint::BigInt;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+/// A symbolic expression, kept as a tree rather than being evaluated, e.g. (x + 1) / 2
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Expr {
+    Num(Number),
+    Symbol(String),
+    /// A functor applied to its arguments, by the name it is registered under, e.g. + or sin. A
+    /// `-` with a single argument is a negation.
+    Apply(String, Vec<Expr>),
+    /// A matrix, as a list of its rows
+    Matrix(Vec<Vec<Expr>>),
+}
+impl Expr {
+    /// Parses an infix expression such as `sin(x)^2 / 2`. Operators and functions are recorded
+    /// rather than run, and names stay symbols even if they are bound to a value.
+    pub fn parse(input: &str, context: &Context) -> Result<Expr> {
+        let tokens = infix_to_commands(input, context)?
+            .into_iter()
+            .flatten()
+            .map(|command| single_command_to_token(command, context))
+            .collect::<Result<Vec<Token>>>()?;
+
+        Expr::from_tokens(&tokens)
+    }
+
+    /// Builds an expression from tokens in reverse polish order, e.g. x 1 + 2 /, or 1 x - for
+    /// x - 1
+    pub fn from_tokens(tokens: &[Token]) -> Result<Expr> {
+        let mut operands: Vec<Expr> = vec![];
+
+        for token in tokens {
+            let expr = match token {
+                Token::Functor(func) => {
+                    if operands.len() < func.arity() {
+                        bail!(
+                            "{} expects {} argument(s) but only {} come before it (usage: {})",
+                            func.name,
+                            func.arity(),
+                            operands.len(),
+                            func.signature()
+                        )
+                    }
+                    let mut args = operands.split_off(operands.len() - func.arity());
+                    // The - functor takes its left hand side from the top of the stack
+                    if func.name == "-" {
+                        args.reverse();
+                    }
+                    Expr::apply(&func.name, args)
+                }
+                other => Expr::from_token(other)?,
+            };
+            operands.push(expr);
+        }
+
+        match operands.len() {
+            0 => bail!("The expression is empty"),
+            1 => Ok(operands.pop().unwrap()),
+            left => bail!(
+                "An expression must come to a single value, but {} are left",
+                left
+            ),
+        }
+    }
+
+    /// Turns a single value into an expression
+    pub fn from_token(token: &Token) -> Result<Expr> {
+        Ok(match token {
+            Token::Const(num) => Expr::Num(num.clone()),
+            Token::Symbol(name) => Expr::Symbol(name.clone()),
+            Token::Variable(var) => Expr::Symbol(var.name.clone()),
+            Token::Expr(expr) => expr.clone(),
+            other => bail!("{} cannot be part of an expression", other),
+        })
+    }
+
+    /// Applies a functor to its arguments. The infix parser writes -x as x -1 *, so that is turned
+    /// back into a negation here, and the negation of a number into a negative number.
+    pub fn apply(name: &str, mut args: Vec<Expr>) -> Expr {
+        let minus_one = Expr::Num(Number::Int(BigInt::from(-1)));
+
+        if name == "*" && args.len() == 2 && args[1] == minus_one {
+            let zero = Number::Int(BigInt::from(0));
+            return match args.swap_remove(0) {
+                Expr::Num(num) => match zero.checked_sub(num.clone()) {
+                    Ok(negated) => Expr::Num(negated),
+                    Err(_) => Expr::Apply("-".to_string(), vec![Expr::Num(num)]),
+                },
+                other => Expr::Apply("-".to_string(), vec![other]),
+            };
+        }
+
+        Expr::Apply(name.to_string(), args)
+    }
+
+    /// How tightly the expression binds when written out inline, higher binds tighter: sums are 1,
+    /// products 2, negations 3, powers 4 and anything that cannot be split up 5
+    pub(crate) fn precedence(&self) -> u8 {
+        match self {
+            // A fraction is written as a division
+            Expr::Num(Number::Rational(..)) => 2,
+            Expr::Num(num) if num.is_negative() => 3,
+            Expr::Apply(name, args) => match (name.as_str(), args.len()) {
+                ("+" | "-", 2) => 1,
+                ("*" | "/", 2) => 2,
+                ("-", 1) => 3,
+                ("^", 2) => 4,
+                _ => 5,
+            },
+            _ => 5,
+        }
+    }
+
+    /// The same as `precedence`, but for when fractions are drawn over a bar: the bar sets them
+    /// apart, so they only need parentheses as the base of a power
+    pub(crate) fn stacked_precedence(&self) -> u8 {
+        match self {
+            Expr::Apply(name, args) if name == "/" && args.len() == 2 => 4,
+            Expr::Num(Number::Rational(..)) => 4,
+            _ => self.precedence(),
+        }
+    }
+}
+
+/// Wraps text in parentheses if `parens` is set
+pub(crate) fn parenthesize(text: String, parens: bool, open: &str, close: &str) -> String {
+    if parens {
+        format!("{}{}{}", open, text, close)
+    } else {
+        text
+    }
+}
+
+/// Whether the operands of a binary operator need parentheses, given the precedence of the
+/// operator and of each operand. Operands which start with a minus sign are always put in
+/// parentheses when on the right, so a + -b is written a + (-b).
+pub(crate) fn operand_parens(
+    name: &str,
+    precedence: u8,
+    lhs: (u8, &str),
+    rhs: (u8, &str),
+) -> (bool, bool) {
+    if name == "^" {
+        // Powers group to the right, so the base needs parentheses where the exponent does not
+        (lhs.0 <= precedence, rhs.0 < precedence)
+    } else {
+        (
+            lhs.0 < precedence,
+            rhs.0 <= precedence || rhs.1.starts_with('-'),
+        )
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Num(num) => write!(f, "{}", num),
+            Expr::Symbol(name) => write!(f, "{}", name),
+            Expr::Apply(name, args) if name == "-" && args.len() == 1 => {
+                let operand = args[0].to_string();
+                let parens = args[0].precedence() < self.precedence();
+                write!(f, "-{}", parenthesize(operand, parens, "(", ")"))
+            }
+            Expr::Apply(name, args) if self.precedence() < 5 => {
+                let (lhs, rhs) = (args[0].to_string(), args[1].to_string());
+                let (lhs_parens, rhs_parens) = operand_parens(
+                    name,
+                    self.precedence(),
+                    (args[0].precedence(), &lhs),
+                    (args[1].precedence(), &rhs),
+                );
+
+                let lhs = parenthesize(lhs, lhs_parens, "(", ")");
+                let rhs = parenthesize(rhs, rhs_parens, "(", ")");
+                if name == "^" {
+                    write!(f, "{}^{}", lhs, rhs)
+                } else {
+                    write!(f, "{} {} {}", lhs, name, rhs)
+                }
+            }
+            Expr::Apply(name, args) => {
+                let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            Expr::Matrix(rows) => {
+                let rows = rows
+                    .iter()
+                    .map(|row| {
+                        let row = row.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+                        format!("[{}]", row.join(", "))
+                    })
+                    .collect::<Vec<_>>();
+                write!(f, "[{}]", rows.join(", "))
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::context::Context;
+use crate::token_defs::Token;
+use std::sync::Arc;
+
+/// The state of a machine between two lines. States share as much as they can with each other:
+/// the stack is a persistent vector, so a state only owns what its line pushed, and the context is
+/// only copied when a line actually changed it, as told by `Context::revision`.
+#[derive(Debug, Clone)]
+pub struct MachineState {
+    pub stack: im::Vector<Token>,
+    pub context: Arc<Context>,
+}
+impl MachineState {
+    fn capture(stack: &[Token], context: &Context) -> Self {
+        MachineState {
+            stack: stack.iter().cloned().collect(),
+            context: Arc::new(context.clone()),
+        }
+    }
+}
+
+/// Undo and redo history of a RevPolStackMachine, see `RevPolStackMachine::enable_history`
+#[derive(Debug)]
+pub struct UndoHistory {
+    /// The state as of the last commit
+    current: MachineState,
+    /// States before each committed change, the latest last
+    undo: Vec<MachineState>,
+    /// States which were undone, the latest undone last
+    redo: Vec<MachineState>,
+    /// Lowest the stack has been since the last commit. Everything below it is still what was
+    /// there at the last commit, as functors only ever pop their arguments and have their results
+    /// pushed back on.
+    low_water: usize,
+    /// Whether committed states are kept to be undone, or only the latest one to roll back to
+    keep_undo: bool,
+}
+impl UndoHistory {
+    pub(crate) fn new(stack: &[Token], context: &Context) -> Self {
+        UndoHistory {
+            current: MachineState::capture(stack, context),
+            undo: vec![],
+            redo: vec![],
+            low_water: stack.len(),
+            keep_undo: true,
+        }
+    }
+
+    /// History which only keeps the latest state, to roll back to
+    pub(crate) fn without_undo(stack: &[Token], context: &Context) -> Self {
+        UndoHistory {
+            keep_undo: false,
+            ..UndoHistory::new(stack, context)
+        }
+    }
+
+    pub fn keeps_undo(&self) -> bool {
+        self.keep_undo
+    }
+
+    /// Notes how far down the stack has been, called whenever it shrinks
+    pub(crate) fn touch(&mut self, len: usize) {
+        self.low_water = self.low_water.min(len);
+    }
+
+    /// Records the machine as it is now as a new state, which can be undone. Only the part of the
+    /// stack above the low water mark is copied.
+    pub(crate) fn commit(&mut self, stack: &[Token], context: &Context) {
+        let keep = self.low_water.min(self.current.stack.len());
+
+        let mut new_stack = self.current.stack.take(keep);
+        new_stack.extend(stack[keep..].iter().cloned());
+
+        let new_context = if context.revision == self.current.context.revision {
+            self.current.context.clone()
+        } else {
+            Arc::new(context.clone())
+        };
+
+        let previous = std::mem::replace(
+            &mut self.current,
+            MachineState {
+                stack: new_stack,
+                context: new_context,
+            },
+        );
+        if self.keep_undo {
+            self.undo.push(previous);
+            self.redo.clear();
+        }
+        self.low_water = stack.len();
+    }
+
+    /// Steps back to the state before the last commit, returning it so it can be restored
+    pub(crate) fn undo(&mut self) -> Option<&MachineState> {
+        let state = self.undo.pop()?;
+        self.redo.push(std::mem::replace(&mut self.current, state));
+        self.low_water = self.current.stack.len();
+        Some(&self.current)
+    }
+
+    /// Steps forward to the last undone state, returning it so it can be restored
+    pub(crate) fn redo(&mut self) -> Option<&MachineState> {
+        let state = self.redo.pop()?;
+        self.undo.push(std::mem::replace(&mut self.current, state));
+        self.low_water = self.current.stack.len();
+        Some(&self.current)
+    }
+
+    /// The state as of the last commit, undo or redo
+    pub fn current(&self) -> &MachineState {
+        &self.current
+    }
+
+    /// The bottom of a stack of `len` tokens which is still as it was at the last commit, shared
+    /// rather than copied
+    #[cfg(feature = "debugger")]
+    pub(crate) fn unchanged(&self, len: usize) -> im::Vector<Token> {
+        let keep = self.low_water.min(self.current.stack.len()).min(len);
+        self.current.stack.take(keep)
+    }
+
+    /// Forgets any changes made since the last commit, returning the state to restore
+    pub(crate) fn rollback(&mut self) -> &MachineState {
+        self.low_water = self.current.stack.len();
+        &self.current
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::number::Number;
+
+    fn int(value: i32) -> Token {
+        Token::Const(Number::Int(value.into()))
+    }
+
+    fn stack(state: &MachineState) -> Vec<Token> {
+        state.stack.iter().cloned().collect()
+    }
+
+    #[test]
+    fn undoes_and_redoes_commits() {
+        let mut context = Context::new();
+        let mut history = UndoHistory::new(&[int(1)], &context);
+        history.commit(&[int(1), int(2)], &context);
+        context.set_variable("x".to_string(), int(3));
+        history.commit(&[int(1), int(2)], &context);
+        assert!(history.can_undo() && !history.can_redo());
+
+        let state = history.undo().unwrap();
+        assert_eq!(stack(state), vec![int(1), int(2)]);
+        assert!(state.context.get_variable("x").is_none());
+        assert_eq!(stack(history.undo().unwrap()), vec![int(1)]);
+        assert!(history.undo().is_none());
+
+        let state = history.redo().unwrap();
+        assert_eq!(stack(state), vec![int(1), int(2)]);
+        assert!(history.redo().unwrap().context.get_variable("x").is_some());
+        assert!(history.redo().is_none());
+
+        // Committing after an undo throws away what was undone
+        history.undo();
+        history.touch(0);
+        history.commit(&[int(4)], &context);
+        assert!(!history.can_redo());
+        assert_eq!(stack(history.current()), vec![int(4)]);
+    }
+
+    #[test]
+    fn keeps_what_is_below_the_low_water_mark() {
+        let context = Context::new();
+        let mut history = UndoHistory::new(&[int(1), int(2), int(3)], &context);
+
+        // A line popped 2 and 3 and pushed their sum, so only the sum is copied
+        history.touch(1);
+        history.commit(&[int(9), int(5)], &context);
+        assert_eq!(stack(history.current()), vec![int(1), int(5)]);
+        assert_eq!(stack(history.undo().unwrap()), vec![int(1), int(2), int(3)]);
+
+        // Rolling back forgets how low the stack went
+        history.touch(0);
+        history.rollback();
+        history.commit(&[int(7), int(2), int(3), int(4)], &context);
+        assert_eq!(
+            stack(history.current()),
+            vec![int(1), int(2), int(3), int(4)]
+        );
+    }
+
+    #[test]
+    fn only_copies_changed_contexts() {
+        let mut context = Context::new();
+        let mut history = UndoHistory::new(&[], &context);
+        let first = history.current().context.clone();
+
+        history.commit(&[int(1)], &context);
+        assert!(Arc::ptr_eq(&first, &history.current().context));
+
+        context.set_variable("x".to_string(), int(1));
+        history.commit(&[int(1)], &context);
+        assert!(!Arc::ptr_eq(&first, &history.current().context));
+        assert_eq!(*history.current().context, context);
+
+        assert!(context.remove_variable("x"));
+        assert!(!context.remove_variable("x"));
+        history.commit(&[int(1)], &context);
+        assert!(history.current().context.get_variable("x").is_none());
+    }
+}
@@ -0,0 +1,323 @@
+use crate::codegen::{Lowered, Operand};
+use crate::expression::Expr;
+use crate::number::Number;
+use anyhow::{Result, anyhow, bail};
+use num_bigfloat::BigFloat;
+use num_bigint::BigInt;
+
+/// An operation of a compiled expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Neg,
+    Sqrt,
+    Sin,
+    Cos,
+    Tan,
+    Exp,
+    Ln,
+    Abs,
+}
+impl Op {
+    fn from_name(name: &str) -> Op {
+        match name {
+            "+" => Op::Add,
+            "-" => Op::Sub,
+            "*" => Op::Mul,
+            "/" => Op::Div,
+            "^" => Op::Pow,
+            "neg" => Op::Neg,
+            "sqrt" => Op::Sqrt,
+            "sin" => Op::Sin,
+            "cos" => Op::Cos,
+            "tan" => Op::Tan,
+            "exp" => Op::Exp,
+            "ln" => Op::Ln,
+            "abs" => Op::Abs,
+            other => unreachable!("{} is not an operation which can be lowered", other),
+        }
+    }
+}
+
+/// Where a step gets an operand from
+#[derive(Debug, Clone)]
+enum Arg<T> {
+    /// The value given for the variable at this index
+    Variable(usize),
+    Value(T),
+    /// The result of an earlier step
+    Step(usize),
+}
+
+/// An expression as a list of steps, each using the variables, constants and results of the
+/// steps before it. Shared subexpressions are one step, so they are only computed once.
+#[derive(Debug, Clone)]
+struct Program<T> {
+    steps: Vec<(Op, Arg<T>, Option<Arg<T>>)>,
+    result: Arg<T>,
+    /// How many values it has to be given, one for each variable
+    inputs: usize,
+}
+impl<T: Clone> Program<T> {
+    fn new(
+        expr: &Expr,
+        variables: &[String],
+        constant: impl Fn(&Operand) -> T,
+    ) -> Result<Program<T>> {
+        let lowered = Lowered::new(expr, variables)?;
+        let arg = |operand: &Operand| match operand {
+            Operand::Input(name) => variables
+                .iter()
+                .position(|v| v == name)
+                .map(Arg::Variable)
+                .ok_or_else(|| anyhow!("{} is not one of the variables", name)),
+            Operand::Temp(idx) => Ok(Arg::Step(*idx)),
+            other => Ok(Arg::Value(constant(other))),
+        };
+
+        let steps = lowered
+            .operations
+            .iter()
+            .map(|operation| {
+                let rhs = operation.args.get(1).map(arg).transpose()?;
+                Ok((Op::from_name(operation.name), arg(&operation.args[0])?, rhs))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Program {
+            steps,
+            result: arg(&lowered.result)?,
+            inputs: variables.len(),
+        })
+    }
+
+    fn run(&self, values: &[T], apply: impl Fn(Op, &T, Option<&T>) -> Result<T>) -> Result<T> {
+        if values.len() != self.inputs {
+            bail!(
+                "Expected {} value(s), one for each variable, but was given {}",
+                self.inputs,
+                values.len()
+            )
+        }
+
+        let mut results: Vec<T> = Vec::with_capacity(self.steps.len());
+        for (op, lhs, rhs) in &self.steps {
+            let lhs = fetch(lhs, values, &results);
+            let rhs = rhs.as_ref().map(|rhs| fetch(rhs, values, &results));
+            let res = apply(*op, lhs, rhs)?;
+            results.push(res);
+        }
+        Ok(fetch(&self.result, values, &results).clone())
+    }
+}
+
+fn fetch<'a, T>(arg: &'a Arg<T>, values: &'a [T], results: &'a [T]) -> &'a T {
+    match arg {
+        Arg::Variable(idx) => &values[*idx],
+        Arg::Value(value) => value,
+        Arg::Step(idx) => &results[*idx],
+    }
+}
+
+/// Compiles an expression into a function of the given variables, which takes their values in
+/// the same order. This skips the stack machine entirely, so it is fit for being called a lot,
+/// e.g. by plotting or numeric integration.
+///
+/// The expression may only use the variables, pi, and + - * / ^ sqrt sin cos tan exp ln abs. The
+/// function fails if it is not given exactly one value for each variable.
+pub fn lambdify(
+    expr: &Expr,
+    variables: &[String],
+) -> Result<impl Fn(&[f64]) -> Result<f64> + Send + Sync + 'static> {
+    let program = Program::new(expr, variables, |operand| match operand {
+        Operand::Const(num) => num.clone().to_float().to_f64(),
+        _ => std::f64::consts::PI,
+    })?;
+
+    Ok(move |values: &[f64]| {
+        program.run(values, |op, lhs, rhs| {
+            Ok(apply_f64(op, *lhs, rhs.copied().unwrap_or(0.0)))
+        })
+    })
+}
+
+fn apply_f64(op: Op, lhs: f64, rhs: f64) -> f64 {
+    match op {
+        Op::Add => lhs + rhs,
+        Op::Sub => lhs - rhs,
+        Op::Mul => lhs * rhs,
+        Op::Div => lhs / rhs,
+        Op::Pow if rhs == rhs.trunc() && rhs.abs() <= i32::MAX as f64 => lhs.powi(rhs as i32),
+        Op::Pow => lhs.powf(rhs),
+        Op::Neg => -lhs,
+        Op::Sqrt => lhs.sqrt(),
+        Op::Sin => lhs.sin(),
+        Op::Cos => lhs.cos(),
+        Op::Tan => lhs.tan(),
+        Op::Exp => lhs.exp(),
+        Op::Ln => lhs.ln(),
+        Op::Abs => lhs.abs(),
+    }
+}
+
+/// The same as `lambdify`, but computing with `Number`s just as the functors would, so integers
+/// stay exact and modular numbers stay modular. Dividing by zero or mixing moduli is an error, as
+/// it is for the functors.
+pub fn lambdify_number(
+    expr: &Expr,
+    variables: &[String],
+) -> Result<impl Fn(&[Number]) -> Result<Number> + Send + Sync + 'static> {
+    let program = Program::new(expr, variables, |operand| match operand {
+        Operand::Const(num) => num.clone(),
+        _ => Number::Float(num_bigfloat::PI),
+    })?;
+
+    Ok(move |values: &[Number]| program.run(values, apply_number))
+}
+
+fn apply_number(op: Op, lhs: &Number, rhs: Option<&Number>) -> Result<Number> {
+    let lhs = lhs.clone();
+    let rhs = rhs.cloned().unwrap_or(Number::Int(BigInt::from(0)));
+
+    Ok(match op {
+        Op::Add => lhs.checked_add(rhs)?,
+        Op::Sub => lhs.checked_sub(rhs)?,
+        Op::Mul => lhs.checked_mul(rhs, None)?,
+        Op::Div => lhs.checked_div(rhs)?,
+        Op::Pow => lhs.pow(rhs, None)?,
+        Op::Neg => Number::Int(BigInt::from(0)).checked_sub(lhs)?,
+        Op::Sqrt => lhs.map_float(BigFloat::sqrt),
+        Op::Sin => lhs.map_float(BigFloat::sin),
+        Op::Cos => lhs.map_float(BigFloat::cos),
+        Op::Tan => lhs.map_float(BigFloat::tan),
+        Op::Exp => lhs.map_float(BigFloat::exp),
+        Op::Ln => lhs.map_float(BigFloat::ln),
+        Op::Abs => lhs.abs(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::parse_infix::infix_to_commands;
+    use crate::parse_rpol_notation::commands_to_sequential_exec_order;
+    use crate::stack_machine::{BufferedExecutor, RevPolStackMachine};
+    use crate::token_defs::Token;
+
+    const SOURCES: [&str; 5] = [
+        "(x + 1) * (x + 1) - y / 2",
+        "x ^ 3 - 2 * x * y + 7",
+        "(x - y) - (y - x) * x",
+        "-x ^ 2 + y / (x + 1)",
+        "abs(x - y) * abs(y - x)",
+    ];
+
+    fn int(value: i64) -> Number {
+        Number::Int(value.into())
+    }
+
+    fn float(value: f64) -> Number {
+        Number::Float(BigFloat::from(value))
+    }
+
+    fn variables() -> Vec<String> {
+        vec!["x".to_string(), "y".to_string()]
+    }
+
+    /// Runs an infix expression on the stack machine, with x and y bound to the values
+    fn evaluate(source: &str, values: &[Number]) -> Result<Number> {
+        crate::set_debug_logging(false);
+        let mut context = Context::pure_math();
+        for (name, value) in variables().into_iter().zip(values) {
+            context.set_variable(name, Token::Const(value.clone()));
+        }
+        let commands = infix_to_commands(source, &context)?;
+        let lines = commands_to_sequential_exec_order(commands, &context)?;
+        let machine = RevPolStackMachine::new_with_ctx(context);
+        let mut executor = BufferedExecutor::new(machine, lines);
+        executor.run_stack()?;
+
+        match executor.machine.stack.as_slice() {
+            [Token::Const(num)] => Ok(num.clone()),
+            other => bail!("Expected a single number, found {:?}", other),
+        }
+    }
+
+    fn compile(source: &str) -> Expr {
+        Expr::parse(source, &Context::pure_math()).unwrap()
+    }
+
+    #[test]
+    fn numbers_match_buffered_executor() {
+        let modular = |value| Number::modular(BigInt::from(value), BigInt::from(11)).unwrap();
+        let fraction = Number::rational(BigInt::from(1), BigInt::from(3)).unwrap();
+        let value_sets = [
+            [int(3), int(-2)],
+            [fraction, int(5)],
+            [modular(4), modular(9)],
+            [float(2.5), int(4)],
+        ];
+
+        for source in SOURCES {
+            let func = lambdify_number(&compile(source), &variables()).unwrap();
+            for values in &value_sets {
+                let expected = evaluate(source, values);
+                let actual = func(values);
+                match (expected, actual) {
+                    (Ok(expected), Ok(actual)) => assert_eq!(actual, expected, "{}", source),
+                    (Err(_), Err(_)) => {}
+                    (expected, actual) => {
+                        panic!("{}: got {:?}, expected {:?}", source, actual, expected)
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn floats_match_buffered_executor() {
+        let sources = SOURCES
+            .iter()
+            .copied()
+            .chain(["sqrt(x) * sin(y) + exp(x / 4) - ln(x) * cos(y) / tan(x)"]);
+
+        for source in sources {
+            let func = lambdify(&compile(source), &variables()).unwrap();
+            for (x, y) in [(2.5, 0.75), (0.5, -3.0), (7.0, 2.0)] {
+                let values = [float(x), float(y)];
+                let expected = evaluate(source, &values).unwrap().to_float().to_f64();
+                let actual = func(&[x, y]).unwrap();
+                let error = (actual - expected).abs() / expected.abs().max(1.0);
+                assert!(error < 1e-12, "{}: {} != {}", source, actual, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn computes_shared_subexpressions_once() {
+        let program = Program::new(&compile("(x + 1) * (x + 1)"), &variables(), |_| 0.0).unwrap();
+        assert_eq!(program.steps.len(), 2);
+    }
+
+    #[test]
+    fn fails_on_the_wrong_number_of_values() {
+        let func = lambdify(&compile("x + y"), &variables()).unwrap();
+        assert_eq!(func(&[1.0, 2.0]).unwrap(), 3.0);
+        assert!(func(&[1.0]).is_err());
+        assert!(func(&[1.0, 2.0, 3.0]).is_err());
+
+        let func = lambdify_number(&compile("x + y"), &variables()).unwrap();
+        assert!(func(&[int(1)]).is_err());
+    }
+
+    #[test]
+    fn refuses_what_it_cannot_compile() {
+        assert!(lambdify(&compile("x + z"), &variables()).is_err());
+        assert!(lambdify(&compile("gcd(x, y)"), &variables()).is_err());
+    }
+}
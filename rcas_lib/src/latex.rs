@@ -0,0 +1,248 @@
+use crate::expression::{Expr, operand_parens, parenthesize};
+
+/// Functions LaTeX has a command for, written as \name
+const LATEX_FUNCTIONS: [&str; 19] = [
+    "sin", "cos", "tan", "sec", "csc", "cot", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "exp", "ln", "log", "det", "min", "max", "gcd",
+];
+
+/// Greek letters, which are written as \name when used as a symbol
+const GREEK_LETTERS: [&str; 35] = [
+    "alpha",
+    "beta",
+    "gamma",
+    "delta",
+    "epsilon",
+    "zeta",
+    "eta",
+    "theta",
+    "iota",
+    "kappa",
+    "lambda",
+    "mu",
+    "nu",
+    "xi",
+    "pi",
+    "rho",
+    "sigma",
+    "tau",
+    "upsilon",
+    "phi",
+    "chi",
+    "psi",
+    "omega",
+    "Gamma",
+    "Delta",
+    "Theta",
+    "Lambda",
+    "Xi",
+    "Pi",
+    "Sigma",
+    "Upsilon",
+    "Phi",
+    "Psi",
+    "Omega",
+    "varepsilon",
+];
+
+/// The name of a symbol as LaTeX: greek letters become \alpha and so on, anything after an
+/// underscore becomes a subscript, and longer names are set upright so they do not read as a
+/// product of single letters
+pub fn symbol_to_latex(name: &str) -> String {
+    if let Some((base, subscript)) = name.split_once('_')
+        && !base.is_empty()
+        && !subscript.is_empty()
+    {
+        return format!(
+            "{}_{{{}}}",
+            symbol_to_latex(base),
+            symbol_to_latex(subscript)
+        );
+    }
+
+    if GREEK_LETTERS.contains(&name) {
+        format!("\\{}", name)
+    } else if name.chars().count() == 1 || name.chars().all(|c| c.is_ascii_digit()) {
+        name.to_string()
+    } else {
+        format!("\\mathrm{{{}}}", escape(name))
+    }
+}
+
+/// The name of a function as LaTeX, e.g. \sin or \operatorname{erf}
+pub fn function_to_latex(name: &str) -> String {
+    if LATEX_FUNCTIONS.contains(&name) {
+        format!("\\{}", name)
+    } else {
+        format!("\\operatorname{{{}}}", escape(name))
+    }
+}
+
+/// Plain text as LaTeX
+pub fn text_to_latex(text: &str) -> String {
+    format!("\\text{{{}}}", escape(text))
+}
+
+/// Escapes the characters which mean something to LaTeX
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            '{' | '}' | '$' | '&' | '#' | '_' | '%' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Expr {
+    /// The expression as LaTeX, with as few parentheses as it needs to be read back the same way
+    pub fn to_latex(&self) -> String {
+        match self {
+            Expr::Num(num) => num.to_latex(),
+            Expr::Symbol(name) => symbol_to_latex(name),
+            Expr::Apply(name, args) if name == "-" && args.len() == 1 => {
+                let parens = args[0].stacked_precedence() < self.stacked_precedence();
+                format!("-{}", latex_parens(args[0].to_latex(), parens))
+            }
+            Expr::Apply(name, args) if name == "/" && args.len() == 2 => {
+                format!("\\frac{{{}}}{{{}}}", args[0].to_latex(), args[1].to_latex())
+            }
+            Expr::Apply(name, args) if name == "^" && args.len() == 2 => {
+                let base = args[0].to_latex();
+                let parens = args[0].stacked_precedence() <= self.stacked_precedence()
+                    || base.starts_with('-');
+                // The exponent is raised, so it never needs parentheses
+                format!("{}^{{{}}}", latex_parens(base, parens), args[1].to_latex())
+            }
+            Expr::Apply(name, args) if self.precedence() < 5 => {
+                let (lhs, rhs) = (args[0].to_latex(), args[1].to_latex());
+                let (lhs_parens, rhs_parens) = operand_parens(
+                    name,
+                    self.stacked_precedence(),
+                    (args[0].stacked_precedence(), &lhs),
+                    (args[1].stacked_precedence(), &rhs),
+                );
+
+                let operator = if name == "*" { "\\cdot" } else { name };
+                format!(
+                    "{} {} {}",
+                    latex_parens(lhs, lhs_parens),
+                    operator,
+                    latex_parens(rhs, rhs_parens)
+                )
+            }
+            Expr::Apply(name, args) if name == "sqrt" && args.len() == 1 => {
+                format!("\\sqrt{{{}}}", args[0].to_latex())
+            }
+            Expr::Apply(name, args) if name == "abs" && args.len() == 1 => {
+                format!("\\left|{}\\right|", args[0].to_latex())
+            }
+            Expr::Apply(name, args) => {
+                let args = args.iter().map(|arg| arg.to_latex()).collect::<Vec<_>>();
+                format!(
+                    "{}{}",
+                    function_to_latex(name),
+                    latex_parens(args.join(", "), true)
+                )
+            }
+            Expr::Matrix(rows) => {
+                let rows = rows
+                    .iter()
+                    .map(|row| {
+                        let row = row.iter().map(|e| e.to_latex()).collect::<Vec<_>>();
+                        row.join(" & ")
+                    })
+                    .collect::<Vec<_>>();
+                format!(
+                    "\\begin{{pmatrix}} {} \\end{{pmatrix}}",
+                    rows.join(" \\\\ ")
+                )
+            }
+        }
+    }
+}
+
+fn latex_parens(text: String, parens: bool) -> String {
+    parenthesize(text, parens, "\\left(", "\\right)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::number::Number;
+    use num_bigint::BigInt;
+
+    fn latex(source: &str) -> String {
+        crate::set_debug_logging(false);
+        Expr::parse(source, &Context::pure_math())
+            .unwrap()
+            .to_latex()
+    }
+
+    fn int(value: i32) -> Expr {
+        Expr::Num(Number::Int(BigInt::from(value)))
+    }
+
+    #[test]
+    fn only_parenthesizes_what_it_needs_to() {
+        assert_eq!(latex("a + b * c"), "a + b \\cdot c");
+        assert_eq!(latex("(a + b) * c"), "\\left(a + b\\right) \\cdot c");
+        assert_eq!(latex("a - (b - c)"), "a - \\left(b - c\\right)");
+        assert_eq!(latex("(a - b) - c"), "a - b - c");
+        assert_eq!(latex("-(a + b)"), "-\\left(a + b\\right)");
+    }
+
+    #[test]
+    fn writes_fractions_with_frac() {
+        assert_eq!(latex("(a + b) / c"), "\\frac{a + b}{c}");
+        assert_eq!(latex("a / b / c"), "\\frac{\\frac{a}{b}}{c}");
+    }
+
+    #[test]
+    fn raises_exponents_and_parenthesizes_bases() {
+        assert_eq!(latex("x ^ (n + 1)"), "x^{n + 1}");
+        assert_eq!(latex("(x + 1) ^ 2"), "\\left(x + 1\\right)^{2}");
+        assert_eq!(latex("(x ^ 2) ^ 3"), "\\left(x^{2}\\right)^{3}");
+        let negative = Expr::Apply("^".to_string(), vec![int(-2), int(2)]);
+        assert_eq!(negative.to_latex(), "\\left(-2\\right)^{2}");
+    }
+
+    #[test]
+    fn writes_function_names_as_commands() {
+        assert_eq!(
+            latex("sin(x) + cos(theta)"),
+            "\\sin\\left(x\\right) + \\cos\\left(\\theta\\right)"
+        );
+        assert_eq!(
+            latex("sqrt(x_1) * abs(y)"),
+            "\\sqrt{x_{1}} \\cdot \\left|y\\right|"
+        );
+        let erf = Expr::Apply("erf".to_string(), vec![Expr::Symbol("speed".to_string())]);
+        assert_eq!(
+            erf.to_latex(),
+            "\\operatorname{erf}\\left(\\mathrm{speed}\\right)"
+        );
+        assert_eq!(symbol_to_latex("a_b_c"), "a_{b_{c}}");
+        assert_eq!(text_to_latex("50% & $5"), "\\text{50\\% \\& \\$5}");
+    }
+
+    #[test]
+    fn writes_matrices_as_pmatrix() {
+        let matrix = Expr::Matrix(vec![
+            vec![int(1), int(2)],
+            vec![Expr::Symbol("x".into()), int(4)],
+        ]);
+        assert_eq!(
+            matrix.to_latex(),
+            "\\begin{pmatrix} 1 & 2 \\\\ x & 4 \\end{pmatrix}"
+        );
+    }
+}
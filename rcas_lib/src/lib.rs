@@ -1,19 +1,54 @@
+pub mod bytecode;
+pub mod codegen;
 pub mod context;
 pub mod default_ctx_content;
 pub mod default_ctx_macros;
+pub mod expression;
+pub mod history;
+pub mod lambdify;
+pub mod latex;
+pub mod limits;
+pub mod mathml;
+pub mod parse_infix;
 pub mod parse_rpol_notation;
+pub mod plot;
+pub mod polynomial;
+pub mod pretty;
+pub mod profiler;
+pub mod save_file;
 pub mod stack_machine;
 pub mod token_defs;
 pub mod number;
+pub mod number_format;
+pub mod number_theory;
+pub mod vm;
 
 #[cfg(feature = "debugger")]
 pub mod debugger;
+#[cfg(feature = "debugger")]
+pub mod trace;
+
+/// Whether the debug! macro prints anything. It is on by default, front ends which do their own
+/// reporting can turn it off with `set_debug_logging(false)`.
+#[cfg(feature = "debugger")]
+pub static DEBUG_LOGGING: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(true);
+
+/// Turns the output of the debug! macro on or off
+#[cfg(feature = "debugger")]
+pub fn set_debug_logging(enabled: bool) {
+    DEBUG_LOGGING.store(enabled, std::sync::atomic::Ordering::Relaxed);
+}
+
+#[cfg(not(feature = "debugger"))]
+pub fn set_debug_logging(_: bool) {}
 
 #[cfg(feature = "debugger")]
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)*) => {{
-        println!("[DEBUG] {}:{} - {}", file!(), line!(), format_args!($($arg)*));
+        if $crate::DEBUG_LOGGING.load(std::sync::atomic::Ordering::Relaxed) {
+            println!("[DEBUG] {}:{} - {}", file!(), line!(), format_args!($($arg)*));
+        }
     }};
 }
 
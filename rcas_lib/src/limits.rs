@@ -0,0 +1,304 @@
+use crate::number::Number;
+use crate::token_defs::Token;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// A flag which can be shared with another thread to stop an executor. Cancellation is
+/// cooperative: the executor only checks it between steps, so a single long running functor will
+/// still run to completion.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Asks every executor holding a clone of this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The reason an executor was stopped by its limits. These are returned wrapped in an
+/// `anyhow::Error`, use `downcast_ref::<LimitError>()` to tell them apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitError {
+    /// More steps were taken than `max_steps` allows
+    StepLimit(usize),
+    /// The stack grew deeper than `max_stack_depth`
+    StackDepth(usize),
+    /// An integer grew longer than `max_int_bits`
+    IntegerSize(u64),
+    /// The deadline passed
+    Deadline,
+    /// The cancellation token was triggered
+    Cancelled,
+}
+impl Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitError::StepLimit(max) => write!(f, "Step limit of {} exceeded", max),
+            LimitError::StackDepth(max) => write!(f, "Stack depth limit of {} exceeded", max),
+            LimitError::IntegerSize(max) => {
+                write!(f, "Integer size limit of {} bits exceeded", max)
+            }
+            LimitError::Deadline => write!(f, "Execution deadline exceeded"),
+            LimitError::Cancelled => write!(f, "Execution was cancelled"),
+        }
+    }
+}
+impl std::error::Error for LimitError {}
+
+/// Caps on how much work an executor is allowed to do. Every limit is off (`None`) by default.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionLimits {
+    /// Maximum number of tokens the executor may pop off the stack
+    pub max_steps: Option<usize>,
+    /// Maximum number of tokens on the stack at once
+    pub max_stack_depth: Option<usize>,
+    /// Maximum bit length of any integer on the stack
+    pub max_int_bits: Option<u64>,
+    /// Point in time after which execution stops
+    pub deadline: Option<Instant>,
+    /// Lets another thread stop execution
+    pub cancellation: Option<CancellationToken>,
+}
+impl ExecutionLimits {
+    /// No limits at all, the same as `ExecutionLimits::default()`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks the limits which apply before each step: the step budget, the deadline and
+    /// cancellation
+    pub fn check_step(&self, steps: usize) -> Result<(), LimitError> {
+        if let Some(max) = self.max_steps
+            && steps > max
+        {
+            return Err(LimitError::StepLimit(max));
+        }
+
+        if let Some(token) = &self.cancellation
+            && token.is_cancelled()
+        {
+            return Err(LimitError::Cancelled);
+        }
+
+        if let Some(deadline) = self.deadline
+            && Instant::now() > deadline
+        {
+            return Err(LimitError::Deadline);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the depth of a stack and the size of every integer on it from `start` up. Everything
+    /// below `start` should have been checked already, so only what was pushed since needs to be.
+    pub fn check_stack(&self, stack: &[Token], start: usize) -> Result<(), LimitError> {
+        self.check_depth(stack.len())?;
+
+        if self.max_int_bits.is_some() {
+            for token in &stack[start.min(stack.len())..] {
+                self.check_token(token)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks a stack depth against `max_stack_depth`
+    pub fn check_depth(&self, depth: usize) -> Result<(), LimitError> {
+        match self.max_stack_depth {
+            Some(max) if depth > max => Err(LimitError::StackDepth(max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks a single token against `max_int_bits`
+    pub fn check_token(&self, token: &Token) -> Result<(), LimitError> {
+        match token {
+            Token::Const(num) => self.check_number(num),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the integers a number is made of against `max_int_bits`
+    pub fn check_number(&self, num: &Number) -> Result<(), LimitError> {
+        match num {
+            Number::Int(int) => check_int_size(self.max_int_bits, int.bits() as f64),
+            Number::Rational(numerator, denominator) => {
+                let bits = numerator.bits().max(denominator.bits());
+                check_int_size(self.max_int_bits, bits as f64)
+            }
+            // The value is always below the modulus, so the modulus is the bigger of the two
+            Number::Mod(_, modulus) => check_int_size(self.max_int_bits, modulus.bits() as f64),
+            Number::Float(_) => Ok(()),
+        }
+    }
+}
+
+/// Checks that an integer of `bits` bits fits in `max_int_bits`. Functors which can build huge
+/// integers call this with an estimate before doing the work, as the executor only sees the result
+/// once it has been computed.
+pub fn check_int_size(max_int_bits: Option<u64>, bits: f64) -> Result<(), LimitError> {
+    match max_int_bits {
+        Some(max) if bits > max as f64 => Err(LimitError::IntegerSize(max)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::parse_rpol_notation::{commands_to_sequential_exec_order, split_into_commands};
+    use crate::stack_machine::{BufferedExecutor, RevPolStackMachine};
+    use std::time::Duration;
+
+    fn executor(script: &str, limits: ExecutionLimits) -> BufferedExecutor {
+        crate::set_debug_logging(false);
+        let commands = split_into_commands(script.to_string());
+        let lines = commands_to_sequential_exec_order(commands, &Context::pure_math()).unwrap();
+        let machine = RevPolStackMachine::new_with_ctx(Context::pure_math());
+        BufferedExecutor::new_with_limits(machine, lines, limits)
+    }
+
+    fn run(script: &str, limits: ExecutionLimits) -> anyhow::Result<Vec<Token>> {
+        let mut executor = executor(script, limits);
+        executor.run_stack()?;
+        Ok(executor.machine.stack)
+    }
+
+    fn limit_error(result: anyhow::Result<Vec<Token>>) -> Option<LimitError> {
+        result.err()?.downcast_ref::<LimitError>().cloned()
+    }
+
+    fn int_bits(max: u64) -> ExecutionLimits {
+        ExecutionLimits {
+            max_int_bits: Some(max),
+            ..ExecutionLimits::new()
+        }
+    }
+
+    fn is_size_error(result: anyhow::Result<Vec<Token>>) -> bool {
+        result.is_err_and(|e| e.downcast_ref::<LimitError>() == Some(&LimitError::IntegerSize(64)))
+    }
+
+    #[test]
+    fn refuses_huge_results_before_computing_them() {
+        // Each of these would take minutes or run out of memory if computed first
+        assert!(is_size_error(run("3 50000000 ^", int_bits(64))));
+        assert!(is_size_error(run("10 4000000000 ^", int_bits(64))));
+    }
+
+    #[test]
+    fn allows_results_within_the_limit() {
+        assert!(run("3 40 ^", int_bits(64)).is_ok());
+        assert!(run("4294967295 4294967295 *", int_bits(64)).is_ok());
+        assert!(is_size_error(run("3 41 ^", int_bits(64))));
+        assert!(is_size_error(run("4294967296 4294967296 *", int_bits(64))));
+    }
+
+    #[test]
+    fn holds_factorials_and_binomials_to_the_limit() {
+        let check = |script| run(script, int_bits(64));
+        assert!(is_size_error(check("4000000000 factorial")));
+        assert!(is_size_error(check("4000000000 2000000000 binomial")));
+        assert!(check("20 factorial").is_ok());
+        assert!(is_size_error(check("21 factorial")));
+        assert!(check("66 33 binomial").is_ok());
+        assert!(is_size_error(check("70 35 binomial")));
+    }
+
+    #[test]
+    fn checks_both_halves_of_fractions() {
+        assert!(run("1/18446744073709551615", int_bits(64)).is_ok());
+        assert!(is_size_error(run("1/18446744073709551616", int_bits(64))));
+        assert!(is_size_error(run("18446744073709551616/3", int_bits(64))));
+    }
+
+    #[test]
+    fn checks_the_modulus_of_modular_numbers() {
+        let limit = || int_bits(64);
+        assert!(run("3 18446744073709551615 Mod", limit()).is_ok());
+        assert!(is_size_error(run("3 18446744073709551616 Mod", limit())));
+    }
+
+    #[test]
+    fn stops_after_the_step_limit() {
+        let script = "1 2 +\n3 *\n4 -";
+        let mut unlimited = executor(script, ExecutionLimits::new());
+        unlimited.run_stack().unwrap();
+        let steps = unlimited.steps;
+
+        let limit = |max_steps| ExecutionLimits {
+            max_steps: Some(max_steps),
+            ..ExecutionLimits::new()
+        };
+        assert_eq!(run(script, limit(steps)).unwrap(), unlimited.machine.stack);
+        let error = limit_error(run(script, limit(steps - 1)));
+        assert_eq!(error, Some(LimitError::StepLimit(steps - 1)));
+    }
+
+    #[test]
+    fn stops_when_the_stack_gets_too_deep() {
+        let depth = ExecutionLimits {
+            max_stack_depth: Some(4),
+            ..ExecutionLimits::new()
+        };
+        assert!(run("1 2\n3 4", depth.clone()).is_ok());
+        let error = limit_error(run("1 2\n3 4 5", depth));
+        assert_eq!(error, Some(LimitError::StackDepth(4)));
+    }
+
+    #[test]
+    fn stops_after_the_deadline() {
+        let past = ExecutionLimits {
+            deadline: Instant::now().checked_sub(Duration::from_secs(1)),
+            ..ExecutionLimits::new()
+        };
+        assert_eq!(limit_error(run("1 2 +", past)), Some(LimitError::Deadline));
+
+        let future = ExecutionLimits {
+            deadline: Some(Instant::now() + Duration::from_secs(60)),
+            ..ExecutionLimits::new()
+        };
+        assert!(run("1 2 +", future).is_ok());
+    }
+
+    #[test]
+    fn stops_once_cancelled() {
+        let token = CancellationToken::new();
+        let limits = ExecutionLimits {
+            cancellation: Some(token.clone()),
+            ..ExecutionLimits::new()
+        };
+        let mut executor = executor("1 2 +\n3 *", limits);
+
+        executor.run_line().unwrap();
+        token.cancel();
+        let error = executor.run_line().unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&LimitError::Cancelled));
+        // What ran before it was cancelled is kept
+        let three = Token::Const(Number::Int(3.into()));
+        assert_eq!(executor.machine.stack, vec![three]);
+    }
+
+    #[test]
+    fn only_checks_what_was_pushed() {
+        let stack = vec![
+            Token::Const(Number::Int(1.into())),
+            Token::Const(Number::Int(num_bigint::BigInt::from(1) << 100)),
+            Token::Const(Number::Int(2.into())),
+        ];
+        assert!(int_bits(64).check_stack(&stack, 0).is_err());
+        assert!(int_bits(64).check_stack(&stack, 2).is_ok());
+    }
+}
@@ -0,0 +1,764 @@
+use crate::context::find_default_function;
+use crate::expression::{Expr, operand_parens};
+use crate::number::Number;
+use crate::pretty::GREEK_LETTERS;
+use anyhow::{Result, anyhow, bail};
+use num_bigfloat::BigFloat;
+use num_bigint::{BigInt, Sign};
+use std::str::FromStr;
+
+const MATHML_NAMESPACE: &str = "http://www.w3.org/1998/Math/MathML";
+
+/// How deep elements may be nested in a document being read. Both the XML parser and the readers
+/// of its elements recurse, so this keeps a hostile document from overflowing the stack.
+const MAX_DEPTH: usize = 256;
+
+/// Functors which have an element of their own in Content MathML and a symbol in OpenMath, as
+/// (functor, Content MathML element, OpenMath content dictionary, OpenMath name)
+const SYMBOLS: [(&str, &str, &str, &str); 11] = [
+    ("+", "plus", "arith1", "plus"),
+    ("-", "minus", "arith1", "minus"),
+    ("*", "times", "arith1", "times"),
+    ("/", "divide", "arith1", "divide"),
+    ("^", "power", "arith1", "power"),
+    ("abs", "abs", "arith1", "abs"),
+    ("sin", "sin", "transc1", "sin"),
+    ("cos", "cos", "transc1", "cos"),
+    ("tan", "tan", "transc1", "tan"),
+    ("exp", "exp", "transc1", "exp"),
+    ("ln", "ln", "transc1", "ln"),
+];
+
+impl Expr {
+    /// The expression as Presentation MathML, which describes how it looks, for web renderers
+    pub fn to_presentation_mathml(&self) -> String {
+        format!(
+            "<math xmlns=\"{}\">{}</math>",
+            MATHML_NAMESPACE,
+            self.presentation()
+        )
+    }
+
+    /// The expression as Content MathML, which describes what it means, for other algebra systems
+    pub fn to_content_mathml(&self) -> String {
+        format!(
+            "<math xmlns=\"{}\">{}</math>",
+            MATHML_NAMESPACE,
+            self.content()
+        )
+    }
+
+    /// Reads an expression from Content MathML, e.g. as written by `to_content_mathml`. Functions
+    /// given as a csymbol or ci are only accepted if a default function has that name.
+    pub fn from_content_mathml(xml: &str) -> Result<Expr> {
+        let root = parse_xml(xml)?;
+        match root.name.as_str() {
+            "math" => from_content(only_child(&root)?),
+            _ => from_content(&root),
+        }
+    }
+
+    /// Reads an expression from an OpenMath object in its XML encoding. Symbols from arith1,
+    /// transc1, nums1 and linalg2 are understood, anything else only if a default function has
+    /// the same name.
+    pub fn from_openmath(xml: &str) -> Result<Expr> {
+        let root = parse_xml(xml)?;
+        match root.name.as_str() {
+            "OMOBJ" => from_openmath(only_child(&root)?),
+            _ => from_openmath(&root),
+        }
+    }
+
+    fn presentation(&self) -> String {
+        match self {
+            Expr::Num(Number::Rational(numerator, denominator)) => {
+                let fraction = format!(
+                    "<mfrac><mn>{}</mn><mn>{}</mn></mfrac>",
+                    numerator.magnitude(),
+                    denominator
+                );
+                match numerator.sign() {
+                    Sign::Minus => format!("<mrow><mo>-</mo>{}</mrow>", fraction),
+                    _ => fraction,
+                }
+            }
+            Expr::Num(num) => {
+                let (mantissa, exponent) = num.readable_parts();
+                let number = match mantissa.strip_prefix('-') {
+                    Some(digits) => format!("<mo>-</mo><mn>{}</mn>", digits),
+                    None => format!("<mn>{}</mn>", mantissa),
+                };
+                match exponent {
+                    Some(exponent) => format!(
+                        "<mrow>{}<mo>&#xD7;</mo><msup><mn>10</mn><mn>{}</mn></msup></mrow>",
+                        number, exponent
+                    ),
+                    None if num.is_negative() => format!("<mrow>{}</mrow>", number),
+                    None => number,
+                }
+            }
+            Expr::Symbol(name) => {
+                let name = GREEK_LETTERS
+                    .iter()
+                    .find(|(greek, _)| greek == name)
+                    .map_or(name.as_str(), |(_, letter)| letter);
+                format!("<mi>{}</mi>", escape(name))
+            }
+            Expr::Apply(name, args) if name == "-" && args.len() == 1 => {
+                let parens = args[0].stacked_precedence() < self.stacked_precedence();
+                format!(
+                    "<mrow><mo>-</mo>{}</mrow>",
+                    mathml_parens(args[0].presentation(), parens)
+                )
+            }
+            Expr::Apply(name, args) if name == "/" && args.len() == 2 => format!(
+                "<mfrac>{}{}</mfrac>",
+                args[0].presentation(),
+                args[1].presentation()
+            ),
+            Expr::Apply(name, args) if name == "^" && args.len() == 2 => {
+                let parens = args[0].stacked_precedence() <= self.stacked_precedence();
+                format!(
+                    "<msup>{}{}</msup>",
+                    mathml_parens(args[0].presentation(), parens),
+                    args[1].presentation()
+                )
+            }
+            Expr::Apply(name, args) if self.precedence() < 5 => {
+                let (lhs_parens, rhs_parens) = operand_parens(
+                    name,
+                    self.stacked_precedence(),
+                    (args[0].stacked_precedence(), &args[0].to_string()),
+                    (args[1].stacked_precedence(), &args[1].to_string()),
+                );
+                let operator = if name == "*" { "&#x22C5;" } else { name };
+                format!(
+                    "<mrow>{}<mo>{}</mo>{}</mrow>",
+                    mathml_parens(args[0].presentation(), lhs_parens),
+                    operator,
+                    mathml_parens(args[1].presentation(), rhs_parens)
+                )
+            }
+            Expr::Apply(name, args) if name == "sqrt" && args.len() == 1 => {
+                format!("<msqrt>{}</msqrt>", args[0].presentation())
+            }
+            Expr::Apply(name, args) if name == "abs" && args.len() == 1 => format!(
+                "<mrow><mo>|</mo>{}<mo>|</mo></mrow>",
+                args[0].presentation()
+            ),
+            Expr::Apply(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.presentation())
+                    .collect::<Vec<_>>()
+                    .join("<mo>,</mo>");
+                // &#x2061; is the invisible function application operator
+                format!(
+                    "<mrow><mi>{}</mi><mo>&#x2061;</mo>{}</mrow>",
+                    escape(name),
+                    mathml_parens(args, true)
+                )
+            }
+            Expr::Matrix(rows) => {
+                let rows = rows
+                    .iter()
+                    .map(|row| {
+                        let cells = row
+                            .iter()
+                            .map(|e| format!("<mtd>{}</mtd>", e.presentation()))
+                            .collect::<String>();
+                        format!("<mtr>{}</mtr>", cells)
+                    })
+                    .collect::<String>();
+                mathml_parens(format!("<mtable>{}</mtable>", rows), true)
+            }
+        }
+    }
+
+    fn content(&self) -> String {
+        match self {
+            Expr::Num(Number::Int(int)) => format!("<cn type=\"integer\">{}</cn>", int),
+            // Written the way the Mod command builds one, as content MathML has no modular numbers
+            Expr::Num(Number::Mod(value, modulus)) => {
+                let args = vec![
+                    Expr::Num(Number::Int(value.clone())),
+                    Expr::Num(Number::Int(modulus.clone())),
+                ];
+                Expr::Apply("Mod".to_string(), args).content()
+            }
+            Expr::Num(Number::Rational(numerator, denominator)) => format!(
+                "<cn type=\"rational\">{}<sep/>{}</cn>",
+                numerator, denominator
+            ),
+            Expr::Num(Number::Float(float)) if float.is_nan() => "<notanumber/>".to_string(),
+            Expr::Num(Number::Float(float)) if float.is_inf_pos() => "<infinity/>".to_string(),
+            Expr::Num(Number::Float(float)) if float.is_inf_neg() => {
+                "<apply><minus/><infinity/></apply>".to_string()
+            }
+            Expr::Num(Number::Float(float)) => match float.to_string().split_once('e') {
+                Some((mantissa, exponent)) => format!(
+                    "<cn type=\"e-notation\">{}<sep/>{}</cn>",
+                    mantissa,
+                    exponent.trim_start_matches('+')
+                ),
+                None => format!("<cn type=\"real\">{}</cn>", float),
+            },
+            Expr::Symbol(name) if name == "pi" => "<pi/>".to_string(),
+            Expr::Symbol(name) => format!("<ci>{}</ci>", escape(name)),
+            Expr::Apply(name, args) => {
+                let head = match SYMBOLS.iter().find(|(functor, ..)| functor == name) {
+                    Some((_, element, ..)) => format!("<{}/>", element),
+                    None if name == "sqrt" => "<root/>".to_string(),
+                    None => format!("<csymbol>{}</csymbol>", escape(name)),
+                };
+                let args = args.iter().map(|arg| arg.content()).collect::<String>();
+                format!("<apply>{}{}</apply>", head, args)
+            }
+            Expr::Matrix(rows) => {
+                let rows = rows
+                    .iter()
+                    .map(|row| {
+                        let cells = row.iter().map(|e| e.content()).collect::<String>();
+                        format!("<matrixrow>{}</matrixrow>", cells)
+                    })
+                    .collect::<String>();
+                format!("<matrix>{}</matrix>", rows)
+            }
+        }
+    }
+}
+
+fn mathml_parens(inner: String, parens: bool) -> String {
+    match parens {
+        true => format!("<mrow><mo>(</mo>{}<mo>)</mo></mrow>", inner),
+        false => inner,
+    }
+}
+
+/// The function a name refers to, if it is one of the default functions
+fn known_function(name: &str, args: Vec<Expr>) -> Result<Expr> {
+    match find_default_function(name) {
+        Some(_) => Ok(Expr::apply(name, args)),
+        None => bail!("No function is called {}", name),
+    }
+}
+
+/// Applies a binary functor to any number of arguments, e.g. plus with three arguments
+fn fold(functor: &str, args: Vec<Expr>) -> Result<Expr> {
+    let mut args = args.into_iter();
+    let first = args
+        .next()
+        .ok_or_else(|| anyhow!("{} needs at least one argument", functor))?;
+    Ok(args.fold(first, |lhs, rhs| Expr::apply(functor, vec![lhs, rhs])))
+}
+
+/// x^(1/n), or sqrt(x) if n is 2
+fn root(radicand: Expr, degree: Expr) -> Expr {
+    if degree == Expr::Num(Number::Int(BigInt::from(2))) {
+        return Expr::apply("sqrt", vec![radicand]);
+    }
+    let one = Expr::Num(Number::Int(BigInt::from(1)));
+    Expr::apply("^", vec![radicand, Expr::apply("/", vec![one, degree])])
+}
+
+fn parse_bigint(text: &str) -> Result<BigInt> {
+    BigInt::from_str(text.trim()).map_err(|e| anyhow!("Bad integer {}: {}", text.trim(), e))
+}
+
+fn parse_int(text: &str) -> Result<Number> {
+    parse_bigint(text).map(Number::Int)
+}
+
+fn parse_float(text: &str) -> Result<Number> {
+    BigFloat::from_str(text.trim())
+        .map(Number::Float)
+        .map_err(|e| anyhow!("Bad number {}: {:?}", text.trim(), e))
+}
+
+fn from_content(element: &Element) -> Result<Expr> {
+    Ok(match element.name.as_str() {
+        "semantics" => from_content(
+            element
+                .elements()
+                .next()
+                .ok_or_else(|| anyhow!("Empty <semantics>"))?,
+        )?,
+        "cn" => {
+            let parts = element.text_parts();
+            match (element.attribute("type"), parts.as_slice()) {
+                (Some("integer"), [int]) => Expr::Num(parse_int(int)?),
+                (Some("e-notation"), [mantissa, exponent]) => Expr::Num(parse_float(&format!(
+                    "{}e{}",
+                    mantissa.trim(),
+                    exponent.trim()
+                ))?),
+                (Some("rational"), [numerator, denominator]) => Expr::Num(Number::rational(
+                    parse_bigint(numerator)?,
+                    parse_bigint(denominator)?,
+                )?),
+                (None | Some("real") | Some("double"), [number]) => {
+                    Expr::Num(parse_int(number).or_else(|_| parse_float(number))?)
+                }
+                (kind, _) => bail!(
+                    "Unsupported <cn> of type {}: {}",
+                    kind.unwrap_or("real"),
+                    parts.join(" ")
+                ),
+            }
+        }
+        "ci" => Expr::Symbol(element.text()),
+        "csymbol" => Expr::Symbol(element.text()),
+        "pi" => Expr::Symbol("pi".to_string()),
+        "exponentiale" => Expr::Symbol("e".to_string()),
+        "infinity" => Expr::Num(Number::Float(num_bigfloat::INF_POS)),
+        "notanumber" => Expr::Num(Number::Float(num_bigfloat::NAN)),
+        "matrix" => Expr::Matrix(
+            element
+                .elements()
+                .map(|row| row.elements().map(from_content).collect())
+                .collect::<Result<_>>()?,
+        ),
+        "apply" => {
+            let mut children = element.elements();
+            let head = children.next().ok_or_else(|| anyhow!("Empty <apply>"))?;
+
+            let mut degree = None;
+            let mut args: Vec<Expr> = vec![];
+            for child in children {
+                match child.name.as_str() {
+                    "degree" => degree = Some(from_content(only_child(child)?)?),
+                    _ => args.push(from_content(child)?),
+                }
+            }
+
+            match (head.name.as_str(), args.len()) {
+                ("minus", 1) => Expr::apply("-", args),
+                ("plus", _) => fold("+", args)?,
+                ("times", _) => fold("*", args)?,
+                ("root", 1) => {
+                    let degree = degree.unwrap_or(Expr::Num(Number::Int(BigInt::from(2))));
+                    root(args.remove(0), degree)
+                }
+                ("csymbol" | "ci", _) => known_function(&head.text(), args)?,
+                (name, _) => match SYMBOLS.iter().find(|(_, element, ..)| *element == name) {
+                    Some((functor, ..)) => Expr::apply(functor, args),
+                    None => bail!("Unsupported Content MathML operator <{}>", name),
+                },
+            }
+        }
+        name => bail!("Unsupported Content MathML element <{}>", name),
+    })
+}
+
+fn from_openmath(element: &Element) -> Result<Expr> {
+    let attribute = |name: &str| {
+        element
+            .attribute(name)
+            .ok_or_else(|| anyhow!("<{}> has no {} attribute", element.name, name))
+    };
+
+    Ok(match element.name.as_str() {
+        "OMI" => Expr::Num(parse_int(&element.text())?),
+        "OMF" => match element.attribute("dec") {
+            Some(dec) => Expr::Num(parse_float(dec)?),
+            None => {
+                let hex = attribute("hex")?;
+                let bits = u64::from_str_radix(hex, 16)
+                    .map_err(|e| anyhow!("Bad hex float {}: {}", hex, e))?;
+                Expr::Num(Number::Float(BigFloat::from_f64(f64::from_bits(bits))))
+            }
+        },
+        "OMV" => Expr::Symbol(attribute("name")?.to_string()),
+        "OMS" => match (attribute("cd")?, attribute("name")?) {
+            ("nums1", "pi") => Expr::Symbol("pi".to_string()),
+            ("nums1", "e") => Expr::Symbol("e".to_string()),
+            ("nums1", "infinity") => Expr::Num(Number::Float(num_bigfloat::INF_POS)),
+            ("nums1", "NaN") => Expr::Num(Number::Float(num_bigfloat::NAN)),
+            (cd, name) => bail!("Unsupported OpenMath constant {}:{}", cd, name),
+        },
+        "OMA" => {
+            let mut children = element.elements();
+            let head = children.next().ok_or_else(|| anyhow!("Empty <OMA>"))?;
+            let children = children.collect::<Vec<_>>();
+
+            if head.name == "OMV" {
+                let args = children
+                    .into_iter()
+                    .map(from_openmath)
+                    .collect::<Result<_>>()?;
+                return known_function(head.attribute("name").unwrap_or_default(), args);
+            }
+            if head.name != "OMS" {
+                bail!(
+                    "Expected <OMS> or <OMV> at the start of <OMA>, found <{}>",
+                    head.name
+                )
+            }
+
+            let cd = head.attribute("cd").unwrap_or_default();
+            let name = head.attribute("name").unwrap_or_default();
+            if (cd, name) == ("linalg2", "matrix") {
+                let rows = children
+                    .into_iter()
+                    .map(|row| {
+                        // Each row is itself an application of linalg2 matrixrow
+                        row.elements().skip(1).map(from_openmath).collect()
+                    })
+                    .collect::<Result<_>>()?;
+                return Ok(Expr::Matrix(rows));
+            }
+
+            let mut args = children
+                .into_iter()
+                .map(from_openmath)
+                .collect::<Result<Vec<_>>>()?;
+            match (cd, name, args.len()) {
+                ("arith1", "unary_minus", 1) => Expr::apply("-", args),
+                ("arith1", "plus", _) => fold("+", args)?,
+                ("arith1", "times", _) => fold("*", args)?,
+                ("arith1", "root", 2) => {
+                    let degree = args.pop().unwrap();
+                    root(args.pop().unwrap(), degree)
+                }
+                ("nums1", "rational", 2) => Expr::apply("/", args),
+                (cd, name, _) => match SYMBOLS.iter().find(|(_, _, d, n)| (*d, *n) == (cd, name)) {
+                    Some((functor, ..)) => Expr::apply(functor, args),
+                    None => known_function(name, args)
+                        .map_err(|_| anyhow!("Unsupported OpenMath symbol {}:{}", cd, name))?,
+                },
+            }
+        }
+        name => bail!("Unsupported OpenMath element <{}>", name),
+    })
+}
+
+/// The single element inside another, ignoring whitespace
+fn only_child(element: &Element) -> Result<&Element> {
+    let mut children = element.elements();
+    match (children.next(), children.next()) {
+        (Some(child), None) => Ok(child),
+        _ => bail!("Expected <{}> to hold exactly one element", element.name),
+    }
+}
+
+/// Escapes text for use inside an XML element
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// An XML element, with the namespace prefix taken off its name
+#[derive(Debug, Clone, PartialEq)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+impl Element {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    /// All the text directly inside the element, trimmed
+    fn text(&self) -> String {
+        self.text_parts().join("").trim().to_string()
+    }
+
+    /// The text directly inside the element, split wherever there is a child element, e.g. at
+    /// the <sep/> of a rational <cn>
+    fn text_parts(&self) -> Vec<String> {
+        let mut parts = vec![String::new()];
+        for node in &self.children {
+            match node {
+                Node::Text(text) => parts.last_mut().unwrap().push_str(text),
+                Node::Element(_) => parts.push(String::new()),
+            }
+        }
+        parts
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+/// Just enough of an XML parser for MathML and OpenMath: elements, attributes, text, entities
+/// and CDATA. Declarations, processing instructions and comments are skipped.
+fn parse_xml(input: &str) -> Result<Element> {
+    let mut parser = XmlParser { input, pos: 0 };
+    parser.skip_misc()?;
+    let root = parser.element(1)?;
+    parser.skip_misc()?;
+    if parser.pos != input.len() {
+        bail!(
+            "Unexpected content after the root element at byte {}",
+            parser.pos
+        )
+    }
+    Ok(root)
+}
+
+struct XmlParser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+impl<'a> XmlParser<'a> {
+    fn rest(&self) -> &str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Moves past the next occurrence of `end`
+    fn skip_past(&mut self, end: &str) -> Result<&'a str> {
+        let input = self.input;
+        let Some(idx) = self.rest().find(end) else {
+            bail!("Expected {} after byte {}", end, self.pos)
+        };
+        let skipped = &input[self.pos..self.pos + idx];
+        self.pos += idx + end.len();
+        Ok(skipped)
+    }
+
+    /// Skips whitespace, comments, processing instructions and declarations such as DOCTYPE
+    fn skip_misc(&mut self) -> Result<()> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with("<!") {
+                self.skip_past(">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let len = self
+            .rest()
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '='))
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            bail!("Expected a name at byte {}", self.pos)
+        }
+        let name = self.rest()[..len].to_string();
+        self.pos += len;
+        Ok(name)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<()> {
+        if !self.rest().starts_with(text) {
+            bail!("Expected {} at byte {}", text, self.pos)
+        }
+        self.pos += text.len();
+        Ok(())
+    }
+
+    /// Reads an element and everything inside it, `depth` being how many elements deep it is
+    fn element(&mut self, depth: usize) -> Result<Element> {
+        if depth > MAX_DEPTH {
+            bail!(
+                "Elements are nested more than {} deep at byte {}",
+                MAX_DEPTH,
+                self.pos
+            )
+        }
+        self.expect("<")?;
+        let name = self.name()?;
+        let local = |name: &str| name.rsplit(':').next().unwrap_or(name).to_string();
+
+        let mut element = Element {
+            name: local(&name),
+            attributes: vec![],
+            children: vec![],
+        };
+
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                return Ok(element);
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+
+            let key = self.name()?;
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let quote = match self.rest().chars().next() {
+                Some(quote @ ('"' | '\'')) => quote,
+                _ => bail!("Expected a quoted value for {} at byte {}", key, self.pos),
+            };
+            self.pos += 1;
+            let value = unescape(self.skip_past(&quote.to_string())?)?;
+            element.attributes.push((local(&key), value));
+        }
+
+        loop {
+            if self.rest().starts_with("</") {
+                self.pos += 2;
+                let closing = self.name()?;
+                if closing != name {
+                    bail!("<{}> is closed by </{}>", name, closing)
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(element);
+            } else if self.rest().starts_with("<![CDATA[") {
+                self.pos += "<![CDATA[".len();
+                let text = self.skip_past("]]>")?.to_string();
+                element.children.push(Node::Text(text));
+            } else if self.rest().starts_with("<!--") || self.rest().starts_with("<?") {
+                self.skip_misc()?;
+            } else if self.rest().starts_with('<') {
+                let child = self.element(depth + 1)?;
+                element.children.push(Node::Element(child));
+            } else if self.rest().is_empty() {
+                bail!("<{}> is never closed", name)
+            } else {
+                let len = self.rest().find('<').unwrap_or(self.rest().len());
+                let text = unescape(&self.rest()[..len])?;
+                self.pos += len;
+                element.children.push(Node::Text(text));
+            }
+        }
+    }
+}
+
+/// Replaces entity and character references with the characters they stand for
+fn unescape(text: &str) -> Result<String> {
+    let mut unescaped = String::new();
+    let mut rest = text;
+
+    while let Some(idx) = rest.find('&') {
+        unescaped.push_str(&rest[..idx]);
+        let Some(end) = rest[idx..].find(';') else {
+            bail!("Unterminated entity in {}", text)
+        };
+        let entity = &rest[idx + 1..idx + end];
+
+        let c = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = match entity.strip_prefix("#x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+                };
+                code.and_then(char::from_u32)
+                    .ok_or_else(|| anyhow!("Unknown entity &{};", entity))?
+            }
+        };
+        unescaped.push(c);
+        rest = &rest[idx + end + 1..];
+    }
+
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::{Context, get_default_functions};
+
+    fn parse(input: &str) -> Expr {
+        let context = Context {
+            functions: get_default_functions(),
+            ..Context::new()
+        };
+        Expr::parse(input, &context).unwrap()
+    }
+
+    #[test]
+    fn content_mathml_round_trips() {
+        let inputs = [
+            "x + 2 * y",
+            "-(a - b) ^ 2",
+            "sin(x) / cos(x)",
+            "sqrt(x + 1)",
+            "abs(-3) + exp(ln(pi))",
+            "1.5e-7 * x",
+        ];
+        let third = Expr::Num(Number::rational(1.into(), (-3).into()).unwrap());
+        for expr in inputs.into_iter().map(parse).chain([third]) {
+            let xml = expr.to_content_mathml();
+            assert_eq!(Expr::from_content_mathml(&xml).unwrap(), expr, "{}", xml);
+        }
+
+        let matrix = Expr::Matrix(vec![
+            vec![parse("1"), parse("x")],
+            vec![parse("x ^ 2"), parse("0")],
+        ]);
+        let xml = matrix.to_content_mathml();
+        assert_eq!(Expr::from_content_mathml(&xml).unwrap(), matrix);
+    }
+
+    #[test]
+    fn reads_openmath() {
+        let xml = r#"<OMOBJ xmlns="http://www.openmath.org/OpenMath">
+            <OMA>
+                <OMS cd="arith1" name="plus"/>
+                <OMA><OMS cd="transc1" name="sin"/><OMV name="x"/></OMA>
+                <OMI>2</OMI>
+                <OMS cd="nums1" name="pi"/>
+            </OMA>
+        </OMOBJ>"#;
+        assert_eq!(Expr::from_openmath(xml).unwrap(), parse("sin(x) + 2 + pi"));
+
+        let unknown = r#"<OMA><OMS cd="foo1" name="bar"/><OMI>1</OMI></OMA>"#;
+        assert!(Expr::from_openmath(unknown).is_err());
+    }
+
+    #[test]
+    fn refuses_deeply_nested_documents() {
+        let depth = 100_000;
+        let mathml = format!(
+            "{}<ci>x</ci>{}",
+            "<apply><minus/>".repeat(depth),
+            "</apply>".repeat(depth)
+        );
+        assert!(Expr::from_content_mathml(&mathml).is_err());
+
+        let openmath = format!(
+            "{}<OMV name=\"x\"/>{}",
+            "<OMA><OMS cd=\"arith1\" name=\"unary_minus\"/>".repeat(depth),
+            "</OMA>".repeat(depth)
+        );
+        assert!(Expr::from_openmath(&openmath).is_err());
+
+        // Anything within the limit is still read
+        let shallow = format!(
+            "{}<ci>x</ci>{}",
+            "<apply><minus/>".repeat(100),
+            "</apply>".repeat(100)
+        );
+        assert!(Expr::from_content_mathml(&shallow).is_ok());
+    }
+}
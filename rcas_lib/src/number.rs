@@ -1,7 +1,11 @@
+use crate::limits::check_int_size;
+use crate::number_format::{DEFAULT_DIGITS, NumberFormat};
+use crate::number_theory::{gcd, log2, modinv, modpow, modulo};
+use anyhow::{Result, anyhow, bail};
 use num_bigfloat::BigFloat;
-use num_bigint::BigInt;
-use std::fmt::Display;
-use std::ops::{Add, Mul, Sub};
+use num_bigint::{BigInt, Sign};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, LowerExp};
 use std::str::FromStr;
 use std::string::ToString;
 
@@ -9,25 +13,28 @@ fn int_to_float(input: BigInt) -> BigFloat {
     BigFloat::from_str(&input.to_string()).expect("HUH TFFFF")
 }
 
+/// Arithmetic is only offered through the checked methods, as there are operands it is not
+/// defined for (see `Number::checked_add`), which an operator could only answer with NaN
 macro_rules! impl_arith_op {
-    ($trait:ident, $method:ident, $op:tt) => {
-        impl $trait for Number {
-            type Output = Number;
-
-            fn $method(self, rhs: Self) -> Self::Output {
-                let float_self = match self {
-                    Number::Int(i) => int_to_float(i),
-                    Number::Float(t) => t
-                };
-
-                let float_rhs = match rhs {
-                    Number::Int(i) => int_to_float(i),
-                    Number::Float(t) => t
-                };
-
-                let res = float_self $op float_rhs;
-                
-                match res.to_
+    ($method:ident, $op:tt, $fraction:expr) => {
+        impl Number {
+            fn $method(self, rhs: Self) -> Result<Number> {
+                // Two integers or fractions stay exact and a modular number keeps the result
+                // modular, but anything with a float is promoted to a float
+                Ok(match (self, rhs) {
+                    (Number::Int(lhs), Number::Int(rhs)) => Number::Int(lhs $op rhs),
+                    (lhs @ Number::Float(_), rhs) | (lhs, rhs @ Number::Float(_)) => {
+                        Number::Float(lhs.to_float() $op rhs.to_float())
+                    }
+                    (lhs @ Number::Mod(..), rhs) | (lhs, rhs @ Number::Mod(..)) => {
+                        let (lhs, rhs, modulus) = lhs.modular_operands(rhs)?;
+                        Number::Mod(modulo(&(lhs $op rhs), &modulus), modulus)
+                    }
+                    (lhs, rhs) => {
+                        let (numerator, denominator) = $fraction(lhs.fraction(), rhs.fraction());
+                        Number::reduced(numerator, denominator)
+                    }
+                })
             }
         }
     };
@@ -35,22 +42,691 @@ macro_rules! impl_arith_op {
 
 /// An abstraction over integer or float - will try and keep to integers as much as possible but
 /// will cast to float if it is needed
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "NumberRepr", try_from = "NumberRepr")]
 pub enum Number {
     Int(BigInt),
     Float(BigFloat),
+    /// An integer modulo a positive modulus, as (value, modulus), with the value always reduced
+    /// into 0..modulus. Arithmetic on it stays modular, see `Number::modular`.
+    Mod(BigInt, BigInt),
+    /// An exact fraction, as (numerator, denominator), always in lowest terms with a denominator
+    /// above 1, see `Number::rational`
+    Rational(BigInt, BigInt),
+}
+
+/// How a Number is serialized: as text, so no digits are lost whatever reads it
+#[derive(Serialize, Deserialize)]
+enum NumberRepr {
+    Int(String),
+    Float(String),
+    Mod(String, String),
+    Rational(String, String),
+}
+impl From<Number> for NumberRepr {
+    fn from(num: Number) -> Self {
+        match num {
+            Number::Int(int) => NumberRepr::Int(int.to_string()),
+            Number::Float(float) => NumberRepr::Float(float.to_string()),
+            Number::Mod(value, modulus) => NumberRepr::Mod(value.to_string(), modulus.to_string()),
+            Number::Rational(numerator, denominator) => {
+                NumberRepr::Rational(numerator.to_string(), denominator.to_string())
+            }
+        }
+    }
+}
+impl TryFrom<NumberRepr> for Number {
+    type Error = String;
+
+    fn try_from(repr: NumberRepr) -> Result<Self, Self::Error> {
+        let parse =
+            |int: &str| BigInt::from_str(int).map_err(|e| format!("Bad integer {}: {}", int, e));
+        match repr {
+            NumberRepr::Int(int) => parse(&int).map(Number::Int),
+            NumberRepr::Float(float) => BigFloat::from_str(&float)
+                .map(Number::Float)
+                .map_err(|e| format!("Bad float {}: {:?}", float, e)),
+            NumberRepr::Mod(value, modulus) => {
+                Number::modular(parse(&value)?, parse(&modulus)?).map_err(|e| e.to_string())
+            }
+            NumberRepr::Rational(numerator, denominator) => {
+                Number::rational(parse(&numerator)?, parse(&denominator)?)
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+impl Number {
+    /// An integer modulo a positive modulus, e.g. 3 mod 7. Values outside 0..modulus are reduced
+    /// into it, so -1 mod 7 is 6 mod 7.
+    pub fn modular(value: BigInt, modulus: BigInt) -> Result<Number> {
+        if modulus.sign() != Sign::Plus {
+            bail!("The modulus must be positive, not {}", modulus)
+        }
+        Ok(Number::Mod(modulo(&value, &modulus), modulus))
+    }
+
+    /// The exact fraction numerator / denominator, e.g. 6 / -8 is -3/4. It is an integer if the
+    /// denominator divides the numerator.
+    pub fn rational(numerator: BigInt, denominator: BigInt) -> Result<Number> {
+        if denominator.sign() == Sign::NoSign {
+            bail!("The denominator of {}/{} is zero", numerator, denominator)
+        }
+        Ok(Number::reduced(numerator, denominator))
+    }
+
+    /// Puts a fraction with a nonzero denominator into lowest terms
+    fn reduced(numerator: BigInt, denominator: BigInt) -> Number {
+        let divisor = gcd(&numerator, &denominator);
+        let divisor = if denominator.sign() == Sign::Minus {
+            -divisor
+        } else {
+            divisor
+        };
+        let (numerator, denominator) = (numerator / &divisor, denominator / divisor);
+
+        if denominator == BigInt::from(1) {
+            Number::Int(numerator)
+        } else {
+            Number::Rational(numerator, denominator)
+        }
+    }
+
+    /// An integer or fraction as (numerator, denominator)
+    fn fraction(self) -> (BigInt, BigInt) {
+        match self {
+            Number::Int(int) => (int, BigInt::from(1)),
+            Number::Rational(numerator, denominator) => (numerator, denominator),
+            _ => unreachable!("only integers and fractions are fractions"),
+        }
+    }
+
+    /// Casts this number to a float, regardless of what it is currently stored as. A modular
+    /// number becomes its value, without the modulus.
+    pub fn to_float(self) -> BigFloat {
+        match self {
+            Number::Int(int) | Number::Mod(int, _) => int_to_float(int),
+            Number::Float(float) => float,
+            Number::Rational(numerator, denominator) => {
+                int_to_float(numerator) / int_to_float(denominator)
+            }
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(int) | Number::Mod(int, _) => int.sign() == Sign::NoSign,
+            Number::Float(float) => float.is_zero(),
+            Number::Rational(..) => false,
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        match self {
+            Number::Int(int) | Number::Rational(int, _) => int.sign() == Sign::Minus,
+            Number::Float(float) => float.is_negative(),
+            Number::Mod(..) => false,
+        }
+    }
+
+    /// Bit length of the largest integer the number is made of, or 0 for a float
+    pub fn int_bits(&self) -> u64 {
+        match self {
+            Number::Int(int) => int.bits(),
+            Number::Float(_) => 0,
+            Number::Mod(_, modulus) => modulus.bits(),
+            Number::Rational(numerator, denominator) => numerator.bits().max(denominator.bits()),
+        }
+    }
+
+    pub fn abs(self) -> Number {
+        match self {
+            Number::Int(int) if int.sign() == Sign::Minus => Number::Int(-int),
+            Number::Rational(numerator, denominator) if numerator.sign() == Sign::Minus => {
+                Number::Rational(-numerator, denominator)
+            }
+            Number::Float(float) => Number::Float(float.abs()),
+            other => other,
+        }
+    }
+
+    /// Raises this number to a power. An integer raised to a non-negative integer power stays
+    /// exact, as does a fraction raised to any integer power, and a modular number raised to an
+    /// integer stays modular, using the inverse for a negative power. Anything else is computed as
+    /// a float. Fails before computing an exact power which would be longer than `max_int_bits`.
+    pub fn pow(self, exponent: Number, max_int_bits: Option<u64>) -> Result<Number> {
+        let exact_bits = match &self {
+            Number::Int(int) => log2(int),
+            Number::Rational(numerator, denominator) => log2(numerator).max(log2(denominator)),
+            _ => 0.0,
+        };
+        // A negative power of an integer is a float, but a fraction's stays exact
+        if let Number::Int(exponent) = &exponent
+            && (exponent.sign() != Sign::Minus || matches!(self, Number::Rational(..)))
+        {
+            check_int_size(max_int_bits, exact_bits * log2(exponent).exp2())?;
+        }
+
+        Ok(match (self, exponent) {
+            (Number::Int(base), Number::Int(exponent)) if exponent.sign() != Sign::Minus => {
+                match u32::try_from(&exponent) {
+                    Ok(exponent) => Number::Int(base.pow(exponent)),
+                    Err(_) => Number::Float(int_to_float(base).pow(&int_to_float(exponent))),
+                }
+            }
+            (Number::Mod(base, modulus), Number::Int(exponent)) => {
+                Number::Mod(modpow(&base, &exponent, &modulus)?, modulus)
+            }
+            (Number::Rational(numerator, denominator), Number::Int(exponent))
+                if u32::try_from(exponent.magnitude()).is_ok() =>
+            {
+                let power = u32::try_from(exponent.magnitude()).unwrap();
+                let (numerator, denominator) = (numerator.pow(power), denominator.pow(power));
+                match exponent.sign() {
+                    Sign::Minus => Number::reduced(denominator, numerator),
+                    _ => Number::Rational(numerator, denominator),
+                }
+            }
+            (_, Number::Mod(..)) => bail!("An exponent cannot be a modular number"),
+            (base, exponent) => Number::Float(base.to_float().pow(&exponent.to_float())),
+        })
+    }
+
+    /// Adds, failing for modular numbers with different moduli, or a fraction whose denominator
+    /// has no inverse modulo the other's modulus
+    pub fn checked_add(self, rhs: Number) -> Result<Number> {
+        self.plus(rhs)
+    }
+
+    /// Subtracts, failing where adding would, see `checked_add`
+    pub fn checked_sub(self, rhs: Number) -> Result<Number> {
+        self.minus(rhs)
+    }
+
+    /// Multiplies, failing where adding would (see `checked_add`), and before computing a
+    /// product of integers which would be longer than `max_int_bits`
+    pub fn checked_mul(self, rhs: Number, max_int_bits: Option<u64>) -> Result<Number> {
+        // Only integers are sure to grow, a product of fractions may well cancel down
+        if let (Number::Int(lhs), Number::Int(rhs)) = (&self, &rhs) {
+            check_int_size(max_int_bits, log2(lhs) + log2(rhs))?;
+        }
+        self.times(rhs)
+    }
+
+    /// Divides, failing on division by zero, and for modular numbers with different moduli or
+    /// without an inverse. Integers stay exact when they divide evenly, and fractions always do.
+    /// Modular numbers are multiplied by the inverse of the divisor. Anything else is divided as
+    /// floats.
+    pub fn checked_div(self, rhs: Number) -> Result<Number> {
+        if rhs.is_zero() {
+            bail!("Division by zero")
+        }
+
+        Ok(match (self, rhs) {
+            (lhs @ Number::Float(_), rhs) | (lhs, rhs @ Number::Float(_)) => {
+                Number::Float(lhs.to_float() / rhs.to_float())
+            }
+            (lhs @ Number::Mod(..), rhs) | (lhs, rhs @ Number::Mod(..)) => lhs.modular_div(rhs)?,
+            (Number::Int(lhs), Number::Int(rhs)) if (&lhs % &rhs).sign() == Sign::NoSign => {
+                Number::Int(lhs / rhs)
+            }
+            (lhs @ Number::Rational(..), rhs) | (lhs, rhs @ Number::Rational(..)) => {
+                let ((a, b), (c, d)) = (lhs.fraction(), rhs.fraction());
+                Number::reduced(a * d, b * c)
+            }
+            (lhs, rhs) => Number::Float(lhs.to_float() / rhs.to_float()),
+        })
+    }
+
+    /// Divides by multiplying with the inverse of the divisor, where at least one is modular and
+    /// neither is a float
+    fn modular_div(self, rhs: Number) -> Result<Number> {
+        let (lhs, rhs, modulus) = self.modular_operands(rhs)?;
+        let inverse = modinv(&rhs, &modulus)?;
+        Ok(Number::Mod(modulo(&(lhs * inverse), &modulus), modulus))
+    }
+
+    /// The values of two numbers modulo the modulus they share, as (lhs, rhs, modulus), where at
+    /// least one is modular and neither is a float. Fails if both are modular with different
+    /// moduli, as neither value is known modulo the other's modulus, or if one is a fraction
+    /// whose denominator has no inverse.
+    fn modular_operands(self, rhs: Number) -> Result<(BigInt, BigInt, BigInt)> {
+        let modulus = match (&self, &rhs) {
+            (Number::Mod(_, lhs_modulus), Number::Mod(_, rhs_modulus))
+                if lhs_modulus != rhs_modulus =>
+            {
+                bail!("{} and {} have different moduli", self, rhs)
+            }
+            (Number::Mod(_, modulus), _) | (_, Number::Mod(_, modulus)) => modulus.clone(),
+            _ => unreachable!("neither number is modular"),
+        };
+        let no_inverse = |num: &Number| anyhow!("{} has no inverse modulo {}", num, modulus);
+        let lhs = self
+            .clone()
+            .residue(&modulus)
+            .ok_or_else(|| no_inverse(&self))?;
+        let rhs = rhs
+            .clone()
+            .residue(&modulus)
+            .ok_or_else(|| no_inverse(&rhs))?;
+        Ok((lhs, rhs, modulus))
+    }
+
+    /// The value of an integer, fraction or modular number modulo a modulus. A fraction is its
+    /// numerator times the inverse of its denominator, so it has none if that has no inverse.
+    fn residue(self, modulus: &BigInt) -> Option<BigInt> {
+        match self {
+            Number::Int(int) | Number::Mod(int, _) => Some(int),
+            Number::Rational(numerator, denominator) => {
+                let inverse = modinv(&denominator, modulus).ok()?;
+                Some(modulo(&(numerator * inverse), modulus))
+            }
+            Number::Float(_) => unreachable!("floats have no residue"),
+        }
+    }
+
+    /// Applies a function of floats, e.g. BigFloat::sin, casting an integer to a float first
+    pub fn map_float(self, func: fn(&BigFloat) -> BigFloat) -> Number {
+        Number::Float(func(&self.to_float()))
+    }
+
+    /// The number as LaTeX, e.g. 1.5 \times 10^{-30}. Floats are written without trailing zeros
+    /// and only use scientific notation when they are very large or very small.
+    pub fn to_latex(&self) -> String {
+        match self {
+            Number::Mod(value, modulus) => return format!("{} \\pmod{{{}}}", value, modulus),
+            Number::Rational(numerator, denominator) => {
+                let sign = if self.is_negative() { "-" } else { "" };
+                return format!(
+                    "{}\\frac{{{}}}{{{}}}",
+                    sign,
+                    numerator.magnitude(),
+                    denominator
+                );
+            }
+            Number::Float(float) if float.is_nan() => return "\\mathrm{NaN}".to_string(),
+            Number::Float(float) if float.is_inf_pos() => return "\\infty".to_string(),
+            Number::Float(float) if float.is_inf_neg() => return "-\\infty".to_string(),
+            _ => {}
+        }
+
+        match self.readable_parts() {
+            (mantissa, None) => mantissa,
+            (mantissa, Some(exponent)) => match mantissa.strip_suffix('1') {
+                Some(sign @ ("" | "-")) => format!("{}10^{{{}}}", sign, exponent),
+                _ => format!("{} \\times 10^{{{}}}", mantissa, exponent),
+            },
+        }
+    }
+
+    /// The number written out for reading, without trailing zeros, along with the power of ten
+    /// to multiply it by if it is too large or too small to write out in full, e.g. ("-1.5",
+    /// Some(-30)). Integers are always written out in full, NaN and infinities as they display.
+    pub(crate) fn readable_parts(&self) -> (String, Option<i64>) {
+        let float = match self {
+            Number::Int(int) => return (int.to_string(), None),
+            Number::Mod(..) | Number::Rational(..) => return (self.to_string(), None),
+            Number::Float(float) if float.is_nan() || float.is_inf() => {
+                return (float.to_string(), None);
+            }
+            Number::Float(float) => float,
+        };
+
+        let (negative, digits, exponent) = float_digits(float);
+        let sign = if negative { "-" } else { "" };
+
+        if (-4..=15).contains(&exponent) {
+            (format!("{}{}", sign, place_point(&digits, exponent)), None)
+        } else {
+            (format!("{}{}", sign, place_point(&digits, 0)), Some(exponent))
+        }
+    }
+
+    /// Formats the number with at most `digits` digits after the decimal point of the mantissa,
+    /// e.g. 1.23456e+5 with 2 digits is 1.23e+5. Integers and fractions are exact and are always
+    /// shown in full.
+    pub fn to_string_with_precision(&self, digits: usize) -> String {
+        let float = match self {
+            Number::Int(_) | Number::Mod(..) | Number::Rational(..) => return self.to_string(),
+            Number::Float(float) if float.is_nan() || float.is_inf() || float.is_zero() => {
+                return float.to_string();
+            }
+            Number::Float(float) => float.to_string(),
+        };
+
+        let (mantissa, exponent) = match float.split_once('e') {
+            Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().unwrap_or(0)),
+            None => (float.as_str(), 0),
+        };
+        let (sign, mantissa) = match mantissa.strip_prefix('-') {
+            Some(mantissa) => ("-", mantissa),
+            None => ("", mantissa),
+        };
+
+        // Round the digits of the mantissa, carrying into a new leading digit if needed
+        let mut all_digits = mantissa
+            .chars()
+            .filter(|c| c.is_ascii_digit())
+            .map(|c| c as u8 - b'0')
+            .collect::<Vec<u8>>();
+        let keep = (digits + 1).min(all_digits.len());
+        let round_up = all_digits.get(keep).is_some_and(|d| *d >= 5);
+        all_digits.truncate(keep);
+
+        let mut exponent = exponent;
+        if round_up {
+            let mut idx = keep;
+            loop {
+                if idx == 0 {
+                    all_digits.insert(0, 1);
+                    all_digits.pop();
+                    exponent += 1;
+                    break;
+                }
+                idx -= 1;
+                if all_digits[idx] == 9 {
+                    all_digits[idx] = 0;
+                } else {
+                    all_digits[idx] += 1;
+                    break;
+                }
+            }
+        }
+
+        let mut out = format!("{}{}", sign, all_digits[0]);
+        if all_digits.len() > 1 {
+            out.push('.');
+            out.extend(all_digits[1..].iter().map(|d| (d + b'0') as char));
+        }
+        if exponent != 0 {
+            out.push_str(&format!("e{:+}", exponent));
+        }
+        out
+    }
+}
+
+/// Splits a float into its sign, its significant digits without trailing zeros, and the power of
+/// ten of the first digit, e.g. -0.0125 is (true, "125", -2)
+pub(crate) fn float_digits(float: &BigFloat) -> (bool, String, i64) {
+    if float.is_zero() {
+        return (false, "0".to_string(), 0);
+    }
+
+    let text = float.to_string();
+    let (mantissa, exponent) = match text.split_once('e') {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().unwrap_or(0)),
+        None => (text.as_str(), 0),
+    };
+    let (negative, mantissa) = match mantissa.strip_prefix('-') {
+        Some(mantissa) => (true, mantissa),
+        None => (false, mantissa),
+    };
+
+    let digits = mantissa
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .collect::<String>();
+    let digits = digits.trim_end_matches('0');
+    let digits = if digits.is_empty() { "0" } else { digits };
+
+    (negative, digits.to_string(), exponent)
+}
+
+/// Writes significant digits out in full, with the first digit at the given power of ten
+fn place_point(digits: &str, exponent: i64) -> String {
+    if exponent < 0 {
+        let zeros = "0".repeat((-exponent - 1) as usize);
+        return format!("0.{}{}", zeros, digits);
+    }
+
+    let int_len = exponent as usize + 1;
+    if digits.len() <= int_len {
+        format!("{}{}", digits, "0".repeat(int_len - digits.len()))
+    } else {
+        format!("{}.{}", &digits[..int_len], &digits[int_len..])
+    }
+}
+
+/// Reads a number literal: an integer, optionally in hex, binary or octal as 0xFF, 0b1010 or
+/// 0o17, a decimal like 1.5e-30, or an exact fraction like 3/4. Digits may be grouped with
+/// underscores, e.g. 1_000_000, and inf and nan are read as floats.
+impl FromStr for Number {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Number> {
+        if let Some((numerator, denominator)) = text.split_once('/') {
+            return match (Number::from_str(numerator)?, Number::from_str(denominator)?) {
+                (Number::Int(numerator), Number::Int(denominator)) => {
+                    Number::rational(numerator, denominator)
+                }
+                _ => bail!(
+                    "A fraction needs a whole numerator and denominator, not {}",
+                    text
+                ),
+            };
+        }
+
+        let (negative, unsigned) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        if matches!(unsigned.to_lowercase().as_str(), "inf" | "infinity" | "nan") {
+            return Ok(Number::Float(
+                BigFloat::from_str(text).map_err(|_| anyhow!("{} is not a number", text))?,
+            ));
+        }
+        if unsigned.starts_with('_') || unsigned.ends_with('_') || unsigned.contains("__") {
+            bail!("Underscores can only go between the digits of {}", text)
+        }
+        let unsigned = unsigned.replace('_', "");
+
+        let radix = match unsigned.get(..2).map(str::to_lowercase).as_deref() {
+            Some("0x") => Some(16),
+            Some("0o") => Some(8),
+            Some("0b") => Some(2),
+            _ => None,
+        };
+        if let Some(radix) = radix {
+            let digits = &unsigned[2..];
+            let int = BigInt::parse_bytes(digits.as_bytes(), radix)
+                .filter(|_| !digits.is_empty() && !digits.starts_with(['+', '-']))
+                .ok_or_else(|| anyhow!("{} is not a number in base {}", text, radix))?;
+            return Ok(Number::Int(if negative { -int } else { int }));
+        }
+
+        let signed = format!("{}{}", if negative { "-" } else { "" }, unsigned);
+        let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        if digits(&unsigned) {
+            return Ok(Number::Int(BigInt::from_str(&signed)?));
+        }
+
+        // BigFloat reads as much as it can and ignores the rest, so check the whole thing is a
+        // decimal first
+        let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+            None => (unsigned.as_str(), None),
+        };
+        let mantissa_ok = mantissa.bytes().any(|b| b.is_ascii_digit())
+            && mantissa.bytes().all(|b| b.is_ascii_digit() || b == b'.')
+            && mantissa.matches('.').count() <= 1;
+        let exponent_ok = exponent
+            .is_none_or(|exponent| digits(exponent.strip_prefix(['+', '-']).unwrap_or(exponent)));
+        if !mantissa_ok || !exponent_ok {
+            bail!("{} is not a number", text)
+        }
+
+        BigFloat::from_str(&signed)
+            .map(Number::Float)
+            .map_err(|_| anyhow!("{} is not a number", text))
+    }
 }
 
+/// A precision, as in {:.3}, writes the number with that many digits after the point, see
+/// `NumberFormat::Fixed`
 impl Display for Number {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(digits) = f.precision() {
+            return write!(f, "{}", self.format(NumberFormat::Fixed(digits)));
+        }
+
         match self {
-            Number::Int(int) => write!(f, "{}", int.to_string()),
-            Number::Float(float) => write!(f, "{}", float.to_string()),
+            Number::Int(int) => write!(f, "{}", int),
+            Number::Float(float) => write!(f, "{}", float),
+            Number::Mod(value, modulus) => write!(f, "Mod({}, {})", value, modulus),
+            Number::Rational(numerator, denominator) => write!(f, "{}/{}", numerator, denominator),
         }
     }
 }
 
-impl_arith_op!(Add, add, +);
-impl_arith_op!(Sub, sub, -);
-impl_arith_op!(Mul, mul, *);
+/// {:e} writes the number in scientific notation, with as many digits after the point as the
+/// precision asks for, see `NumberFormat::Scientific`
+impl LowerExp for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let digits = f.precision().unwrap_or(DEFAULT_DIGITS);
+        write!(f, "{}", self.format(NumberFormat::Scientific(digits)))
+    }
+}
+
+impl_arith_op!(plus, +, |(a, b): (BigInt, BigInt), (c, d): (BigInt, BigInt)| {
+    (a * &d + c * &b, b * d)
+});
+impl_arith_op!(minus, -, |(a, b): (BigInt, BigInt), (c, d): (BigInt, BigInt)| {
+    (a * &d - c * &b, b * d)
+});
+impl_arith_op!(times, *, |(a, b): (BigInt, BigInt), (c, d): (BigInt, BigInt)| {
+    (a * c, b * d)
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modular(value: i64, modulus: i64) -> Number {
+        Number::modular(value.into(), modulus.into()).unwrap()
+    }
 
+    fn fraction(numerator: i64, denominator: i64) -> Number {
+        Number::rational(numerator.into(), denominator.into()).unwrap()
+    }
+
+    #[test]
+    fn modular_arithmetic_stays_modular() {
+        let sum = modular(5, 7).checked_add(modular(4, 7)).unwrap();
+        assert_eq!(sum, modular(2, 7));
+        let difference = modular(2, 7).checked_sub(Number::Int(5.into())).unwrap();
+        assert_eq!(difference, modular(4, 7));
+        let product = modular(3, 7).checked_mul(modular(5, 7), None).unwrap();
+        assert_eq!(product, modular(1, 7));
+        let quotient = modular(3, 7).checked_div(modular(5, 7)).unwrap();
+        assert_eq!(quotient, modular(2, 7));
+        // A fraction is its numerator times the inverse of its denominator
+        let sum = fraction(1, 2).checked_add(modular(0, 7)).unwrap();
+        assert_eq!(sum, modular(4, 7));
+        let inverse = modular(3, 7).pow(Number::Int((-1).into()), None).unwrap();
+        assert_eq!(inverse, modular(5, 7));
+    }
+
+    #[test]
+    fn division_keeps_what_it_can_exact() {
+        let int = |value: i64| Number::Int(value.into());
+        assert_eq!(int(6).checked_div(int(3)).unwrap(), int(2));
+        let half = int(1).checked_div(int(2)).unwrap();
+        assert!(matches!(half, Number::Float(_)));
+        assert_eq!(fraction(1, 2).checked_div(int(2)).unwrap(), fraction(1, 4));
+        assert_eq!(int(3).checked_div(fraction(3, 2)).unwrap(), int(2));
+        let float = modular(3, 7).checked_div(Number::Float(2.into())).unwrap();
+        assert!(matches!(float, Number::Float(_)));
+        assert!(int(1).checked_div(fraction(0, 1)).is_err());
+    }
+
+    #[test]
+    fn mixed_moduli_are_refused() {
+        let (lhs, rhs) = (modular(3, 7), modular(3, 5));
+        let error = lhs.clone().checked_add(rhs.clone()).unwrap_err();
+        let message = "Mod(3, 7) and Mod(3, 5) have different moduli";
+        assert_eq!(error.to_string(), message);
+        assert!(lhs.clone().checked_sub(rhs.clone()).is_err());
+        assert!(lhs.clone().checked_mul(rhs.clone(), None).is_err());
+        assert!(lhs.checked_div(rhs).is_err());
+    }
+
+    #[test]
+    fn missing_inverses_are_refused() {
+        // 2 has no inverse modulo 4, neither as a divisor nor as a denominator
+        assert!(modular(1, 4).checked_div(modular(2, 4)).is_err());
+        assert!(modular(1, 4).checked_add(fraction(1, 2)).is_err());
+        assert!(fraction(1, 2).checked_mul(modular(1, 4), None).is_err());
+        assert!(modular(0, 4).checked_div(modular(0, 4)).is_err());
+    }
+
+    fn read(text: &str) -> Number {
+        Number::from_str(text).unwrap()
+    }
+
+    fn float(number: Number) -> f64 {
+        match number {
+            Number::Float(float) => float.to_f64(),
+            other => panic!("{} is not a float", other),
+        }
+    }
+
+    #[test]
+    fn reads_integer_literals() {
+        assert_eq!(read("42"), Number::Int(42.into()));
+        assert_eq!(read("-42"), Number::Int((-42).into()));
+        assert_eq!(read("+42"), Number::Int(42.into()));
+        assert_eq!(read("0xFF"), Number::Int(255.into()));
+        assert_eq!(read("0Xff"), Number::Int(255.into()));
+        assert_eq!(read("-0x10"), Number::Int((-16).into()));
+        assert_eq!(read("0b1010"), Number::Int(10.into()));
+        assert_eq!(read("0o17"), Number::Int(15.into()));
+        assert_eq!(read("1_000_000"), Number::Int(1_000_000.into()));
+        assert_eq!(read("0xFF_FF"), Number::Int(0xFFFF.into()));
+    }
+
+    #[test]
+    fn reads_fraction_and_decimal_literals() {
+        assert_eq!(read("3/4"), fraction(3, 4));
+        assert_eq!(read("-6/8"), fraction(-3, 4));
+        assert_eq!(read("1.5"), Number::Float(BigFloat::from(1.5)));
+        assert_eq!(float(read("1.5e3")), 1500.0);
+        assert_eq!(float(read("25E-2")), 0.25);
+        assert_eq!(float(read(".5")), 0.5);
+        assert_eq!(float(read("1_000.5")), 1000.5);
+        assert!(float(read("inf")).is_infinite());
+        assert!(float(read("-Infinity")) < 0.0);
+        assert!(float(read("NaN")).is_nan());
+    }
+
+    #[test]
+    fn refuses_malformed_literals() {
+        let malformed = [
+            "", "abc", "1__0", "_1", "1_", "0x", "0xg", "0b102", "0x-1", "1/0", "1.5/2", "1/x",
+            "1e", "1e+", "1.2.3", "1.5x", "--1", ".",
+        ];
+        for text in malformed {
+            assert!(Number::from_str(text).is_err(), "{:?} was read", text);
+        }
+
+        let error = Number::from_str("1__0").unwrap_err().to_string();
+        assert_eq!(error, "Underscores can only go between the digits of 1__0");
+        let error = Number::from_str("0xg").unwrap_err().to_string();
+        assert_eq!(error, "0xg is not a number in base 16");
+    }
+
+    #[test]
+    fn malformed_literals_are_errors_not_names() {
+        use crate::context::Context;
+        use crate::parse_rpol_notation::single_command_to_token;
+        use crate::token_defs::Token;
+
+        let context = Context::pure_math();
+        let token = |text: &str| single_command_to_token(text.to_string(), &context);
+        assert!(matches!(token("0x1F"), Ok(Token::Const(Number::Int(_)))));
+        assert!(matches!(token("_1"), Ok(Token::Symbol(_))));
+        assert!(token("1__0").is_err());
+        assert!(token("0xg").is_err());
+        assert!(token("-1.2.3").is_err());
+    }
+}
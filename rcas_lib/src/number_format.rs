@@ -0,0 +1,332 @@
+use crate::number::{Number, float_digits};
+use hashbrown::HashMap;
+use num_bigint::{BigInt, Sign};
+use serde::{Deserialize, Serialize};
+
+/// Digits after the point used when a format is picked without saying how many
+pub(crate) const DEFAULT_DIGITS: usize = 6;
+
+/// Most digits after the point the Format command may ask for, as each one is worked out exactly
+pub(crate) const MAX_DIGITS: usize = 1000;
+
+/// A float is turned into the simplest fraction within this many significant digits of it, so
+/// 0.333... computed as a float is shown as 1/3
+const FRACTION_DIGITS: u32 = 30;
+
+/// How many digits a repeating decimal is worked out to before giving up on finding the repeat
+const MAX_REPEATING_DIGITS: usize = 1000;
+
+/// How numbers are written out. Modular numbers, NaN and the infinities are always written as
+/// they display.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NumberFormat {
+    /// As `Display` writes them
+    #[default]
+    Auto,
+    /// With this many digits after the decimal point, e.g. 3.14
+    Fixed(usize),
+    /// With a single digit before the point and this many after, e.g. 1.23e+5
+    Scientific(usize),
+    /// As scientific, but with a power of ten divisible by three, e.g. 123.46e+3
+    Engineering(usize),
+    /// As a fraction in lowest terms, e.g. 7/4
+    Fraction,
+    /// As a whole number and a proper fraction, e.g. 1 3/4
+    Mixed,
+    /// As a decimal with the repeating digits in parentheses, e.g. 0.(142857) for 1/7
+    Repeating,
+}
+impl NumberFormat {
+    /// Looks a format up by its name as given to the Format command, which may be followed by a
+    /// number of digits up to `MAX_DIGITS`, e.g. "fixed 4" or "sci"
+    pub fn from_name(name: &str) -> Option<NumberFormat> {
+        let mut words = name.split_whitespace();
+        let kind = words.next()?.to_lowercase();
+        let digits = match words.next() {
+            Some(digits) => digits
+                .parse::<usize>()
+                .ok()
+                .filter(|digits| *digits <= MAX_DIGITS)?,
+            None => DEFAULT_DIGITS,
+        };
+        if words.next().is_some() {
+            return None;
+        }
+
+        Some(match kind.as_str() {
+            "auto" => NumberFormat::Auto,
+            "fixed" => NumberFormat::Fixed(digits),
+            "sci" | "scientific" => NumberFormat::Scientific(digits),
+            "eng" | "engineering" => NumberFormat::Engineering(digits),
+            "fraction" => NumberFormat::Fraction,
+            "mixed" => NumberFormat::Mixed,
+            "repeating" => NumberFormat::Repeating,
+            _ => return None,
+        })
+    }
+}
+
+impl Number {
+    /// Writes the number out in the given format, see `NumberFormat`
+    pub fn format(&self, format: NumberFormat) -> String {
+        let Some((numerator, denominator)) = self.exact_fraction() else {
+            return self.to_string();
+        };
+        let sign = if numerator.sign() == Sign::Minus {
+            "-"
+        } else {
+            ""
+        };
+        let numerator = BigInt::from(numerator.magnitude().clone());
+
+        match format {
+            NumberFormat::Auto => self.to_string(),
+            NumberFormat::Fixed(digits) => {
+                let scaled = scale(&numerator, &denominator, digits as i64);
+                let sign = if scaled.sign() == Sign::NoSign {
+                    ""
+                } else {
+                    sign
+                };
+                format!("{}{}", sign, place_point(&scaled, digits))
+            }
+            NumberFormat::Scientific(digits) => {
+                scientific(sign, &numerator, &denominator, digits, 1)
+            }
+            NumberFormat::Engineering(digits) => {
+                scientific(sign, &numerator, &denominator, digits, 3)
+            }
+            NumberFormat::Fraction => {
+                let (numerator, denominator) = self.simple_fraction(numerator, denominator);
+                match denominator == BigInt::from(1) {
+                    true => format!("{}{}", sign, numerator),
+                    false => format!("{}{}/{}", sign, numerator, denominator),
+                }
+            }
+            NumberFormat::Mixed => {
+                let (numerator, denominator) = self.simple_fraction(numerator, denominator);
+                let (whole, rest) = (&numerator / &denominator, &numerator % &denominator);
+                match (whole.sign(), rest.sign()) {
+                    (_, Sign::NoSign) => format!("{}{}", sign, whole),
+                    (Sign::NoSign, _) => format!("{}{}/{}", sign, rest, denominator),
+                    _ => format!("{}{} {}/{}", sign, whole, rest, denominator),
+                }
+            }
+            NumberFormat::Repeating => match self {
+                // A float is not exact, so it has no repeat to find
+                Number::Float(_) => self.to_string(),
+                _ => format!("{}{}", sign, repeating(&numerator, &denominator)),
+            },
+        }
+    }
+
+    /// The exact value as (numerator, denominator), with a positive denominator, if the number is
+    /// an integer, a fraction or a finite float. Floats are stored in decimal, so they are exactly
+    /// some number of digits over a power of ten.
+    fn exact_fraction(&self) -> Option<(BigInt, BigInt)> {
+        match self {
+            Number::Int(int) => Some((int.clone(), BigInt::from(1))),
+            Number::Rational(numerator, denominator) => {
+                Some((numerator.clone(), denominator.clone()))
+            }
+            Number::Float(float) if float.is_nan() || float.is_inf() => None,
+            Number::Float(float) => {
+                let (negative, digits, exponent) = float_digits(float);
+                let mut numerator = digits.parse::<BigInt>().ok()?;
+                if negative {
+                    numerator = -numerator;
+                }
+                let shift = exponent - (digits.len() as i64 - 1);
+                match shift >= 0 {
+                    true => Some((numerator * pow10(shift), BigInt::from(1))),
+                    false => Some((numerator, pow10(-shift))),
+                }
+            }
+            Number::Mod(..) => None,
+        }
+    }
+
+    /// The fraction itself for integers and fractions, but the simplest fraction close enough to a
+    /// float, which is only ever an approximation of one. Works on the magnitude.
+    fn simple_fraction(&self, numerator: BigInt, denominator: BigInt) -> (BigInt, BigInt) {
+        match self {
+            Number::Float(_) => approximate(&numerator, &denominator),
+            _ => (numerator, denominator),
+        }
+    }
+}
+
+/// 10^exponent for a non-negative exponent, which stays small as the digits asked for are capped
+fn pow10(exponent: i64) -> BigInt {
+    let exponent = u32::try_from(exponent).expect("powers of ten are never negative or huge");
+    BigInt::from(10).pow(exponent)
+}
+
+/// numerator / denominator * 10^exponent rounded to the nearest integer, halves rounded up, for a
+/// non-negative numerator
+fn scale(numerator: &BigInt, denominator: &BigInt, exponent: i64) -> BigInt {
+    let (numerator, denominator) = match exponent >= 0 {
+        true => (numerator * pow10(exponent), denominator.clone()),
+        false => (numerator.clone(), denominator * pow10(-exponent)),
+    };
+    (numerator * 2 + &denominator) / (denominator * 2)
+}
+
+/// Writes an integer with the last `digits` of it after a decimal point
+fn place_point(int: &BigInt, digits: usize) -> String {
+    let text = format!("{:0>width$}", int.to_string(), width = digits + 1);
+    match digits {
+        0 => text,
+        _ => format!(
+            "{}.{}",
+            &text[..text.len() - digits],
+            &text[text.len() - digits..]
+        ),
+    }
+}
+
+/// The power of ten of the first digit of a positive numerator / denominator
+fn decimal_exponent(numerator: &BigInt, denominator: &BigInt) -> i64 {
+    let exponent = numerator.to_string().len() as i64 - denominator.to_string().len() as i64;
+    let below = match exponent >= 0 {
+        true => *numerator < denominator * pow10(exponent),
+        false => numerator * pow10(-exponent) < *denominator,
+    };
+    if below { exponent - 1 } else { exponent }
+}
+
+/// Writes a non-negative numerator / denominator with `digits` digits after the point and a
+/// power of ten divisible by `step`, so 1 for scientific and 3 for engineering notation
+fn scientific(
+    sign: &str,
+    numerator: &BigInt,
+    denominator: &BigInt,
+    digits: usize,
+    step: i64,
+) -> String {
+    if numerator.sign() == Sign::NoSign {
+        return format!("{}e+0", place_point(&BigInt::from(0), digits));
+    }
+
+    let mut exponent = decimal_exponent(numerator, denominator).div_euclid(step) * step;
+    let mut mantissa = scale(numerator, denominator, digits as i64 - exponent);
+    // Rounding up may carry into another digit before the point, e.g. 9.99 to 10.0, which is one
+    // too many
+    if mantissa >= pow10(digits as i64 + step) {
+        exponent += step;
+        mantissa = scale(numerator, denominator, digits as i64 - exponent);
+    }
+
+    format!("{}{}e{:+}", sign, place_point(&mantissa, digits), exponent)
+}
+
+/// The simplest fraction within `FRACTION_DIGITS` significant digits of a non-negative
+/// numerator / denominator, found from its continued fraction
+fn approximate(numerator: &BigInt, denominator: &BigInt) -> (BigInt, BigInt) {
+    let tolerance = BigInt::from(10).pow(FRACTION_DIGITS);
+    let (mut a, mut b) = (numerator.clone(), denominator.clone());
+    let (mut h, mut h_prev) = (BigInt::from(1), BigInt::from(0));
+    let (mut k, mut k_prev) = (BigInt::from(0), BigInt::from(1));
+
+    while b.sign() != Sign::NoSign {
+        let term = &a / &b;
+        (h, h_prev) = (&term * &h + &h_prev, h);
+        (k, k_prev) = (&term * &k + &k_prev, k);
+        (a, b) = (b.clone(), a - term * b);
+
+        // |numerator / denominator - h / k| is at most numerator / denominator / 10^digits
+        let error = (numerator * &k - &h * denominator).magnitude().clone();
+        if BigInt::from(error) * &tolerance <= numerator * &k {
+            break;
+        }
+    }
+
+    (h, k)
+}
+
+/// Writes a non-negative fraction as a decimal with its repeating digits in parentheses, found by
+/// long division stopping once a remainder comes up again
+fn repeating(numerator: &BigInt, denominator: &BigInt) -> String {
+    let whole = numerator / denominator;
+    let mut remainder = numerator % denominator;
+    let mut digits = String::new();
+    let mut seen = HashMap::new();
+
+    while remainder.sign() != Sign::NoSign {
+        if let Some(start) = seen.get(&remainder) {
+            return format!("{}.{}({})", whole, &digits[..*start], &digits[*start..]);
+        }
+        if digits.len() == MAX_REPEATING_DIGITS {
+            return format!("{}.{}...", whole, digits);
+        }
+        seen.insert(remainder.clone(), digits.len());
+
+        remainder *= 10;
+        digits.push_str(&(&remainder / denominator).to_string());
+        remainder %= denominator;
+    }
+
+    match digits.is_empty() {
+        true => whole.to_string(),
+        false => format!("{}.{}", whole, digits),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigfloat::BigFloat;
+
+    fn fraction(numerator: i64, denominator: i64) -> Number {
+        Number::rational(numerator.into(), denominator.into()).unwrap()
+    }
+
+    #[test]
+    fn writes_repeating_decimals() {
+        assert_eq!(fraction(1, 7).format(NumberFormat::Repeating), "0.(142857)");
+        assert_eq!(fraction(-1, 6).format(NumberFormat::Repeating), "-0.1(6)");
+        assert_eq!(fraction(7, 4).format(NumberFormat::Repeating), "1.75");
+        assert_eq!(Number::Int(5.into()).format(NumberFormat::Repeating), "5");
+    }
+
+    #[test]
+    fn writes_each_format() {
+        let number = fraction(7, 4);
+        assert_eq!(number.format(NumberFormat::Auto), "7/4");
+        assert_eq!(number.format(NumberFormat::Fixed(3)), "1.750");
+        assert_eq!(number.format(NumberFormat::Scientific(2)), "1.75e+0");
+        assert_eq!(number.format(NumberFormat::Fraction), "7/4");
+        assert_eq!(number.format(NumberFormat::Mixed), "1 3/4");
+
+        let number = Number::Int(123456.into());
+        assert_eq!(number.format(NumberFormat::Scientific(2)), "1.23e+5");
+        assert_eq!(number.format(NumberFormat::Engineering(2)), "123.46e+3");
+        assert_eq!(fraction(-1, 3).format(NumberFormat::Fixed(2)), "-0.33");
+        assert_eq!(fraction(-1, 1000).format(NumberFormat::Fixed(2)), "0.00");
+
+        // A float is shown as the simplest fraction close to it
+        let third = Number::Float(BigFloat::from(1) / BigFloat::from(3));
+        assert_eq!(third.format(NumberFormat::Fraction), "1/3");
+        let two_and_a_half = Number::Float(BigFloat::from(2.5));
+        assert_eq!(two_and_a_half.format(NumberFormat::Mixed), "2 1/2");
+    }
+
+    #[test]
+    fn reads_format_names() {
+        let read = NumberFormat::from_name;
+        assert_eq!(read("auto"), Some(NumberFormat::Auto));
+        assert_eq!(read("Fixed 4"), Some(NumberFormat::Fixed(4)));
+        assert_eq!(read("sci"), Some(NumberFormat::Scientific(DEFAULT_DIGITS)));
+        assert_eq!(read("engineering 2"), Some(NumberFormat::Engineering(2)));
+        assert_eq!(read("mixed"), Some(NumberFormat::Mixed));
+        assert_eq!(read("repeating"), Some(NumberFormat::Repeating));
+        assert_eq!(read("fixed 1000"), Some(NumberFormat::Fixed(MAX_DIGITS)));
+
+        // Too many digits would take forever to work out
+        assert_eq!(read("fixed 1001"), None);
+        assert_eq!(read("sci 99999999999999"), None);
+        assert_eq!(read("fixed -1"), None);
+        assert_eq!(read("fixed 4 4"), None);
+        assert_eq!(read("hex"), None);
+    }
+}
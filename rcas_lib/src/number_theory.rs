@@ -0,0 +1,772 @@
+use anyhow::{Result, bail};
+use num_bigint::{BigInt, Sign};
+
+/// Primes below 100, used for trial division and as Miller-Rabin bases
+const SMALL_PRIMES: [u32; 25] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+];
+
+/// Testing against the first 13 primes as bases is known to be exact below this bound
+/// (3 317 044 064 679 887 385 961 981), see Sorenson and Webster, "Strong pseudoprimes to
+/// twelve prime bases"
+const DETERMINISTIC_BOUND: &str = "3317044064679887385961981";
+
+/// How many more bases numbers past `DETERMINISTIC_BOUND` are tested with. A composite passes a
+/// round with probability at most 1/4, so this leaves less than a 4^-40 chance of a mistake.
+const EXTRA_ROUNDS: u32 = 40;
+
+/// How far Pollard's rho goes before leaving n to ECM. It takes about the square root of a factor
+/// in steps to find it, so this finds factors up to about 10^8.
+const RHO_STEPS: u64 = 1 << 14;
+
+/// Stage one bounds for ECM and how many curves to try with each before going on to the next,
+/// after the table GMP-ECM gives for finding factors of 15, 20, 25, 30 and 35 digits. The last
+/// one is kept trying for as long as it takes.
+const ECM_LEVELS: [(u64, u32); 5] = [
+    (2_000, 25),
+    (11_000, 90),
+    (50_000, 300),
+    (250_000, 700),
+    (1_000_000, 1_800),
+];
+
+/// How much further than the stage one bound stage two of ECM goes
+const ECM_STAGE_TWO: u64 = 50;
+
+/// The most bits a factorial or binomial coefficient may have, even when the executor has no size
+/// limit. Anything bigger takes too long to compute to be worth waiting for (2^20 bits is about
+/// 70000! or 315000 digits, which takes under a second).
+pub const MAX_RESULT_BITS: u64 = 1 << 20;
+
+fn int(value: u32) -> BigInt {
+    BigInt::from(value)
+}
+
+fn is_zero(value: &BigInt) -> bool {
+    value.sign() == Sign::NoSign
+}
+
+fn is_negative(value: &BigInt) -> bool {
+    value.sign() == Sign::Minus
+}
+
+fn abs(value: &BigInt) -> BigInt {
+    match value.sign() {
+        Sign::Minus => -value,
+        _ => value.clone(),
+    }
+}
+
+/// About log2 |n|, for estimating how many bits a result will have before computing it
+pub fn log2(n: &BigInt) -> f64 {
+    let shift = n.bits().saturating_sub(64);
+    let top = (n.magnitude() >> shift)
+        .iter_u64_digits()
+        .next()
+        .unwrap_or(0);
+    (top as f64).log2() + shift as f64
+}
+
+/// The remainder of a divided by m, in 0..m for a positive m
+pub(crate) fn modulo(a: &BigInt, m: &BigInt) -> BigInt {
+    let rem = a % m;
+    if is_negative(&rem) { rem + m } else { rem }
+}
+
+/// The greatest common divisor, which is never negative
+pub fn gcd(a: &BigInt, b: &BigInt) -> BigInt {
+    let (mut a, mut b) = (abs(a), abs(b));
+    while !is_zero(&b) {
+        let rem = &a % &b;
+        a = std::mem::replace(&mut b, rem);
+    }
+    a
+}
+
+/// The least common multiple, which is never negative, and 0 if either number is
+pub fn lcm(a: &BigInt, b: &BigInt) -> BigInt {
+    if is_zero(a) || is_zero(b) {
+        return int(0);
+    }
+    abs(&(a / gcd(a, b) * b))
+}
+
+/// The extended Euclidean algorithm: (g, x, y) where g is the gcd of a and b and a x + b y = g
+pub fn egcd(a: &BigInt, b: &BigInt) -> (BigInt, BigInt, BigInt) {
+    let (mut old_r, mut r) = (a.clone(), b.clone());
+    let (mut old_x, mut x) = (int(1), int(0));
+    let (mut old_y, mut y) = (int(0), int(1));
+    while !is_zero(&r) {
+        let quotient = &old_r / &r;
+        let next_r = &old_r - &quotient * &r;
+        old_r = std::mem::replace(&mut r, next_r);
+        let next_x = &old_x - &quotient * &x;
+        old_x = std::mem::replace(&mut x, next_x);
+        let next_y = &old_y - &quotient * &y;
+        old_y = std::mem::replace(&mut y, next_y);
+    }
+
+    if is_negative(&old_r) {
+        (-old_r, -old_x, -old_y)
+    } else {
+        (old_r, old_x, old_y)
+    }
+}
+
+fn check_modulus(m: &BigInt) -> Result<()> {
+    if m.sign() != Sign::Plus {
+        bail!("The modulus must be positive, not {}", m)
+    }
+    Ok(())
+}
+
+/// The inverse of a modulo m, in 0..m. It only exists if a and m are coprime.
+pub fn modinv(a: &BigInt, m: &BigInt) -> Result<BigInt> {
+    check_modulus(m)?;
+    let (g, x, _) = egcd(&modulo(a, m), m);
+    if g != int(1) {
+        bail!(
+            "{} has no inverse modulo {}, they share the factor {}",
+            a,
+            m,
+            g
+        )
+    }
+    Ok(modulo(&x, m))
+}
+
+/// base^exponent modulo m, in 0..m. A negative exponent raises the inverse of the base.
+pub fn modpow(base: &BigInt, exponent: &BigInt, m: &BigInt) -> Result<BigInt> {
+    check_modulus(m)?;
+    if is_negative(exponent) {
+        let inverse = modinv(base, m)?;
+        return Ok(inverse.modpow(&-exponent, m));
+    }
+    Ok(modulo(base, m).modpow(exponent, m))
+}
+
+/// Whether n is prime. Small factors are found by trial division, then Miller-Rabin is run with
+/// the first 13 primes as bases, which is exact below `DETERMINISTIC_BOUND`. Larger numbers are
+/// tested with `EXTRA_ROUNDS` more bases on top, so a wrong answer is vanishingly unlikely but
+/// not ruled out.
+pub fn is_prime(n: &BigInt) -> bool {
+    if *n < int(2) {
+        return false;
+    }
+    for prime in SMALL_PRIMES {
+        let prime = int(prime);
+        if *n == prime {
+            return true;
+        }
+        if is_zero(&(n % &prime)) {
+            return false;
+        }
+    }
+    // Everything below 100^2 without a factor below 100 is prime
+    if *n < int(10_000) {
+        return true;
+    }
+
+    let n_minus_one: BigInt = n - 1;
+    let shift = n_minus_one.trailing_zeros().unwrap_or(0);
+    let odd = &n_minus_one >> shift;
+    let witness = |base: &BigInt| {
+        let mut x = base.modpow(&odd, n);
+        if x == int(1) || x == n_minus_one {
+            return false;
+        }
+        for _ in 1..shift {
+            x = &x * &x % n;
+            if x == n_minus_one {
+                return false;
+            }
+        }
+        true
+    };
+
+    if SMALL_PRIMES[..13].iter().any(|base| witness(&int(*base))) {
+        return false;
+    }
+    if *n < DETERMINISTIC_BOUND.parse::<BigInt>().unwrap() {
+        return true;
+    }
+
+    // Bases spread over 2..n-2 by a fixed generator, so the answer is the same every time
+    let mut state = int(0x9E37_79B9);
+    for _ in 0..EXTRA_ROUNDS {
+        state = (&state * int(6_364_136) + int(1_442_695)) % &n_minus_one;
+        let base = modulo(&state, &(n - 3)) + 2;
+        if witness(&base) {
+            return false;
+        }
+    }
+    true
+}
+
+/// The smallest prime greater than n
+pub fn next_prime(n: &BigInt) -> BigInt {
+    if *n < int(2) {
+        return int(2);
+    }
+    // Only odd candidates past 2
+    let mut candidate: BigInt = n + 1;
+    if !candidate.bit(0) {
+        if candidate == int(2) {
+            return candidate;
+        }
+        candidate += 1;
+    }
+    while !is_prime(&candidate) {
+        candidate += 2;
+    }
+    candidate
+}
+
+/// The prime factors of n with their multiplicities, smallest first. Negative numbers have -1 as
+/// a factor, and 1 has none. Small factors are found by trial division, medium ones by Pollard's
+/// rho method with Brent's cycle finding, and the rest with the elliptic curve method.
+pub fn factorint(n: &BigInt) -> Result<Vec<(BigInt, u32)>> {
+    if is_zero(n) {
+        bail!("0 cannot be factored")
+    }
+
+    let mut factors = vec![];
+    if is_negative(n) {
+        factors.push(BigInt::from(-1));
+    }
+    let mut rest = abs(n);
+    for prime in SMALL_PRIMES {
+        let prime = int(prime);
+        while is_zero(&(&rest % &prime)) {
+            rest /= &prime;
+            factors.push(prime.clone());
+        }
+    }
+    split(rest, &mut factors);
+
+    factors.sort();
+    let mut counted: Vec<(BigInt, u32)> = vec![];
+    for factor in factors {
+        match counted.last_mut() {
+            Some((last, count)) if *last == factor => *count += 1,
+            _ => counted.push((factor, 1)),
+        }
+    }
+    Ok(counted)
+}
+
+/// Splits n into prime factors, none of which are below 100
+fn split(n: BigInt, factors: &mut Vec<BigInt>) {
+    if n == int(1) {
+        return;
+    }
+    if is_prime(&n) {
+        factors.push(n);
+        return;
+    }
+    if let Some(root) = exact_sqrt(&n) {
+        split(root.clone(), factors);
+        split(root, factors);
+        return;
+    }
+
+    let divisor = pollard_brent(&n, &int(1)).unwrap_or_else(|| ecm(&n));
+    split(&n / &divisor, factors);
+    split(divisor, factors);
+}
+
+fn exact_sqrt(n: &BigInt) -> Option<BigInt> {
+    let root = n.sqrt();
+    (&root * &root == *n).then_some(root)
+}
+
+/// Looks for a proper divisor of a composite n with the sequence x^2 + c, multiplying up
+/// differences so that only every hundredth step needs a gcd. Gives up after about `RHO_STEPS`.
+fn pollard_brent(n: &BigInt, c: &BigInt) -> Option<BigInt> {
+    let step = |x: &BigInt| (x * x + c) % n;
+    let (mut y, mut r, mut q) = (int(2), 1u64, int(1));
+    let (mut x, mut saved, mut g) = (int(0), int(0), int(1));
+    const BATCH: u64 = 100;
+
+    while g == int(1) {
+        if r > RHO_STEPS {
+            return None;
+        }
+        x = y.clone();
+        for _ in 0..r {
+            y = step(&y);
+        }
+        let mut k = 0;
+        while k < r && g == int(1) {
+            saved = y.clone();
+            for _ in 0..BATCH.min(r - k) {
+                y = step(&y);
+                q = q * abs(&(&x - &y)) % n;
+            }
+            g = gcd(&q, n);
+            k += BATCH;
+        }
+        r *= 2;
+    }
+
+    // The batch overshot, so go back over it one step at a time
+    if g == *n {
+        loop {
+            saved = step(&saved);
+            g = gcd(&abs(&(&x - &saved)), n);
+            if g != int(1) {
+                break;
+            }
+        }
+    }
+    (g != *n).then_some(g)
+}
+
+/// Finds a proper divisor of a composite n with Lenstra's elliptic curve method, going through
+/// `ECM_LEVELS` until one turns up. It uses Montgomery curves picked with Suyama's
+/// parametrization.
+fn ecm(n: &BigInt) -> BigInt {
+    let last = ECM_LEVELS[ECM_LEVELS.len() - 1];
+    let mut sigma = 6;
+    for (bound, curves) in ECM_LEVELS.into_iter().chain(std::iter::repeat(last)) {
+        let primes = primes_up_to(bound);
+        for _ in 0..curves {
+            if let Some(divisor) = ecm_curve(n, sigma, bound, &primes) {
+                return divisor;
+            }
+            sigma += 1;
+        }
+    }
+    unreachable!("the last level is tried forever")
+}
+
+/// Runs ECM on the curve given by sigma. Stage one multiplies its starting point by every prime
+/// power up to the bound, and stage two looks for one more prime factor up to `ECM_STAGE_TWO`
+/// times the bound. A divisor turns up if the number of points on the curve modulo one of the
+/// factors of n is made of nothing larger.
+fn ecm_curve(n: &BigInt, sigma: u64, bound: u64, primes: &[u64]) -> Option<BigInt> {
+    let sigma = BigInt::from(sigma);
+    let u = modulo(&(&sigma * &sigma - 5), n);
+    let v = modulo(&(&sigma * 4), n);
+    let (x, z) = (u.modpow(&int(3), n), v.modpow(&int(3), n));
+
+    // (A + 2) / 4 = (v - u)^3 (3u + v) / (16 u^3 v). Without an inverse, the gcd may be a factor.
+    let numerator = modulo(&(&v - &u), n).modpow(&int(3), n) * (3 * &u + &v) % n;
+    let denominator = 16 * &x * &v % n;
+    let g = gcd(&denominator, n);
+    if g != int(1) {
+        return (g != *n).then_some(g);
+    }
+    let curve = Curve {
+        n,
+        a24: numerator * modinv(&denominator, n).ok()? % n,
+    };
+
+    let mut point = (x, z);
+    for &prime in primes {
+        let mut power = prime;
+        while power * prime <= bound {
+            power *= prime;
+        }
+        point = curve.multiply(&point, power);
+    }
+    let g = gcd(&point.1, n);
+    if g != int(1) {
+        return (g != *n).then_some(g);
+    }
+
+    let g = gcd(&curve.stage_two(&point, bound), n);
+    (g != int(1) && g != *n).then_some(g)
+}
+
+/// A point on a Montgomery curve, by its X and Z coordinates alone
+type Point = (BigInt, BigInt);
+
+/// A Montgomery curve B y^2 = x^3 + A x^2 + x modulo n, by (A + 2) / 4
+struct Curve<'a> {
+    n: &'a BigInt,
+    a24: BigInt,
+}
+impl Curve<'_> {
+    fn double(&self, (x, z): &Point) -> Point {
+        let sum = (x + z) * (x + z) % self.n;
+        let diff = (x - z) * (x - z) % self.n;
+        let t = &sum - &diff;
+        let z = modulo(&(&t * (&diff + &self.a24 * &t)), self.n);
+        (sum * diff % self.n, z)
+    }
+
+    /// p + q, which can only be worked out from the X and Z coordinates given p - q as well
+    fn add(&self, p: &Point, q: &Point, diff: &Point) -> Point {
+        let u = (&p.0 - &p.1) * (&q.0 + &q.1);
+        let v = (&p.0 + &p.1) * (&q.0 - &q.1);
+        let (sum, difference) = (&u + &v, &u - &v);
+        (
+            modulo(&(&diff.1 * &sum * &sum), self.n),
+            modulo(&(&diff.0 * &difference * &difference), self.n),
+        )
+    }
+
+    /// The product of the differences between q j and q m 210 for every j below 105 coprime to 210,
+    /// as m 210 goes past the stage one bound up to `ECM_STAGE_TWO` times it. If q has a prime
+    /// order l modulo a factor of n in that range, some m 210 is +-j modulo l, so the factor
+    /// divides the product.
+    fn stage_two(&self, q: &Point, bound: u64) -> BigInt {
+        const D: u64 = 210;
+        let baby = (1..D / 2)
+            .filter(|j| j % 2 == 1 && j % 3 != 0 && j % 5 != 0 && j % 7 != 0)
+            .map(|j| self.multiply(q, j))
+            .collect::<Vec<_>>();
+
+        // Giant steps go up by D each, which needs the step before to add with
+        let step = self.multiply(q, D);
+        let mut m = (bound / D).max(2);
+        let mut previous = self.multiply(q, (m - 1) * D);
+        let mut giant = self.multiply(q, m * D);
+        let mut product = int(1);
+        while (m - 1) * D <= bound * ECM_STAGE_TWO {
+            for s in &baby {
+                product = product * (&giant.0 * &s.1 - &s.0 * &giant.1) % self.n;
+            }
+            let next = self.add(&giant, &step, &previous);
+            previous = std::mem::replace(&mut giant, next);
+            m += 1;
+        }
+        product
+    }
+
+    /// k times the point, with the Montgomery ladder, which keeps two points a point apart
+    fn multiply(&self, point: &Point, k: u64) -> Point {
+        let (mut low, mut high) = (point.clone(), self.double(point));
+        for bit in (0..63 - k.leading_zeros()).rev() {
+            if k >> bit & 1 == 1 {
+                low = self.add(&high, &low, point);
+                high = self.double(&high);
+            } else {
+                high = self.add(&low, &high, point);
+                low = self.double(&low);
+            }
+        }
+        low
+    }
+}
+
+/// The primes up to and including the bound, by the sieve of Eratosthenes
+fn primes_up_to(bound: u64) -> Vec<u64> {
+    let mut composite = vec![false; bound as usize + 1];
+    let mut primes = vec![];
+    for idx in 2..=bound as usize {
+        if composite[idx] {
+            continue;
+        }
+        primes.push(idx as u64);
+        for multiple in (idx * idx..=bound as usize).step_by(idx) {
+            composite[multiple] = true;
+        }
+    }
+    primes
+}
+
+/// Euler's totient: how many of 1..=n are coprime to n
+pub fn totient(n: &BigInt) -> Result<BigInt> {
+    if n.sign() != Sign::Plus {
+        bail!(
+            "The totient is only defined for positive numbers, not {}",
+            n
+        )
+    }
+    let mut res = n.clone();
+    for (prime, _) in factorint(n)? {
+        res = res / &prime * (&prime - 1);
+    }
+    Ok(res)
+}
+
+/// The positive divisors of n, smallest first
+pub fn divisors(n: &BigInt) -> Result<Vec<BigInt>> {
+    let mut divisors = vec![int(1)];
+    for (prime, count) in factorint(n)? {
+        if is_negative(&prime) {
+            continue;
+        }
+        let mut more = vec![];
+        for divisor in &divisors {
+            let mut power = divisor.clone();
+            for _ in 0..count {
+                power *= &prime;
+                more.push(power.clone());
+            }
+        }
+        divisors.extend(more);
+    }
+    divisors.sort();
+    Ok(divisors)
+}
+
+/// The Jacobi symbol (a/n) for an odd positive n, which is -1, 0 or 1
+pub fn jacobi(a: &BigInt, n: &BigInt) -> Result<i32> {
+    if n.sign() != Sign::Plus || !n.bit(0) {
+        bail!("The Jacobi symbol (a/n) needs an odd positive n, not {}", n)
+    }
+
+    let (mut a, mut n) = (modulo(a, n), n.clone());
+    let mut res = 1;
+    while !is_zero(&a) {
+        let twos = a.trailing_zeros().unwrap_or(0);
+        a >>= twos;
+        // (2/n) is -1 when n is 3 or 5 mod 8
+        let n_mod_8 = u32::try_from(&n % 8).unwrap();
+        if twos % 2 == 1 && (n_mod_8 == 3 || n_mod_8 == 5) {
+            res = -res;
+        }
+        // Quadratic reciprocity flips the sign when both are 3 mod 4
+        std::mem::swap(&mut a, &mut n);
+        if u32::try_from(&a % 4).unwrap() == 3 && u32::try_from(&n % 4).unwrap() == 3 {
+            res = -res;
+        }
+        a = &a % &n;
+    }
+
+    Ok(if n == int(1) { res } else { 0 })
+}
+
+/// Combines x = r1 (mod m1) and x = r2 (mod m2) into a single x = r (mod m), returned as (r, m).
+/// The moduli do not need to be coprime, but then the two have to agree where they overlap.
+pub fn crt(r1: &BigInt, m1: &BigInt, r2: &BigInt, m2: &BigInt) -> Result<(BigInt, BigInt)> {
+    check_modulus(m1)?;
+    check_modulus(m2)?;
+
+    let (g, x, _) = egcd(m1, m2);
+    let diff = r2 - r1;
+    if !is_zero(&(&diff % &g)) {
+        bail!(
+            "x = {} (mod {}) and x = {} (mod {}) have no common solution",
+            r1,
+            m1,
+            r2,
+            m2
+        )
+    }
+
+    let m = m1 / &g * m2;
+    let r = modulo(&(r1 + m1 * (&diff / &g * x)), &m);
+    Ok((r, m))
+}
+
+/// About how many bits n! has, from Stirling's approximation, which is never more than it really
+/// has. 0 for a negative n.
+pub fn factorial_bits(n: &BigInt) -> f64 {
+    if n.sign() != Sign::Plus {
+        return 0.0;
+    }
+    // n! >= sqrt(2 pi n) (n / e)^n
+    let log_n = log2(n);
+    let n = log_n.exp2();
+    let bits = n * (log_n - std::f64::consts::LOG2_E) + 0.5 * (std::f64::consts::TAU * n).log2();
+    bits.max(0.0)
+}
+
+/// About how many bits n choose k has, never more than it really has, see `binomial`
+pub fn binomial_bits(n: &BigInt, k: &BigInt) -> f64 {
+    if is_negative(k) {
+        return 0.0;
+    }
+    if is_negative(n) {
+        return binomial_bits(&(k - n - 1), k);
+    }
+    if k > n {
+        return 0.0;
+    }
+    // The Stirling estimates are each off by less than a bit, so take a bit off for each
+    let bits = factorial_bits(n) - factorial_bits(k) - factorial_bits(&(n - k)) - 2.0;
+    bits.max(0.0)
+}
+
+/// The binomial coefficient n choose k, for any integer n. It is 0 for a negative k, and
+/// n choose k = (-1)^k (k - n - 1 choose k) for a negative n.
+pub fn binomial(n: &BigInt, k: &BigInt) -> Result<BigInt> {
+    if is_negative(k) {
+        return Ok(int(0));
+    }
+    if is_negative(n) {
+        let res = binomial(&(k - n - 1), k)?;
+        return Ok(if k.bit(0) { -res } else { res });
+    }
+    if k > n {
+        return Ok(int(0));
+    }
+    if binomial_bits(n, k) > MAX_RESULT_BITS as f64 {
+        bail!(
+            "{} choose {} has more than {} bits, which is too large to compute",
+            n,
+            k,
+            MAX_RESULT_BITS
+        )
+    }
+
+    // The smaller of k and n - k, so there are fewer steps
+    let k = std::cmp::min(k.clone(), n - k);
+    let Ok(steps) = u64::try_from(&k) else {
+        bail!("{} choose {} is too large to compute", n, k)
+    };
+    let mut res = int(1);
+    for idx in 0..steps {
+        // Each partial product is itself a binomial coefficient, so the division is exact
+        res = res * (n - idx) / (idx + 1);
+    }
+    Ok(res)
+}
+
+/// n!, for a non-negative n
+pub fn factorial(n: &BigInt) -> Result<BigInt> {
+    if is_negative(n) {
+        bail!(
+            "The factorial is only defined for non-negative numbers, not {}",
+            n
+        )
+    }
+    if factorial_bits(n) > MAX_RESULT_BITS as f64 {
+        bail!(
+            "{}! has more than {} bits, which is too large to compute",
+            n,
+            MAX_RESULT_BITS
+        )
+    }
+    let Ok(n) = u32::try_from(n) else {
+        bail!("{}! is too large to compute", n)
+    };
+    Ok((2..=n).fold(int(1), |acc, idx| acc * idx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn big(int: &str) -> BigInt {
+        BigInt::from_str(int).unwrap()
+    }
+
+    fn ints(values: &[i64]) -> Vec<BigInt> {
+        values.iter().map(|value| BigInt::from(*value)).collect()
+    }
+
+    #[test]
+    fn tells_primes_apart() {
+        let primes = (0..100)
+            .filter(|n| is_prime(&BigInt::from(*n)))
+            .collect::<Vec<i64>>();
+        assert_eq!(ints(&primes), ints(&SMALL_PRIMES.map(i64::from)));
+
+        // Carmichael numbers fool the Fermat test but not this one
+        for carmichael in ["561", "1105", "41041", "3215031751"] {
+            assert!(!is_prime(&big(carmichael)), "{}", carmichael);
+        }
+        // Mersenne primes either side of the deterministic bound, and a product of two primes
+        assert!(is_prime(&big("2305843009213693951")));
+        assert!(is_prime(&big("170141183460469231731687303715884105727")));
+        assert!(!is_prime(&(big("2305843009213693951") * big("1000000007"))));
+        assert!(!is_prime(&big("-7")));
+        assert_eq!(next_prime(&big("1000000000")), big("1000000007"));
+    }
+
+    #[test]
+    fn factors_integers() {
+        let factors = |n: &str| {
+            factorint(&big(n))
+                .unwrap()
+                .into_iter()
+                .map(|(prime, count)| (prime.to_string(), count))
+                .collect::<Vec<(String, u32)>>()
+        };
+        let expected = |pairs: &[(&str, u32)]| {
+            pairs
+                .iter()
+                .map(|(prime, count)| (prime.to_string(), *count))
+                .collect::<Vec<(String, u32)>>()
+        };
+
+        assert_eq!(factors("1"), expected(&[]));
+        assert_eq!(factors("360"), expected(&[("2", 3), ("3", 2), ("5", 1)]));
+        assert_eq!(factors("-12"), expected(&[("-1", 1), ("2", 2), ("3", 1)]));
+        // Both factors are past trial division, so Pollard's rho or ECM has to find them
+        assert_eq!(
+            factors("1000000016000000063"),
+            expected(&[("1000000007", 1), ("1000000009", 1)])
+        );
+        assert_eq!(
+            factors("4294967297"),
+            expected(&[("641", 1), ("6700417", 1)])
+        );
+        assert!(factorint(&big("0")).is_err());
+
+        assert_eq!(totient(&big("36")).unwrap(), big("12"));
+        assert_eq!(divisors(&big("12")).unwrap(), ints(&[1, 2, 3, 4, 6, 12]));
+    }
+
+    #[test]
+    fn finds_large_factors_with_ecm() {
+        // Both factors are too large for Pollard's rho to get to within its steps
+        let p = next_prime(&big("10000000000"));
+        let q = next_prime(&big("30000000000"));
+        assert_eq!(
+            factorint(&(&p * &q)).unwrap(),
+            [(p.clone(), 1), (q.clone(), 1)]
+        );
+
+        // Cubes are not caught as squares, so have to be split too
+        let cube = &q * &q * &q * 6;
+        assert_eq!(
+            factorint(&cube).unwrap(),
+            [(int(2), 1), (int(3), 1), (q, 3)]
+        );
+
+        assert_eq!(primes_up_to(30), [2, 3, 5, 7, 11, 13, 17, 19, 23, 29]);
+    }
+
+    #[test]
+    fn jacobi_symbols() {
+        // The row for n = 15 from the table of Jacobi symbols
+        let row = (0..15)
+            .map(|a| jacobi(&BigInt::from(a), &big("15")).unwrap())
+            .collect::<Vec<i32>>();
+        assert_eq!(row, [0, 1, 1, 0, 1, 0, 0, -1, 1, 0, 0, -1, 0, -1, -1]);
+
+        // For a prime it is the Legendre symbol: 2 is a square mod 7, 3 is not
+        assert_eq!(jacobi(&big("2"), &big("7")).unwrap(), 1);
+        assert_eq!(jacobi(&big("3"), &big("7")).unwrap(), -1);
+        assert_eq!(jacobi(&big("-1"), &big("13")).unwrap(), 1);
+        assert!(jacobi(&big("3"), &big("8")).is_err());
+        assert!(jacobi(&big("3"), &big("-7")).is_err());
+    }
+
+    #[test]
+    fn chinese_remainders() {
+        assert_eq!(
+            crt(&big("2"), &big("3"), &big("3"), &big("5")).unwrap(),
+            (big("8"), big("15"))
+        );
+        // Moduli which share a factor, agreeing on it
+        assert_eq!(
+            crt(&big("3"), &big("4"), &big("5"), &big("6")).unwrap(),
+            (big("11"), big("12"))
+        );
+        // and disagreeing on it
+        assert!(crt(&big("0"), &big("4"), &big("1"), &big("6")).is_err());
+        assert!(crt(&big("1"), &big("0"), &big("1"), &big("6")).is_err());
+    }
+
+    #[test]
+    fn binomials_and_factorials() {
+        assert_eq!(factorial(&big("0")).unwrap(), big("1"));
+        assert_eq!(factorial(&big("20")).unwrap(), big("2432902008176640000"));
+        assert_eq!(binomial(&big("10"), &big("3")).unwrap(), big("120"));
+        assert_eq!(binomial(&big("-4"), &big("3")).unwrap(), big("-20"));
+        assert_eq!(binomial(&big("3"), &big("5")).unwrap(), big("0"));
+
+        // Even without an executor limit, these are refused rather than run for hours
+        assert!(factorial(&big("4000000000")).is_err());
+        assert!(binomial(&big("4000000000"), &big("2000000000")).is_err());
+        assert!(binomial(&big("-4000000000"), &big("2000000000")).is_err());
+    }
+}
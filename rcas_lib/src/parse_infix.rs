@@ -0,0 +1,387 @@
+use anyhow::{Result, bail};
+
+use crate::context::Context;
+
+/// A binary operator the infix parser knows how to place
+#[derive(Debug, PartialEq)]
+struct Operator {
+    symbol: &'static str,
+    precedence: u8,
+    right_assoc: bool,
+}
+
+const OPERATORS: [Operator; 6] = [
+    Operator {
+        symbol: ":=",
+        precedence: 0,
+        right_assoc: true,
+    },
+    Operator {
+        symbol: "+",
+        precedence: 1,
+        right_assoc: false,
+    },
+    Operator {
+        symbol: "-",
+        precedence: 1,
+        right_assoc: false,
+    },
+    Operator {
+        symbol: "*",
+        precedence: 2,
+        right_assoc: false,
+    },
+    Operator {
+        symbol: "/",
+        precedence: 2,
+        right_assoc: false,
+    },
+    Operator {
+        symbol: "^",
+        precedence: 4,
+        right_assoc: true,
+    },
+];
+
+/// Unary minus binds tighter than * but looser than ^, so -x^2 is -(x^2)
+const NEGATE_PRECEDENCE: u8 = 3;
+
+fn find_operator(symbol: &str) -> Option<&'static Operator> {
+    OPERATORS.iter().find(|op| op.symbol == symbol)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum InfixToken {
+    Operand(String),
+    Operator(String),
+    Function(String),
+    LeftParen,
+    RightParen,
+    Comma,
+}
+
+/// What is waiting on the operator stack of the shunting yard
+#[derive(Debug, Clone, PartialEq)]
+enum Pending {
+    Operator(&'static Operator),
+    Negate,
+    /// A function, and how many operands were already waiting when it was called
+    Function(String, usize),
+    LeftParen,
+}
+
+fn tokenize(input: &str, context: &Context) -> Result<Vec<InfixToken>> {
+    let chars = input.chars().collect::<Vec<char>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push(InfixToken::LeftParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(InfixToken::RightParen);
+            i += 1;
+        } else if c == ',' {
+            tokens.push(InfixToken::Comma);
+            i += 1;
+        } else if c == '"' {
+            let Some(len) = chars[i + 1..].iter().position(|c| *c == '"') else {
+                bail!("Unterminated string starting at column {}", i + 1)
+            };
+            let string = chars[i..i + len + 2].iter().collect::<String>();
+            tokens.push(InfixToken::Operand(string));
+            i += len + 2;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            let hex = matches!(chars.get(i + 1), Some('x' | 'X')) && c == '0';
+            let in_number = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_');
+            while i < chars.len() && in_number(chars[i]) {
+                // Allow the sign of an exponent, e.g. 1.5e-3, where e is not a hex digit
+                if !hex
+                    && matches!(chars[i], 'e' | 'E')
+                    && matches!(chars.get(i + 1), Some('+' | '-'))
+                {
+                    i += 1;
+                }
+                i += 1;
+            }
+            tokens.push(InfixToken::Operand(chars[start..i].iter().collect()));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let name = chars[start..i].iter().collect::<String>();
+
+            let is_call = chars[i..].iter().find(|c| !c.is_whitespace()) == Some(&'(');
+            if is_call && context.functions.iter().any(|f| f.name == name) {
+                tokens.push(InfixToken::Function(name));
+            } else {
+                tokens.push(InfixToken::Operand(name));
+            }
+        } else {
+            // Take the longest operator that matches, so := is not read as : and =
+            let Some(op) = OPERATORS
+                .iter()
+                .filter(|op| chars[i..].starts_with(&op.symbol.chars().collect::<Vec<_>>()))
+                .max_by_key(|op| op.symbol.len())
+            else {
+                bail!("Unexpected character '{}' at column {}", c, i + 1)
+            };
+            tokens.push(InfixToken::Operator(op.symbol.to_string()));
+            i += op.symbol.chars().count();
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Moves a pending operator or function to the output. `operands` holds where each operand in
+/// the output starts, and the operands an operator or function takes are merged into one.
+fn emit(pending: Pending, output: &mut Vec<(String, bool)>, operands: &mut Vec<usize>) {
+    match pending {
+        Pending::Operator(op) => {
+            let rhs = operands.pop().unwrap_or(output.len());
+            let lhs = operands.pop().unwrap_or(rhs);
+            // The - functor takes the top of the stack as its left hand side, so a - b has to run
+            // as b a -
+            if op.symbol == "-" {
+                output[lhs..].rotate_left(rhs - lhs);
+            }
+            output.push((op.symbol.to_string(), true));
+            operands.push(lhs);
+        }
+        Pending::Negate => {
+            output.push(("-1".to_string(), false));
+            output.push(("*".to_string(), true));
+        }
+        Pending::Function(name, waiting) => {
+            let start = operands.get(waiting).copied().unwrap_or(output.len());
+            operands.truncate(waiting);
+            output.push((name, true));
+            operands.push(start);
+        }
+        Pending::LeftParen => {}
+    }
+}
+
+/// Converts an infix expression such as `2 * (x + 1)` into RevPol commands, using the shunting yard
+/// algorithm. Every functor ends its own line, as BufferedExecutor only reduces the functor at the
+/// top of the stack, so `1 + 2 * 3` becomes the lines `1 2 3 *` and `+`.
+pub fn infix_to_commands(input: &str, context: &Context) -> Result<Vec<Vec<String>>> {
+    // Each output entry is a command, and whether it is a functor
+    let mut output: Vec<(String, bool)> = vec![];
+    let mut operands: Vec<usize> = vec![];
+    let mut pending: Vec<Pending> = vec![];
+    // Whether the next token has to be an operand (or something which starts one)
+    let mut expect_operand = true;
+    let mut last_token = None;
+
+    for token in tokenize(input, context)? {
+        match token.clone() {
+            InfixToken::Operand(operand) => {
+                if !expect_operand {
+                    bail!("Expected an operator before {}", operand)
+                }
+                operands.push(output.len());
+                output.push((operand, false));
+                expect_operand = false;
+            }
+            InfixToken::Function(name) => {
+                if !expect_operand {
+                    bail!("Expected an operator before {}", name)
+                }
+                pending.push(Pending::Function(name, operands.len()));
+            }
+            InfixToken::LeftParen => {
+                if !expect_operand {
+                    bail!("Expected an operator before (")
+                }
+                pending.push(Pending::LeftParen);
+            }
+            // A call without any arguments, e.g. Functions()
+            InfixToken::RightParen
+                if last_token == Some(InfixToken::LeftParen)
+                    && matches!(pending.iter().rev().nth(1), Some(Pending::Function(..))) =>
+            {
+                pending.pop();
+                let func = pending.pop().unwrap();
+                emit(func, &mut output, &mut operands);
+                expect_operand = false;
+            }
+            InfixToken::Comma | InfixToken::RightParen => {
+                if expect_operand {
+                    bail!(
+                        "Expected an operand before {}",
+                        if token == InfixToken::Comma { "," } else { ")" }
+                    )
+                }
+
+                loop {
+                    match pending.pop() {
+                        Some(Pending::LeftParen) => break,
+                        Some(other) => emit(other, &mut output, &mut operands),
+                        None => bail!("Unbalanced parentheses"),
+                    }
+                }
+
+                if token == InfixToken::Comma {
+                    // Keep the parenthesis open for the next argument
+                    pending.push(Pending::LeftParen);
+                    expect_operand = true;
+                } else if let Some(Pending::Function(..)) = pending.last() {
+                    let func = pending.pop().unwrap();
+                    emit(func, &mut output, &mut operands);
+                }
+            }
+            InfixToken::Operator(symbol) if expect_operand => {
+                if symbol != "-" {
+                    bail!("Expected an operand before {}", symbol)
+                }
+                pending.push(Pending::Negate);
+            }
+            InfixToken::Operator(symbol) => {
+                let op = find_operator(&symbol).expect("tokenize only yields known operators");
+
+                while let Some(top) = pending.last() {
+                    let top_precedence = match top {
+                        Pending::Operator(top) => top.precedence,
+                        Pending::Negate => NEGATE_PRECEDENCE,
+                        Pending::Function(..) | Pending::LeftParen => break,
+                    };
+
+                    if top_precedence > op.precedence
+                        || (top_precedence == op.precedence && !op.right_assoc)
+                    {
+                        let top = pending.pop().unwrap();
+                        emit(top, &mut output, &mut operands);
+                    } else {
+                        break;
+                    }
+                }
+
+                pending.push(Pending::Operator(op));
+                expect_operand = true;
+            }
+        }
+
+        last_token = Some(token);
+    }
+
+    if expect_operand {
+        bail!("Expression ended where an operand was expected")
+    }
+
+    while let Some(top) = pending.pop() {
+        if let Pending::LeftParen | Pending::Function(..) = top {
+            bail!("Unbalanced parentheses")
+        }
+        emit(top, &mut output, &mut operands);
+    }
+
+    // Break the commands into lines, each ending in a functor
+    let mut lines = vec![];
+    let mut line = vec![];
+    for (command, is_functor) in output {
+        line.push(command);
+        if is_functor {
+            lines.push(std::mem::take(&mut line));
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The commands on one line, so the order they run in is easy to read
+    fn rpn(input: &str) -> String {
+        let lines = infix_to_commands(input, &Context::pure_math()).unwrap();
+        lines.concat().join(" ")
+    }
+
+    #[test]
+    fn respects_precedence() {
+        assert_eq!(rpn("1 + 2 * 3"), "1 2 3 * +");
+        assert_eq!(rpn("1 * 2 + 3"), "1 2 * 3 +");
+        assert_eq!(rpn("2 * 3 ^ 2"), "2 3 2 ^ *");
+        assert_eq!(rpn("(1 + 2) * 3"), "1 2 + 3 *");
+        assert_eq!(rpn("x := 1 + 2"), "x 1 2 + :=");
+    }
+
+    #[test]
+    fn respects_associativity() {
+        // - takes the top of the stack as its left hand side
+        assert_eq!(rpn("8 - 4 - 2"), "2 4 8 - -");
+        assert_eq!(rpn("8 - (4 - 2)"), "2 4 - 8 -");
+        assert_eq!(rpn("8 / 4 / 2"), "8 4 / 2 /");
+        assert_eq!(rpn("2 ^ 3 ^ 2"), "2 3 2 ^ ^");
+    }
+
+    #[test]
+    fn negates_below_powers() {
+        assert_eq!(rpn("-x ^ 2"), "x 2 ^ -1 *");
+        assert_eq!(rpn("-x * 2"), "x -1 * 2 *");
+        assert_eq!(rpn("2 - -3"), "3 -1 * 2 -");
+    }
+
+    #[test]
+    fn calls_functions() {
+        assert_eq!(rpn("sqrt(16) + 1"), "16 sqrt 1 +");
+        assert_eq!(rpn("gcd(12, 2 * 9)"), "12 2 9 * gcd");
+    }
+
+    #[test]
+    fn ends_lines_at_functors() {
+        let lines = infix_to_commands("1 + 2 * 3", &Context::pure_math()).unwrap();
+        assert_eq!(lines, vec![vec!["1", "2", "3", "*"], vec!["+"]]);
+    }
+
+    #[test]
+    fn evaluates_like_the_operators_read() {
+        use crate::number::Number;
+        use crate::parse_rpol_notation::commands_to_sequential_exec_order;
+        use crate::stack_machine::{BufferedExecutor, RevPolStackMachine};
+        use crate::token_defs::Token;
+
+        crate::set_debug_logging(false);
+        let context = Context::pure_math();
+        for (input, expected) in [
+            ("10 - 4 - 3", 3),
+            ("2 ^ 10 / 4", 256),
+            ("(7 - 1) * 2 ^ 2", 24),
+            ("gcd(12, 20) - 2 * 3", -2),
+            ("1 - 2 ^ 2 - (4 - 9)", 2),
+        ] {
+            let commands = infix_to_commands(input, &context).unwrap();
+            let lines = commands_to_sequential_exec_order(commands, &context).unwrap();
+            let machine = RevPolStackMachine::new_with_ctx(context.clone());
+            let mut executor = BufferedExecutor::new(machine, lines);
+            executor.run_stack().unwrap();
+            assert_eq!(
+                executor.machine.stack,
+                vec![Token::Const(Number::Int(expected.into()))],
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let context = Context::pure_math();
+        for input in ["1 +", "(1 + 2", "1 + 2)", "1 2", "* 3", "gcd(1,)"] {
+            assert!(infix_to_commands(input, &context).is_err(), "{}", input);
+        }
+    }
+}
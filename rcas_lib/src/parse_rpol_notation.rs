@@ -1,6 +1,4 @@
 use anyhow::{Result, bail};
-use num_bigfloat::BigFloat;
-use num_bigint::BigInt;
 use std::fs::read_to_string;
 use std::path::Path;
 use std::str::FromStr;
@@ -19,45 +17,111 @@ pub fn get_content_at(file: String) -> Result<String> {
 
     let content = read_to_string(path)?;
 
-    return Ok(content.trim().replace("\r", "").to_string());
+    Ok(content.trim().replace("\r", "").to_string())
 }
 
+/// Where a command came from in the source: the 1-based line and column it starts at, and its
+/// length, all counted in characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+/// An error which can be pointed at a specific part of the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpannedError {
+    pub span: Span,
+    pub message: String,
+}
+impl std::fmt::Display for SpannedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (line {}, column {})",
+            self.message, self.span.line, self.span.column
+        )
+    }
+}
+impl std::error::Error for SpannedError {}
+
 /// A simple function to take content of a file and split it up into a sequential list of commands
 /// e.g. 1.2 5 + -> [Token::Const(Number::float(1.2)), Token::Const(Number::Int(5)),
 /// Token::Function(5)]
 pub fn split_into_commands(input: String) -> Vec<Vec<String>> {
-    let input_lines = input.split('\n');
+    split_into_spanned_commands(&input)
+        .into_iter()
+        .map(|line| line.into_iter().map(|(command, _)| command).collect())
+        .collect()
+}
+
+/// The same as split_into_commands, but each command also keeps the span it was found at. Blank
+/// lines and comment lines are skipped, so the line numbers in the spans are the only way to find
+/// a command's place in the source. A string left open at the end of a line carries on over the
+/// line break, and the line it ends on is part of the line it started on.
+pub fn split_into_spanned_commands(input: &str) -> Vec<Vec<(String, Span)>> {
     let mut next_split = vec![];
+    let mut line = vec![];
+    let mut current = String::new();
+    let mut start = Span {
+        line: 1,
+        column: 1,
+        len: 0,
+    };
+    let mut inside_quotes = false;
+
+    let push_token = |line: &mut Vec<(String, Span)>, current: &mut String, start: Span| {
+        if !current.is_empty() {
+            let len = current.chars().count();
+            line.push((std::mem::take(current), Span { len, ..start }));
+        }
+    };
+
+    for (idx, item) in input.split('\n').enumerate() {
+        let item = item.trim_end_matches('\r');
 
-    // Helper closure to split a line into tokens while respecting quoted strings
-    let split_line = |line: &str| -> Vec<String> {
-        let mut tokens = Vec::new();
-        let mut start = 0; // Byte index for the start of the current token
-        let mut inside_quotes = false;
+        if inside_quotes {
+            current.push('\n');
+        } else if item.starts_with("//") {
+            // Skip comment lines (starting with "//")
+            continue;
+        }
+
+        for (i, c) in item.chars().enumerate() {
+            if c == ' ' && !inside_quotes {
+                // Split at space only when not inside quotes
+                push_token(&mut line, &mut current, start);
+                continue;
+            }
 
-        // Iterate over each character with its byte index
-        for (i, c) in line.char_indices() {
+            if current.is_empty() {
+                // Columns are 1-based
+                start = Span {
+                    line: idx + 1,
+                    column: i + 1,
+                    len: 0,
+                };
+            }
             if c == '"' {
                 // Toggle quote state when encountering a double quote
                 inside_quotes = !inside_quotes;
-            } else if c == ' ' && !inside_quotes {
-                // Split at space only when not inside quotes
-                tokens.push(line[start..i].to_string());
-                start = i + 1; // Move start past the space
             }
+            current.push(c);
         }
-        // Push the last token after processing all characters
-        tokens.push(line[start..].to_string());
-        tokens
-    };
 
-    for item in input_lines {
-        // Skip comment lines (starting with "//")
-        if item.starts_with("//") {
-            continue;
+        if !inside_quotes {
+            push_token(&mut line, &mut current, start);
+            if !line.is_empty() {
+                next_split.push(std::mem::take(&mut line));
+            }
         }
-        // Process the line and add its tokens to the result
-        next_split.push(split_line(item));
+    }
+
+    // A string which is never closed still ends the last line
+    push_token(&mut line, &mut current, start);
+    if !line.is_empty() {
+        next_split.push(line);
     }
 
     next_split
@@ -78,50 +142,68 @@ pub fn commands_to_sequential_exec_order(
         .collect()
 }
 
-/// Turn a single string into a RevPol token
-pub fn single_command_to_token(input: String, context: &Context) -> Result<Token> {
-    // Variable | String
-    if input.starts_with("\"") && input.ends_with("\"") {
-        let string_content = input[1..input.len() - 1].to_string();
+/// Checks if a string can be used as the name of a variable: it must start with a letter or an
+/// underscore, and only contain letters, digits and underscores
+pub fn is_valid_name(input: &str) -> bool {
+    let mut chars = input.chars();
 
-        for item in context.variables.iter() {
-            if item.name == string_content {
-                return Ok(Token::Variable(item.clone()));
-            }
-        }
-
-        return Ok(Token::String(string_content));
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {}
+        _ => return false,
     }
 
-    // Constant
-    if let Ok(int) = BigInt::from_str(&input) {
-        return Ok(Token::Const(Number::Int(int)));
-    } else if let Ok(float) = BigFloat::from_str(&input) {
-        return Ok(Token::Const(Number::Float(float)));
-    }
+    chars.all(|c| c.is_alphanumeric() || c == '_')
+}
 
-    // Variable
-    if let Some(var) = context.variables.iter().find_map(|variable| {
-        if variable.name == input {
-            return Some(variable);
-        }
+/// The same as commands_to_sequential_exec_order, but a command which cannot be turned into a
+/// token fails with a SpannedError pointing at it
+pub fn spanned_commands_to_exec_order(
+    input: &[Vec<(String, Span)>],
+    context: &Context,
+) -> Result<Vec<Vec<Token>>> {
+    input
+        .iter()
+        .map(|line| {
+            line.iter()
+                .map(|(command, span)| {
+                    single_command_to_token(command.clone(), context).map_err(|e| {
+                        anyhow::Error::new(SpannedError {
+                            span: *span,
+                            message: e.to_string(),
+                        })
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Turn a single string into a RevPol token
+pub fn single_command_to_token(input: String, context: &Context) -> Result<Token> {
+    // String
+    if input.len() >= 2 && input.starts_with("\"") && input.ends_with("\"") {
+        return Ok(Token::String(input[1..input.len() - 1].to_string()));
+    }
 
-        return None;
-    }) {
-        return Ok(Token::Variable(var.clone()));
+    // Constant, e.g. 12, 0xFF, 1_000, 1.5e-30 or 3/4. Anything starting with a digit can only be
+    // a number, so it is better to say why it is not one.
+    let unsigned = input.trim_start_matches(['-', '+']);
+    match Number::from_str(&input) {
+        Ok(num) => return Ok(Token::Const(num)),
+        Err(e) if unsigned.starts_with(|c: char| c.is_ascii_digit()) => return Err(e),
+        Err(_) => {}
     }
 
     // Function
-    if let Some(func) = context.functions.iter().find_map(|function| {
-        if function.name == input {
-            return Some(function);
-        }
-
-        return None;
-    }) {
+    if let Some(func) = context.functions.iter().find(|function| function.name == input) {
         return Ok(Token::Functor(func.clone()));
     }
 
+    // Variable - these are not looked up yet, as their value may change before they are used
+    if is_valid_name(&input) {
+        return Ok(Token::Symbol(input));
+    }
+
     // Nothing?? Invalid!
     bail!("Unqualified token found: {}", input)
 }
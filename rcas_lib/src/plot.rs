@@ -0,0 +1,622 @@
+use crate::expression::Expr;
+use crate::lambdify::lambdify;
+use crate::mathml::escape;
+use anyhow::{Result, bail};
+
+/// How many evenly spaced samples are taken before any are refined
+const SAMPLES: usize = 200;
+/// How many times an interval between two samples can be halved where the curve bends sharply
+const MAX_DEPTH: u32 = 6;
+
+const WIDTH: f64 = 640.0;
+const HEIGHT: f64 = 480.0;
+/// Space around the plot area for the title, tick labels and axis labels
+const LEFT: f64 = 70.0;
+const RIGHT: f64 = 20.0;
+const TOP: f64 = 40.0;
+const BOTTOM: f64 = 50.0;
+
+/// Curves sampled from expressions, ready to be drawn as SVG or ASCII art
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plot {
+    pub title: String,
+    pub x_label: String,
+    pub y_label: String,
+    /// The curve as runs of points to join up. It is split wherever it is undefined or jumps,
+    /// e.g. at the poles of tan(x).
+    pub segments: Vec<Vec<(f64, f64)>>,
+    /// The part of the plane which is shown, as (from, to)
+    pub x_range: (f64, f64),
+    pub y_range: (f64, f64),
+}
+impl Plot {
+    /// Plots an expression of one variable for the variable going from `from` to `to`
+    pub fn function(expr: &Expr, variable: &str, from: f64, to: f64) -> Result<Plot> {
+        check_range(from, to)?;
+        let func = lambdify(expr, &[variable.to_string()])?;
+        // It is always given its one value, so it cannot fail
+        let func = |t| func(&[t]).unwrap_or(f64::NAN);
+
+        let (segments, _, y_range) = sample(&|t| (t, func(t)), from, to);
+        Ok(Plot {
+            title: expr.to_string(),
+            x_label: variable.to_string(),
+            y_label: String::new(),
+            segments,
+            x_range: (from, to),
+            y_range,
+        })
+    }
+
+    /// Plots the curve (x, y) traced out by two expressions of one variable as it goes from
+    /// `from` to `to`
+    pub fn parametric(x: &Expr, y: &Expr, variable: &str, from: f64, to: f64) -> Result<Plot> {
+        check_range(from, to)?;
+        let inputs = [variable.to_string()];
+        let (x_func, y_func) = (lambdify(x, &inputs)?, lambdify(y, &inputs)?);
+        // Each is always given its one value, so neither can fail
+        let point = |t| {
+            let x = x_func(&[t]).unwrap_or(f64::NAN);
+            (x, y_func(&[t]).unwrap_or(f64::NAN))
+        };
+
+        let (segments, x_range, y_range) = sample(&point, from, to);
+        Ok(Plot {
+            title: format!("({}, {}) for {} from {} to {}", x, y, variable, from, to),
+            x_label: "x".to_string(),
+            y_label: "y".to_string(),
+            segments,
+            x_range,
+            y_range,
+        })
+    }
+
+    /// The plot as an SVG image, with a frame, grid lines, ticks and labels
+    pub fn to_svg(&self) -> String {
+        let (x0, x1) = self.x_range;
+        let (y0, y1) = self.y_range;
+        let (plot_width, plot_height) = (WIDTH - LEFT - RIGHT, HEIGHT - TOP - BOTTOM);
+        // Points far off the plot are pulled in, as the curve is clipped to the plot area anyway
+        let to_x = |x: f64| (LEFT + (x - x0) / (x1 - x0) * plot_width).clamp(-WIDTH, 2.0 * WIDTH);
+        let to_y = |y: f64| (TOP + (y1 - y) / (y1 - y0) * plot_height).clamp(-HEIGHT, 2.0 * HEIGHT);
+
+        let mut svg = vec![
+            format!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+                 viewBox=\"0 0 {w} {h}\" font-family=\"sans-serif\" font-size=\"12\">",
+                w = WIDTH,
+                h = HEIGHT
+            ),
+            format!(
+                "<rect width=\"{}\" height=\"{}\" fill=\"white\"/>",
+                WIDTH, HEIGHT
+            ),
+            format!(
+                "<clipPath id=\"plot-area\"><rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>\
+                 </clipPath>",
+                LEFT, TOP, plot_width, plot_height
+            ),
+        ];
+
+        let x_ticks = ticks(x0, x1, 8);
+        for (tick, label) in &x_ticks {
+            let x = to_x(*tick);
+            svg.push(format!(
+                "<line x1=\"{x:.2}\" y1=\"{}\" x2=\"{x:.2}\" y2=\"{}\" stroke=\"#e0e0e0\"/>",
+                TOP,
+                TOP + plot_height
+            ));
+            svg.push(format!(
+                "<line x1=\"{x:.2}\" y1=\"{}\" x2=\"{x:.2}\" y2=\"{}\" stroke=\"black\"/>",
+                TOP + plot_height,
+                TOP + plot_height + 5.0
+            ));
+            svg.push(format!(
+                "<text x=\"{x:.2}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+                TOP + plot_height + 18.0,
+                label
+            ));
+        }
+        for (tick, label) in ticks(y0, y1, 8) {
+            let y = to_y(tick);
+            svg.push(format!(
+                "<line x1=\"{}\" y1=\"{y:.2}\" x2=\"{}\" y2=\"{y:.2}\" stroke=\"#e0e0e0\"/>",
+                LEFT,
+                LEFT + plot_width
+            ));
+            svg.push(format!(
+                "<line x1=\"{}\" y1=\"{y:.2}\" x2=\"{}\" y2=\"{y:.2}\" stroke=\"black\"/>",
+                LEFT - 5.0,
+                LEFT
+            ));
+            svg.push(format!(
+                "<text x=\"{}\" y=\"{:.2}\" text-anchor=\"end\">{}</text>",
+                LEFT - 8.0,
+                y + 4.0,
+                label
+            ));
+        }
+
+        // The axes themselves, where they cross the plot area
+        if x0 < 0.0 && 0.0 < x1 {
+            svg.push(format!(
+                "<line x1=\"{x:.2}\" y1=\"{}\" x2=\"{x:.2}\" y2=\"{}\" stroke=\"#808080\"/>",
+                TOP,
+                TOP + plot_height,
+                x = to_x(0.0)
+            ));
+        }
+        if y0 < 0.0 && 0.0 < y1 {
+            svg.push(format!(
+                "<line x1=\"{}\" y1=\"{y:.2}\" x2=\"{}\" y2=\"{y:.2}\" stroke=\"#808080\"/>",
+                LEFT,
+                LEFT + plot_width,
+                y = to_y(0.0)
+            ));
+        }
+        svg.push(format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"black\"/>",
+            LEFT, TOP, plot_width, plot_height
+        ));
+
+        for segment in &self.segments {
+            let points = segment
+                .iter()
+                .map(|(x, y)| format!("{:.2},{:.2}", to_x(*x), to_y(*y)))
+                .collect::<Vec<_>>();
+            svg.push(format!(
+                "<polyline clip-path=\"url(#plot-area)\" fill=\"none\" stroke=\"#1f77b4\" \
+                 stroke-width=\"1.5\" stroke-linejoin=\"round\" points=\"{}\"/>",
+                points.join(" ")
+            ));
+        }
+
+        svg.push(format!(
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" font-size=\"14\">{}</text>",
+            LEFT + plot_width / 2.0,
+            TOP - 15.0,
+            escape(&self.title)
+        ));
+        svg.push(format!(
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+            LEFT + plot_width / 2.0,
+            HEIGHT - 10.0,
+            escape(&self.x_label)
+        ));
+        if !self.y_label.is_empty() {
+            svg.push(format!(
+                "<text x=\"15\" y=\"{y}\" text-anchor=\"middle\" transform=\"rotate(-90 15 {y})\">\
+                 {}</text>",
+                escape(&self.y_label),
+                y = TOP + plot_height / 2.0
+            ));
+        }
+        svg.push("</svg>".to_string());
+
+        svg.join("\n") + "\n"
+    }
+
+    /// The plot drawn with characters on a grid of the given size, with the ends of each axis
+    /// labelled, for showing in a terminal
+    pub fn to_ascii(&self, width: usize, height: usize) -> String {
+        let (width, height) = (width.max(2), height.max(2));
+        let (x0, x1) = self.x_range;
+        let (y0, y1) = self.y_range;
+        let to_col = |x: f64| (x - x0) / (x1 - x0) * (width - 1) as f64;
+        let to_row = |y: f64| (y1 - y) / (y1 - y0) * (height - 1) as f64;
+
+        let mut grid = vec![vec![' '; width]; height];
+        let axis_row = (y0 <= 0.0 && 0.0 <= y1).then(|| to_row(0.0).round() as usize);
+        let axis_col = (x0 <= 0.0 && 0.0 <= x1).then(|| to_col(0.0).round() as usize);
+        if let Some(row) = axis_row {
+            grid[row].fill('-');
+        }
+        if let Some(col) = axis_col {
+            for line in grid.iter_mut() {
+                line[col] = '|';
+            }
+        }
+        if let (Some(row), Some(col)) = (axis_row, axis_col) {
+            grid[row][col] = '+';
+        }
+
+        let mut mark = |col: f64, row: f64| {
+            let (col, row) = (col.round(), row.round());
+            if (0.0..width as f64).contains(&col) && (0.0..height as f64).contains(&row) {
+                grid[row as usize][col as usize] = '*';
+            }
+        };
+        for segment in &self.segments {
+            if let [(x, y)] = segment.as_slice() {
+                mark(to_col(*x), to_row(*y));
+            }
+            for pair in segment.windows(2) {
+                let (from, to) = (
+                    (to_col(pair[0].0), to_row(pair[0].1)),
+                    (to_col(pair[1].0), to_row(pair[1].1)),
+                );
+                // Steps of at most one cell, up to a limit so lines far off the grid stay cheap
+                let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).ceil();
+                let steps = steps.clamp(1.0, (4 * (width + height)) as f64) as usize;
+                for step in 0..=steps {
+                    let t = step as f64 / steps as f64;
+                    mark(from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+                }
+            }
+        }
+
+        let (top, bottom) = (number_label(y1), number_label(y0));
+        let margin = top.len().max(bottom.len());
+        let mut lines = vec![self.title.clone()];
+        for (idx, row) in grid.iter().enumerate() {
+            let label = match idx {
+                0 => &top,
+                idx if idx == height - 1 => &bottom,
+                _ => "",
+            };
+            let row = row.iter().collect::<String>();
+            lines.push(format!(
+                "{:>margin$} {}",
+                label,
+                row.trim_end(),
+                margin = margin
+            ));
+        }
+
+        let (left, right) = (number_label(x0), number_label(x1));
+        let gap = (width + 1).saturating_sub(left.len() + right.len()).max(1);
+        lines.push(format!(
+            "{:margin$} {}{}{}",
+            "",
+            left,
+            " ".repeat(gap),
+            right,
+            margin = margin
+        ));
+        if !self.x_label.is_empty() {
+            let line = format!(
+                "{:margin$} {:^width$}",
+                "",
+                self.x_label,
+                margin = margin,
+                width = width
+            );
+            lines.push(line.trim_end().to_string());
+        }
+
+        lines.join("\n")
+    }
+}
+
+fn check_range(from: f64, to: f64) -> Result<()> {
+    if !from.is_finite() || !to.is_finite() || from >= to {
+        bail!(
+            "Cannot plot from {} to {}, the range must be finite and increasing",
+            from,
+            to
+        )
+    }
+    Ok(())
+}
+
+/// Samples a curve as its parameter goes from `from` to `to`. Intervals where the curve bends
+/// are halved until it is straight to within a fraction of the view, and the curve is split where
+/// it is undefined or still jumps across most of the view once an interval cannot be halved again.
+/// The view, the x and y ranges which are returned with the curve, comes from the evenly spaced
+/// samples, as the refined ones crowd around the places where the curve shoots off.
+fn sample(point: &dyn Fn(f64) -> (f64, f64), from: f64, to: f64) -> Sampled {
+    let coarse = (0..=SAMPLES)
+        .map(|idx| from + (to - from) * idx as f64 / SAMPLES as f64)
+        .map(|t| (t, point(t)))
+        .collect::<Vec<_>>();
+
+    let x_range = view_range(coarse.iter().map(|(_, p)| p.0));
+    let y_range = view_range(coarse.iter().map(|(_, p)| p.1));
+    let sampler = Sampler {
+        point,
+        x_range,
+        y_range,
+    };
+
+    let mut points = vec![coarse[0].1];
+    for pair in coarse.windows(2) {
+        sampler.refine(pair[0], pair[1], 0, &mut points);
+    }
+
+    let mut segments = vec![];
+    let mut segment = vec![];
+    for (x, y) in points {
+        if x.is_finite() && y.is_finite() {
+            segment.push((x, y));
+        } else if !segment.is_empty() {
+            segments.push(std::mem::take(&mut segment));
+        }
+    }
+    if !segment.is_empty() {
+        segments.push(segment);
+    }
+    (segments, x_range, y_range)
+}
+
+type Sampled = (Vec<Vec<(f64, f64)>>, (f64, f64), (f64, f64));
+
+struct Sampler<'a> {
+    point: &'a dyn Fn(f64) -> (f64, f64),
+    /// The view, which deviations and jumps are measured against
+    x_range: (f64, f64),
+    y_range: (f64, f64),
+}
+impl Sampler<'_> {
+    /// Adds the points after `start` up to and including `end`, with a point which is not finite
+    /// put in between wherever the curve should be split
+    fn refine(
+        &self,
+        start: (f64, (f64, f64)),
+        end: (f64, (f64, f64)),
+        depth: u32,
+        points: &mut Vec<(f64, f64)>,
+    ) {
+        let (t0, p0) = start;
+        let (t1, p1) = end;
+        let tm = (t0 + t1) / 2.0;
+        let pm = (self.point)(tm);
+
+        let finite = [p0, pm, p1]
+            .iter()
+            .all(|p| p.0.is_finite() && p.1.is_finite());
+        let undefined = [p0, pm, p1]
+            .iter()
+            .all(|p| !p.0.is_finite() || !p.1.is_finite());
+        let bend = self.distance(pm, ((p0.0 + p1.0) / 2.0, (p0.1 + p1.1) / 2.0));
+        if depth < MAX_DEPTH && !undefined && (!finite || bend > 1e-3) {
+            self.refine(start, (tm, pm), depth + 1, points);
+            self.refine((tm, pm), end, depth + 1, points);
+            return;
+        }
+
+        // A jump only needs a split if it would draw a line across the view
+        for (a, b) in [(p0, pm), (pm, p1)] {
+            if finite && self.distance(a, b) > 0.5 && !self.off_view_together(a, b) {
+                points.push((f64::NAN, f64::NAN));
+            }
+            points.push(b);
+        }
+    }
+
+    /// The distance between two points, as a fraction of the size of the view
+    fn distance(&self, a: (f64, f64), b: (f64, f64)) -> f64 {
+        let dx = (a.0 - b.0) / (self.x_range.1 - self.x_range.0);
+        let dy = (a.1 - b.1) / (self.y_range.1 - self.y_range.0);
+        (dx * dx + dy * dy).sqrt()
+    }
+
+    /// Whether both points are past the same edge of the view, so nothing between them shows
+    fn off_view_together(&self, a: (f64, f64), b: (f64, f64)) -> bool {
+        let side = |value: f64, (low, high): (f64, f64)| match value {
+            value if value < low => -1,
+            value if value > high => 1,
+            _ => 0,
+        };
+        let (ax, bx) = (side(a.0, self.x_range), side(b.0, self.x_range));
+        let (ay, by) = (side(a.1, self.y_range), side(b.1, self.y_range));
+        (ax != 0 && ax == bx) || (ay != 0 && ay == by)
+    }
+}
+
+/// A range showing all of the finite values with a margin around them, unless a few of them are
+/// far away from the rest (e.g. near a pole), in which case those are left out
+fn view_range(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    let mut finite = values.filter(|v| v.is_finite()).collect::<Vec<_>>();
+    if finite.is_empty() {
+        return (-1.0, 1.0);
+    }
+    finite.sort_by(f64::total_cmp);
+
+    let (min, max) = (finite[0], finite[finite.len() - 1]);
+    let quantile = |p: f64| finite[((finite.len() - 1) as f64 * p).round() as usize];
+    let (low, high) = (quantile(0.05), quantile(0.95));
+    // Each end is cut off at a quantile if the values beyond it run far away, and then given
+    // more room, as the curve carries on past it
+    let spread = high - low;
+    let (low, low_margin) = match spread > 0.0 && low - min > 2.0 * spread {
+        true => (low, 0.25),
+        false => (min, 0.05),
+    };
+    let (high, high_margin) = match spread > 0.0 && max - high > 2.0 * spread {
+        true => (high, 0.25),
+        false => (max, 0.05),
+    };
+
+    if high - low <= f64::EPSILON * low.abs().max(1.0) {
+        (low - 1.0, high + 1.0)
+    } else {
+        let height = high - low;
+        (low - height * low_margin, high + height * high_margin)
+    }
+}
+
+/// Evenly spaced values at round numbers between `low` and `high`, about `count` of them, each
+/// with a label
+fn ticks(low: f64, high: f64, count: usize) -> Vec<(f64, String)> {
+    let raw = (high - low) / count as f64;
+    let magnitude = 10f64.powf(raw.log10().floor());
+    let step = match raw / magnitude {
+        r if r < 1.5 => 1.0,
+        r if r < 3.5 => 2.0,
+        r if r < 7.5 => 5.0,
+        _ => 10.0,
+    } * magnitude;
+    let decimals = (-step.log10().floor()).max(0.0) as usize;
+
+    let mut ticks = vec![];
+    let mut idx = (low / step).ceil();
+    while idx * step <= high {
+        let value = idx * step;
+        let label = if value == 0.0 {
+            "0".to_string()
+        } else if value.abs() >= 1e6 || decimals > 4 {
+            format!("{:e}", value)
+        } else {
+            format!("{:.*}", decimals, value)
+        };
+        ticks.push((value, label));
+        idx += 1.0;
+    }
+    ticks
+}
+
+/// A short label for the value at the end of an axis
+fn number_label(value: f64) -> String {
+    if value == 0.0 {
+        "0".to_string()
+    } else if value.abs() >= 1e6 || value.abs() < 1e-3 {
+        format!("{:.2e}", value)
+    } else {
+        let text = format!("{:.3}", value);
+        text.trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+
+    fn plot(source: &str, from: f64, to: f64) -> Plot {
+        crate::set_debug_logging(false);
+        let expr = Expr::parse(source, &Context::pure_math()).unwrap();
+        Plot::function(&expr, "x", from, to).unwrap()
+    }
+
+    fn line() -> Plot {
+        Plot {
+            title: "y = x".to_string(),
+            x_label: "x".to_string(),
+            y_label: String::new(),
+            segments: vec![vec![(-1.0, -1.0), (1.0, 1.0)]],
+            x_range: (-1.0, 1.0),
+            y_range: (-1.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn samples_smooth_curves_in_one_piece() {
+        let plot = plot("x ^ 2", -1.0, 1.0);
+        assert_eq!(plot.segments.len(), 1);
+        let points = &plot.segments[0];
+        assert_eq!(points.first(), Some(&(-1.0, 1.0)));
+        assert_eq!(points.last(), Some(&(1.0, 1.0)));
+        assert!(points.len() > SAMPLES);
+        assert!(points.iter().all(|(x, y)| (x * x - y).abs() < 1e-12));
+        assert!(points.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        // The view is the range of the curve with a margin around it
+        assert_eq!(plot.x_range, (-1.0, 1.0));
+        assert!(plot.y_range.0 < 0.0 && plot.y_range.0 > -0.1);
+        assert!(plot.y_range.1 > 1.0 && plot.y_range.1 < 1.1);
+    }
+
+    #[test]
+    fn splits_and_refines_at_poles() {
+        let plot = plot("tan(x)", -3.0, 3.0);
+        assert_eq!(plot.segments.len(), 3);
+
+        // Each piece ends closer to the pole than the evenly spaced samples get
+        let pole = std::f64::consts::FRAC_PI_2;
+        let step = 6.0 / SAMPLES as f64;
+        let left = plot.segments[0].last().unwrap().0;
+        let right = plot.segments[1].first().unwrap().0;
+        assert!(left < -pole && -pole - left < step / 4.0);
+        assert!(right > -pole && right + pole < step / 4.0);
+
+        // The view leaves out how far the curve shoots off near the poles
+        assert!(plot.y_range.1 < 100.0 && plot.y_range.0 > -100.0);
+
+        let plot = self::plot("1 / x", -1.0, 1.0);
+        assert_eq!(plot.segments.len(), 2);
+        assert!(plot.segments[0].iter().all(|(x, y)| *x < 0.0 && *y < 0.0));
+        assert!(plot.segments[1].iter().all(|(x, y)| *x > 0.0 && *y > 0.0));
+    }
+
+    #[test]
+    fn leaves_out_where_it_is_undefined() {
+        let plot = plot("sqrt(x)", -1.0, 1.0);
+        assert_eq!(plot.segments.len(), 1);
+        let start = plot.segments[0][0].0;
+        assert!((0.0..0.01).contains(&start));
+
+        let plot = self::plot("ln(x - 5)", 0.0, 1.0);
+        assert!(plot.segments.is_empty());
+        assert_eq!(plot.y_range, (-1.0, 1.0));
+    }
+
+    #[test]
+    fn refuses_empty_and_infinite_ranges() {
+        let expr = Expr::Symbol("x".to_string());
+        assert!(Plot::function(&expr, "x", 1.0, 1.0).is_err());
+        assert!(Plot::function(&expr, "x", 2.0, 1.0).is_err());
+        assert!(Plot::function(&expr, "x", 0.0, f64::INFINITY).is_err());
+        assert!(Plot::function(&expr, "y", 0.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn puts_ticks_at_round_numbers() {
+        let values = |ticks: Vec<(f64, String)>| ticks.into_iter().map(|t| t.1).collect::<Vec<_>>();
+        assert_eq!(
+            values(ticks(0.0, 10.0, 8)),
+            ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "10"]
+        );
+        assert_eq!(
+            values(ticks(-0.45, 0.45, 8)),
+            [
+                "-0.4", "-0.3", "-0.2", "-0.1", "0", "0.1", "0.2", "0.3", "0.4"
+            ]
+        );
+        assert_eq!(
+            values(ticks(0.0, 1e7, 4)),
+            ["0", "2e6", "4e6", "6e6", "8e6", "1e7"]
+        );
+        assert_eq!(
+            values(ticks(-1.05, 1.05, 8)),
+            [
+                "-1.0", "-0.8", "-0.6", "-0.4", "-0.2", "0", "0.2", "0.4", "0.6", "0.8", "1.0"
+            ]
+        );
+
+        assert_eq!(number_label(2.5000), "2.5");
+        assert_eq!(number_label(-3.0), "-3");
+        assert_eq!(number_label(1234567.0), "1.23e6");
+    }
+
+    #[test]
+    fn draws_svg() {
+        let mut plot = line();
+        plot.title = "x < 1 & y".to_string();
+        plot.segments.push(vec![(0.5, 0.0)]);
+        let svg = plot.to_svg();
+
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"640\""));
+        assert!(svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<polyline").count(), 2);
+        assert!(svg.contains("points=\"70.00,430.00 620.00,40.00\""));
+        assert!(svg.contains(">x &lt; 1 &amp; y</text>"));
+        // Both axes cross the plot area
+        assert_eq!(svg.matches("stroke=\"#808080\"").count(), 2);
+        assert_eq!(svg.matches(">-0.8</text>").count(), 2);
+    }
+
+    #[test]
+    fn draws_ascii() {
+        assert_eq!(
+            line().to_ascii(9, 5),
+            "y = x\n \
+             1     |   *\n       \
+             | **\n   \
+             ----**---\n     \
+             **|\n\
+             -1 **  |\n   \
+             -1       1\n       \
+             x"
+        );
+    }
+}
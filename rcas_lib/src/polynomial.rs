@@ -0,0 +1,561 @@
+use crate::expression::Expr;
+use crate::number::Number;
+use crate::number_theory::{factorint, is_prime, modinv, modulo};
+use anyhow::{Result, bail};
+use num_bigint::{BigInt, Sign};
+use std::fmt::Display;
+
+/// The highest degree a polynomial may reach, so that e.g. (x + 1)^1000000 fails rather than
+/// running out of memory
+pub const MAX_DEGREE: usize = 1024;
+
+/// The highest degree of a polynomial tested for irreducibility, and so of n in GF(p^n). The test
+/// takes about n^3 steps.
+pub const MAX_FIELD_DEGREE: usize = 128;
+
+/// A polynomial over GF(p), the integers modulo a prime p. The coefficients are reduced into
+/// 0..p and stored lowest power first, without trailing zeros, so the zero polynomial has none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Polynomial {
+    coefficients: Vec<BigInt>,
+    prime: BigInt,
+}
+impl Polynomial {
+    /// The polynomial with the given coefficients, lowest power first, e.g. [1, 0, 3] is
+    /// 3x^2 + 1. Fails if p is not a prime.
+    pub fn new(coefficients: Vec<BigInt>, prime: &BigInt) -> Result<Polynomial> {
+        if !is_prime(prime) {
+            bail!("GF({}) needs a prime, which {} is not", prime, prime)
+        }
+        Ok(Polynomial::reduced(coefficients, prime))
+    }
+
+    /// Reduces the coefficients modulo a prime which has already been checked
+    fn reduced(coefficients: Vec<BigInt>, prime: &BigInt) -> Polynomial {
+        let mut coefficients = coefficients
+            .iter()
+            .map(|coefficient| modulo(coefficient, prime))
+            .collect::<Vec<BigInt>>();
+        while coefficients.last().is_some_and(is_zero) {
+            coefficients.pop();
+        }
+        Polynomial {
+            coefficients,
+            prime: prime.clone(),
+        }
+    }
+
+    fn constant(&self, value: BigInt) -> Polynomial {
+        Polynomial::reduced(vec![value], &self.prime)
+    }
+
+    /// The polynomial x, over the same field as this one
+    fn x(&self) -> Polynomial {
+        Polynomial::reduced(vec![BigInt::from(0), BigInt::from(1)], &self.prime)
+    }
+
+    /// Reads a polynomial in one variable from an expression, e.g. (x + 1)^2 - 3*x. Coefficients
+    /// can be integers, fractions whose denominator has an inverse modulo p, and numbers modulo
+    /// p. A polynomial can only be divided by a nonzero constant.
+    pub fn from_expr(expr: &Expr, variable: &str, prime: &BigInt) -> Result<Polynomial> {
+        let zero = Polynomial::new(vec![], prime)?;
+        zero.read(expr, variable)
+    }
+
+    /// Reads an expression over the same field as this polynomial
+    fn read(&self, expr: &Expr, variable: &str) -> Result<Polynomial> {
+        let prime = &self.prime;
+        let (name, args) = match expr {
+            Expr::Num(num) => {
+                let value = match num {
+                    Number::Int(int) => int.clone(),
+                    Number::Mod(value, modulus) if modulus == prime => value.clone(),
+                    Number::Rational(numerator, denominator) => {
+                        numerator * modinv(denominator, prime)?
+                    }
+                    _ => bail!("{} is not a coefficient in GF({})", num, prime),
+                };
+                return Ok(self.constant(value));
+            }
+            Expr::Symbol(name) if name == variable => return Ok(self.x()),
+            Expr::Symbol(name) => bail!("{} is not the polynomial's variable {}", name, variable),
+            Expr::Matrix(_) => bail!("A matrix is not a polynomial"),
+            Expr::Apply(name, args) => (name.as_str(), args.as_slice()),
+        };
+
+        match (name, args) {
+            ("^", [base, exponent]) => match exponent {
+                Expr::Num(Number::Int(exponent)) if exponent.sign() != Sign::Minus => {
+                    self.read(base, variable)?.pow(exponent)
+                }
+                other => bail!(
+                    "A polynomial can only be raised to a whole positive power, not {}",
+                    other
+                ),
+            },
+            ("-", [operand]) => self
+                .constant(BigInt::from(0))
+                .checked_sub(&self.read(operand, variable)?),
+            ("+" | "-" | "*" | "/", [lhs, rhs]) => {
+                let lhs = self.read(lhs, variable)?;
+                let rhs = self.read(rhs, variable)?;
+                match name {
+                    "+" => lhs.checked_add(&rhs),
+                    "-" => lhs.checked_sub(&rhs),
+                    "*" => lhs.checked_mul(&rhs),
+                    _ if rhs.degree() == Some(0) => lhs.checked_mul(&rhs.inverse_constant()),
+                    _ => bail!("A polynomial can only be divided by a nonzero constant"),
+                }
+            }
+            _ => bail!("{} is not a polynomial", expr),
+        }
+    }
+
+    /// The polynomial as an expression in the variable, highest power first, e.g. 3 * x^2 + 1
+    pub fn to_expr(&self, variable: &str) -> Expr {
+        let x = Expr::Symbol(variable.to_string());
+        let int = |value: BigInt| Expr::Num(Number::Int(value));
+
+        let terms = self
+            .coefficients
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, coefficient)| !is_zero(coefficient))
+            .map(|(power, coefficient)| {
+                let power = match power {
+                    0 => return int(coefficient.clone()),
+                    1 => x.clone(),
+                    _ => Expr::Apply("^".to_string(), vec![x.clone(), int(power.into())]),
+                };
+                match coefficient == &BigInt::from(1) {
+                    true => power,
+                    false => Expr::Apply("*".to_string(), vec![int(coefficient.clone()), power]),
+                }
+            });
+
+        terms
+            .reduce(|lhs, rhs| Expr::Apply("+".to_string(), vec![lhs, rhs]))
+            .unwrap_or(int(BigInt::from(0)))
+    }
+
+    /// The coefficients, lowest power first
+    pub fn coefficients(&self) -> &[BigInt] {
+        &self.coefficients
+    }
+
+    pub fn prime(&self) -> &BigInt {
+        &self.prime
+    }
+
+    /// The highest power with a nonzero coefficient, None for the zero polynomial
+    pub fn degree(&self) -> Option<usize> {
+        self.coefficients.len().checked_sub(1)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.coefficients.is_empty()
+    }
+
+    /// Fails if the two are not over the same field
+    fn check_field(&self, rhs: &Polynomial) -> Result<()> {
+        if self.prime != rhs.prime {
+            bail!(
+                "A polynomial over GF({}) and one over GF({}) cannot be combined",
+                self.prime,
+                rhs.prime
+            )
+        }
+        Ok(())
+    }
+
+    pub fn checked_add(&self, rhs: &Polynomial) -> Result<Polynomial> {
+        self.check_field(rhs)?;
+        let len = self.coefficients.len().max(rhs.coefficients.len());
+        let sum = (0..len)
+            .map(|idx| self.coefficient(idx) + rhs.coefficient(idx))
+            .collect();
+        Ok(Polynomial::reduced(sum, &self.prime))
+    }
+
+    pub fn checked_sub(&self, rhs: &Polynomial) -> Result<Polynomial> {
+        self.check_field(rhs)?;
+        let len = self.coefficients.len().max(rhs.coefficients.len());
+        let difference = (0..len)
+            .map(|idx| self.coefficient(idx) - rhs.coefficient(idx))
+            .collect();
+        Ok(Polynomial::reduced(difference, &self.prime))
+    }
+
+    /// Multiplies, failing if the product would have a degree above `MAX_DEGREE`
+    pub fn checked_mul(&self, rhs: &Polynomial) -> Result<Polynomial> {
+        self.check_field(rhs)?;
+        let (Some(lhs_degree), Some(rhs_degree)) = (self.degree(), rhs.degree()) else {
+            return Ok(self.constant(BigInt::from(0)));
+        };
+        if lhs_degree + rhs_degree > MAX_DEGREE {
+            bail!(
+                "The product would have degree {}, above the limit of {}",
+                lhs_degree + rhs_degree,
+                MAX_DEGREE
+            )
+        }
+
+        let mut product = vec![BigInt::from(0); lhs_degree + rhs_degree + 1];
+        for (lhs_idx, lhs) in self.coefficients.iter().enumerate() {
+            for (rhs_idx, rhs) in rhs.coefficients.iter().enumerate() {
+                product[lhs_idx + rhs_idx] += lhs * rhs;
+            }
+        }
+        Ok(Polynomial::reduced(product, &self.prime))
+    }
+
+    /// Raises to a power, failing if the result would have a degree above `MAX_DEGREE`
+    pub fn pow(&self, exponent: &BigInt) -> Result<Polynomial> {
+        // A constant can be raised to any power, anything else only to one under the limit
+        if self.degree().is_some_and(|degree| degree > 0) {
+            let limit = BigInt::from(MAX_DEGREE / self.degree().unwrap_or(1));
+            if exponent > &limit {
+                bail!(
+                    "Raising a polynomial of degree {} to the power {} goes above the degree limit \
+                     of {}",
+                    self.degree().unwrap_or(0),
+                    exponent,
+                    MAX_DEGREE
+                )
+            }
+        }
+        self.pow_with(exponent, |lhs, rhs| lhs.checked_mul(rhs))
+    }
+
+    /// Raises to a power modulo another polynomial
+    pub fn pow_mod(&self, exponent: &BigInt, modulus: &Polynomial) -> Result<Polynomial> {
+        let base = self.div_rem(modulus)?.1;
+        base.pow_with(exponent, |lhs, rhs| {
+            Ok(lhs.checked_mul(rhs)?.div_rem(modulus)?.1)
+        })
+    }
+
+    /// Square and multiply with the given multiplication, for a non-negative exponent
+    fn pow_with(
+        &self,
+        exponent: &BigInt,
+        mul: impl Fn(&Polynomial, &Polynomial) -> Result<Polynomial>,
+    ) -> Result<Polynomial> {
+        if exponent.sign() == Sign::Minus {
+            bail!(
+                "A polynomial cannot be raised to the negative power {}",
+                exponent
+            )
+        }
+        let mut res = self.constant(BigInt::from(1));
+        for bit in (0..exponent.bits()).rev() {
+            res = mul(&res, &res)?;
+            if exponent.bit(bit) {
+                res = mul(&res, self)?;
+            }
+        }
+        Ok(res)
+    }
+
+    /// Long division, giving (quotient, remainder) with the remainder of a lower degree than the
+    /// divisor
+    pub fn div_rem(&self, divisor: &Polynomial) -> Result<(Polynomial, Polynomial)> {
+        self.check_field(divisor)?;
+        let Some(divisor_degree) = divisor.degree() else {
+            bail!("Division by the zero polynomial")
+        };
+        let lead_inverse = modinv(&divisor.coefficients[divisor_degree], &self.prime)?;
+
+        let mut remainder = self.coefficients.clone();
+        let quotient_len = (remainder.len() + 1).saturating_sub(divisor.coefficients.len());
+        let mut quotient = vec![BigInt::from(0); quotient_len];
+        for idx in (0..quotient_len).rev() {
+            let factor = modulo(
+                &(&remainder[idx + divisor_degree] * &lead_inverse),
+                &self.prime,
+            );
+            for (offset, coefficient) in divisor.coefficients.iter().enumerate() {
+                remainder[idx + offset] -= &factor * coefficient;
+            }
+            quotient[idx] = factor;
+        }
+
+        Ok((
+            Polynomial::reduced(quotient, &self.prime),
+            Polynomial::reduced(remainder, &self.prime),
+        ))
+    }
+
+    /// The greatest common divisor, made monic so it is unique. The gcd of two zero polynomials
+    /// is zero.
+    pub fn gcd(&self, rhs: &Polynomial) -> Result<Polynomial> {
+        let (mut a, mut b) = (self.clone(), rhs.clone());
+        while !b.is_zero() {
+            let remainder = a.div_rem(&b)?.1;
+            (a, b) = (b, remainder);
+        }
+        Ok(a.monic())
+    }
+
+    /// Divides by the leading coefficient, so that it becomes 1
+    pub fn monic(&self) -> Polynomial {
+        match self.degree() {
+            Some(degree) => {
+                let inverse = self
+                    .constant(self.coefficients[degree].clone())
+                    .inverse_constant();
+                self.scale(&inverse.coefficient(0))
+            }
+            None => self.clone(),
+        }
+    }
+
+    /// Whether the polynomial has no factors of a lower, nonzero degree, using Rabin's test. The
+    /// polynomial has to have a degree from 1 to `MAX_FIELD_DEGREE`.
+    pub fn is_irreducible(&self) -> Result<bool> {
+        let degree = match self.degree() {
+            Some(degree @ 1..=MAX_FIELD_DEGREE) => degree,
+            Some(0) | None => bail!("Only a polynomial of degree 1 or more can be irreducible"),
+            Some(degree) => bail!(
+                "Testing a polynomial of degree {} is above the limit of {}",
+                degree,
+                MAX_FIELD_DEGREE
+            ),
+        };
+        let powers = self.frobenius_powers(degree)?;
+        let x = self.x().div_rem(self)?.1;
+
+        // f is irreducible if x^(p^n) = x modulo f, and x^(p^(n/q)) - x shares no factor with f
+        // for any prime q dividing n
+        for (prime, _) in factorint(&BigInt::from(degree))? {
+            let power = usize::try_from(&prime).unwrap_or(degree);
+            let shared = powers[degree / power - 1].checked_sub(&x)?.gcd(self)?;
+            if shared.degree() != Some(0) {
+                return Ok(false);
+            }
+        }
+        Ok(powers[degree - 1] == x)
+    }
+
+    /// x^(p^k) modulo this polynomial for k from 1 to `count`. Raising to the power p is linear
+    /// over GF(p), as (a + b)^p = a^p + b^p and c^p = c, so once the powers (x^p)^i are known each
+    /// next one is a sum of those instead of another exponentiation.
+    fn frobenius_powers(&self, count: usize) -> Result<Vec<Polynomial>> {
+        let degree = self.degree().unwrap_or(0);
+        let x_to_p = self.x().pow_mod(&self.prime, self)?;
+
+        let mut basis = vec![self.constant(BigInt::from(1))];
+        for idx in 1..degree {
+            let next = basis[idx - 1].checked_mul(&x_to_p)?.div_rem(self)?.1;
+            basis.push(next);
+        }
+
+        let mut powers = vec![x_to_p];
+        while powers.len() < count {
+            let last = powers.last().unwrap();
+            let mut next = self.constant(BigInt::from(0));
+            for (coefficient, power) in last.coefficients.iter().zip(&basis) {
+                next = next.checked_add(&power.scale(coefficient))?;
+            }
+            powers.push(next);
+        }
+        Ok(powers)
+    }
+
+    fn coefficient(&self, idx: usize) -> BigInt {
+        self.coefficients
+            .get(idx)
+            .cloned()
+            .unwrap_or(BigInt::from(0))
+    }
+
+    fn scale(&self, factor: &BigInt) -> Polynomial {
+        let scaled = self.coefficients.iter().map(|c| c * factor).collect();
+        Polynomial::reduced(scaled, &self.prime)
+    }
+
+    /// The inverse of a nonzero constant polynomial
+    fn inverse_constant(&self) -> Polynomial {
+        // p is prime, so every nonzero constant has an inverse
+        let inverse = modinv(&self.coefficient(0), &self.prime).unwrap_or_default();
+        self.constant(inverse)
+    }
+}
+impl Display for Polynomial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} over GF({})", self.to_expr("x"), self.prime)
+    }
+}
+
+/// The finite field GF(p^n), whose elements are the polynomials over GF(p) of a degree below n,
+/// with arithmetic done modulo an irreducible polynomial of degree n
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FiniteField {
+    modulus: Polynomial,
+}
+impl FiniteField {
+    /// The field modulo the given polynomial, which fails if the polynomial is not irreducible
+    pub fn new(modulus: Polynomial) -> Result<FiniteField> {
+        if !modulus.is_irreducible()? {
+            bail!(
+                "{} is not irreducible, so it does not give a field",
+                modulus
+            )
+        }
+        Ok(FiniteField { modulus })
+    }
+
+    /// How many elements the field has, p^n
+    pub fn order(&self) -> BigInt {
+        let degree = self.modulus.degree().unwrap_or(0);
+        self.modulus.prime.pow(degree as u32)
+    }
+
+    /// The element a polynomial stands for, its remainder modulo the field's polynomial
+    pub fn element(&self, polynomial: &Polynomial) -> Result<Polynomial> {
+        Ok(polynomial.div_rem(&self.modulus)?.1)
+    }
+
+    pub fn add(&self, lhs: &Polynomial, rhs: &Polynomial) -> Result<Polynomial> {
+        self.element(&lhs.checked_add(rhs)?)
+    }
+
+    pub fn sub(&self, lhs: &Polynomial, rhs: &Polynomial) -> Result<Polynomial> {
+        self.element(&lhs.checked_sub(rhs)?)
+    }
+
+    pub fn mul(&self, lhs: &Polynomial, rhs: &Polynomial) -> Result<Polynomial> {
+        self.element(&self.element(lhs)?.checked_mul(&self.element(rhs)?)?)
+    }
+
+    /// The inverse of a nonzero element, from the extended Euclidean algorithm
+    pub fn inverse(&self, element: &Polynomial) -> Result<Polynomial> {
+        let element = self.element(element)?;
+        if element.is_zero() {
+            bail!("0 has no inverse in GF({})", self.order())
+        }
+
+        // Keeps a = s * element modulo the field's polynomial throughout
+        let (mut a, mut b) = (element.clone(), self.modulus.clone());
+        let (mut s, mut t) = (
+            element.constant(BigInt::from(1)),
+            element.constant(BigInt::from(0)),
+        );
+        while !b.is_zero() {
+            let (quotient, remainder) = a.div_rem(&b)?;
+            let next = s.checked_sub(&quotient.checked_mul(&t)?)?;
+            (a, b) = (b, remainder);
+            (s, t) = (t, next);
+        }
+
+        // The modulus is irreducible, so a is a nonzero constant
+        let inverse = a.inverse_constant();
+        self.element(&s.checked_mul(&inverse)?)
+    }
+
+    pub fn div(&self, lhs: &Polynomial, rhs: &Polynomial) -> Result<Polynomial> {
+        self.mul(lhs, &self.inverse(rhs)?)
+    }
+
+    /// Raises an element to any integer power, using the inverse for a negative one
+    pub fn pow(&self, element: &Polynomial, exponent: &BigInt) -> Result<Polynomial> {
+        match exponent.sign() {
+            Sign::Minus => self.inverse(element)?.pow_mod(&-exponent, &self.modulus),
+            _ => element.pow_mod(exponent, &self.modulus),
+        }
+    }
+}
+
+fn is_zero(value: &BigInt) -> bool {
+    value.sign() == Sign::NoSign
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::{Context, get_default_functions};
+
+    fn poly(input: &str, prime: i64) -> Polynomial {
+        let context = Context {
+            functions: get_default_functions(),
+            ..Context::new()
+        };
+        let expr = Expr::parse(input, &context).unwrap();
+        Polynomial::from_expr(&expr, "x", &prime.into()).unwrap()
+    }
+
+    fn ints(values: &[i64]) -> Vec<BigInt> {
+        values.iter().map(|value| BigInt::from(*value)).collect()
+    }
+
+    #[test]
+    fn reads_and_writes_polynomials() {
+        assert_eq!(poly("(x + 1)^2 - 3*x", 5).coefficients(), ints(&[1, 4, 1]));
+        assert_eq!(poly("x / 2 + 1/3", 7).coefficients(), ints(&[5, 4]));
+        assert_eq!(poly("7*x^3 + 7", 7).degree(), None);
+        assert_eq!(
+            poly("(x + 1)^2 - 3*x", 5).to_expr("x").to_string(),
+            "x^2 + 4 * x + 1"
+        );
+
+        let expr = |input: &str| Expr::parse(input, &Context::pure_math()).unwrap();
+        let prime = BigInt::from(5);
+        assert!(Polynomial::from_expr(&expr("x + y"), "x", &prime).is_err());
+        assert!(Polynomial::from_expr(&expr("1 / x"), "x", &prime).is_err());
+        assert!(Polynomial::from_expr(&expr("x ^ 2000"), "x", &prime).is_err());
+        assert!(Polynomial::from_expr(&expr("x + 1"), "x", &BigInt::from(6)).is_err());
+    }
+
+    #[test]
+    fn polynomial_arithmetic_over_gf_p() {
+        // (x + 1)(x + 2) = x^2 + 3x + 2, so it divides evenly
+        let (quotient, remainder) = poly("x^2 + 3*x + 2", 7).div_rem(&poly("x + 1", 7)).unwrap();
+        assert_eq!(quotient, poly("x + 2", 7));
+        assert!(remainder.is_zero());
+
+        // Over GF(2), x^2 + 1 = (x + 1)^2
+        assert_eq!(poly("x + 1", 2).pow(&2.into()).unwrap(), poly("x^2 + 1", 2));
+        let gcd = poly("x^2 + 1", 2).gcd(&poly("x^3 + 1", 2)).unwrap();
+        assert_eq!(gcd, poly("x + 1", 2));
+        assert!(poly("x", 2).div_rem(&poly("0", 2)).is_err());
+        assert!(poly("x", 2).checked_add(&poly("x", 3)).is_err());
+    }
+
+    #[test]
+    fn tests_irreducibility() {
+        assert!(poly("x^2 + x + 1", 2).is_irreducible().unwrap());
+        assert!(!poly("x^2 + 1", 2).is_irreducible().unwrap());
+        // The polynomial behind AES's GF(2^8)
+        assert!(poly("x^8 + x^4 + x^3 + x + 1", 2).is_irreducible().unwrap());
+        // Neither has a root modulo 3, but only the first is irreducible
+        assert!(poly("x^2 + 1", 3).is_irreducible().unwrap());
+        assert!(!poly("(x^2 + 1)*(x^2 + x + 2)", 3).is_irreducible().unwrap());
+        assert!(poly("3", 5).is_irreducible().is_err());
+    }
+
+    #[test]
+    fn arithmetic_in_gf_p_n() {
+        let field = FiniteField::new(poly("x^8 + x^4 + x^3 + x + 1", 2)).unwrap();
+        assert_eq!(field.order(), BigInt::from(256));
+
+        // The example from FIPS 197: {57} * {83} = {c1}
+        let product = field
+            .mul(&poly("x^6 + x^4 + x^2 + x + 1", 2), &poly("x^7 + x + 1", 2))
+            .unwrap();
+        assert_eq!(product, poly("x^7 + x^6 + 1", 2));
+
+        // and {53} and {ca} are each other's inverses
+        let inverse = field.inverse(&poly("x^6 + x^4 + x + 1", 2)).unwrap();
+        assert_eq!(inverse, poly("x^7 + x^6 + x^3 + x", 2));
+
+        // Every nonzero element to the power of p^n - 1 is one
+        let element = poly("x^5 + x^2", 2);
+        assert_eq!(field.pow(&element, &255.into()).unwrap(), poly("1", 2));
+        let back = field.pow(&element, &(-1).into()).unwrap();
+        assert_eq!(field.mul(&back, &element).unwrap(), poly("1", 2));
+        assert!(field.inverse(&poly("0", 2)).is_err());
+
+        assert!(FiniteField::new(poly("x^2 + 1", 2)).is_err());
+    }
+}
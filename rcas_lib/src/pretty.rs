@@ -0,0 +1,407 @@
+use crate::expression::{Expr, operand_parens};
+use crate::token_defs::Token;
+use serde::{Deserialize, Serialize};
+
+/// Which characters the pretty printer draws with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrettyStyle {
+    /// Only ASCII, e.g. -- for fraction bars and \/ for roots
+    Ascii,
+    /// Box drawing characters and greek letters
+    Unicode,
+}
+impl PrettyStyle {
+    /// Looks a style up by its name, as given to the PrettyPrint command
+    pub fn from_name(name: &str) -> Option<PrettyStyle> {
+        match name.to_lowercase().as_str() {
+            "ascii" => Some(PrettyStyle::Ascii),
+            "unicode" => Some(PrettyStyle::Unicode),
+            _ => None,
+        }
+    }
+
+    fn pick(&self, ascii: &'static str, unicode: &'static str) -> &'static str {
+        match self {
+            PrettyStyle::Ascii => ascii,
+            PrettyStyle::Unicode => unicode,
+        }
+    }
+}
+
+/// Greek letters by name, as drawn in place of a symbol of that name
+pub(crate) const GREEK_LETTERS: [(&str, &str); 24] = [
+    ("alpha", "α"),
+    ("beta", "β"),
+    ("gamma", "γ"),
+    ("delta", "δ"),
+    ("epsilon", "ε"),
+    ("zeta", "ζ"),
+    ("eta", "η"),
+    ("theta", "θ"),
+    ("kappa", "κ"),
+    ("lambda", "λ"),
+    ("mu", "μ"),
+    ("nu", "ν"),
+    ("xi", "ξ"),
+    ("pi", "π"),
+    ("rho", "ρ"),
+    ("sigma", "σ"),
+    ("tau", "τ"),
+    ("phi", "φ"),
+    ("chi", "χ"),
+    ("psi", "ψ"),
+    ("omega", "ω"),
+    ("Gamma", "Γ"),
+    ("Delta", "Δ"),
+    ("Omega", "Ω"),
+];
+
+/// A rectangle of text. Every line is padded to the same width, and the baseline is the line
+/// which lines up with the text around it, e.g. the bar of a fraction.
+#[derive(Debug, Clone)]
+struct Block {
+    lines: Vec<String>,
+    baseline: usize,
+    width: usize,
+}
+impl Block {
+    fn text(text: &str) -> Block {
+        Block {
+            lines: vec![text.to_string()],
+            baseline: 0,
+            width: text.chars().count(),
+        }
+    }
+
+    fn height(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Puts blocks side by side, lining up their baselines
+    fn beside(blocks: &[Block]) -> Block {
+        let above = blocks.iter().map(|b| b.baseline).max().unwrap_or(0);
+        let below = blocks
+            .iter()
+            .map(|b| b.height() - b.baseline)
+            .max()
+            .unwrap_or(1);
+
+        let mut lines = vec![String::new(); above + below];
+        for block in blocks {
+            let top = above - block.baseline;
+            for (idx, line) in lines.iter_mut().enumerate() {
+                match idx.checked_sub(top).and_then(|idx| block.lines.get(idx)) {
+                    Some(text) => line.push_str(text),
+                    None => line.push_str(&" ".repeat(block.width)),
+                }
+            }
+        }
+
+        Block {
+            lines,
+            baseline: above,
+            width: blocks.iter().map(|b| b.width).sum(),
+        }
+    }
+
+    /// Pads the block with spaces to the given width, keeping it centred
+    fn centred(&self, width: usize) -> Block {
+        let left = (width - self.width) / 2;
+        let right = width - self.width - left;
+        Block {
+            lines: self
+                .lines
+                .iter()
+                .map(|line| format!("{}{}{}", " ".repeat(left), line, " ".repeat(right)))
+                .collect(),
+            baseline: self.baseline,
+            width,
+        }
+    }
+
+    /// A column of characters as tall as this block: `top` and `bottom` at the ends and `middle`
+    /// in between, or `single` if the block is one line tall
+    fn column(&self, single: &str, top: &str, middle: &str, bottom: &str) -> Block {
+        let height = self.height();
+        let lines = match height {
+            1 => vec![single.to_string()],
+            _ => (0..height)
+                .map(|idx| match idx {
+                    0 => top,
+                    idx if idx == height - 1 => bottom,
+                    _ => middle,
+                })
+                .map(str::to_string)
+                .collect(),
+        };
+
+        Block {
+            lines,
+            baseline: self.baseline,
+            width: single.chars().count(),
+        }
+    }
+
+    fn parens(&self, style: PrettyStyle) -> Block {
+        let left = self.column(
+            "(",
+            style.pick("/", "⎛"),
+            style.pick("|", "⎜"),
+            style.pick("\\", "⎝"),
+        );
+        let right = self.column(
+            ")",
+            style.pick("\\", "⎞"),
+            style.pick("|", "⎟"),
+            style.pick("/", "⎠"),
+        );
+        Block::beside(&[left, self.clone(), right])
+    }
+
+    fn fraction(numerator: Block, denominator: Block, style: PrettyStyle) -> Block {
+        let width = numerator.width.max(denominator.width) + 2;
+        let mut lines = numerator.centred(width).lines;
+        lines.push(style.pick("-", "─").repeat(width));
+        lines.extend(denominator.centred(width).lines);
+
+        Block {
+            lines,
+            baseline: numerator.height(),
+            width,
+        }
+    }
+
+    /// Raises the exponent so its last line sits just above the first line of the base
+    fn power(base: Block, exponent: Block) -> Block {
+        let mut lines = exponent
+            .lines
+            .iter()
+            .map(|line| format!("{}{}", " ".repeat(base.width), line))
+            .collect::<Vec<_>>();
+        lines.extend(
+            base.lines
+                .iter()
+                .map(|line| format!("{}{}", line, " ".repeat(exponent.width))),
+        );
+
+        Block {
+            lines,
+            baseline: exponent.height() + base.baseline,
+            width: base.width + exponent.width,
+        }
+    }
+
+    /// Draws a radical sign, its slope as tall as the radicand, with a bar over the radicand
+    fn root(radicand: Block, style: PrettyStyle) -> Block {
+        let height = radicand.height();
+        let (slope, foot) = (style.pick("/", "╱"), style.pick("\\", "╲"));
+
+        let mut lines = vec![format!(
+            "{}{}",
+            " ".repeat(height + 1),
+            "_".repeat(radicand.width)
+        )];
+        for (idx, line) in radicand.lines.iter().enumerate() {
+            let lead = match idx {
+                idx if idx == height - 1 => foot.to_string(),
+                _ => " ".repeat(height - idx),
+            };
+            lines.push(format!("{}{}{}{}", lead, slope, " ".repeat(idx), line));
+        }
+
+        Block {
+            lines,
+            baseline: radicand.baseline + 1,
+            width: height + 1 + radicand.width,
+        }
+    }
+
+    /// Stacks the rows of a matrix, each entry centred in its column, inside brackets
+    fn matrix(rows: Vec<Vec<Block>>, style: PrettyStyle) -> Block {
+        let columns = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        // Nothing to stack, and a body with no lines has no middle to put the baseline on
+        if columns == 0 {
+            return Block::text("[ ]");
+        }
+        let widths = (0..columns)
+            .map(|col| {
+                rows.iter()
+                    .filter_map(|row| row.get(col))
+                    .map(|b| b.width)
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+        let tall = rows.iter().flatten().any(|b| b.height() > 1);
+
+        let mut lines = vec![];
+        for (idx, row) in rows.iter().enumerate() {
+            if tall && idx > 0 {
+                lines.push(String::new());
+            }
+
+            let mut cells = vec![];
+            for (col, cell) in row.iter().enumerate() {
+                if col > 0 {
+                    cells.push(Block::text("  "));
+                }
+                cells.push(cell.centred(widths[col]));
+            }
+            lines.extend(Block::beside(&cells).lines);
+        }
+
+        let width = widths.iter().sum::<usize>() + 2 * columns.saturating_sub(1);
+        let body = Block {
+            lines: lines
+                .into_iter()
+                .map(|line| format!("{:<width$}", line, width = width))
+                .collect(),
+            baseline: 0,
+            width,
+        };
+        let body = Block {
+            baseline: (body.height() - 1) / 2,
+            ..body
+        };
+
+        let left = body.column(
+            "[",
+            style.pick("[", "⎡"),
+            style.pick("[", "⎢"),
+            style.pick("[", "⎣"),
+        );
+        let right = body.column(
+            "]",
+            style.pick("]", "⎤"),
+            style.pick("]", "⎥"),
+            style.pick("]", "⎦"),
+        );
+        Block::beside(&[left, Block::text(" "), body, Block::text(" "), right])
+    }
+}
+
+impl Expr {
+    /// Draws the expression over several lines, with fractions over bars, raised exponents,
+    /// radical signs and bracketed matrices
+    pub fn to_pretty(&self, style: PrettyStyle) -> String {
+        let block = self.layout(style);
+        block
+            .lines
+            .iter()
+            .map(|line| line.trim_end())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn layout(&self, style: PrettyStyle) -> Block {
+        match self {
+            Expr::Num(num) => Block::text(&num.to_string()),
+            Expr::Symbol(name) => match GREEK_LETTERS.iter().find(|(greek, _)| greek == name) {
+                Some((_, letter)) if style == PrettyStyle::Unicode => Block::text(letter),
+                _ => Block::text(name),
+            },
+            Expr::Apply(name, args) if name == "-" && args.len() == 1 => {
+                let operand = args[0].layout_within(self, style);
+                Block::beside(&[Block::text("-"), operand])
+            }
+            Expr::Apply(name, args) if name == "/" && args.len() == 2 => {
+                Block::fraction(args[0].layout(style), args[1].layout(style), style)
+            }
+            Expr::Apply(name, args) if name == "^" && args.len() == 2 => {
+                let base = args[0].layout(style);
+                let base = if args[0].stacked_precedence() <= self.stacked_precedence() {
+                    base.parens(style)
+                } else {
+                    base
+                };
+                Block::power(base, args[1].layout(style))
+            }
+            Expr::Apply(name, args) if self.precedence() < 5 => {
+                let (lhs_parens, rhs_parens) = operand_parens(
+                    name,
+                    self.stacked_precedence(),
+                    (args[0].stacked_precedence(), &args[0].to_string()),
+                    (args[1].stacked_precedence(), &args[1].to_string()),
+                );
+                let parens = |block: Block, parens: bool| match parens {
+                    true => block.parens(style),
+                    false => block,
+                };
+
+                let operator = match name.as_str() {
+                    "*" => style.pick(" * ", " · "),
+                    name => &format!(" {} ", name),
+                };
+                Block::beside(&[
+                    parens(args[0].layout(style), lhs_parens),
+                    Block::text(operator),
+                    parens(args[1].layout(style), rhs_parens),
+                ])
+            }
+            Expr::Apply(name, args) if name == "sqrt" && args.len() == 1 => {
+                Block::root(args[0].layout(style), style)
+            }
+            Expr::Apply(name, args) if name == "abs" && args.len() == 1 => {
+                let operand = args[0].layout(style);
+                let bar = style.pick("|", "│");
+                let bar = operand.column(bar, bar, bar, bar);
+                Block::beside(&[bar.clone(), operand, bar])
+            }
+            Expr::Apply(name, args) => {
+                let mut inner = vec![];
+                for (idx, arg) in args.iter().enumerate() {
+                    if idx > 0 {
+                        inner.push(Block::text(", "));
+                    }
+                    inner.push(arg.layout(style));
+                }
+                let inner = match inner.is_empty() {
+                    true => Block::text(""),
+                    false => Block::beside(&inner),
+                };
+                Block::beside(&[Block::text(name), inner.parens(style)])
+            }
+            Expr::Matrix(rows) => {
+                let rows = rows
+                    .iter()
+                    .map(|row| row.iter().map(|e| e.layout(style)).collect())
+                    .collect();
+                Block::matrix(rows, style)
+            }
+        }
+    }
+
+    /// Lays out an operand of `parent`, in parentheses if it binds more loosely than the parent
+    fn layout_within(&self, parent: &Expr, style: PrettyStyle) -> Block {
+        let block = self.layout(style);
+        if self.stacked_precedence() < parent.stacked_precedence() {
+            block.parens(style)
+        } else {
+            block
+        }
+    }
+}
+
+impl Token {
+    /// The token drawn by the pretty printer if it is an expression, see `Expr::to_pretty`, or
+    /// the same as its `Display` otherwise
+    pub fn to_pretty(&self, style: PrettyStyle) -> String {
+        match self {
+            Token::Expr(expr) => expr.to_pretty(style),
+            other => other.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_empty_matrices() {
+        for style in [PrettyStyle::Ascii, PrettyStyle::Unicode] {
+            assert_eq!(Expr::Matrix(vec![]).to_pretty(style), "[ ]");
+            assert_eq!(Expr::Matrix(vec![vec![], vec![]]).to_pretty(style), "[ ]");
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::token_defs::Token;
+use hashbrown::HashMap;
+use std::io::Write;
+use std::time::Duration;
+
+/// What the calls to one functor on one line (or everything summed over some of those) added up to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProfileEntry {
+    pub calls: usize,
+    pub total: Duration,
+    /// Bit length of the largest integer any of the calls returned, counting the parts of
+    /// fractions and the moduli of modular numbers
+    pub peak_bits: u64,
+}
+impl ProfileEntry {
+    fn add(&mut self, other: &ProfileEntry) {
+        self.calls += other.calls;
+        self.total += other.total;
+        self.peak_bits = self.peak_bits.max(other.peak_bits);
+    }
+}
+
+/// Records how often each functor is called on each line, how long that takes and how big the
+/// integers it returns get. Turned on with `BufferedExecutor::enable_profiling`.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    /// Keyed by the index of the line and the name of the functor
+    pub calls: HashMap<(usize, String), ProfileEntry>,
+}
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        line: usize,
+        name: String,
+        elapsed: Duration,
+        results: &[Token],
+    ) {
+        let peak_bits = results
+            .iter()
+            .map(|token| match token {
+                Token::Const(num) => num.int_bits(),
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0);
+
+        self.calls
+            .entry((line, name))
+            .or_default()
+            .add(&ProfileEntry {
+                calls: 1,
+                total: elapsed,
+                peak_bits,
+            });
+    }
+
+    /// Totals for each functor, slowest first
+    pub fn by_functor(&self) -> Vec<(String, ProfileEntry)> {
+        let mut totals = HashMap::<String, ProfileEntry>::new();
+        for ((_, name), entry) in &self.calls {
+            totals.entry(name.clone()).or_default().add(entry);
+        }
+        sorted(totals)
+    }
+
+    /// Totals for each line, slowest first
+    pub fn by_line(&self) -> Vec<(usize, ProfileEntry)> {
+        let mut totals = HashMap::<usize, ProfileEntry>::new();
+        for ((line, _), entry) in &self.calls {
+            totals.entry(*line).or_default().add(entry);
+        }
+        sorted(totals)
+    }
+
+    /// A table of the totals for each functor and then for each line, slowest first. Lines are
+    /// shown with `line_name`, so callers can show the source line they came from rather than the
+    /// index of the executor line.
+    pub fn report(&self, line_name: impl Fn(usize) -> String) -> String {
+        let mut report = format!(
+            "{:<24} {:>10} {:>14} {:>10}\n",
+            "functor", "calls", "total", "peak bits"
+        );
+        for (name, entry) in self.by_functor() {
+            report.push_str(&report_row(&name, &entry));
+        }
+
+        report.push_str(&format!(
+            "\n{:<24} {:>10} {:>14} {:>10}\n",
+            "line", "calls", "total", "peak bits"
+        ));
+        for (line, entry) in self.by_line() {
+            report.push_str(&report_row(&line_name(line), &entry));
+        }
+
+        report
+    }
+
+    /// Writes the calls in the folded stack format read by flamegraph tools, one `line;functor`
+    /// stack per row weighted by its total time in microseconds
+    pub fn write_folded(
+        &self,
+        mut out: impl Write,
+        line_name: impl Fn(usize) -> String,
+    ) -> std::io::Result<()> {
+        let mut calls = self.calls.iter().collect::<Vec<_>>();
+        calls.sort_by(|a, b| a.0.cmp(b.0));
+
+        for ((line, name), entry) in calls {
+            // ; separates frames and spaces separate the weight, so neither can be in a frame
+            let frame = |text: String| text.replace([';', ' '], "_");
+            writeln!(
+                out,
+                "{};{} {}",
+                frame(line_name(*line)),
+                frame(name.clone()),
+                entry.total.as_micros()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn sorted<K>(totals: HashMap<K, ProfileEntry>) -> Vec<(K, ProfileEntry)> {
+    let mut totals = totals.into_iter().collect::<Vec<_>>();
+    totals.sort_by_key(|entry| std::cmp::Reverse(entry.1.total));
+    totals
+}
+
+fn report_row(name: &str, entry: &ProfileEntry) -> String {
+    format!(
+        "{:<24} {:>10} {:>14} {:>10}\n",
+        name,
+        entry.calls,
+        format!("{:.3?}", entry.total),
+        entry.peak_bits
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::number::Number;
+    use crate::parse_rpol_notation::{commands_to_sequential_exec_order, split_into_commands};
+    use crate::stack_machine::{BufferedExecutor, RevPolStackMachine};
+    use num_bigint::BigInt;
+
+    fn profile(script: &str) -> Profiler {
+        crate::set_debug_logging(false);
+        let context = Context::pure_math();
+        let lines =
+            commands_to_sequential_exec_order(split_into_commands(script.to_string()), &context)
+                .unwrap();
+        let machine = RevPolStackMachine::new_with_ctx(context);
+        let mut executor = BufferedExecutor::new(machine, lines);
+        executor.enable_profiling();
+        executor.run_stack().unwrap();
+        executor.profiler.unwrap()
+    }
+
+    fn entry(calls: usize, total: u64, peak_bits: u64) -> ProfileEntry {
+        ProfileEntry {
+            calls,
+            total: Duration::from_micros(total),
+            peak_bits,
+        }
+    }
+
+    fn int(value: BigInt) -> Token {
+        Token::Const(Number::Int(value))
+    }
+
+    #[test]
+    fn counts_calls_on_each_line() {
+        let profiler = profile("2 3 +\n4 5 *\n6 *\n2 100 ^");
+        let entry = |line: usize, name: &str| profiler.calls[&(line, name.to_string())];
+        assert_eq!(profiler.calls.len(), 4);
+        assert_eq!(entry(0, "+").calls, 1);
+        assert_eq!(entry(2, "*").calls, 1);
+        assert_eq!(entry(2, "*").peak_bits, 7);
+        assert_eq!(entry(3, "^").peak_bits, 101);
+
+        let by_functor = profiler.by_functor();
+        let (_, times) = by_functor.iter().find(|(name, _)| name == "*").unwrap();
+        assert_eq!(times.calls, 2);
+        assert_eq!(profiler.by_line().len(), 4);
+    }
+
+    #[test]
+    fn measures_every_exact_number() {
+        let mut profiler = Profiler::new();
+        let big = BigInt::from(1) << 80u32;
+        let results = [
+            Token::Const(Number::rational(BigInt::from(1), big.clone()).unwrap()),
+            Token::Const(Number::Float(3.0.into())),
+        ];
+        profiler.record(0, "/".to_string(), Duration::ZERO, &results);
+        let modular = Token::Const(Number::modular(BigInt::from(2), big.clone() + 1).unwrap());
+        profiler.record(1, "Mod".to_string(), Duration::ZERO, &[modular]);
+        profiler.record(2, "+".to_string(), Duration::ZERO, &[int(-big)]);
+
+        for line in 0..3 {
+            assert_eq!(profiler.by_line()[line].1.peak_bits, 81);
+        }
+        profiler.record(3, "+".to_string(), Duration::ZERO, &[]);
+        assert_eq!(profiler.calls[&(3, "+".to_string())].peak_bits, 0);
+    }
+
+    #[test]
+    fn sums_and_sorts_totals() {
+        let mut profiler = Profiler::new();
+        profiler.calls.insert((0, "+".to_string()), entry(2, 10, 3));
+        profiler.calls.insert((0, "*".to_string()), entry(1, 45, 8));
+        profiler.calls.insert((1, "+".to_string()), entry(5, 50, 4));
+
+        assert_eq!(
+            profiler.by_functor(),
+            [
+                ("+".to_string(), entry(7, 60, 4)),
+                ("*".to_string(), entry(1, 45, 8))
+            ]
+        );
+        assert_eq!(
+            profiler.by_line(),
+            [(0, entry(3, 55, 8)), (1, entry(5, 50, 4))]
+        );
+
+        let report = profiler.report(|line| format!("line {}", line + 1));
+        let rows = report.lines().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 7);
+        assert!(rows[0].starts_with("functor") && rows[0].ends_with("peak bits"));
+        assert_eq!(
+            rows[1].split_whitespace().collect::<Vec<_>>(),
+            ["+", "7", "60.000µs", "4"]
+        );
+        assert!(rows[3].is_empty() && rows[4].starts_with("line"));
+        assert!(rows[5].starts_with("line 1 "));
+    }
+
+    #[test]
+    fn writes_folded_stacks() {
+        let mut profiler = Profiler::new();
+        profiler.calls.insert((1, "+".to_string()), entry(1, 25, 0));
+        profiler
+            .calls
+            .insert((0, "a;b c".to_string()), entry(3, 1500, 0));
+
+        let mut out = vec![];
+        profiler
+            .write_folded(&mut out, |line| format!("x = {}", line))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "x_=_0;a_b_c 1500\nx_=_1;+ 25\n"
+        );
+    }
+}
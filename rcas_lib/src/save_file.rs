@@ -0,0 +1,208 @@
+use crate::context::Context;
+use crate::token_defs::{Functor, Token};
+use anyhow::{Context as _, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// Marks a file as a saved machine, so that loading some other JSON file fails clearly
+const FORMAT: &str = "rcas-machine";
+
+/// Version of the save file format written by this build. Files from older versions can still be
+/// loaded, files from newer ones cannot.
+pub const SAVE_VERSION: u32 = 1;
+
+/// A saved machine: its stack, and its context with the variables, functions and any settings.
+/// Functions are saved by name, and are looked up among the default functions when loaded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedMachine {
+    pub format: String,
+    pub version: u32,
+    pub stack: Vec<Token>,
+    pub context: Context,
+}
+
+impl SavedMachine {
+    /// Checks that every functor on the saved stack and in the saved variables is one of
+    /// `functions`, so that loading a file cannot hand a context a function it was not given
+    pub fn check_functions(&self, functions: &[Functor]) -> Result<()> {
+        let values = self
+            .context
+            .variables
+            .iter()
+            .filter_map(|var| var.value.as_deref());
+        for token in self.stack.iter().chain(values) {
+            check_functor(token, functions)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_functor(token: &Token, functions: &[Functor]) -> Result<()> {
+    match token {
+        Token::Functor(func) if !functions.contains(func) => {
+            bail!("The save file uses {}, which is not available", func.name)
+        }
+        Token::Variable(var) => match &var.value {
+            Some(value) => check_functor(value, functions),
+            None => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// The same as SavedMachine, but borrowing, so a machine does not need copying to be saved
+#[derive(Serialize)]
+struct SavedMachineRef<'a> {
+    format: &'a str,
+    version: u32,
+    stack: &'a [Token],
+    context: &'a Context,
+}
+
+pub fn write_machine(stack: &[Token], context: &Context, out: impl Write) -> Result<()> {
+    let saved = SavedMachineRef {
+        format: FORMAT,
+        version: SAVE_VERSION,
+        stack,
+        context,
+    };
+    serde_json::to_writer_pretty(out, &saved)?;
+    Ok(())
+}
+
+pub fn read_machine(input: impl Read) -> Result<SavedMachine> {
+    // The header is checked first, so a file from a newer version is reported as such rather than
+    // as whatever part of it this version cannot make sense of
+    #[derive(Deserialize)]
+    struct Header {
+        format: String,
+        version: u32,
+    }
+
+    let value = serde_json::from_reader::<_, serde_json::Value>(input)?;
+    let header = Header::deserialize(&value).context("Not a saved machine")?;
+    if header.format != FORMAT {
+        bail!("Not a saved machine, the format is {}", header.format)
+    }
+    if header.version > SAVE_VERSION {
+        bail!(
+            "The machine was saved by a newer version (format version {}, this reads up to {})",
+            header.version,
+            SAVE_VERSION
+        )
+    }
+
+    Ok(SavedMachine::deserialize(value)?)
+}
+
+/// Saves a stack and context to a file
+pub fn save_machine(stack: &[Token], context: &Context, path: &str) -> Result<()> {
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to create save file <{}>", path))?;
+    let mut out = std::io::BufWriter::new(file);
+    write_machine(stack, context, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Loads a stack and context saved by `save_machine`
+pub fn load_machine(path: &str) -> Result<SavedMachine> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open save file <{}>", path))?;
+    read_machine(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to load save file <{}>", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::{Capability, find_default_function, get_default_functions};
+    use crate::number::Number;
+    use crate::parse_rpol_notation::{commands_to_sequential_exec_order, split_into_commands};
+    use crate::stack_machine::{BufferedExecutor, RevPolStackMachine};
+    use crate::token_defs::Variable;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Tests run at the same time, so each load needs a file of its own
+    fn temp_path() -> String {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rcas_load_{}_{}.json",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        path.to_str().unwrap().to_string()
+    }
+
+    /// Saves the stack and variables to a file, then loads it into a pure math machine which is
+    /// allowed to load files
+    fn load_into_pure_math(stack: &[Token], variables: Vec<Variable>) -> Result<Vec<Token>> {
+        crate::set_debug_logging(false);
+        let path = temp_path();
+        let saved = Context {
+            variables,
+            ..Context::new()
+        };
+        save_machine(stack, &saved, &path)?;
+
+        let mut context = Context::pure_math();
+        let load = find_default_function("Load").unwrap();
+        context.functions.push(load);
+        let script = format!("\"{}\" Load", path);
+        let lines = commands_to_sequential_exec_order(split_into_commands(script), &context)?;
+        let machine = RevPolStackMachine::new_with_ctx(context);
+        let mut executor = BufferedExecutor::new(machine, lines);
+        let result = executor.run_stack();
+        std::fs::remove_file(&path)?;
+        result?;
+        Ok(executor.machine.stack)
+    }
+
+    fn functor(name: &str) -> Token {
+        Token::Functor(find_default_function(name).unwrap())
+    }
+
+    #[test]
+    fn loads_functors_the_context_has() {
+        let stack = vec![Token::Const(Number::Int(2.into()))];
+        let variables = vec![Variable {
+            name: "f".into(),
+            value: Some(Box::new(functor("+"))),
+        }];
+        assert_eq!(load_into_pure_math(&stack, variables).unwrap(), stack);
+    }
+
+    #[test]
+    fn refuses_functors_the_context_lacks() {
+        let stack = vec![Token::Const(Number::Int(1.into())), functor("Print")];
+        assert!(load_into_pure_math(&stack, vec![]).is_err());
+
+        // Also when hidden in a variable, or in a variable inside a variable
+        let hidden = Variable {
+            name: "inner".into(),
+            value: Some(Box::new(functor("Print"))),
+        };
+        let variables = vec![Variable {
+            name: "x".into(),
+            value: Some(Box::new(Token::Variable(hidden))),
+        }];
+        assert!(load_into_pure_math(&[], variables).is_err());
+    }
+
+    #[test]
+    fn loads_machines_only_with_allowed_functions() {
+        let path = temp_path();
+        let context = Context {
+            functions: get_default_functions(),
+            ..Context::new()
+        };
+        save_machine(&[], &context, &path).unwrap();
+
+        let pure = RevPolStackMachine::load(&path, &Capability::PURE_MATH);
+        let all = RevPolStackMachine::load(&path, &Capability::ALL);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(pure.is_err());
+        assert_eq!(all.unwrap().context.functions, context.functions);
+    }
+}
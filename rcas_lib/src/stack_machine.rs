@@ -1,26 +1,148 @@
-use crate::context::Context;
+use crate::context::{Capability, Context, get_functions_with};
 use crate::debug;
+use crate::history::{MachineState, UndoHistory};
+use crate::limits::ExecutionLimits;
+use crate::profiler::Profiler;
+use crate::save_file::{load_machine, save_machine};
 use crate::token_defs::Token;
 use anyhow::{Result, bail};
+use std::time::Instant;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct RevPolStackMachine {
     pub stack: Vec<Token>,
     pub context: Context,
+    /// Committed states which can be undone and redone, None until `enable_history` or
+    /// `enable_rollback` is called
+    pub history: Option<UndoHistory>,
+    /// Number of tokens the functor run by the last call to next took off the stack, or 0 if that
+    /// did not run a functor
+    pub last_consumed: usize,
 }
 impl RevPolStackMachine {
     pub fn new() -> Self {
-        return RevPolStackMachine {
+        RevPolStackMachine {
             stack: vec![],
             context: Context::new(),
-        };
+            history: None,
+            last_consumed: 0,
+        }
     }
 
     pub fn new_with_ctx(context: Context) -> Self {
-        return RevPolStackMachine {
+        RevPolStackMachine {
             stack: vec![],
             context,
+            history: None,
+            last_consumed: 0,
+        }
+    }
+
+    /// Saves the stack and context to a file, see `save_file`
+    pub fn save(&self, path: &str) -> Result<()> {
+        save_machine(&self.stack, &self.context, path)
+    }
+
+    /// Creates a machine from a file written by `save`, with the functions saved in it. Those all
+    /// have to belong to one of the given capability groups, so a file cannot grant a function the
+    /// caller would not have. It starts without any undo history.
+    pub fn load(path: &str, capabilities: &[Capability]) -> Result<Self> {
+        let saved = load_machine(path)?;
+        let allowed = get_functions_with(capabilities);
+        let functions = &saved.context.functions;
+        if let Some(func) = functions.iter().find(|func| !allowed.contains(func)) {
+            bail!("The save file uses {}, which is not available", func.name)
+        }
+        saved.check_functions(&saved.context.functions)?;
+        let mut machine = Self::new_with_ctx(saved.context);
+        machine.stack = saved.stack;
+        Ok(machine)
+    }
+
+    /// Starts keeping undo history, with the machine as it is now as the first state. This has to
+    /// be called again if the stack is ever shrunk by hand rather than by running functors.
+    pub fn enable_history(&mut self) {
+        self.history = Some(UndoHistory::new(&self.stack, &self.context));
+    }
+
+    /// Keeps just enough history to roll back whatever was done since the last commit, without
+    /// keeping anything to undo. BufferedExecutor does this for a machine without history.
+    pub fn enable_rollback(&mut self) {
+        self.history = Some(UndoHistory::without_undo(&self.stack, &self.context));
+    }
+
+    /// Whether committed states are kept to be undone, see `enable_history`
+    pub fn keeps_history(&self) -> bool {
+        self.history.as_ref().is_some_and(UndoHistory::keeps_undo)
+    }
+
+    /// Records the machine as it is now, so it can be returned to with `redo` after an `undo`.
+    /// BufferedExecutor commits after every line it runs. Does nothing without history.
+    pub fn commit(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.commit(&self.stack, &self.context);
+        }
+    }
+
+    /// Goes back to the state before the last commit, returning false if there is nothing to undo.
+    /// Anything done since the last commit is thrown away.
+    pub fn undo(&mut self) -> bool {
+        let Some(state) = self.history.as_mut().and_then(|history| history.undo()) else {
+            return false;
+        };
+        Self::restore(&mut self.stack, &mut self.context, state);
+        true
+    }
+
+    /// Goes forward to the last undone state, returning false if there is nothing to redo
+    pub fn redo(&mut self) -> bool {
+        let Some(state) = self.history.as_mut().and_then(|history| history.redo()) else {
+            return false;
         };
+        Self::restore(&mut self.stack, &mut self.context, state);
+        true
+    }
+
+    /// Throws away everything done since the last commit, e.g. after a line failed halfway through
+    pub fn rollback(&mut self) {
+        if let Some(history) = &mut self.history {
+            let state = history.rollback();
+            Self::restore(&mut self.stack, &mut self.context, state);
+        }
+    }
+
+    fn restore(stack: &mut Vec<Token>, context: &mut Context, state: &MachineState) {
+        *stack = state.stack.iter().cloned().collect();
+        // The limit and the output belong to whoever runs the machine rather than to the state
+        let (max_int_bits, output) = (context.max_int_bits, context.output.clone());
+        *context = Context::clone(&state.context);
+        context.max_int_bits = max_int_bits;
+        context.output = output;
+    }
+
+    /// Pops the top of the stack without running it, even if it is a functor
+    pub fn discard(&mut self) -> Option<Token> {
+        let token = self.stack.pop()?;
+        self.touch();
+        self.last_consumed = 0;
+        Some(token)
+    }
+
+    /// The bottom of the stack which has not changed since the last commit, taken from the history
+    /// so it is not copied. Empty without history.
+    #[cfg(feature = "debugger")]
+    pub(crate) fn unchanged_stack(&self) -> im::Vector<Token> {
+        match &self.history {
+            Some(history) => history.unchanged(self.stack.len()),
+            None => im::Vector::new(),
+        }
+    }
+
+    /// Tells the history how far down the stack has gone
+    pub(crate) fn touch(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.touch(self.stack.len());
+        }
     }
 }
 
@@ -29,25 +151,45 @@ impl Iterator for RevPolStackMachine {
 
     fn next(&mut self) -> Option<Self::Item> {
         // Check what's at the top of the stack
-        match self.stack.pop()? {
+        let token = self.stack.pop()?;
+        self.touch();
+        self.last_consumed = 0;
+
+        match token {
             Token::Const(const_val) => {
                 debug!("Popped Token::Const: {}", const_val);
-                return Some(Ok(Token::Const(const_val)));
+                Some(Ok(Token::Const(const_val)))
             }
             Token::Variable(var) => {
                 debug!("Popped Token::Variable: {}", var);
-                return Some(Ok(Token::Variable(var)));
+                Some(Ok(Token::Variable(var)))
             }
             Token::String(str) => {
                 debug!("Popped Token::String: {}", str);
-                return Some(Ok(Token::String(str)));
+                Some(Ok(Token::String(str)))
+            }
+            Token::Expr(expr) => {
+                debug!("Popped Token::Expr: {}", expr);
+                Some(Ok(Token::Expr(expr)))
+            }
+            Token::Symbol(name) => {
+                // Symbols are bound late, so look the name up in the context as it is right now
+                debug!("Popped Token::Symbol: {}", name);
+                Some(Ok(self.context.resolve(Token::Symbol(name))))
             }
             Token::Functor(func) => {
                 // Behaviour on popping a functor is to feed it the stack as an argument
                 // and feed the output to the stack again
                 // NOTE: This is where the functions are actually executed
                 debug!("Popped Token::Functor: {}", func.name);
+                if let Err(e) = func.check_args(&self.stack, &self.context) {
+                    return Some(Err(e));
+                }
+
+                let depth = self.stack.len();
                 let res = (func.func)(&mut self.stack, &mut self.context);
+                self.touch();
+                self.last_consumed = depth.saturating_sub(self.stack.len());
                 debug!("|-> Result: {:?}", res);
 
                 let mut result = match res {
@@ -62,7 +204,7 @@ impl Iterator for RevPolStackMachine {
     }
 }
 
-fn line_is_done(line: &Vec<Token>) -> bool {
+fn line_is_done(line: &[Token]) -> bool {
     !line.iter().any(|token| matches!(token, Token::Functor(_)))
 }
 
@@ -72,71 +214,193 @@ pub struct BufferedExecutor {
     pub machine: RevPolStackMachine,
     pub lines: Vec<Vec<Token>>,
     pub current_line: usize,
+    /// Change these with `set_limits`, which also hands the integer size limit to the functors
+    pub limits: ExecutionLimits,
+    /// Number of tokens popped off the stack so far, counted against `limits.max_steps`
+    pub steps: usize,
+    /// Whether the current line is on the stack, see `step`
+    line_started: bool,
+    /// Records every functor call when profiling is on, see `enable_profiling`
+    pub profiler: Option<Profiler>,
 }
 impl BufferedExecutor {
     pub fn new(machine: RevPolStackMachine, lines: Vec<Vec<Token>>) -> Self {
+        Self::new_with_limits(machine, lines, ExecutionLimits::new())
+    }
+
+    /// Creates an executor running the lines on the machine. A failed line is rolled back using the
+    /// machine's history, so a machine without any is given just enough to roll back with, see
+    /// `RevPolStackMachine::enable_rollback`.
+    pub fn new_with_limits(
+        mut machine: RevPolStackMachine,
+        lines: Vec<Vec<Token>>,
+        limits: ExecutionLimits,
+    ) -> Self {
+        machine.context.max_int_bits = limits.max_int_bits;
+        if machine.history.is_none() {
+            machine.enable_rollback();
+        }
+
         BufferedExecutor {
             machine,
             lines,
             current_line: 0,
+            limits,
+            steps: 0,
+            line_started: false,
+            profiler: None,
         }
     }
 
+    pub fn set_limits(&mut self, limits: ExecutionLimits) {
+        self.machine.context.max_int_bits = limits.max_int_bits;
+        self.limits = limits;
+    }
+
     /// Helper function: checks if the executor is 'done' (the current_line = lines.len())
     pub(crate) fn machine_is_complete(&self) -> bool {
-        let cond = self.current_line == self.lines.len();
-        cond
-    }
-
-    fn preprocess_str(&mut self, tokens: &mut Vec<Token>) -> Vec<Token> {
-        let mut res: Vec<Token> = Vec::new();
-
-        for token in tokens.iter() {
-            let rectx_token = match token {
-                Token::String(str) => {
-                    if let Some(var) = self
-                        .machine
-                        .context
-                        .variables
-                        .iter()
-                        .find(|p| p.name == *str)
-                    {
-                        // Clone the variable if needed, or use reference if your Token supports it
-                        Token::Variable(var.clone())
-                    } else {
-                        Token::String(str.clone())
-                    }
-                }
-                // Clone other tokens instead of returning
-                other => other.clone(),
-            };
-            res.push(rectx_token);
-        }
-
-        res
+        self.current_line == self.lines.len()
     }
 
     /// Helper function: populates the machine with the current line and then runs it until it
     /// completes, then increments the current line count.
     pub fn run_line(&mut self) -> Result<()> {
-        let mut line = self.lines[self.current_line].clone();
-        let mut line = self.preprocess_str(&mut line);
+        while !self.step()? {}
+        Ok(())
+    }
 
-        self.machine.stack.append(&mut line);
+    /// Starts recording how long each functor call takes, which can be read back from `profiler`
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
 
-        while !line_is_done(&self.machine.stack) {
-            let val = self.machine.next();
+    /// Whether the current line has been put on the stack but has not finished running yet
+    pub fn line_in_progress(&self) -> bool {
+        self.line_started
+    }
 
-            if let Some(Err(_)) = val {
-                bail!(
+    /// The token the machine will pop next, if a line is in progress or about to start
+    pub fn next_token(&self) -> Option<&Token> {
+        if self.line_started {
+            self.machine.stack.last()
+        } else {
+            self.lines.get(self.current_line)?.last()
+        }
+    }
+
+    /// Runs a single step of the current line, putting the line on the stack first if it has not
+    /// been yet. Returns whether that finished the line, in which case the current line count is
+    /// incremented. A failed line is rolled back to how the machine was before it started, so the
+    /// next step starts it again from scratch.
+    pub fn step(&mut self) -> Result<bool> {
+        self.advance(true)
+    }
+
+    /// Like `step`, but pops the next token without running it if it is a functor
+    pub fn skip(&mut self) -> Result<bool> {
+        self.advance(false)
+    }
+
+    fn advance(&mut self, run: bool) -> Result<bool> {
+        let result = self.try_advance(run);
+        if result.is_err() && self.line_started {
+            self.machine.rollback();
+            self.line_started = false;
+        }
+        result
+    }
+
+    fn try_advance(&mut self, run: bool) -> Result<bool> {
+        if !self.line_started {
+            // Names are left as symbols here, they are only resolved once a functor consumes them
+            let mut line = self.lines[self.current_line].clone();
+
+            // Without undo history nothing commits between lines, so anything changed by hand since
+            // the last line is committed here, where a failed line is rolled back to
+            if !self.machine.keeps_history() {
+                self.machine.touch();
+                self.machine.commit();
+            }
+            let start = self.machine.stack.len();
+            self.machine.stack.append(&mut line);
+            self.line_started = true;
+            self.limits.check_stack(&self.machine.stack, start)?;
+        }
+
+        if !line_is_done(&self.machine.stack) {
+            self.steps += 1;
+            self.limits.check_step(self.steps)?;
+
+            let depth = self.machine.stack.len();
+            // Only functor calls are timed, popping anything else takes no time worth knowing
+            let profiled = match (&self.profiler, self.machine.stack.last()) {
+                (Some(_), Some(Token::Functor(func))) if run => {
+                    Some((func.name.clone(), Instant::now()))
+                }
+                _ => None,
+            };
+
+            let val = if run {
+                self.machine.next()
+            } else {
+                self.machine.discard().map(Ok)
+            };
+
+            // The functor and its arguments were popped and its results pushed in their place
+            let bottom = (depth - 1 - self.machine.last_consumed).min(self.machine.stack.len());
+            if let Some((name, started)) = profiled
+                && let Some(profiler) = &mut self.profiler
+            {
+                let elapsed = started.elapsed();
+                profiler.record(
+                    self.current_line,
+                    name,
+                    elapsed,
+                    &self.machine.stack[bottom..],
+                );
+            }
+
+            if let Some(Err(e)) = val {
+                return Err(e.context(format!(
                     "Failed to execute BufferedReader line: {}",
                     self.current_line
-                )
+                )));
             }
+
+            self.limits.check_stack(&self.machine.stack, bottom)?;
         }
 
+        if !line_is_done(&self.machine.stack) {
+            return Ok(false);
+        }
+
+        self.line_started = false;
         self.current_line += 1;
-        Ok(())
+        self.machine.commit();
+        Ok(true)
+    }
+
+    /// Undoes the last line that ran, so that it is the next one to run again. Needs the machine to
+    /// keep history, see `RevPolStackMachine::enable_history`.
+    pub fn undo_line(&mut self) -> bool {
+        if self.current_line == 0 || !self.machine.undo() {
+            return false;
+        }
+
+        self.line_started = false;
+        self.current_line -= 1;
+        true
+    }
+
+    /// Redoes the last line that was undone, without running it again
+    pub fn redo_line(&mut self) -> bool {
+        if self.machine_is_complete() || !self.machine.redo() {
+            return false;
+        }
+
+        self.line_started = false;
+        self.current_line += 1;
+        true
     }
 
     /// Run the entire stack that has been created here!
@@ -148,3 +412,110 @@ impl BufferedExecutor {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::number::Number;
+    use crate::parse_rpol_notation::{commands_to_sequential_exec_order, split_into_commands};
+
+    fn executor(script: &str, history: bool) -> BufferedExecutor {
+        crate::set_debug_logging(false);
+        let context = Context::pure_math();
+        let commands = split_into_commands(script.to_string());
+        let lines = commands_to_sequential_exec_order(commands, &context).unwrap();
+        let mut machine = RevPolStackMachine::new_with_ctx(context);
+        if history {
+            machine.enable_history();
+        }
+        BufferedExecutor::new(machine, lines)
+    }
+
+    fn int(value: i64) -> Token {
+        Token::Const(Number::Int(value.into()))
+    }
+
+    #[test]
+    fn symbols_resolve_when_consumed() {
+        // x is left on the stack as a symbol, so rebinding it afterwards still counts
+        let mut assigned = executor("x 1 :=\nx\nx 7 :=\n2 *", false);
+        assigned.run_line().unwrap();
+        assigned.run_line().unwrap();
+        assert_eq!(assigned.machine.stack, vec![Token::Symbol("x".to_string())]);
+        assigned.run_stack().unwrap();
+        assert_eq!(assigned.machine.stack, vec![int(14)]);
+
+        let mut rebound = executor("x\n2 *", false);
+        rebound.run_line().unwrap();
+        rebound
+            .machine
+            .context
+            .set_variable("x".to_string(), int(5));
+        rebound.run_stack().unwrap();
+        assert_eq!(rebound.machine.stack, vec![int(10)]);
+    }
+
+    #[test]
+    fn failed_line_is_rolled_back() {
+        for history in [false, true] {
+            let mut executor = executor("1\n5 \"a\" +", history);
+            executor.run_line().unwrap();
+
+            // Stepping on after a failure starts the line again rather than stacking it up
+            for _ in 0..3 {
+                while executor.step().is_ok() {}
+                assert_eq!(executor.machine.stack, vec![int(1)]);
+                assert!(!executor.line_in_progress());
+                assert_eq!(executor.current_line, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn failed_line_can_be_retried() {
+        for history in [false, true] {
+            let mut executor = executor("3\ny 2 :=\nx y *", history);
+            assert!(executor.run_stack().is_err());
+            assert_eq!(executor.machine.stack, vec![int(3)]);
+            assert_eq!(executor.current_line, 2);
+
+            executor
+                .machine
+                .context
+                .set_variable("x".to_string(), int(5));
+            executor.run_stack().unwrap();
+            assert_eq!(executor.machine.stack, vec![int(3), int(10)]);
+        }
+    }
+
+    #[test]
+    fn rollback_restores_what_the_line_popped() {
+        for history in [false, true] {
+            let mut executor = executor("1\n0\n/", history);
+            assert!(executor.run_stack().is_err());
+            assert_eq!(executor.machine.stack, vec![int(1), int(0)]);
+        }
+    }
+
+    #[test]
+    fn rollback_keeps_changes_made_between_lines() {
+        let limits = ExecutionLimits {
+            max_int_bits: Some(64),
+            ..ExecutionLimits::new()
+        };
+        let mut executor = executor("1\n1 0 /", false);
+        executor.set_limits(limits);
+        executor.run_line().unwrap();
+
+        executor
+            .machine
+            .context
+            .set_variable("x".to_string(), int(2));
+        executor.machine.stack.push(int(3));
+        assert!(executor.run_line().is_err());
+        assert_eq!(executor.machine.stack, vec![int(1), int(3)]);
+        assert!(executor.machine.context.get_variable("x").is_some());
+        assert_eq!(executor.machine.context.max_int_bits, Some(64));
+        assert!(!executor.machine.keeps_history());
+    }
+}
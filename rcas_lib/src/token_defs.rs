@@ -1,14 +1,18 @@
-use crate::context::Context;
-use anyhow::Result;
+use crate::context::{Context, find_default_function};
+use anyhow::{Result, bail};
 use std::boxed::Box;
 use std::fmt::Display;
+use crate::expression::Expr;
+use crate::latex::{function_to_latex, symbol_to_latex, text_to_latex};
 use crate::number::Number;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 /// fn(&mut stack) -> push_to_stack
 pub type FunctionObject = fn(&mut Vec<Token>, &mut Context) -> Result<Vec<Token>>;
 
 /// An abstraction over the name and possible value of a variable
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Variable {
     pub name: String,
     pub value: Option<Box<Token>>,
@@ -22,12 +26,132 @@ impl Display for Variable {
     }
 }
 
+/// The kind of token a functor accepts as one of its arguments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    /// Any token at all
+    Any,
+    /// A constant, or a name bound to one
+    Number,
+    /// A string literal
+    String,
+    /// The name of a variable: a symbol, a string or a variable
+    Name,
+}
+impl ArgType {
+    /// Checks if a token is acceptable for this argument type, resolving names where needed
+    pub fn accepts(&self, token: &Token, ctx: &Context) -> bool {
+        match self {
+            ArgType::Any => true,
+            ArgType::Number => matches!(ctx.resolve(token.clone()), Token::Const(_)),
+            ArgType::String => matches!(token, Token::String(_)),
+            ArgType::Name => matches!(
+                token,
+                Token::Symbol(_) | Token::String(_) | Token::Variable(_)
+            ),
+        }
+    }
+}
+impl Display for ArgType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgType::Any => write!(f, "Any"),
+            ArgType::Number => write!(f, "Number"),
+            ArgType::String => write!(f, "String"),
+            ArgType::Name => write!(f, "Name"),
+        }
+    }
+}
+
 /// Represents some arbitrary rust function imported in under a new name, for example the Exit
-/// function will ignore the stack and just quit
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// function will ignore the stack and just quit. Functors are serialized by name, and can only be
+/// read back if they are one of the default functions.
+#[derive(Debug, Clone)]
 pub struct Functor {
     pub name: String,
     pub func: FunctionObject,
+    /// The arguments taken off the stack, in the order they are written (so the last one is on
+    /// top of the stack)
+    pub args: &'static [ArgType],
+    /// Documentation shown by the Help command
+    pub doc: &'static str,
+}
+impl Functor {
+    /// Creates a functor without any argument checks or documentation
+    pub fn new(name: String, func: FunctionObject) -> Self {
+        Functor {
+            name,
+            func,
+            args: &[],
+            doc: "",
+        }
+    }
+
+    /// The number of arguments this functor takes off the stack
+    pub fn arity(&self) -> usize {
+        self.args.len()
+    }
+
+    /// The usage of this functor, written the way it would be called, e.g. "Number Number +"
+    pub fn signature(&self) -> String {
+        self.args
+            .iter()
+            .map(|arg| arg.to_string())
+            .chain(std::iter::once(self.name.clone()))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// Checks that the top of the stack holds the arguments this functor expects, before it is
+    /// run, so that it fails without having touched the stack or the context
+    pub fn check_args(&self, stack: &[Token], ctx: &Context) -> Result<()> {
+        if stack.len() < self.arity() {
+            bail!(
+                "{} expects {} argument(s) but the stack only holds {} (usage: {})",
+                self.name,
+                self.arity(),
+                stack.len(),
+                self.signature()
+            )
+        }
+
+        let args = &stack[stack.len() - self.arity()..];
+        for (expected, token) in self.args.iter().zip(args) {
+            if !expected.accepts(token, ctx) {
+                bail!(
+                    "{} expects a {} argument but found {} (usage: {})",
+                    self.name,
+                    expected,
+                    token,
+                    self.signature()
+                )
+            }
+        }
+
+        Ok(())
+    }
+}
+/// Functors are compared by name, as function pointer addresses are not guaranteed to be unique
+impl PartialEq for Functor {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+impl Eq for Functor {}
+impl Serialize for Functor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+/// Names are looked up among every default function, as there is no context to go by here. Whatever
+/// reads functors from outside has to check them against what its context is allowed, e.g. with
+/// `SavedMachine::check_functions`.
+impl<'de> Deserialize<'de> for Functor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        find_default_function(&name)
+            .ok_or_else(|| D::Error::custom(format!("No default function is called {}", name)))
+    }
 }
 impl Display for Functor {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -37,12 +161,33 @@ impl Display for Functor {
 
 /// Represents what a token could be. Everything is either a Constant, Variable, Functor or special
 /// character or delimeter
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Token {
     Const(Number),
     Variable(Variable),
     Functor(Functor),
     String(String),
+    /// A bare name which is only looked up in the `Context` once it is used, so it always sees the
+    /// current value of the variable rather than the one at parse time
+    Symbol(String),
+    /// A symbolic expression, which is passed around as a value rather than evaluated
+    Expr(Expr),
+}
+impl Token {
+    /// The token as LaTeX, see `Expr::to_latex` and `Number::to_latex`
+    pub fn to_latex(&self) -> String {
+        match self {
+            Token::Const(num) => num.to_latex(),
+            Token::Variable(var) => match &var.value {
+                Some(value) => format!("{} = {}", symbol_to_latex(&var.name), value.to_latex()),
+                None => symbol_to_latex(&var.name),
+            },
+            Token::Functor(func) => function_to_latex(&func.name),
+            Token::String(str) => text_to_latex(str),
+            Token::Symbol(name) => symbol_to_latex(name),
+            Token::Expr(expr) => expr.to_latex(),
+        }
+    }
 }
 impl Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -51,6 +196,133 @@ impl Display for Token {
             Token::Variable(var) => write!(f, "{}", var),
             Token::Functor(func) => write!(f, "{}", func),
             Token::String(str) => write!(f, "{}", str),
+            Token::Symbol(name) => write!(f, "{}", name),
+            Token::Expr(expr) => write!(f, "{}", expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::get_default_functions;
+    #[cfg(feature = "debugger")]
+    use crate::debugger::RevPolBufSnapshot;
+    use crate::number_format::NumberFormat;
+    use crate::pretty::PrettyStyle;
+    #[cfg(feature = "debugger")]
+    use crate::stack_machine::{BufferedExecutor, RevPolStackMachine};
+    use num_bigint::BigInt;
+    use serde::de::DeserializeOwned;
+    use std::str::FromStr;
+
+    fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> T {
+        serde_json::from_str(&serde_json::to_string(value).unwrap()).unwrap()
+    }
+
+    fn big(int: &str) -> BigInt {
+        BigInt::from_str(int).unwrap()
+    }
+
+    #[test]
+    fn numbers_keep_every_digit() {
+        let numbers = [
+            Number::Int(big("123456789012345678901234567890123456789")),
+            Number::Int(big("-42")),
+            Number::Float(num_bigfloat::BigFloat::from_str("1.25e-300").unwrap()),
+            Number::modular(big("-1"), big("1000000007")).unwrap(),
+            Number::rational(big("-3"), big("123456789012345678901234567890")).unwrap(),
+        ];
+        for number in numbers {
+            assert_eq!(round_trip(&number), number);
         }
     }
+
+    #[test]
+    fn numbers_are_checked_when_read() {
+        assert!(serde_json::from_str::<Number>(r#"{"Int":"12x"}"#).is_err());
+        assert!(serde_json::from_str::<Number>(r#"{"Mod":["3","-7"]}"#).is_err());
+        assert!(serde_json::from_str::<Number>(r#"{"Rational":["1","0"]}"#).is_err());
+        // A fraction read back is put into lowest terms like any other
+        let half = serde_json::from_str::<Number>(r#"{"Rational":["2","4"]}"#).unwrap();
+        assert_eq!(half, Number::rational(1.into(), 2.into()).unwrap());
+    }
+
+    #[test]
+    fn every_kind_of_token_round_trips() {
+        let context = Context {
+            functions: get_default_functions(),
+            ..Context::new()
+        };
+        let tokens = [
+            Token::Const(Number::Int(7.into())),
+            Token::Variable(Variable {
+                name: "x".into(),
+                value: Some(Box::new(Token::String("a \"quoted\" value".into()))),
+            }),
+            Token::Variable(Variable {
+                name: "y".into(),
+                value: None,
+            }),
+            Token::Functor(find_default_function("+").unwrap()),
+            Token::String("text".into()),
+            Token::Symbol("z".into()),
+            Token::Expr(Expr::parse("sin(x)^2 / 2", &context).unwrap()),
+        ];
+        for token in tokens {
+            assert_eq!(round_trip(&token), token);
+        }
+    }
+
+    #[test]
+    fn functors_are_written_by_name() {
+        let functor = Token::Functor(find_default_function("Print").unwrap());
+        assert_eq!(
+            serde_json::to_string(&functor).unwrap(),
+            r#"{"Functor":"Print"}"#
+        );
+        assert!(serde_json::from_str::<Token>(r#"{"Functor":"NoSuchThing"}"#).is_err());
+
+        // The function behind the name is the registered one, not just a functor with its name
+        let read = serde_json::from_str::<Functor>(r#""+""#).unwrap();
+        let mut stack = vec![
+            Token::Const(Number::Int(2.into())),
+            Token::Const(Number::Int(3.into())),
+        ];
+        let result = (read.func)(&mut stack, &mut Context::new()).unwrap();
+        assert_eq!(result, vec![Token::Const(Number::Int(5.into()))]);
+    }
+
+    #[test]
+    fn contexts_round_trip() {
+        let mut context = Context::pure_math();
+        context.set_variable("x".into(), Token::Const(Number::Int(3.into())));
+        context.pretty_print = Some(PrettyStyle::Unicode);
+        context.number_format = NumberFormat::Scientific(4);
+
+        assert_eq!(round_trip(&context), context);
+    }
+
+    #[test]
+    #[cfg(feature = "debugger")]
+    fn snapshots_write_errors_as_their_message() {
+        let machine = RevPolStackMachine::new_with_ctx(Context::pure_math());
+        let lines = vec![vec![Token::Const(Number::Int(1.into()))]];
+        let executor = BufferedExecutor::new(machine, lines.clone());
+        let snapshot = RevPolBufSnapshot::from_machine_with_result(
+            &executor,
+            lines,
+            Err(anyhow::anyhow!("it broke")),
+        );
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        let error = serde_json::json!({ "Err": "it broke" });
+        assert_eq!(json["last_result"], error);
+        assert_eq!(json["current_line"], 0);
+        assert_eq!(json["machine_is_complete"], false);
+        let lines = serde_json::from_value::<Vec<Vec<Token>>>(json["lines"].clone()).unwrap();
+        assert_eq!(lines, *snapshot.lines);
+        let context = serde_json::from_value::<Context>(json["context"].clone()).unwrap();
+        assert_eq!(context, snapshot.stack_machine_ctx);
+    }
 }
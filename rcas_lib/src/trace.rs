@@ -0,0 +1,672 @@
+use crate::context::Context;
+use crate::debugger::{BufExecDebugger, IntoDebugger, RevPolBufSnapshot, StepMode, TokenStep};
+use crate::history::MachineState;
+use crate::number::Number;
+use crate::stack_machine::BufferedExecutor;
+use crate::token_defs::{Token, Variable};
+use anyhow::{Context as _, Result, anyhow, bail};
+use num_bigfloat::BigFloat;
+use num_bigint::BigInt;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+
+const HEADER: &str = "rcas-trace 1";
+
+/// Everything a single step did to the machine, in the order it happened: the line is put on the
+/// stack (if the step started it), tokens are popped off the top, results are pushed back on, and
+/// variables are changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceStep {
+    /// Index of the line the step ran in
+    pub line: usize,
+    /// Tokens of the line, if the step put it on the stack
+    pub loaded: Vec<Token>,
+    /// Number of tokens popped, i.e. the token that was run and the arguments it took
+    pub popped: usize,
+    pub pushed: Vec<Token>,
+    /// Variables which were bound or rebound
+    pub set: Vec<Variable>,
+    /// Names of variables which were deleted
+    pub deleted: Vec<String>,
+}
+
+/// A record of every stack mutation of a run, which can be written to a compact text file and
+/// replayed to get the state after any step
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    pub initial_stack: Vec<Token>,
+    pub initial_variables: Vec<Variable>,
+    pub steps: Vec<TraceStep>,
+    /// The error the run stopped on, and the line it happened in
+    pub error: Option<(usize, String)>,
+}
+impl Trace {
+    /// Replays the trace up to and including the given number of steps, returning the stack and
+    /// variables at that point. Fails if a step pops more than is on the stack, which only a
+    /// trace that was edited or not written by a run can do.
+    pub fn state_at(&self, steps: usize) -> Result<(Vec<Token>, Vec<Variable>)> {
+        let mut stack = self.initial_stack.clone();
+        let mut context = Context {
+            variables: self.initial_variables.clone(),
+            ..Context::new()
+        };
+
+        for (idx, step) in self.steps.iter().take(steps).enumerate() {
+            stack.extend(step.loaded.iter().cloned());
+            let Some(left) = stack.len().checked_sub(step.popped) else {
+                bail!(
+                    "Step {} pops {} tokens, but only {} are on the stack",
+                    idx,
+                    step.popped,
+                    stack.len()
+                )
+            };
+            stack.truncate(left);
+            stack.extend(step.pushed.iter().cloned());
+
+            // Rebinding keeps the variable where it was, the same as Context::set_variable
+            for var in &step.set {
+                let old = context.variables.iter_mut().find(|old| old.name == var.name);
+                match old {
+                    Some(old) => *old = var.clone(),
+                    None => context.variables.push(var.clone()),
+                }
+            }
+            for name in &step.deleted {
+                context.remove_variable(name);
+            }
+        }
+
+        Ok((stack, context.variables))
+    }
+
+    /// Finds the latest step before `before` which pushed a token matching the predicate, e.g. to
+    /// find where a bad value came from
+    pub fn find_producer(&self, before: usize, pred: impl Fn(&Token) -> bool) -> Option<usize> {
+        self.steps[..before.min(self.steps.len())]
+            .iter()
+            .rposition(|step| step.pushed.iter().any(&pred))
+    }
+
+    pub fn write(&self, mut out: impl Write) -> Result<()> {
+        writeln!(out, "{}", HEADER)?;
+        writeln!(out, "init {}", encode_tokens(&self.initial_stack))?;
+        for var in &self.initial_variables {
+            writeln!(out, "var {}", encode_variable(var))?;
+        }
+
+        for step in &self.steps {
+            writeln!(
+                out,
+                "step {} {} {} {}",
+                step.line,
+                step.popped,
+                encode_tokens(&step.loaded),
+                encode_tokens(&step.pushed)
+            )?;
+            for var in &step.set {
+                writeln!(out, "set {}", encode_variable(var))?;
+            }
+            for name in &step.deleted {
+                writeln!(out, "del {}", name)?;
+            }
+        }
+
+        if let Some((line, message)) = &self.error {
+            writeln!(out, "error {} {}", line, encode_string(message))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create trace file <{}>", path))?;
+        self.write(std::io::BufWriter::new(file))
+    }
+
+    /// Reads a trace back, looking the functors in it up by name in the context
+    pub fn read(input: impl BufRead, context: &Context) -> Result<Trace> {
+        let mut lines = input.lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            bail!("Not a trace file, expected it to start with '{}'", HEADER)
+        }
+
+        let mut trace = Trace::default();
+        for (idx, line) in lines.enumerate() {
+            let line = line?;
+            let words = split_words(&line);
+            let mut words = words.iter().map(String::as_str);
+
+            let parsed = match words.next() {
+                Some("init") => decode_tokens(&mut words, context)
+                    .map(|tokens| trace.initial_stack = tokens),
+                Some("var") => decode_variable(&mut words, context)
+                    .map(|var| trace.initial_variables.push(var)),
+                Some("step") => decode_step(&mut words, context).map(|step| trace.steps.push(step)),
+                Some(kind @ ("set" | "del")) => match trace.steps.last_mut() {
+                    Some(step) if kind == "set" => {
+                        decode_variable(&mut words, context).map(|var| step.set.push(var))
+                    }
+                    Some(step) => next_word(&mut words).map(|name| step.deleted.push(name.into())),
+                    None => Err(anyhow!("{} before the first step", kind)),
+                },
+                Some("error") => next_word(&mut words)
+                    .and_then(|line| Ok(line.parse::<usize>()?))
+                    .and_then(|line| Ok((line, decode_string(next_word(&mut words)?)?)))
+                    .map(|error| trace.error = Some(error)),
+                Some(other) => Err(anyhow!("Unknown record '{}'", other)),
+                None => Ok(()),
+            };
+            // Line numbers are 1 based and the header is the first line
+            parsed.with_context(|| format!("Bad trace record on line {}", idx + 2))?;
+        }
+
+        Ok(trace)
+    }
+
+    pub fn load(path: &str, context: &Context) -> Result<Trace> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open trace file <{}>", path))?;
+        Self::read(std::io::BufReader::new(file), context)
+    }
+}
+
+/// Splits a record into words on whitespace, keeping quoted strings (which may contain escaped
+/// quotes) in one piece
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in line.chars() {
+        if c.is_whitespace() && !quoted {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+
+        word.push(c);
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quoted {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+fn next_word<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<&'a str> {
+    words.next().ok_or_else(|| anyhow!("Record ended too early"))
+}
+
+fn encode_string(string: &str) -> String {
+    let escaped = string
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("\"{}\"", escaped)
+}
+
+fn decode_string(word: &str) -> Result<String> {
+    let Some(inner) = word.strip_prefix('"').and_then(|w| w.strip_suffix('"')) else {
+        bail!("Expected a quoted string, found {}", word)
+    };
+
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => string.push('\n'),
+            Some('r') => string.push('\r'),
+            Some(c) => string.push(c),
+            None => bail!("String ends in an escape: {}", word),
+        }
+    }
+
+    Ok(string)
+}
+
+/// Tokens are tagged by their first character: # integer, % float, ! modular number (as
+/// value:modulus), / fraction (as numerator:denominator), " string, @ functor, $ symbol and
+/// & variable (followed by =value if it has one). An expression is ~ followed by its JSON as a
+/// quoted string.
+fn encode_token(token: &Token) -> String {
+    match token {
+        Token::Const(Number::Int(int)) => format!("#{}", int),
+        Token::Const(Number::Float(float)) => format!("%{}", float),
+        Token::Const(Number::Mod(value, modulus)) => format!("!{}:{}", value, modulus),
+        Token::Const(Number::Rational(numerator, denominator)) => {
+            format!("/{}:{}", numerator, denominator)
+        }
+        Token::String(string) => encode_string(string),
+        Token::Functor(func) => format!("@{}", func.name),
+        Token::Symbol(name) => format!("${}", name),
+        Token::Variable(var) => format!("&{}", encode_variable(var)),
+        Token::Expr(expr) => format!(
+            "~{}",
+            encode_string(&serde_json::to_string(expr).expect("expressions always serialize"))
+        ),
+    }
+}
+
+fn decode_token(word: &str, context: &Context) -> Result<Token> {
+    let mut chars = word.chars();
+    let tag = chars.next().ok_or_else(|| anyhow!("Empty token"))?;
+    let rest = chars.as_str();
+
+    Ok(match tag {
+        '#' => Token::Const(Number::Int(BigInt::from_str(rest)?)),
+        '%' => Token::Const(Number::Float(
+            BigFloat::from_str(rest).map_err(|e| anyhow!("Bad float {}: {:?}", rest, e))?,
+        )),
+        '!' => match rest.split_once(':') {
+            Some((value, modulus)) => Token::Const(Number::modular(
+                BigInt::from_str(value)?,
+                BigInt::from_str(modulus)?,
+            )?),
+            None => bail!("Bad modular number {}", rest),
+        },
+        '/' => match rest.split_once(':') {
+            Some((numerator, denominator)) => Token::Const(Number::rational(
+                BigInt::from_str(numerator)?,
+                BigInt::from_str(denominator)?,
+            )?),
+            None => bail!("Bad fraction {}", rest),
+        },
+        '"' => Token::String(decode_string(word)?),
+        '@' => match context.functions.iter().find(|f| f.name == rest) {
+            Some(func) => Token::Functor(func.clone()),
+            None => bail!("No function is called {}", rest),
+        },
+        '$' => Token::Symbol(rest.to_string()),
+        '&' => Token::Variable(decode_variable(&mut std::iter::once(rest), context)?),
+        '~' => Token::Expr(serde_json::from_str(&decode_string(rest)?)?),
+        _ => bail!("Unknown token {}", word),
+    })
+}
+
+fn encode_tokens(tokens: &[Token]) -> String {
+    let mut encoded = tokens.len().to_string();
+    for token in tokens {
+        encoded.push(' ');
+        encoded.push_str(&encode_token(token));
+    }
+    encoded
+}
+
+/// Reads a count, followed by that many tokens
+fn decode_tokens<'a>(
+    words: &mut impl Iterator<Item = &'a str>,
+    context: &Context,
+) -> Result<Vec<Token>> {
+    let count = next_word(words)?.parse::<usize>()?;
+    (0..count)
+        .map(|_| decode_token(next_word(words)?, context))
+        .collect()
+}
+
+fn encode_variable(var: &Variable) -> String {
+    match &var.value {
+        Some(value) => format!("{}={}", var.name, encode_token(value)),
+        None => var.name.clone(),
+    }
+}
+
+fn decode_variable<'a>(
+    words: &mut impl Iterator<Item = &'a str>,
+    context: &Context,
+) -> Result<Variable> {
+    let word = next_word(words)?;
+    Ok(match word.split_once('=') {
+        Some((name, value)) => Variable {
+            name: name.to_string(),
+            value: Some(Box::new(decode_token(value, context)?)),
+        },
+        None => Variable {
+            name: word.to_string(),
+            value: None,
+        },
+    })
+}
+
+fn decode_step<'a>(
+    words: &mut impl Iterator<Item = &'a str>,
+    context: &Context,
+) -> Result<TraceStep> {
+    Ok(TraceStep {
+        line: next_word(words)?.parse()?,
+        popped: next_word(words)?.parse()?,
+        loaded: decode_tokens(words, context)?,
+        pushed: decode_tokens(words, context)?,
+        set: vec![],
+        deleted: vec![],
+    })
+}
+
+/// Debuggers which can go back to earlier steps as well as forward
+pub trait TimeTravel<Snapshot>: IntoDebugger<Snapshot> {
+    /// Number of steps taken so far
+    fn position(&self) -> usize;
+    /// Goes back a step, returning None if already at the start
+    fn step_back_with_snapshot(&mut self) -> Option<Snapshot>;
+    /// Goes to the state after the given number of steps, running forward if that has not been
+    /// reached yet
+    fn seek_with_snapshot(&mut self, step: usize) -> Snapshot;
+}
+
+/// The state after a recorded step
+struct Frame {
+    state: MachineState,
+    current_line: usize,
+    line_in_progress: bool,
+    step: Option<TokenStep>,
+    error: Option<String>,
+}
+
+/// Steps through a BufferedExecutor a token at a time, recording every step so it can go back to
+/// any earlier one. States share their stacks and contexts with each other (see MachineState), so
+/// keeping all of them is cheap.
+pub struct TraceDebugger {
+    debugger: BufExecDebugger,
+    trace: Trace,
+    /// The state before any step, then the state after each one
+    frames: Vec<Frame>,
+    /// Which frame is being looked at, the last one being where the executor really is
+    position: usize,
+    /// The executor's lines, which every snapshot shares
+    lines: Arc<Vec<Vec<Token>>>,
+}
+impl TraceDebugger {
+    pub fn new(executor: BufferedExecutor) -> Self {
+        let machine = &executor.machine;
+        let trace = Trace {
+            initial_stack: machine.stack.clone(),
+            initial_variables: machine.context.variables.clone(),
+            steps: vec![],
+            error: None,
+        };
+        let start = Frame {
+            state: MachineState {
+                stack: machine.stack.iter().cloned().collect(),
+                context: Arc::new(machine.context.clone()),
+            },
+            current_line: executor.current_line,
+            line_in_progress: executor.line_in_progress(),
+            step: None,
+            error: None,
+        };
+
+        let lines = Arc::new(executor.lines.clone());
+
+        Self {
+            debugger: BufExecDebugger::new(executor).with_mode(StepMode::Token),
+            trace,
+            lines,
+            frames: vec![start],
+            position: 0,
+        }
+    }
+
+    /// Everything recorded so far
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    /// Runs the live executor a step, recording what it did
+    fn record(&mut self) {
+        // Whichever line is current before a step is the one the step runs in
+        let line = self.debugger.executor().current_line;
+        let result = self.debugger.step_token();
+        let executor = self.debugger.executor();
+        let prev = self.frames.last().expect("there is always a start frame");
+
+        let mut frame = Frame {
+            state: prev.state.clone(),
+            current_line: executor.current_line,
+            line_in_progress: executor.line_in_progress(),
+            step: None,
+            error: None,
+        };
+
+        let step = match result {
+            Ok(step) => step,
+            Err(e) => {
+                // A failed step rolls its line back, so the state is taken as the executor left it
+                frame.state = MachineState {
+                    stack: executor.machine.stack.iter().cloned().collect(),
+                    context: Arc::new(executor.machine.context.clone()),
+                };
+                frame.error = Some(format!("{:#}", e));
+                self.trace.error = Some((executor.current_line, format!("{:#}", e)));
+                self.frames.push(frame);
+                return;
+            }
+        };
+
+        let loaded = if prev.line_in_progress {
+            vec![]
+        } else {
+            executor.lines[line].clone()
+        };
+        let (popped, pushed) = match &step {
+            Some(step) => (step.consumed.len() + 1, step.produced.clone()),
+            None => (0, vec![]),
+        };
+        frame.step = step;
+
+        frame.state.stack.extend(loaded.iter().cloned());
+        frame
+            .state
+            .stack
+            .truncate(frame.state.stack.len() - popped);
+        frame.state.stack.extend(pushed.iter().cloned());
+
+        let old_vars = &prev.state.context.variables;
+        let new_vars = &executor.machine.context.variables;
+        let set = new_vars
+            .iter()
+            .filter(|var| !old_vars.contains(var))
+            .cloned()
+            .collect::<Vec<Variable>>();
+        let deleted = old_vars
+            .iter()
+            .filter(|old| !new_vars.iter().any(|var| var.name == old.name))
+            .map(|old| old.name.clone())
+            .collect::<Vec<String>>();
+        if executor.machine.context.revision != prev.state.context.revision {
+            frame.state.context = Arc::new(executor.machine.context.clone());
+        }
+
+        self.trace.steps.push(TraceStep {
+            line,
+            loaded,
+            popped,
+            pushed,
+            set,
+            deleted,
+        });
+        self.frames.push(frame);
+    }
+
+    fn frame_snapshot(&self, idx: usize) -> RevPolBufSnapshot {
+        let frame = &self.frames[idx];
+
+        RevPolBufSnapshot {
+            stack_machine_stack: frame.state.stack.iter().cloned().collect(),
+            stack_machine_ctx: Context::clone(&frame.state.context),
+            lines: Arc::clone(&self.lines),
+            current_line: frame.current_line,
+            line_in_progress: frame.line_in_progress,
+            machine_is_complete: frame.current_line == self.lines.len(),
+            last_result: Some(match &frame.error {
+                Some(e) => Err(anyhow!("{}", e)),
+                None => Ok(()),
+            }),
+            last_step: frame.step.clone(),
+        }
+    }
+}
+impl IntoDebugger<RevPolBufSnapshot> for TraceDebugger {
+    /// Recording stops at the first error, so a trace always ends with it
+    fn is_done(&self) -> bool {
+        self.position + 1 == self.frames.len()
+            && (self.debugger.is_done() || self.trace.error.is_some())
+    }
+
+    fn next_with_snapshot(&mut self) -> RevPolBufSnapshot {
+        if self.position + 1 == self.frames.len() {
+            self.record();
+        }
+        self.position += 1;
+        self.frame_snapshot(self.position)
+    }
+
+    fn static_snapshot(&self) -> RevPolBufSnapshot {
+        self.frame_snapshot(self.position)
+    }
+}
+impl TimeTravel<RevPolBufSnapshot> for TraceDebugger {
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn step_back_with_snapshot(&mut self) -> Option<RevPolBufSnapshot> {
+        self.position = self.position.checked_sub(1)?;
+        Some(self.frame_snapshot(self.position))
+    }
+
+    fn seek_with_snapshot(&mut self, step: usize) -> RevPolBufSnapshot {
+        while self.frames.len() <= step && !self.debugger.is_done() && self.trace.error.is_none() {
+            self.record();
+        }
+        self.position = step.min(self.frames.len() - 1);
+        self.frame_snapshot(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_rpol_notation::{commands_to_sequential_exec_order, split_into_commands};
+    use crate::stack_machine::RevPolStackMachine;
+
+    /// Records every step of a script, up to its end or its first error
+    fn record(script: &str) -> (Trace, BufferedExecutor) {
+        crate::set_debug_logging(false);
+        let context = Context::pure_math();
+        let commands = split_into_commands(script.to_string());
+        let lines = commands_to_sequential_exec_order(commands, &context).unwrap();
+        let machine = RevPolStackMachine::new_with_ctx(context.clone());
+        let mut debugger = TraceDebugger::new(BufferedExecutor::new(machine, lines.clone()));
+        while !debugger.is_done() {
+            debugger.next_with_snapshot();
+        }
+
+        let machine = RevPolStackMachine::new_with_ctx(context);
+        let mut executor = BufferedExecutor::new(machine, lines);
+        let _ = executor.run_stack();
+        (debugger.trace().clone(), executor)
+    }
+
+    fn round_trip(trace: &Trace) -> Trace {
+        let mut out = vec![];
+        trace.write(&mut out).unwrap();
+        Trace::read(out.as_slice(), &Context::pure_math()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_every_kind_of_token() {
+        let script = [
+            "x 2 :=",
+            "x 3 *",
+            "1.5 2 *",
+            "1/3 2 +",
+            "3 7 Mod",
+            "5 *",
+            "\"a string with spaces\"",
+            "\"(y + 1) / 2\" Expr",
+            "x &",
+            "y",
+        ]
+        .join("\n");
+        let (trace, _) = record(&script);
+
+        assert!(trace.error.is_none());
+        assert!(trace.steps.iter().any(|step| !step.set.is_empty()));
+        assert!(trace.steps.iter().any(|step| !step.deleted.is_empty()));
+        assert_eq!(round_trip(&trace), trace);
+    }
+
+    #[test]
+    fn round_trips_an_error() {
+        let (trace, _) = record("1 2 +\n5 \"a\" +");
+
+        assert_eq!(trace.error.as_ref().map(|(line, _)| *line), Some(1));
+        assert_eq!(round_trip(&trace), trace);
+    }
+
+    #[test]
+    fn replays_to_the_final_state() {
+        let (trace, executor) = record("x 4 :=\nx x *\n1 2 3\n+\n+");
+        let (stack, variables) = trace.state_at(trace.steps.len()).unwrap();
+
+        assert_eq!(stack, executor.machine.stack);
+        assert_eq!(variables, executor.machine.context.variables);
+    }
+
+    #[test]
+    fn replays_rebinding_in_place() {
+        let (trace, executor) = record("x 1 :=\ny 2 :=\nx 3 :=");
+        let (_, variables) = trace.state_at(trace.steps.len()).unwrap();
+
+        assert_eq!(variables, executor.machine.context.variables);
+        assert_eq!(variables[0].name, "x");
+    }
+
+    #[test]
+    fn fails_to_replay_popping_too_much() {
+        let input = format!("{}\ninit 1 #1\nstep 0 3 0 0", HEADER);
+        let trace = Trace::read(input.as_bytes(), &Context::pure_math()).unwrap();
+
+        assert!(trace.state_at(0).is_ok());
+        assert!(trace.state_at(1).is_err());
+    }
+
+    #[test]
+    fn snapshots_share_the_lines() {
+        let context = Context::pure_math();
+        let commands = split_into_commands("1 2 +\n3 *".to_string());
+        let lines = commands_to_sequential_exec_order(commands, &context).unwrap();
+        let machine = RevPolStackMachine::new_with_ctx(context);
+        let mut debugger = TraceDebugger::new(BufferedExecutor::new(machine, lines));
+
+        let first = debugger.next_with_snapshot();
+        let second = debugger.next_with_snapshot();
+        assert!(Arc::ptr_eq(&first.lines, &second.lines));
+        let back = debugger.step_back_with_snapshot().unwrap();
+        assert_eq!(back.stack_machine_stack, first.stack_machine_stack);
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_trace() {
+        let context = Context::pure_math();
+        assert!(Trace::read("init #1".as_bytes(), &context).is_err());
+        assert!(Trace::read(format!("{}\nstep 0", HEADER).as_bytes(), &context).is_err());
+        assert!(Trace::read(format!("{}\nbogus 1", HEADER).as_bytes(), &context).is_err());
+    }
+}
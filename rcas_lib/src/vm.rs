@@ -0,0 +1,296 @@
+use crate::bytecode::{Instruction, Program};
+use crate::limits::ExecutionLimits;
+use crate::stack_machine::RevPolStackMachine;
+use crate::token_defs::{Functor, Token};
+use anyhow::Result;
+
+/// Runs a compiled `Program` with the same semantics as `BufferedExecutor`: each line is pushed
+/// onto the stack, and the top of the stack is popped until no functors are left on it. Steps are
+/// counted the same way, one for every token popped, and a failed line is rolled back the same
+/// way too.
+///
+/// Instead of rescanning the stack after every step, the VM keeps track of where the functors on
+/// the stack are. This relies on functors following the `FunctionObject` contract, which is that
+/// they only pop their arguments off the stack and hand their results back to be pushed.
+pub struct VirtualMachine {
+    pub machine: RevPolStackMachine,
+    pub program: Program,
+    pub current_line: usize,
+    /// Change these with `set_limits`, which also hands the integer size limit to the functors
+    pub limits: ExecutionLimits,
+    /// Number of tokens popped off the stack so far, counted against `limits.max_steps`. Tokens
+    /// the VM skips rather than pushing and popping count too.
+    pub steps: usize,
+    /// Stack indices holding a `Token::Functor`, in ascending order
+    functor_positions: Vec<usize>,
+}
+impl VirtualMachine {
+    pub fn new(machine: RevPolStackMachine, program: Program) -> Self {
+        Self::new_with_limits(machine, program, ExecutionLimits::new())
+    }
+
+    /// Creates a VM running the program on the machine. Like `BufferedExecutor`, a machine without
+    /// history is given just enough to roll a failed line back with.
+    pub fn new_with_limits(
+        mut machine: RevPolStackMachine,
+        program: Program,
+        limits: ExecutionLimits,
+    ) -> Self {
+        machine.context.max_int_bits = limits.max_int_bits;
+        if machine.history.is_none() {
+            machine.enable_rollback();
+        }
+        let functor_positions = functor_positions(&machine.stack);
+
+        VirtualMachine {
+            machine,
+            program,
+            current_line: 0,
+            limits,
+            steps: 0,
+            functor_positions,
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: ExecutionLimits) {
+        self.machine.context.max_int_bits = limits.max_int_bits;
+        self.limits = limits;
+    }
+
+    /// Helper function: checks if the VM is 'done' (the current_line = lines.len())
+    pub fn machine_is_complete(&self) -> bool {
+        self.current_line == self.program.lines.len()
+    }
+
+    /// Pushes the current line and reduces it until no functors are left, then moves on to the
+    /// next line. A failed line is rolled back to how the machine was before it started, so it
+    /// can be run again.
+    pub fn run_line(&mut self) -> Result<()> {
+        // The same as BufferedExecutor: without undo history nothing commits between lines, so
+        // anything changed by hand since the last line is committed here
+        if !self.machine.keeps_history() {
+            self.machine.touch();
+            self.machine.commit();
+        }
+
+        if let Err(e) = self.execute_line() {
+            self.machine.rollback();
+            self.functor_positions = functor_positions(&self.machine.stack);
+            return Err(e.context(format!("Failed to execute line: {}", self.current_line)));
+        }
+
+        self.current_line += 1;
+        self.machine.commit();
+        Ok(())
+    }
+
+    /// Run every remaining line of the program
+    pub fn run(&mut self) -> Result<()> {
+        while !self.machine_is_complete() {
+            self.run_line()?
+        }
+
+        Ok(())
+    }
+
+    fn execute_line(&mut self) -> Result<()> {
+        let line = self.program.lines[self.current_line];
+
+        // Tokens after the last call would be popped and discarded straight away, and the last
+        // call would be popped right after them, so neither is pushed at all. They are still
+        // held to the limits as if they had been.
+        let push_end = line.last_call.unwrap_or(line.end);
+        for ip in line.start..push_end {
+            let instruction = self.program.code[ip];
+            self.push(self.program.materialize(instruction))?;
+        }
+        self.limits
+            .check_depth(self.machine.stack.len() + line.end - push_end)?;
+        for ip in push_end..line.end {
+            if let Instruction::Const(idx) = self.program.code[ip] {
+                let constant = &self.program.constants[idx as usize];
+                self.limits.check_number(constant)?;
+            }
+        }
+
+        if let Some(ip) = line.last_call {
+            let Instruction::Call(idx) = self.program.code[ip] else {
+                unreachable!("LineInfo::last_call must point at a call")
+            };
+            let func = self.program.functions[idx as usize].clone();
+            // Each skipped token is a step, and so is popping the call itself
+            self.count_steps(line.end - ip)?;
+            self.call(&func)?;
+        }
+
+        while let Some(&pos) = self.functor_positions.last() {
+            // Anything above the topmost functor would be popped and dropped one at a time
+            self.count_steps(self.machine.stack.len() - pos)?;
+            self.machine.stack.truncate(pos + 1);
+            self.functor_positions.pop();
+
+            let Some(Token::Functor(func)) = self.machine.stack.pop() else {
+                unreachable!("functor_positions out of sync with the stack")
+            };
+            self.machine.touch();
+            self.call(&func)?;
+        }
+
+        Ok(())
+    }
+
+    /// Counts and checks one step at a time, so the VM stops on the same step BufferedExecutor
+    /// would have
+    fn count_steps(&mut self, steps: usize) -> Result<()> {
+        for _ in 0..steps {
+            self.steps += 1;
+            self.limits.check_step(self.steps)?;
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, func: &Functor) -> Result<()> {
+        func.check_args(&self.machine.stack, &self.machine.context)?;
+        let res = (func.func)(&mut self.machine.stack, &mut self.machine.context);
+        self.machine.touch();
+
+        // Forget any functors the call consumed as arguments
+        let len = self.machine.stack.len();
+        while self.functor_positions.last().is_some_and(|&pos| pos >= len) {
+            self.functor_positions.pop();
+        }
+
+        for token in res? {
+            self.push(token)?;
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, token: Token) -> Result<()> {
+        self.limits.check_depth(self.machine.stack.len() + 1)?;
+        self.limits.check_token(&token)?;
+
+        if let Token::Functor(_) = token {
+            self.functor_positions.push(self.machine.stack.len());
+        }
+
+        self.machine.stack.push(token);
+        Ok(())
+    }
+}
+
+/// Stack indices holding a `Token::Functor`, in ascending order
+fn functor_positions(stack: &[Token]) -> Vec<usize> {
+    stack
+        .iter()
+        .enumerate()
+        .filter(|(_, token)| matches!(token, Token::Functor(_)))
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::parse_rpol_notation::{commands_to_sequential_exec_order, split_into_commands};
+    use crate::stack_machine::BufferedExecutor;
+
+    fn parse(script: &str) -> Vec<Vec<Token>> {
+        let commands = split_into_commands(script.to_string());
+        commands_to_sequential_exec_order(commands, &Context::pure_math()).unwrap()
+    }
+
+    /// Runs a script on both executors, checking that they end in the same state: the same
+    /// stack, variables and line, having taken the same number of steps. Returns whether they
+    /// failed.
+    fn run_both(script: &str, limits: ExecutionLimits) -> bool {
+        crate::set_debug_logging(false);
+        let lines = parse(script);
+
+        let machine = RevPolStackMachine::new_with_ctx(Context::pure_math());
+        let mut buffered =
+            BufferedExecutor::new_with_limits(machine, lines.clone(), limits.clone());
+        let buffered_failed = buffered.run_stack().is_err();
+
+        let machine = RevPolStackMachine::new_with_ctx(Context::pure_math());
+        let program = Program::compile(&lines).unwrap();
+        let mut vm = VirtualMachine::new_with_limits(machine, program, limits);
+        let vm_failed = vm.run().is_err();
+
+        assert_eq!(buffered_failed, vm_failed, "{}", script);
+        assert_eq!(buffered.machine.stack, vm.machine.stack, "{}", script);
+        assert_eq!(buffered.machine.context, vm.machine.context, "{}", script);
+        assert_eq!(buffered.current_line, vm.current_line, "{}", script);
+        assert_eq!(buffered.steps, vm.steps, "{}", script);
+        vm_failed
+    }
+
+    #[test]
+    fn matches_buffered_executor() {
+        let scripts = [
+            "1 2 +\n3 *",
+            "0\n1 +\n1 +\n1 +",
+            "10 4 -\n2 ^\n5 /",
+            "x 5 :=\nx 2 *\nx 3 :=\nx x *",
+            "1.5 2 *\n7 2 /\n2 sqrt",
+            "1 2 3\n+\n+",
+            // Tokens after the last call are popped and dropped, and count as steps
+            "1 2 + 3 4",
+            "2 3 * 5 6 7",
+        ];
+
+        for script in scripts {
+            assert!(
+                !run_both(script, ExecutionLimits::new()),
+                "{} failed",
+                script
+            );
+        }
+    }
+
+    #[test]
+    fn fails_like_buffered_executor() {
+        // The failed line is rolled back, so both stop with what the lines before it left
+        assert!(run_both("1 2 +\n1 0 /\n4 5 +", ExecutionLimits::new()));
+        assert!(run_both("x 2 :=\n1\n\"a\" x +", ExecutionLimits::new()));
+    }
+
+    #[test]
+    fn stops_at_the_same_limits() {
+        for max_steps in 0..8 {
+            let limits = ExecutionLimits {
+                max_steps: Some(max_steps),
+                ..ExecutionLimits::new()
+            };
+            run_both("1 2 + 3\n4 *\n5 +", limits);
+        }
+
+        let depth = ExecutionLimits {
+            max_stack_depth: Some(3),
+            ..ExecutionLimits::new()
+        };
+        assert!(run_both("1 2 + 3 4 5", depth));
+        let int_bits = ExecutionLimits {
+            max_int_bits: Some(64),
+            ..ExecutionLimits::new()
+        };
+        assert!(run_both("1 2 + 18446744073709551616", int_bits));
+    }
+
+    #[test]
+    fn stops_at_step_limit() {
+        let lines = parse("0\n1 +\n1 +\n1 +");
+        let limits = ExecutionLimits {
+            max_steps: Some(2),
+            ..ExecutionLimits::new()
+        };
+        let machine = RevPolStackMachine::new_with_ctx(Context::pure_math());
+        let program = Program::compile(&lines).unwrap();
+        let mut vm = VirtualMachine::new_with_limits(machine, program, limits);
+
+        let err = vm.run().unwrap_err();
+        assert!(err.downcast_ref::<crate::limits::LimitError>().is_some());
+    }
+}